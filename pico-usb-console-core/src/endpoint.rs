@@ -0,0 +1,157 @@
+//! Writing to a serial endpoint that may be temporarily full.
+
+use crate::log_queue::LogQueue;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EndpointError<E> {
+    /// The output buffer is full. The operation can be retried later.
+    WouldBlock,
+    Other(E),
+}
+
+/// Non-blocking serial output, e.g. a USB CDC port.
+pub trait SerialEndpoint {
+    type Error: core::fmt::Debug;
+
+    /// Add as many bytes as possible to the output buffer. Returns the number of bytes written.
+    fn write(&mut self, data: &[u8]) -> Result<usize, EndpointError<Self::Error>>;
+
+    /// Start sending the output buffer. Returns `WouldBlock` until it is fully sent.
+    fn flush(&mut self) -> Result<(), EndpointError<Self::Error>> {
+        Ok(())
+    }
+
+    /// If true, the output buffer won't be drained for a while, e.g. because the bus is suspended.
+    /// Instead of retrying, blocked writes are dropped.
+    fn suspended(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteOutcome {
+    Written,
+    /// The endpoint got suspended, the given number of bytes at the end were dropped.
+    Dropped(usize),
+}
+
+/// Write the whole buffer, retrying while the endpoint is full.
+pub fn write_all<E: SerialEndpoint>(
+    endpoint: &mut E,
+    data: &[u8],
+) -> Result<WriteOutcome, EndpointError<E::Error>> {
+    let mut bytes_to_send = data;
+
+    while !bytes_to_send.is_empty() {
+        match endpoint.write(bytes_to_send) {
+            Err(EndpointError::WouldBlock) if endpoint.suspended() => {
+                return Ok(WriteOutcome::Dropped(bytes_to_send.len()))
+            }
+
+            // Output buffer is full. Retry.
+            Err(EndpointError::WouldBlock) => (),
+
+            Err(e) => return Err(e),
+
+            Ok(written_size) => {
+                // Keep only the tail that hasn't been sent yet.
+                bytes_to_send = &bytes_to_send[written_size..];
+            }
+        }
+    }
+
+    Ok(WriteOutcome::Written)
+}
+
+/// Wait until the output buffer is sent. Returns immediately if the endpoint is suspended.
+pub fn flush_all<E: SerialEndpoint>(endpoint: &mut E) -> Result<(), EndpointError<E::Error>> {
+    loop {
+        match endpoint.flush() {
+            Ok(()) => return Ok(()),
+
+            Err(EndpointError::WouldBlock) if endpoint.suspended() => return Ok(()),
+
+            // Output buffer hasn't been fully flushed. Retry.
+            Err(EndpointError::WouldBlock) => {}
+
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write as much of the queue as fits into the endpoint without blocking.
+/// Returns true if the queue has been emptied.
+pub fn pump<const SIZE: usize, E: SerialEndpoint>(
+    queue: &mut LogQueue<SIZE>,
+    endpoint: &mut E,
+) -> bool {
+    while !queue.is_empty() {
+        match endpoint.write(queue.peek()) {
+            Ok(written_size) => queue.consume(written_size),
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockError, MockSerial};
+
+    #[test]
+    fn write_all_retries_partial_writes() {
+        let mut serial = MockSerial::new().with_chunk_size(3);
+        serial.block_next(2);
+
+        assert_eq!(write_all(&mut serial, b"Hello, world"), Ok(WriteOutcome::Written));
+        assert_eq!(serial.output(), b"Hello, world");
+    }
+
+    #[test]
+    fn write_all_drops_output_when_suspended() {
+        let mut serial = MockSerial::new().with_capacity(4);
+        serial.set_suspended(true);
+
+        assert_eq!(write_all(&mut serial, b"abcdef"), Ok(WriteOutcome::Dropped(2)));
+        assert_eq!(serial.output(), b"abcd");
+    }
+
+    #[test]
+    fn write_all_returns_errors() {
+        let mut serial = MockSerial::new();
+        serial.fail_next();
+
+        assert_eq!(
+            write_all(&mut serial, b"abc"),
+            Err(EndpointError::Other(MockError))
+        );
+    }
+
+    #[test]
+    fn flush_all_retries() {
+        let mut serial = MockSerial::new();
+        serial.block_next(3);
+
+        assert_eq!(flush_all(&mut serial), Ok(()));
+        assert_eq!(serial.flushes(), 4);
+    }
+
+    #[test]
+    fn pump_stops_when_full() {
+        let mut queue: LogQueue<16> = LogQueue::new();
+        // Wrap around the end of the queue.
+        queue.push_record(b"0123456789\n");
+        queue.consume(11);
+        queue.push_record(b"first\n");
+        queue.push_record(b"second\n");
+
+        let mut serial = MockSerial::new().with_capacity(8);
+        assert!(!pump(&mut queue, &mut serial));
+        assert_eq!(serial.output(), b"first\nse");
+
+        serial.drain();
+        assert!(pump(&mut queue, &mut serial));
+        assert_eq!(serial.output(), b"cond\n");
+    }
+}
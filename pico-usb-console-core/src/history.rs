@@ -0,0 +1,155 @@
+//! Ring buffer keeping the most recent log output.
+
+/// Keeps the last `SIZE` bytes written to it. Positions are counted from the start of the output
+/// and wrap around at `usize::MAX`.
+pub struct HistoryRing<const SIZE: usize> {
+    data: [u8; SIZE],
+    // Total number of bytes written.
+    end: usize,
+    // Index in `data` where the next byte goes, kept below SIZE so that it doesn't depend on how
+    // `end` wraps around.
+    next: usize,
+    // More than SIZE bytes have been written, so the oldest byte is at `next`.
+    overwritten: bool,
+}
+
+impl<const SIZE: usize> HistoryRing<SIZE> {
+    pub const fn new() -> Self {
+        HistoryRing {
+            data: [0; SIZE],
+            end: 0,
+            next: 0,
+            overwritten: false,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.overwritten |= self.end == SIZE;
+            self.data[self.next] = byte;
+            self.next += 1;
+            if self.next == SIZE {
+                self.next = 0;
+            }
+            self.end = self.end.wrapping_add(1);
+        }
+    }
+
+    fn len(&self) -> usize {
+        if self.overwritten {
+            SIZE
+        } else {
+            self.end
+        }
+    }
+
+    // Index in `data` of the byte `offset` bytes after the oldest one. `offset` is below `len()`.
+    fn index(&self, offset: usize) -> usize {
+        let start = if self.overwritten { self.next } else { 0 };
+        (start + offset) % SIZE
+    }
+
+    /// Position of the oldest byte still kept.
+    pub fn start_pos(&self) -> usize {
+        self.end.wrapping_sub(self.len())
+    }
+
+    /// Position right after the last written byte.
+    pub fn end_pos(&self) -> usize {
+        self.end
+    }
+
+    /// Position of the first complete line. Once the buffer has wrapped around, the oldest line
+    /// is usually cut.
+    pub fn first_line_pos(&self) -> usize {
+        let start = self.start_pos();
+        if !self.overwritten {
+            return start;
+        }
+        for offset in 0..SIZE {
+            if self.data[self.index(offset)] == b'\n' {
+                return start.wrapping_add(offset + 1);
+            }
+        }
+        start
+    }
+
+    /// Copy the bytes starting from `pos` into `out`. Returns the number of copied bytes, which is
+    /// 0 if `pos` has been overwritten or is at the end.
+    pub fn read_at(&self, pos: usize, out: &mut [u8]) -> usize {
+        let offset = pos.wrapping_sub(self.start_pos());
+        if offset >= self.len() {
+            return 0;
+        }
+
+        let size = core::cmp::min(out.len(), self.len() - offset);
+        for (i, byte) in out[..size].iter_mut().enumerate() {
+            *byte = self.data[self.index(offset + i)];
+        }
+        size
+    }
+}
+
+impl<const SIZE: usize> Default for HistoryRing<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump<const SIZE: usize>(history: &HistoryRing<SIZE>) -> String {
+        let mut out = Vec::new();
+        let mut pos = history.first_line_pos();
+        let mut buf = [0; 3];
+        loop {
+            let size = history.read_at(pos, &mut buf);
+            if size == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..size]);
+            pos = pos.wrapping_add(size);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn keeps_everything_until_full() {
+        let mut history: HistoryRing<16> = HistoryRing::new();
+        history.push(b"one\ntwo\n");
+        assert_eq!(dump(&history), "one\ntwo\n");
+    }
+
+    #[test]
+    fn keeps_complete_recent_lines() {
+        let mut history: HistoryRing<10> = HistoryRing::new();
+        history.push(b"one\ntwo\nthree\n");
+        assert_eq!(history.start_pos(), 4);
+        assert_eq!(dump(&history), "three\n");
+    }
+
+    #[test]
+    fn overwritten_positions_are_empty() {
+        let mut history: HistoryRing<4> = HistoryRing::new();
+        history.push(b"abcdef");
+
+        let mut buf = [0; 4];
+        assert_eq!(history.read_at(0, &mut buf), 0);
+        assert_eq!(history.read_at(3, &mut buf), 3);
+        assert_eq!(&buf[..3], b"def");
+        assert_eq!(history.read_at(6, &mut buf), 0);
+    }
+
+    #[test]
+    fn wraps_positions_around() {
+        let mut history: HistoryRing<10> = HistoryRing::new();
+        history.push(b"0123456789a");
+        history.end = usize::MAX - 5;
+        history.push(b"abc\ndefghij\n");
+        assert_eq!(history.end_pos(), 6);
+        assert_eq!(history.start_pos(), usize::MAX - 3);
+        assert_eq!(dump(&history), "defghij\n");
+    }
+}
@@ -0,0 +1,84 @@
+//! Hardware-independent part of pico-usb-console: formatting, queueing and writing of log records.
+//!
+//! Can be tested on the host:
+//!
+//! ```text
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+use core::fmt::Write as _;
+
+mod endpoint;
+mod history;
+mod line_reader;
+mod log_queue;
+#[cfg(any(test, feature = "std"))]
+pub mod mock;
+mod router;
+mod throttle;
+
+pub use endpoint::{flush_all, pump, write_all, EndpointError, SerialEndpoint, WriteOutcome};
+pub use history::HistoryRing;
+pub use line_reader::LineReader;
+pub use log_queue::{LineBuffer, LogQueue};
+pub use router::LogRouter;
+pub use throttle::{Throttle, ThrottleConfig};
+
+// Records longer than this are truncated.
+pub const MAX_RECORD_SIZE: usize = 256;
+
+/// Whether a record with the given metadata should be logged.
+pub fn enabled(metadata: &log::Metadata) -> bool {
+    metadata.level() <= log::Level::Info
+}
+
+/// Format a log record as a single line, ending with a newline.
+pub fn format_record(record: &log::Record) -> LineBuffer<MAX_RECORD_SIZE> {
+    let mut line = LineBuffer::new();
+    writeln!(&mut line, "{}", record.args()).ok();
+    line.finish_line();
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_line(args: core::fmt::Arguments) -> LineBuffer<MAX_RECORD_SIZE> {
+        format_record(&log::Record::builder().args(args).build())
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let info = log::Metadata::builder().level(log::Level::Info).build();
+        let debug = log::Metadata::builder().level(log::Level::Debug).build();
+
+        assert!(enabled(&info));
+        assert!(!enabled(&debug));
+    }
+
+    #[test]
+    fn formats_record_as_line() {
+        let line = record_line(format_args!("Hello {}", 42));
+        assert_eq!(line.as_bytes(), b"Hello 42\n");
+    }
+
+    #[test]
+    fn truncates_long_records() {
+        let long = "x".repeat(2 * MAX_RECORD_SIZE);
+        let line = record_line(format_args!("{long}"));
+
+        assert_eq!(line.len(), MAX_RECORD_SIZE);
+        assert!(line.as_bytes().ends_with(b"x\n"));
+    }
+
+    #[test]
+    fn truncates_at_char_boundary() {
+        let mut line: LineBuffer<8> = LineBuffer::new();
+        line.write_str("abcdeфж").ok();
+        line.finish_line();
+
+        assert_eq!(line.as_bytes(), "abcdeф\n".as_bytes());
+    }
+}
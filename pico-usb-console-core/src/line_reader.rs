@@ -0,0 +1,83 @@
+//! Splitting of the input received from the host into lines.
+
+pub struct LineReader<const SIZE: usize> {
+    data: [u8; SIZE],
+    len: usize,
+    // The current line is longer than the buffer, and will be dropped.
+    overflow: bool,
+}
+
+impl<const SIZE: usize> LineReader<SIZE> {
+    pub const fn new() -> Self {
+        LineReader {
+            data: [0; SIZE],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Add a received byte. Returns the line when the byte terminates it. Both "\r" and "\n" are
+    /// accepted as line terminators, and empty lines are skipped, so "\r\n" works as well.
+    /// Lines that don't fit in the buffer or aren't valid UTF-8 are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<&str> {
+        if byte == b'\r' || byte == b'\n' {
+            let len = self.len;
+            let overflow = self.overflow;
+            self.len = 0;
+            self.overflow = false;
+
+            if len == 0 || overflow {
+                return None;
+            }
+            return core::str::from_utf8(&self.data[..len]).ok();
+        }
+
+        if self.len == SIZE {
+            self.overflow = true;
+        } else {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+        None
+    }
+}
+
+impl<const SIZE: usize> Default for LineReader<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines<const SIZE: usize>(reader: &mut LineReader<SIZE>, input: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &byte in input {
+            if let Some(line) = reader.push(byte) {
+                lines.push(line.to_string());
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn splits_lines() {
+        let mut reader: LineReader<16> = LineReader::new();
+        assert_eq!(lines(&mut reader, b"r\r\ns\nstats\rpartial"), ["r", "s", "stats"]);
+        assert_eq!(lines(&mut reader, b" line\n"), ["partial line"]);
+    }
+
+    #[test]
+    fn drops_long_lines() {
+        let mut reader: LineReader<4> = LineReader::new();
+        assert_eq!(lines(&mut reader, b"12345\nabcd\n"), ["abcd"]);
+    }
+
+    #[test]
+    fn drops_invalid_utf8() {
+        let mut reader: LineReader<4> = LineReader::new();
+        assert_eq!(lines(&mut reader, b"\xff\xfe\nok\n"), ["ok"]);
+    }
+}
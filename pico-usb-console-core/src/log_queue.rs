@@ -0,0 +1,225 @@
+//! Queue of formatted log records waiting to be sent to the USB serial port.
+
+use core::fmt;
+
+pub struct LogQueue<const SIZE: usize> {
+    data: [u8; SIZE],
+    // Index of the first byte in the queue.
+    start: usize,
+    len: usize,
+    // Number of records that didn't fit in the queue since the last successful push.
+    dropped: u32,
+}
+
+impl<const SIZE: usize> LogQueue<SIZE> {
+    pub const fn new() -> Self {
+        LogQueue {
+            data: [0; SIZE],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn free_space(&self) -> usize {
+        SIZE - self.len
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        debug_assert!(bytes.len() <= self.free_space());
+        for &byte in bytes {
+            self.data[(self.start + self.len) % SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Add a record to the queue. The record is either added completely, or dropped if there is
+    /// not enough space for it. In the latter case, a note with the number of dropped records will
+    /// be added before the next record that fits.
+    pub fn push_record(&mut self, record: &[u8]) -> bool {
+        if self.dropped > 0 {
+            let mut note: LineBuffer<48> = LineBuffer::new();
+            fmt::Write::write_fmt(
+                &mut note,
+                format_args!("[{} log records dropped]\n", self.dropped),
+            )
+            .ok();
+            if note.len() + record.len() > self.free_space() {
+                self.dropped += 1;
+                return false;
+            }
+            self.push_bytes(note.as_bytes());
+            self.dropped = 0;
+        }
+
+        if record.len() > self.free_space() {
+            self.dropped += 1;
+            return false;
+        }
+
+        self.push_bytes(record);
+        true
+    }
+
+    /// Drop everything in the queue, including the count of dropped records.
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+        self.dropped = 0;
+    }
+
+    /// The longest contiguous slice from the beginning of the queue.
+    pub fn peek(&self) -> &[u8] {
+        let end = core::cmp::min(self.start + self.len, SIZE);
+        &self.data[self.start..end]
+    }
+
+    /// Remove `n` bytes from the beginning of the queue.
+    pub fn consume(&mut self, n: usize) {
+        let n = core::cmp::min(n, self.len);
+        self.start = (self.start + n) % SIZE;
+        self.len -= n;
+    }
+}
+
+impl<const SIZE: usize> Default for LogQueue<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fixed-size buffer for formatting a single line. Output that doesn't fit is truncated.
+pub struct LineBuffer<const SIZE: usize> {
+    data: [u8; SIZE],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const SIZE: usize> LineBuffer<SIZE> {
+    pub fn new() -> Self {
+        LineBuffer {
+            data: [0; SIZE],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Make sure that the line ends with a newline, even if it was truncated.
+    pub fn finish_line(&mut self) {
+        if self.len > 0 && self.data[self.len - 1] == b'\n' {
+            return;
+        }
+        if self.len == SIZE {
+            // Back off to a character boundary.
+            self.len -= 1;
+            while self.len > 0 && self.data[self.len] & 0xC0 == 0x80 {
+                self.len -= 1;
+            }
+        }
+        self.data[self.len] = b'\n';
+        self.len += 1;
+    }
+}
+
+impl<const SIZE: usize> Default for LineBuffer<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> fmt::Write for LineBuffer<SIZE> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Ok(());
+        }
+
+        let mut n = core::cmp::min(s.len(), SIZE - self.len);
+        if n < s.len() {
+            self.truncated = true;
+            while !s.is_char_boundary(n) {
+                n -= 1;
+            }
+        }
+
+        self.data[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pop_all<const SIZE: usize>(queue: &mut LogQueue<SIZE>) -> Vec<u8> {
+        let mut out = Vec::new();
+        while !queue.is_empty() {
+            let chunk = queue.peek().to_vec();
+            queue.consume(chunk.len());
+            out.extend_from_slice(&chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn keeps_records_in_order() {
+        let mut queue: LogQueue<32> = LogQueue::new();
+        assert!(queue.push_record(b"one\n"));
+        assert!(queue.push_record(b"two\n"));
+
+        assert_eq!(pop_all(&mut queue), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut queue: LogQueue<8> = LogQueue::new();
+        assert!(queue.push_record(b"12345\n"));
+        queue.consume(4);
+        assert!(queue.push_record(b"abcd\n"));
+
+        // The first slice ends at the end of the buffer.
+        assert_eq!(queue.peek(), b"5\nab");
+        assert_eq!(pop_all(&mut queue), b"5\nabcd\n");
+    }
+
+    #[test]
+    fn drops_whole_records_and_reports_them() {
+        let mut queue: LogQueue<36> = LogQueue::new();
+        assert!(queue.push_record(b"0123456789012345678901234567890\n"));
+        assert!(!queue.push_record(b"dropped\n"));
+        assert!(!queue.push_record(b"dropped too\n"));
+        assert_eq!(pop_all(&mut queue), b"0123456789012345678901234567890\n");
+
+        assert!(queue.push_record(b"next\n"));
+        assert_eq!(pop_all(&mut queue), b"[2 log records dropped]\nnext\n");
+    }
+
+    #[test]
+    fn clear_forgets_dropped_records() {
+        let mut queue: LogQueue<8> = LogQueue::new();
+        assert!(queue.push_record(b"stale\n"));
+        assert!(!queue.push_record(b"dropped\n"));
+        queue.clear();
+
+        assert!(queue.is_empty());
+        assert!(queue.push_record(b"fresh\n"));
+        assert_eq!(pop_all(&mut queue), b"fresh\n");
+    }
+}
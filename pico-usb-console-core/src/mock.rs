@@ -0,0 +1,133 @@
+//! Serial endpoint for host-side tests, simulating a buffer of limited size that is drained by the
+//! host.
+
+use crate::endpoint::{EndpointError, SerialEndpoint};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MockError;
+
+pub struct MockSerial {
+    output: Vec<u8>,
+    // Maximum number of bytes in the output buffer before the host drains it.
+    capacity: Option<usize>,
+    // Maximum number of bytes accepted by a single write.
+    chunk_size: Option<usize>,
+    blocked_calls: usize,
+    fail_next: bool,
+    suspended: bool,
+    writes: usize,
+    flushes: usize,
+}
+
+impl MockSerial {
+    pub fn new() -> Self {
+        MockSerial {
+            output: Vec::new(),
+            capacity: None,
+            chunk_size: None,
+            blocked_calls: 0,
+            fail_next: false,
+            suspended: false,
+            writes: 0,
+            flushes: 0,
+        }
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// The next `n` writes or flushes return `WouldBlock`.
+    pub fn block_next(&mut self, n: usize) {
+        self.blocked_calls = n;
+    }
+
+    /// The next write or flush fails with `MockError`.
+    pub fn fail_next(&mut self) {
+        self.fail_next = true;
+    }
+
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.suspended = suspended;
+    }
+
+    /// Bytes written since the last `drain()`.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn output_str(&self) -> &str {
+        core::str::from_utf8(&self.output).unwrap()
+    }
+
+    /// Simulate the host reading the output buffer.
+    pub fn drain(&mut self) {
+        self.output.clear();
+    }
+
+    /// Number of calls to `write`, including the failed ones.
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Number of calls to `flush`, including the failed ones.
+    pub fn flushes(&self) -> usize {
+        self.flushes
+    }
+
+    fn check_call(&mut self) -> Result<(), EndpointError<MockError>> {
+        if self.fail_next {
+            self.fail_next = false;
+            return Err(EndpointError::Other(MockError));
+        }
+        if self.blocked_calls > 0 {
+            self.blocked_calls -= 1;
+            return Err(EndpointError::WouldBlock);
+        }
+        Ok(())
+    }
+}
+
+impl Default for MockSerial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialEndpoint for MockSerial {
+    type Error = MockError;
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, EndpointError<MockError>> {
+        self.writes += 1;
+        self.check_call()?;
+
+        let mut size = data.len();
+        if let Some(chunk_size) = self.chunk_size {
+            size = size.min(chunk_size);
+        }
+        if let Some(capacity) = self.capacity {
+            size = size.min(capacity - self.output.len());
+        }
+        if size == 0 && !data.is_empty() {
+            return Err(EndpointError::WouldBlock);
+        }
+
+        self.output.extend_from_slice(&data[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<(), EndpointError<MockError>> {
+        self.flushes += 1;
+        self.check_call()
+    }
+
+    fn suspended(&self) -> bool {
+        self.suspended
+    }
+}
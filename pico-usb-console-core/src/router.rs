@@ -0,0 +1,114 @@
+//! Mapping of log targets to output ports.
+
+/// Routing table from log target patterns to port numbers. Records with targets not matching any
+/// pattern go to port 0.
+///
+/// A pattern ending with `*` matches all the targets starting with the rest of the pattern, so
+/// `wifi::*` matches `wifi::scan` but not `wifi`. Other patterns match the module itself and all
+/// its submodules, so `wifi` matches both `wifi` and `wifi::scan`. The longest matching pattern
+/// wins.
+pub struct LogRouter<const SIZE: usize> {
+    routes: [Option<(&'static str, u8)>; SIZE],
+}
+
+fn matches(pattern: &str, target: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => target.starts_with(prefix),
+        None => {
+            target == pattern
+                || (target.starts_with(pattern) && target[pattern.len()..].starts_with("::"))
+        }
+    }
+}
+
+impl<const SIZE: usize> LogRouter<SIZE> {
+    pub const fn new() -> Self {
+        LogRouter {
+            routes: [None; SIZE],
+        }
+    }
+
+    /// Add a route, replacing the existing one with the same pattern.
+    /// Returns false if the table is full.
+    pub fn add(&mut self, pattern: &'static str, port: u8) -> bool {
+        if let Some(route) = self
+            .routes
+            .iter_mut()
+            .flatten()
+            .find(|(existing, _)| *existing == pattern)
+        {
+            route.1 = port;
+            return true;
+        }
+
+        match self.routes.iter_mut().find(|route| route.is_none()) {
+            Some(route) => {
+                *route = Some((pattern, port));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.routes = [None; SIZE];
+    }
+
+    /// The port for the given log target.
+    pub fn route(&self, target: &str) -> u8 {
+        self.routes
+            .iter()
+            .flatten()
+            .filter(|(pattern, _)| matches(pattern, target))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map_or(0, |(_, port)| *port)
+    }
+}
+
+impl<const SIZE: usize> Default for LogRouter<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_by_prefix() {
+        let mut router: LogRouter<4> = LogRouter::new();
+        assert!(router.add("wifi::*", 1));
+        assert!(router.add("app", 2));
+
+        assert_eq!(router.route("wifi::scan"), 1);
+        assert_eq!(router.route("wifi"), 0);
+        assert_eq!(router.route("app"), 2);
+        assert_eq!(router.route("app::net"), 2);
+        assert_eq!(router.route("apple"), 0);
+        assert_eq!(router.route("main"), 0);
+    }
+
+    #[test]
+    fn longest_pattern_wins() {
+        let mut router: LogRouter<4> = LogRouter::new();
+        router.add("wifi", 1);
+        router.add("wifi::spi", 0);
+
+        assert_eq!(router.route("wifi::scan"), 1);
+        assert_eq!(router.route("wifi::spi::trace"), 0);
+    }
+
+    #[test]
+    fn replaces_routes_and_reports_full_table() {
+        let mut router: LogRouter<1> = LogRouter::new();
+        assert!(router.add("wifi", 1));
+        assert!(router.add("wifi", 2));
+        assert!(!router.add("app", 1));
+
+        assert_eq!(router.route("wifi"), 2);
+
+        router.clear();
+        assert_eq!(router.route("wifi"), 0);
+    }
+}
@@ -0,0 +1,192 @@
+//! Suppression of repeated log records and of log floods.
+
+use core::fmt::Write as _;
+
+use crate::log_queue::LineBuffer;
+use crate::MAX_RECORD_SIZE;
+
+const WINDOW_US: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleConfig {
+    /// Collapse identical consecutive records into "last message repeated N times".
+    pub collapse_duplicates: bool,
+    /// Maximum number of bytes of log output per second. The records exceeding the budget are
+    /// dropped.
+    pub bytes_per_sec: Option<u32>,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            collapse_duplicates: true,
+            // About 1% of the USB full speed bandwidth.
+            bytes_per_sec: Some(16 * 1024),
+        }
+    }
+}
+
+pub struct Throttle {
+    config: ThrottleConfig,
+    // Last emitted record, truncated to MAX_RECORD_SIZE, and its full length. None if no record
+    // has been emitted yet.
+    last: [u8; MAX_RECORD_SIZE],
+    last_len: Option<usize>,
+    repeats: u32,
+    window_start_us: u32,
+    window_bytes: u32,
+    suppressed_records: u32,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle {
+            config,
+            last: [0; MAX_RECORD_SIZE],
+            last_len: None,
+            repeats: 0,
+            window_start_us: 0,
+            window_bytes: 0,
+            suppressed_records: 0,
+        }
+    }
+
+    /// Process a formatted record. Calls `emit` with the lines that should be logged: the record
+    /// itself and possibly notes about the suppressed records before it. `now_us` is a wrapping
+    /// microsecond timestamp.
+    pub fn process<F: FnMut(&[u8])>(&mut self, record: &[u8], now_us: u32, mut emit: F) {
+        if now_us.wrapping_sub(self.window_start_us) >= WINDOW_US {
+            self.window_start_us = now_us;
+            self.window_bytes = 0;
+        }
+
+        if self.config.collapse_duplicates && self.is_last(record) {
+            self.repeats += 1;
+            return;
+        }
+
+        let mut note: LineBuffer<64> = LineBuffer::new();
+        if self.repeats > 0 {
+            writeln!(&mut note, "[last message repeated {} times]", self.repeats).ok();
+        }
+        if self.suppressed_records > 0 {
+            writeln!(&mut note, "[{} log records suppressed]", self.suppressed_records).ok();
+        }
+
+        // The notes are short and are not counted against the budget, otherwise with a small
+        // budget they could prevent any record from getting through.
+        if let Some(budget) = self.config.bytes_per_sec {
+            let size = record.len() as u32;
+            if self.window_bytes + size > budget {
+                // The repeat count is kept, so that it is reported before the next record.
+                self.suppressed_records += 1;
+                return;
+            }
+            self.window_bytes += size;
+        }
+
+        self.repeats = 0;
+        self.suppressed_records = 0;
+        if self.config.collapse_duplicates {
+            self.set_last(record);
+        }
+
+        if !note.is_empty() {
+            emit(note.as_bytes());
+        }
+        emit(record);
+    }
+
+    fn is_last(&self, record: &[u8]) -> bool {
+        let n = core::cmp::min(record.len(), MAX_RECORD_SIZE);
+        self.last_len == Some(record.len()) && self.last[..n] == record[..n]
+    }
+
+    fn set_last(&mut self, record: &[u8]) {
+        let n = core::cmp::min(record.len(), MAX_RECORD_SIZE);
+        self.last[..n].copy_from_slice(&record[..n]);
+        self.last_len = Some(record.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(throttle: &mut Throttle, records: &[(&str, u32)]) -> String {
+        let mut out = String::new();
+        for (record, now_us) in records {
+            throttle.process(record.as_bytes(), *now_us, |bytes| {
+                out.push_str(core::str::from_utf8(bytes).unwrap())
+            });
+        }
+        out
+    }
+
+    #[test]
+    fn collapses_duplicates() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            collapse_duplicates: true,
+            bytes_per_sec: None,
+        });
+
+        let out = run(
+            &mut throttle,
+            &[("a\n", 0), ("a\n", 1), ("a\n", 2), ("b\n", 3), ("a\n", 4)],
+        );
+        assert_eq!(out, "a\n[last message repeated 2 times]\nb\na\n");
+    }
+
+    #[test]
+    fn keeps_duplicates_when_disabled() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            collapse_duplicates: false,
+            bytes_per_sec: None,
+        });
+
+        assert_eq!(run(&mut throttle, &[("a\n", 0), ("a\n", 1)]), "a\na\n");
+    }
+
+    #[test]
+    fn limits_bytes_per_second() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            collapse_duplicates: false,
+            bytes_per_sec: Some(6),
+        });
+
+        let out = run(
+            &mut throttle,
+            &[("one\n", 0), ("two\n", 10), ("three\n", 20), ("four\n", WINDOW_US)],
+        );
+        assert_eq!(out, "one\n[2 log records suppressed]\nfour\n");
+    }
+
+    #[test]
+    fn compares_dropped_record_with_last_emitted() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            collapse_duplicates: true,
+            bytes_per_sec: Some(6),
+        });
+
+        // "two" is dropped, so the second "two" isn't a repeat of what has been logged.
+        let out = run(
+            &mut throttle,
+            &[("one\n", 0), ("two\n", 10), ("two\n", WINDOW_US)],
+        );
+        assert_eq!(out, "one\n[1 log records suppressed]\ntwo\n");
+    }
+
+    #[test]
+    fn handles_timer_wraparound() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            collapse_duplicates: false,
+            bytes_per_sec: Some(4),
+        });
+
+        let out = run(
+            &mut throttle,
+            &[("one\n", u32::MAX - 10), ("two\n", 100)],
+        );
+        assert_eq!(out, "one\n");
+    }
+}
@@ -0,0 +1,120 @@
+//! USB DFU 1.1 runtime interface. It doesn't implement the firmware download itself: on
+//! DFU_DETACH the device reboots into the ROM bootloader, which exposes the UF2 mass storage
+//! drive and the PICOBOOT interface.
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::Result;
+
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xfe;
+const USB_SUBCLASS_DFU: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+
+const DFU_FUNCTIONAL: u8 = 0x21;
+// The device detaches itself after DFU_DETACH, without waiting for a bus reset.
+const DFU_WILL_DETACH: u8 = 0x08;
+const DFU_DETACH_TIMEOUT_MS: u16 = 1000;
+const DFU_TRANSFER_SIZE: u16 = 64;
+
+const DFU_DETACH: u8 = 0;
+const DFU_GET_STATUS: u8 = 3;
+const DFU_GET_STATE: u8 = 5;
+
+const DFU_STATE_APP_IDLE: u8 = 0;
+
+// Time given to the host to complete the DETACH control transfer before the reboot.
+const DETACH_DELAY_US: u32 = 10_000;
+
+pub struct DfuRuntimeClass {
+    interface: InterfaceNumber,
+    detach_requested: bool,
+}
+
+impl DfuRuntimeClass {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+        DfuRuntimeClass {
+            interface: alloc.interface(),
+            detach_requested: false,
+        }
+    }
+
+    /// Whether the host has sent DFU_DETACH.
+    pub fn detach_requested(&self) -> bool {
+        self.detach_requested
+    }
+
+    fn is_our_request(&self, request_type: RequestType, recipient: Recipient, index: u16) -> bool {
+        request_type == RequestType::Class
+            && recipient == Recipient::Interface
+            && index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntimeClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            USB_CLASS_APPLICATION_SPECIFIC,
+            USB_SUBCLASS_DFU,
+            DFU_PROTOCOL_RUNTIME,
+        )?;
+        writer.write(
+            DFU_FUNCTIONAL,
+            &[
+                DFU_WILL_DETACH,
+                (DFU_DETACH_TIMEOUT_MS & 0xff) as u8,
+                (DFU_DETACH_TIMEOUT_MS >> 8) as u8,
+                (DFU_TRANSFER_SIZE & 0xff) as u8,
+                (DFU_TRANSFER_SIZE >> 8) as u8,
+                0x10,
+                0x01, // bcdDFUVersion 1.1
+            ],
+        )
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_our_request(req.request_type, req.recipient, req.index) {
+            return;
+        }
+
+        match req.request {
+            DFU_DETACH => {
+                self.detach_requested = true;
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_our_request(req.request_type, req.recipient, req.index) {
+            return;
+        }
+
+        match req.request {
+            // bStatus OK, bwPollTimeout 0, bState, iString.
+            DFU_GET_STATUS => xfer.accept_with(&[0, 0, 0, 0, DFU_STATE_APP_IDLE, 0]).ok(),
+            DFU_GET_STATE => xfer.accept_with(&[DFU_STATE_APP_IDLE]).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+}
+
+/// Reboot into the USB bootloader in ROM. Waits a bit first, so that the status stage of the
+/// DETACH request gets to the host.
+pub fn reboot_to_bootloader() -> ! {
+    let start = crate::now_us();
+    while crate::now_us().wrapping_sub(start) < DETACH_DELAY_US {}
+
+    unsafe {
+        // reset_to_usb_boot(gpio_activity_pin_mask, disable_interface_mask). Both interfaces of
+        // the bootloader stay enabled, and no activity LED is used.
+        let reset_to_usb_boot: extern "C" fn(u32, u32) -> ! =
+            core::mem::transmute(crate::unique_id::rom_func(b"UB"));
+        reset_to_usb_boot(0, 0)
+    }
+}
@@ -0,0 +1,43 @@
+//! HardFault handler writing a crash report to the console.
+//!
+//! Cortex-M0+ doesn't have the configurable fault status registers of the bigger cores, so the
+//! report consists of the registers stacked on exception entry, plus ICSR and SHCSR.
+
+use core::fmt::Write as _;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use pico_usb_console_core::LineBuffer;
+
+#[exception]
+unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
+    let scb = &*SCB::PTR;
+
+    let mut report: LineBuffer<320> = LineBuffer::new();
+    write!(
+        &mut report,
+        "\r\nHardFault at PC={:#010x}\r\n\
+         R0={:#010x} R1={:#010x} R2={:#010x} R3={:#010x}\r\n\
+         R12={:#010x} LR={:#010x} xPSR={:#010x}\r\n\
+         ICSR={:#010x} SHCSR={:#010x}\r\n",
+        ef.pc(),
+        ef.r0(),
+        ef.r1(),
+        ef.r2(),
+        ef.r3(),
+        ef.r12(),
+        ef.lr(),
+        ef.xpsr(),
+        scb.icsr.read(),
+        scb.shcsr.read(),
+    )
+    .ok();
+
+    if crate::usb_manager_initialized() {
+        crate::write_polling(report.as_bytes());
+    }
+
+    #[cfg(feature = "panic")]
+    crate::panic_behavior::halt();
+    #[cfg(not(feature = "panic"))]
+    loop {}
+}
@@ -1,48 +1,345 @@
 #![no_std]
 
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::fmt::Write as _;
 use core::panic::PanicInfo;
+use pico_usb_console_core::{
+    self as console_core, EndpointError, HistoryRing, LineReader, LogQueue, LogRouter,
+    SerialEndpoint, Throttle,
+};
 use rp2040_hal as hal;
 use rp2040_hal::{pac::interrupt, usb::UsbBus};
 use usb_device::{
     bus::UsbBusAllocator,
-    device::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+    class::UsbClass,
+    device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid},
 };
 use usbd_serial::{SerialPort, UsbError};
 
+#[cfg(feature = "dfu")]
+mod dfu;
+#[cfg(feature = "hard-fault")]
+mod hard_fault;
+#[cfg(feature = "midi")]
+mod midi;
+#[cfg(feature = "panic")]
+mod panic_behavior;
+mod unique_id;
+
+#[cfg(feature = "midi")]
+pub use midi::MidiMessage;
+#[cfg(feature = "panic")]
+pub use panic_behavior::{set_panic_led, set_panic_reboot};
+pub use pico_usb_console_core::{LogRouter, ThrottleConfig};
+pub use usbd_serial::{ParityType, StopBits};
+
+const LOG_QUEUE_SIZE: usize = 2048;
+const MAX_LOG_ROUTES: usize = 8;
+const LOG_HISTORY_SIZE: usize = 4096;
+const DEFAULT_RECONNECT_BANNER: &str = "\r\n--- console reconnected ---\r\n";
+
+/// Number of USB serial ports. Port 0 is the main console, the others can only receive log
+/// records routed to them with `set_log_route`.
+#[cfg(not(feature = "multi-port"))]
+pub const NUM_PORTS: usize = 1;
+#[cfg(feature = "multi-port")]
+pub const NUM_PORTS: usize = 2;
+
+/// Maximum number of application classes polled by the console, see
+/// `init_usb_manager_with_classes`.
+pub const MAX_USER_CLASSES: usize = 4;
+// Serial ports, MIDI, DFU and the application classes.
+const MAX_CLASSES: usize = NUM_PORTS + 2 + MAX_USER_CLASSES;
+// Received lines longer than this are dropped.
+const MAX_INPUT_LINE_SIZE: usize = 128;
+
+/// Function giving the USB interrupt access to the application classes. It has to borrow the
+/// classes, typically from a static `Mutex`, and pass them to the provided callback.
+pub type PollUserClasses = fn(&mut dyn FnMut(&mut [&mut dyn UsbClass<UsbBus>]));
+
+/// Serial port settings requested by the host, and the state of the control lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineState {
+    pub data_rate: u32,
+    pub data_bits: u8,
+    pub parity: ParityType,
+    pub stop_bits: StopBits,
+    pub dtr: bool,
+    pub rts: bool,
+}
+
 struct UsbManager {
     device: UsbDevice<'static, UsbBus>,
-    serial: SerialPort<'static, UsbBus>,
+    serials: [SerialPort<'static, UsbBus>; NUM_PORTS],
+    #[cfg(feature = "midi")]
+    midi: midi::MidiClass<'static, UsbBus>,
+    #[cfg(feature = "dfu")]
+    dfu: dfu::DfuRuntimeClass,
+    poll_user_classes: Option<PollUserClasses>,
+    suspended: bool,
+    line_state: LineState,
+    // The host terminal has dropped DTR on the main console and hasn't reconnected yet. Output is
+    // dropped in the meantime, so that the reconnecting terminal doesn't get stale data.
+    disconnected: bool,
 }
 
 impl UsbManager {
-    fn new(alloc: &'static UsbBusAllocator<UsbBus>) -> Self {
-        let serial = usbd_serial::SerialPort::new(alloc);
-
-        let device = UsbDeviceBuilder::new(alloc, UsbVidPid(0x2E8A, 0x000a))
+    fn new<F>(
+        alloc: &'static UsbBusAllocator<UsbBus>,
+        serial_number: &'static str,
+        build_user_classes: Option<F>,
+    ) -> Self
+    where
+        F: FnOnce(&'static UsbBusAllocator<UsbBus>) -> PollUserClasses,
+    {
+        let serials = core::array::from_fn(|_| usbd_serial::SerialPort::new(alloc));
+        #[cfg(feature = "midi")]
+        let midi = midi::MidiClass::new(alloc);
+        #[cfg(feature = "dfu")]
+        let dfu = dfu::DfuRuntimeClass::new(alloc);
+        // The application classes have to be allocated before the device is built.
+        let poll_user_classes = build_user_classes.map(|build| build(alloc));
+
+        let builder = UsbDeviceBuilder::new(alloc, UsbVidPid(0x2E8A, 0x000a))
             .manufacturer("Raspberry Pi")
             .product("Pico")
-            .serial_number("TEST")
-            .device_class(2)
-            .device_protocol(1)
-            .build();
+            .serial_number(serial_number)
+            .supports_remote_wakeup(true);
+
+        // With more than one function, the device has to be declared as a composite one, using
+        // interface association descriptors.
+        let composite = cfg!(any(feature = "dfu", feature = "midi", feature = "multi-port"))
+            || poll_user_classes.is_some();
+        let builder = if composite {
+            builder
+                .device_class(0xEF)
+                .device_sub_class(0x02)
+                .device_protocol(0x01)
+        } else {
+            builder.device_class(2).device_protocol(1)
+        };
+
+        let device = builder.build();
+
+        UsbManager {
+            device,
+            serials,
+            #[cfg(feature = "midi")]
+            midi,
+            #[cfg(feature = "dfu")]
+            dfu,
+            poll_user_classes,
+            suspended: false,
+            disconnected: false,
+            line_state: LineState {
+                data_rate: 0,
+                data_bits: 0,
+                parity: ParityType::None,
+                stop_bits: StopBits::One,
+                dtr: false,
+                rts: false,
+            },
+        }
+    }
 
-        UsbManager { device, serial }
+    // The main console port.
+    fn serial(&mut self) -> &mut SerialPort<'static, UsbBus> {
+        &mut self.serials[0]
+    }
+
+    fn current_line_state(&self) -> LineState {
+        let serial = &self.serials[0];
+        let coding = serial.line_coding();
+        LineState {
+            data_rate: coding.data_rate(),
+            data_bits: coding.data_bits(),
+            parity: coding.parity_type(),
+            stop_bits: coding.stop_bits(),
+            dtr: serial.dtr(),
+            rts: serial.rts(),
+        }
+    }
+
+    // Returns the new line state if the host has changed it since the last call.
+    fn update_line_state(&mut self) -> Option<LineState> {
+        let line_state = self.current_line_state();
+        if line_state == self.line_state {
+            return None;
+        }
+
+        if self.line_state.dtr && !line_state.dtr {
+            self.disconnected = true;
+        } else if !self.line_state.dtr && line_state.dtr && self.disconnected {
+            self.disconnected = false;
+            // Drop whatever was logged while nobody was listening, and separate the new output
+            // from a partial line that might still be in the serial port buffer.
+            let banner = cortex_m::interrupt::free(|cs| RECONNECT_BANNER.borrow(cs).get());
+            with_log_queues(|queues| {
+                queues[0].clear();
+                queues[0].push_record(banner.as_bytes());
+            });
+        }
+
+        self.line_state = line_state;
+        Some(line_state)
+    }
+
+    // Output to the main console is dropped instead of blocking.
+    fn dropping_output(&self) -> bool {
+        self.suspended || self.disconnected
     }
 
     unsafe fn interrupt(&mut self) {
-        if self.device.poll(&mut [&mut self.serial]) {}
+        match self.poll_user_classes {
+            Some(poll_user_classes) => {
+                poll_user_classes(&mut |user_classes| self.poll(user_classes))
+            }
+            None => self.poll(&mut []),
+        }
+
+        #[cfg(feature = "dfu")]
+        if self.dfu.detach_requested() {
+            dfu::reboot_to_bootloader();
+        }
+
+        // The device goes into the Suspend state when the host stops sending SOFs for 3 ms and
+        // returns to its previous state on resume.
+        self.suspended = self.device.state() == UsbDeviceState::Suspend;
+    }
+
+    // Application classes beyond MAX_USER_CLASSES are not polled.
+    fn poll(&mut self, user_classes: &mut [&mut dyn UsbClass<UsbBus>]) {
+        let mut classes: heapless::Vec<&mut dyn UsbClass<UsbBus>, MAX_CLASSES> =
+            heapless::Vec::new();
+        for serial in self.serials.iter_mut() {
+            classes.push(serial).ok();
+        }
+        #[cfg(feature = "midi")]
+        classes.push(&mut self.midi).ok();
+        #[cfg(feature = "dfu")]
+        classes.push(&mut self.dfu).ok();
+        for class in user_classes.iter_mut() {
+            classes.push(&mut **class).ok();
+        }
+
+        if self.device.poll(&mut classes) {}
+    }
+
+    // Write as much of the queued log output as fits into the serial port buffers without
+    // blocking. Returns true if the queue of the main console has been emptied. Other ports are
+    // not waited for, so that logging doesn't block if nobody listens on them.
+    fn pump(&mut self) -> bool {
+        with_log_queues(|queues| {
+            let mut main_empty = true;
+            for (port, (queue, serial)) in
+                queues.iter_mut().zip(self.serials.iter_mut()).enumerate()
+            {
+                let empty = console_core::pump(queue, &mut BorrowedSerial(serial));
+                if port == 0 {
+                    main_empty = empty;
+                }
+            }
+            main_empty
+        })
     }
 
     fn ready(&self) -> bool {
-        self.serial.dtr() && self.serial.rts()
+        !self.suspended && self.serials[0].dtr() && self.serials[0].rts()
+    }
+
+    // Signal resume on the bus if the host has allowed us to do that.
+    // Returns true if the remote wakeup was issued.
+    fn remote_wakeup(&mut self) -> bool {
+        if !self.suspended || !self.device.remote_wakeup_enabled() {
+            return false;
+        }
+
+        // rp2040-hal doesn't expose remote wakeup, so set SIE_CTRL.RESUME directly. The bit is
+        // cleared by hardware once the resume signalling is done.
+        unsafe {
+            (*hal::pac::USBCTRL_REGS::ptr())
+                .sie_ctrl
+                .modify(|_, w| w.resume().set_bit());
+        }
+        true
+    }
+}
+
+fn endpoint_error(e: UsbError) -> EndpointError<UsbError> {
+    match e {
+        UsbError::WouldBlock => EndpointError::WouldBlock,
+        e => EndpointError::Other(e),
+    }
+}
+
+// Serial port of the manager, borrowed for a single non-blocking operation.
+struct BorrowedSerial<'a>(&'a mut SerialPort<'static, UsbBus>);
+
+impl SerialEndpoint for BorrowedSerial<'_> {
+    type Error = UsbError;
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, EndpointError<UsbError>> {
+        self.0.write(data).map_err(endpoint_error)
+    }
+
+    fn flush(&mut self) -> Result<(), EndpointError<UsbError>> {
+        self.0.flush().map_err(endpoint_error)
+    }
+}
+
+// Serial port with the given index, accessed through USB_MANAGER for each operation, so that the
+// USB interrupt can drain the buffer between retries.
+struct ManagedSerial(usize);
+
+impl SerialEndpoint for ManagedSerial {
+    type Error = UsbError;
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, EndpointError<UsbError>> {
+        borrow_manager(|manager| match manager {
+            Some(m) => BorrowedSerial(&mut m.serials[self.0]).write(data),
+            None => Err(EndpointError::Other(UsbError::InvalidState)),
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), EndpointError<UsbError>> {
+        borrow_manager(|manager| match manager {
+            Some(m) => BorrowedSerial(&mut m.serials[self.0]).flush(),
+            None => Err(EndpointError::Other(UsbError::InvalidState)),
+        })
+    }
+
+    fn suspended(&self) -> bool {
+        borrow_manager(|manager| match manager {
+            Some(m) => m.suspended || (self.0 == 0 && m.disconnected),
+            None => false,
+        })
     }
 }
 
 static mut USB_BUS: Option<UsbBusAllocator<UsbBus>> = None;
+// Flash unique ID in hex. Has to be static, since UsbDevice keeps a reference to it.
+static mut SERIAL_NUMBER: [u8; 16] = [0; 16];
 static USB_MANAGER: cortex_m::interrupt::Mutex<RefCell<Option<UsbManager>>> =
     cortex_m::interrupt::Mutex::new(RefCell::new(None));
+const EMPTY_LOG_QUEUE: LogQueue<LOG_QUEUE_SIZE> = LogQueue::new();
+// One queue per port.
+static LOG_QUEUES: cortex_m::interrupt::Mutex<RefCell<[LogQueue<LOG_QUEUE_SIZE>; NUM_PORTS]>> =
+    cortex_m::interrupt::Mutex::new(RefCell::new([EMPTY_LOG_QUEUE; NUM_PORTS]));
+// Recent log output, kept regardless of whether anybody listens on the console.
+static LOG_HISTORY: cortex_m::interrupt::Mutex<RefCell<HistoryRing<LOG_HISTORY_SIZE>>> =
+    cortex_m::interrupt::Mutex::new(RefCell::new(HistoryRing::new()));
+static LOG_ROUTER: cortex_m::interrupt::Mutex<RefCell<LogRouter<MAX_LOG_ROUTES>>> =
+    cortex_m::interrupt::Mutex::new(RefCell::new(LogRouter::new()));
+static RECONNECT_BANNER: cortex_m::interrupt::Mutex<Cell<&'static str>> =
+    cortex_m::interrupt::Mutex::new(Cell::new(DEFAULT_RECONNECT_BANNER));
+static LINE_STATE_CALLBACK: cortex_m::interrupt::Mutex<Cell<Option<fn(LineState)>>> =
+    cortex_m::interrupt::Mutex::new(Cell::new(None));
+static BYTE_CALLBACK: cortex_m::interrupt::Mutex<Cell<Option<fn(u8)>>> =
+    cortex_m::interrupt::Mutex::new(Cell::new(None));
+static LINE_CALLBACK: cortex_m::interrupt::Mutex<Cell<Option<fn(&str)>>> =
+    cortex_m::interrupt::Mutex::new(Cell::new(None));
+static LOG_THROTTLE: cortex_m::interrupt::Mutex<RefCell<Option<Throttle>>> =
+    cortex_m::interrupt::Mutex::new(RefCell::new(None));
 
 // Execute a closure with &mut UsbManager. The closure will be executed in interrupt-free context
 // and must not block.
@@ -56,22 +353,187 @@ where
     })
 }
 
+// Microseconds since boot, wrapping every ~71 minutes.
+fn now_us() -> u32 {
+    unsafe { (*hal::pac::TIMER::ptr()).timerawl.read().bits() }
+}
+
+// Same as borrow_manager, but for the queues of log records.
+fn with_log_queues<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut [LogQueue<LOG_QUEUE_SIZE>; NUM_PORTS]) -> R,
+{
+    cortex_m::interrupt::free(|cs| {
+        let mut queues = LOG_QUEUES.borrow(cs).borrow_mut();
+        f(&mut *queues)
+    })
+}
+
 #[allow(non_snake_case)]
 #[interrupt]
 unsafe fn USBCTRL_IRQ() {
-    borrow_manager(|manager| match manager {
-        Some(m) => m.interrupt(),
-        None => (),
+    static mut LINE_READER: LineReader<MAX_INPUT_LINE_SIZE> = LineReader::new();
+
+    let changed_line_state = borrow_manager(|manager| match manager {
+        Some(m) => {
+            m.interrupt();
+            let changed_line_state = m.update_line_state();
+            m.pump();
+            changed_line_state
+        }
+        None => None,
+    });
+
+    // Called outside of the critical section, so that the callback can use the console.
+    if let Some(line_state) = changed_line_state {
+        let callback = cortex_m::interrupt::free(|cs| LINE_STATE_CALLBACK.borrow(cs).get());
+        if let Some(callback) = callback {
+            callback(line_state);
+        }
+    }
+
+    let (on_byte, on_line) = cortex_m::interrupt::free(|cs| {
+        (BYTE_CALLBACK.borrow(cs).get(), LINE_CALLBACK.borrow(cs).get())
+    });
+
+    // Without callbacks the input is left in the serial port buffer.
+    if on_byte.is_none() && on_line.is_none() {
+        return;
+    }
+
+    let mut buf = [0; 64];
+    loop {
+        let size = borrow_manager(|manager| match manager {
+            Some(m) => m.serial().read(&mut buf).unwrap_or(0),
+            None => 0,
+        });
+        if size == 0 {
+            break;
+        }
+
+        for &byte in buf[..size].iter() {
+            if let Some(on_byte) = on_byte {
+                on_byte(byte);
+            }
+            if let Some(on_line) = on_line {
+                if let Some(line) = LINE_READER.push(byte) {
+                    on_line(line);
+                }
+            }
+        }
+    }
+}
+
+fn in_thread_mode() -> bool {
+    cortex_m::peripheral::SCB::vect_active() == cortex_m::peripheral::scb::VectActive::ThreadMode
+}
+
+/// Send the queued log records without blocking. Records logged from interrupt handlers are only
+/// queued, so this can be called from the idle loop to send them out without waiting for the
+/// next USB interrupt.
+pub fn pump() {
+    borrow_manager(|manager| {
+        if let Some(m) = manager {
+            m.pump();
+        }
     })
 }
 
+// Block until all the queued log records are written to the serial port buffer.
+fn pump_blocking() {
+    loop {
+        let done = borrow_manager(|manager| match manager {
+            Some(m) => m.pump() || m.dropping_output(),
+            None => true,
+        });
+        if done {
+            return;
+        }
+    }
+}
+
+// Write to the main console while polling the USB device directly. Used in the fault and panic
+// handlers, where the USB interrupt may not run. Gives up and returns false if nothing can be
+// sent for 100 ms.
+#[cfg(any(feature = "hard-fault", feature = "panic"))]
+fn write_polling(data: &[u8]) -> bool {
+    const TIMEOUT_US: u32 = 100_000;
+
+    let mut bytes_to_send = data;
+    let mut last_progress_us = now_us();
+
+    while now_us().wrapping_sub(last_progress_us) < TIMEOUT_US {
+        let (written_size, flushed) = borrow_manager(|manager| match manager {
+            Some(m) => {
+                unsafe { m.interrupt() };
+                // Send the queued log records first.
+                if !m.pump() {
+                    return (0, false);
+                }
+                let written_size = m.serial().write(bytes_to_send).unwrap_or(0);
+                (written_size, m.serial().flush().is_ok())
+            }
+            None => (bytes_to_send.len(), true),
+        });
+
+        if written_size > 0 {
+            bytes_to_send = &bytes_to_send[written_size..];
+            last_progress_us = now_us();
+        }
+        if bytes_to_send.is_empty() && flushed {
+            return true;
+        }
+    }
+    false
+}
+
 /// Initialize UsbBus and UsbManager. Will block until the USB connection is established.
+///
+/// The unique ID of the flash chip is used as the USB serial number, so this must be called while
+/// core 1 is not running code from flash.
 pub fn init_usb_manager(
     usbctrl_regs: hal::pac::USBCTRL_REGS,
     usbctrl_dpram: hal::pac::USBCTRL_DPRAM,
     usb_clock: hal::clocks::UsbClock,
     resets: &mut hal::pac::RESETS,
 ) {
+    init(
+        usbctrl_regs,
+        usbctrl_dpram,
+        usb_clock,
+        resets,
+        None::<fn(&'static UsbBusAllocator<UsbBus>) -> PollUserClasses>,
+    );
+}
+
+/// Same as `init_usb_manager`, but makes the console a part of a composite device with the
+/// application's own USB classes.
+///
+/// `build_classes` is called with the bus allocator once the console classes are allocated. It
+/// should create the classes, store them where both the application and the USB interrupt can
+/// reach them, e.g. in a static `Mutex<RefCell<Option<..>>>`, and return the function which passes
+/// them to the interrupt handler. At most `MAX_USER_CLASSES` classes are polled.
+pub fn init_usb_manager_with_classes<F>(
+    usbctrl_regs: hal::pac::USBCTRL_REGS,
+    usbctrl_dpram: hal::pac::USBCTRL_DPRAM,
+    usb_clock: hal::clocks::UsbClock,
+    resets: &mut hal::pac::RESETS,
+    build_classes: F,
+) where
+    F: FnOnce(&'static UsbBusAllocator<UsbBus>) -> PollUserClasses,
+{
+    init(usbctrl_regs, usbctrl_dpram, usb_clock, resets, Some(build_classes));
+}
+
+fn init<F>(
+    usbctrl_regs: hal::pac::USBCTRL_REGS,
+    usbctrl_dpram: hal::pac::USBCTRL_DPRAM,
+    usb_clock: hal::clocks::UsbClock,
+    resets: &mut hal::pac::RESETS,
+    build_user_classes: Option<F>,
+) where
+    F: FnOnce(&'static UsbBusAllocator<UsbBus>) -> PollUserClasses,
+{
     let usb_bus = UsbBusAllocator::new(UsbBus::new(
         usbctrl_regs,
         usbctrl_dpram,
@@ -82,8 +544,23 @@ pub fn init_usb_manager(
 
     unsafe { USB_BUS = Some(usb_bus); }
 
+    // TIMER is used for log throttling and wait_until_ready_timer. Taking it out of reset doesn't
+    // interfere with hal::timer::Timer, which does the same.
+    resets.reset.modify(|_, w| w.timer().clear_bit());
+    while resets.reset_done.read().timer().bit_is_clear() {}
+
+    let serial_number = unsafe {
+        unique_id::format_unique_id(&unique_id::read_unique_id(), &mut SERIAL_NUMBER);
+        // Only contains hex digits.
+        core::str::from_utf8_unchecked(&SERIAL_NUMBER)
+    };
+
     {
-        let manager = UsbManager::new(unsafe { USB_BUS.as_ref().unwrap() } );
+        let manager = UsbManager::new(
+            unsafe { USB_BUS.as_ref().unwrap() },
+            serial_number,
+            build_user_classes,
+        );
         borrow_manager(|opt_manager| {
             // Ignoring the returned reference.
             let _ = opt_manager.insert(manager);
@@ -94,6 +571,36 @@ pub fn init_usb_manager(
     unsafe { hal::pac::NVIC::unmask(hal::pac::Interrupt::USBCTRL_IRQ); }
 }
 
+/// Shut down the USB console and return the USB peripheral, so that it can be handed over to some
+/// other code, e.g. a different USB device stack or a bootloader.
+///
+/// The USB controller is put in reset, so the device disconnects from the host. `UsbClock` can't
+/// be returned since it is consumed by `UsbBus`, but the USB clock keeps running.
+///
+/// Returns None if the console hasn't been initialized. After this call, logging via the console
+/// becomes a no-op.
+pub fn deinit_usb_manager(
+    resets: &mut hal::pac::RESETS,
+) -> Option<(hal::pac::USBCTRL_REGS, hal::pac::USBCTRL_DPRAM)> {
+    hal::pac::NVIC::mask(hal::pac::Interrupt::USBCTRL_IRQ);
+
+    let manager = borrow_manager(|manager| manager.take())?;
+    // The UsbDevice and the serial port borrow USB_BUS, so they have to be dropped first.
+    drop(manager);
+    unsafe { USB_BUS = None; }
+
+    resets.reset.modify(|_, w| w.usbctrl().set_bit());
+
+    // The HAL doesn't give the peripherals back, but nothing else refers to them now.
+    let pac = unsafe { hal::pac::Peripherals::steal() };
+    Some((pac.USBCTRL_REGS, pac.USBCTRL_DPRAM))
+}
+
+/// Unique ID of the flash chip, which is also used as the USB serial number.
+pub fn unique_id() -> [u8; 8] {
+    unique_id::read_unique_id()
+}
+
 pub fn usb_manager_initialized() -> bool {
     borrow_manager(|manager| manager.is_some())
 }
@@ -108,6 +615,124 @@ fn usb_manager_ready() -> bool {
     })
 }
 
+/// Send log records with targets matching the pattern to the given serial port. See
+/// `LogRouter` for the pattern syntax. Records that don't match any route go to port 0.
+/// Returns false if the port doesn't exist or there are too many routes.
+pub fn set_log_route(pattern: &'static str, port: usize) -> bool {
+    if port >= NUM_PORTS {
+        return false;
+    }
+    cortex_m::interrupt::free(|cs| LOG_ROUTER.borrow(cs).borrow_mut().add(pattern, port as u8))
+}
+
+pub fn clear_log_routes() {
+    cortex_m::interrupt::free(|cs| LOG_ROUTER.borrow(cs).borrow_mut().clear())
+}
+
+/// Enable or disable (with None) collapsing of repeated log records and limiting of the log
+/// output rate. Disabled by default.
+pub fn set_log_throttle(config: Option<ThrottleConfig>) {
+    cortex_m::interrupt::free(|cs| {
+        *LOG_THROTTLE.borrow(cs).borrow_mut() = config.map(Throttle::new);
+    })
+}
+
+/// The current serial port settings and control lines set by the host. The baud rate isn't used
+/// by USB CDC, so it can serve as a simple way to pass configuration from the host, e.g. 1200 baud
+/// as a reset request.
+pub fn line_state() -> Option<LineState> {
+    borrow_manager(|manager| manager.as_ref().map(|m| m.current_line_state()))
+}
+
+/// Set the text sent when a terminal reconnects to the console after dropping DTR. Output logged
+/// while the terminal was disconnected is discarded, and the banner is sent instead.
+pub fn set_reconnect_banner(banner: &'static str) {
+    cortex_m::interrupt::free(|cs| RECONNECT_BANNER.borrow(cs).set(banner))
+}
+
+/// Register a function that will be called from the USB interrupt every time the host changes the
+/// line coding, DTR or RTS.
+pub fn set_line_state_callback(callback: Option<fn(LineState)>) {
+    cortex_m::interrupt::free(|cs| LINE_STATE_CALLBACK.borrow(cs).set(callback))
+}
+
+/// Register a function that will be called from the USB interrupt for every byte received from
+/// the host.
+pub fn on_byte(callback: Option<fn(u8)>) {
+    cortex_m::interrupt::free(|cs| BYTE_CALLBACK.borrow(cs).set(callback))
+}
+
+/// Register a function that will be called from the USB interrupt for every line received from
+/// the host, without the line terminator. Lines longer than 128 bytes are dropped.
+pub fn on_line(callback: Option<fn(&str)>) {
+    cortex_m::interrupt::free(|cs| LINE_CALLBACK.borrow(cs).set(callback))
+}
+
+/// Returns true if the host has suspended the USB bus. While suspended, writes to the console
+/// are dropped instead of blocking.
+pub fn is_suspended() -> bool {
+    borrow_manager(|manager| match manager {
+        Some(m) => m.suspended,
+        None => false,
+    })
+}
+
+/// Ask the host to resume the suspended bus, e.g. when the firmware has urgent data to send.
+/// Only works if the host has enabled remote wakeup for the device.
+/// Returns true if the resume signalling was started.
+pub fn remote_wakeup() -> bool {
+    borrow_manager(|manager| match manager {
+        Some(m) => m.remote_wakeup(),
+        None => false,
+    })
+}
+
+/// Send a MIDI message to the host. Returns `UsbError::WouldBlock` if the previous message
+/// hasn't been picked up by the host yet.
+#[cfg(feature = "midi")]
+pub fn midi_send(message: MidiMessage) -> usb_device::Result<()> {
+    borrow_manager(|manager| match manager {
+        Some(m) => m.midi.send(message),
+        None => Err(UsbError::InvalidState),
+    })
+}
+
+/// Get the next MIDI message received from the host, if any. Up to 16 messages are buffered, the
+/// oldest ones are dropped if they are not read in time.
+#[cfg(feature = "midi")]
+pub fn midi_receive() -> Option<MidiMessage> {
+    borrow_manager(|manager| manager.as_mut().and_then(|m| m.midi.receive()))
+}
+
+/// Write the last 4 KiB of log output to the console. The history is kept even when no host is
+/// connected, so this shows what happened before e.g. a fault or a reconnect.
+///
+/// Blocks until everything is written, so it must not be called from interrupt handlers,
+/// including the console input callbacks. Set a flag there and call it from the main loop instead.
+pub fn dump_history() {
+    let console = UsbConsole;
+    let mut pos = cortex_m::interrupt::free(|cs| LOG_HISTORY.borrow(cs).borrow().first_line_pos());
+    let end = cortex_m::interrupt::free(|cs| LOG_HISTORY.borrow(cs).borrow().end_pos());
+
+    // Copy in small chunks, since the history can't be borrowed while writing. The records logged
+    // during the dump are not included.
+    let mut buf = [0; 64];
+    while pos != end {
+        let max_size = core::cmp::min(buf.len(), end.wrapping_sub(pos));
+        let size = cortex_m::interrupt::free(|cs| {
+            LOG_HISTORY.borrow(cs).borrow().read_at(pos, &mut buf[..max_size])
+        });
+        if size == 0 {
+            // The rest has been overwritten in the meantime.
+            break;
+        }
+        if console.write_all_bytes(&buf[..size]).is_err() {
+            break;
+        }
+        pos = pos.wrapping_add(size);
+    }
+}
+
 /// Waits until USB console is ready.
 /// Returns the number of millisecond for which the function needed to block.
 pub fn wait_until_ready(delay: &mut cortex_m::delay::Delay) -> u32 {
@@ -119,6 +744,20 @@ pub fn wait_until_ready(delay: &mut cortex_m::delay::Delay) -> u32 {
     latency_ms
 }
 
+/// Same as `wait_until_ready`, but uses the TIMER peripheral instead of SysTick, so the caller
+/// doesn't need to give up the `Delay`. Doesn't interfere with `hal::timer::Timer`, since the timer
+/// is only read.
+/// Returns the number of millisecond for which the function needed to block.
+pub fn wait_until_ready_timer() -> u32 {
+    let start_us = now_us();
+    while !usb_manager_ready() {
+        // Don't hammer the USB_MANAGER critical section, so that the interrupt can run.
+        let poll_start_us = now_us();
+        while now_us().wrapping_sub(poll_start_us) < 1000 {}
+    }
+    now_us().wrapping_sub(start_us) / 1000
+}
+
 /// Waits until USB console is initialized.
 /// Returns the number of millisecond for which the function needed to block.
 pub fn wait_until_initialized(delay: &mut cortex_m::delay::Delay) -> u32 {
@@ -136,17 +775,35 @@ pub struct UsbConsole;
 impl UsbConsole {
     pub fn ready(&self) -> bool { usb_manager_ready() }
 
-    // Write bytes to the USB serial in UsbManager.
-    // Returns the number of bytes that were actually written (added to the output buffer).
-    fn write(&self, data: &[u8]) -> usbd_serial::Result<usize> {
-        borrow_manager(|manager| {
-            if let Some(m) = manager {
-                m.serial.write(data)
-            } else {
-                Err(usbd_serial::UsbError::InvalidState)
-            }
+    /// Add as many bytes as fit into the serial port output buffer without blocking.
+    /// Returns the number of bytes written, or `UsbError::WouldBlock` if the buffer is full.
+    pub fn write_bytes(&self, data: &[u8]) -> usbd_serial::Result<usize> {
+        ManagedSerial(0).write(data).map_err(|e| match e {
+            EndpointError::WouldBlock => UsbError::WouldBlock,
+            EndpointError::Other(e) => e,
+        })
+    }
+
+    /// Read the bytes received from the host without blocking. Returns `UsbError::WouldBlock` if
+    /// there are none. Only works while no `on_byte` or `on_line` callbacks are set, since they
+    /// consume the input in the USB interrupt. The host can't send more while the buffer is
+    /// full, so the input isn't lost if it is read slowly.
+    pub fn read_bytes(&self, data: &mut [u8]) -> usbd_serial::Result<usize> {
+        borrow_manager(|manager| match manager {
+            Some(m) => m.serial().read(data),
+            None => Err(UsbError::InvalidState),
         })
     }
+
+    /// Write all the bytes, blocking while the output buffer is full. Like with text output, the
+    /// data is dropped if the host suspends the bus.
+    pub fn write_all_bytes(&self, data: &[u8]) -> usbd_serial::Result<()> {
+        match console_core::write_all(&mut ManagedSerial(0), data) {
+            Ok(_) => Ok(()),
+            Err(EndpointError::WouldBlock) => Err(UsbError::WouldBlock),
+            Err(EndpointError::Other(e)) => Err(e),
+        }
+    }
 }
 
 impl core::fmt::Write for UsbConsole {
@@ -155,54 +812,64 @@ impl core::fmt::Write for UsbConsole {
         //     return Result::Err(core::fmt::Error);
         // }
 
-        let mut bytes_to_send = s.as_bytes();
-
-        while !bytes_to_send.is_empty() {
-            match self.write(bytes_to_send) {
-                // Output buffer is full. Retry.
-                Err(UsbError::WouldBlock) => (),
+        // If the host suspends the bus, the rest of the output is dropped instead of blocking.
+        match self.write_all_bytes(s.as_bytes()) {
+            Ok(()) => Ok(()),
 
-                // Shouldn't happen, but it's not like we can do much about it, unless there
-                // is some panic handler not relying on the USB console.
-                Err(e) => panic!("Error while writing to USB: {e:?}"),
-
-                Ok(written_size) => {
-                    // Keep only the tail that hasn't been sent yet.
-                    bytes_to_send = &bytes_to_send[written_size..];
-                }
-            }
+            // Shouldn't happen, but it's not like we can do much about it, unless there
+            // is some panic handler not relying on the USB console.
+            Err(e) => panic!("Error while writing to USB: {e:?}"),
         }
-
-        Ok(())
     }
 }
 
 impl log::Log for UsbConsole {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= log::Level::Info
+        console_core::enabled(metadata)
     }
 
     fn log(&self, record: &log::Record) {
-        let mut copy = *self;
-        writeln!(&mut copy, "{}", record.args()).unwrap();
+        if !usb_manager_initialized() {
+            return;
+        }
+
+        if record.level() == log::Level::Error {
+            remote_wakeup();
+        }
+
+        // Only enqueue the record here, so that logging from interrupt handlers never blocks or
+        // re-enters the serial port.
+        let line = console_core::format_record(record);
+        let now = now_us();
+        cortex_m::interrupt::free(|cs| {
+            let port = LOG_ROUTER.borrow(cs).borrow().route(record.target()) as usize;
+            let mut queues = LOG_QUEUES.borrow(cs).borrow_mut();
+            let queue = &mut queues[port];
+            let mut history = LOG_HISTORY.borrow(cs).borrow_mut();
+            let mut push = |bytes: &[u8]| {
+                history.push(bytes);
+                queue.push_record(bytes);
+            };
+            match LOG_THROTTLE.borrow(cs).borrow_mut().as_mut() {
+                Some(throttle) => throttle.process(line.as_bytes(), now, push),
+                None => push(line.as_bytes()),
+            }
+        });
+
+        if in_thread_mode() {
+            pump_blocking();
+        }
     }
 
     fn flush(&self) {
-        loop {
-            match borrow_manager(|manager| {
-                if let Some(m) = manager {
-                    m.serial.flush()
-                } else {
-                    Err(usbd_serial::UsbError::InvalidState)
-                }
-            }) {
-                Ok(()) => return,
+        if !usb_manager_initialized() {
+            return;
+        }
 
-                // Output buffer hasn't been fully flushed. Retry.
-                Err(UsbError::WouldBlock) => {},
+        pump_blocking();
 
-                Err(e) => panic!("Error while flushing USB: {e:?}"),
-            }
+        if let Err(e) = console_core::flush_all(&mut ManagedSerial(0)) {
+            panic!("Error while flushing USB: {e:?}");
         }
     }
 }
@@ -213,11 +880,28 @@ pub fn get_console() -> &'static UsbConsole {
     &USB_CONSOLE
 }
 
+// Output of the panic handler, which mustn't wait for the USB interrupt or a host that doesn't
+// read. The rest of the message is dropped after the first timeout.
+#[cfg(feature = "panic")]
+struct PanicWriter;
+
+#[cfg(feature = "panic")]
+impl core::fmt::Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if write_polling(s.as_bytes()) {
+            Ok(())
+        } else {
+            Err(core::fmt::Error)
+        }
+    }
+}
+
 #[cfg(feature = "panic")]
 #[panic_handler]
 fn panic(panic_info: &PanicInfo) -> ! {
-    let mut console = UsbConsole;
-    write!(&mut console, "{}\n", panic_info).ok();
-    loop {}
+    if usb_manager_initialized() {
+        writeln!(&mut PanicWriter, "{panic_info}").ok();
+    }
+    panic_behavior::halt()
 }
 
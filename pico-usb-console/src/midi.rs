@@ -0,0 +1,263 @@
+//! Minimal USB MIDI 1.0 class with one embedded input and one embedded output jack.
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const USB_SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const USB_SUBCLASS_MIDISTREAMING: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+const AC_HEADER: u8 = 0x01;
+const MS_HEADER: u8 = 0x01;
+const MS_MIDI_IN_JACK: u8 = 0x02;
+const MS_MIDI_OUT_JACK: u8 = 0x03;
+const MS_GENERAL: u8 = 0x01;
+
+const JACK_EMBEDDED: u8 = 0x01;
+const JACK_EXTERNAL: u8 = 0x02;
+
+const IN_JACK_EMBEDDED_ID: u8 = 1;
+const IN_JACK_EXTERNAL_ID: u8 = 2;
+const OUT_JACK_EMBEDDED_ID: u8 = 3;
+const OUT_JACK_EXTERNAL_ID: u8 = 4;
+
+// Class-specific MIDI streaming header, 4 jacks and 2 endpoints with their class-specific parts.
+const MS_TOTAL_LENGTH: u16 = 7 + 6 + 6 + 9 + 9 + 7 + 5 + 7 + 5;
+
+const MAX_PACKET_SIZE: u16 = 64;
+const RX_QUEUE_SIZE: usize = 16;
+
+/// A MIDI message, sent or received as a 4-byte USB MIDI event packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    PitchBend { channel: u8, value: u16 },
+    /// Any other USB MIDI event packet, including the cable number and the code index.
+    Raw([u8; 4]),
+}
+
+impl MidiMessage {
+    fn from_packet(packet: [u8; 4]) -> Self {
+        let channel = packet[1] & 0x0f;
+        match packet[0] & 0x0f {
+            0x8 => MidiMessage::NoteOff {
+                channel,
+                note: packet[2],
+                velocity: packet[3],
+            },
+            0x9 => MidiMessage::NoteOn {
+                channel,
+                note: packet[2],
+                velocity: packet[3],
+            },
+            0xb => MidiMessage::ControlChange {
+                channel,
+                control: packet[2],
+                value: packet[3],
+            },
+            0xc => MidiMessage::ProgramChange {
+                channel,
+                program: packet[2],
+            },
+            0xe => MidiMessage::PitchBend {
+                channel,
+                value: (packet[2] as u16) | ((packet[3] as u16) << 7),
+            },
+            _ => MidiMessage::Raw(packet),
+        }
+    }
+
+    fn to_packet(self) -> [u8; 4] {
+        // Cable number is always 0, the code index number matches the status nibble for the
+        // channel messages.
+        match self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => [0x08, 0x80 | (channel & 0x0f), note & 0x7f, velocity & 0x7f],
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => [0x09, 0x90 | (channel & 0x0f), note & 0x7f, velocity & 0x7f],
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => [0x0b, 0xb0 | (channel & 0x0f), control & 0x7f, value & 0x7f],
+            MidiMessage::ProgramChange { channel, program } => {
+                [0x0c, 0xc0 | (channel & 0x0f), program & 0x7f, 0]
+            }
+            MidiMessage::PitchBend { channel, value } => [
+                0x0e,
+                0xe0 | (channel & 0x0f),
+                (value & 0x7f) as u8,
+                ((value >> 7) & 0x7f) as u8,
+            ],
+            MidiMessage::Raw(packet) => packet,
+        }
+    }
+}
+
+pub struct MidiClass<'a, B: UsbBus> {
+    audio_control_if: InterfaceNumber,
+    midi_streaming_if: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+    rx_queue: [[u8; 4]; RX_QUEUE_SIZE],
+    rx_start: usize,
+    rx_len: usize,
+}
+
+impl<'a, B: UsbBus> MidiClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        MidiClass {
+            audio_control_if: alloc.interface(),
+            midi_streaming_if: alloc.interface(),
+            ep_out: alloc.bulk(MAX_PACKET_SIZE),
+            ep_in: alloc.bulk(MAX_PACKET_SIZE),
+            rx_queue: [[0; 4]; RX_QUEUE_SIZE],
+            rx_start: 0,
+            rx_len: 0,
+        }
+    }
+
+    pub fn send(&mut self, message: MidiMessage) -> Result<()> {
+        self.ep_in.write(&message.to_packet()).map(|_| ())
+    }
+
+    pub fn receive(&mut self) -> Option<MidiMessage> {
+        if self.rx_len == 0 {
+            return None;
+        }
+        let packet = self.rx_queue[self.rx_start];
+        self.rx_start = (self.rx_start + 1) % RX_QUEUE_SIZE;
+        self.rx_len -= 1;
+        Some(MidiMessage::from_packet(packet))
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.iad(
+            self.audio_control_if,
+            2,
+            USB_CLASS_AUDIO,
+            USB_SUBCLASS_AUDIOCONTROL,
+            0,
+        )?;
+
+        writer.interface(
+            self.audio_control_if,
+            USB_CLASS_AUDIO,
+            USB_SUBCLASS_AUDIOCONTROL,
+            0,
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                AC_HEADER,
+                0x00,
+                0x01, // bcdADC 1.0
+                0x09,
+                0x00, // wTotalLength
+                0x01, // bInCollection
+                self.midi_streaming_if.into(),
+            ],
+        )?;
+
+        writer.interface(
+            self.midi_streaming_if,
+            USB_CLASS_AUDIO,
+            USB_SUBCLASS_MIDISTREAMING,
+            0,
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MS_HEADER,
+                0x00,
+                0x01, // bcdMSC 1.0
+                (MS_TOTAL_LENGTH & 0xff) as u8,
+                (MS_TOTAL_LENGTH >> 8) as u8,
+            ],
+        )?;
+
+        writer.write(
+            CS_INTERFACE,
+            &[MS_MIDI_IN_JACK, JACK_EMBEDDED, IN_JACK_EMBEDDED_ID, 0],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[MS_MIDI_IN_JACK, JACK_EXTERNAL, IN_JACK_EXTERNAL_ID, 0],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MS_MIDI_OUT_JACK,
+                JACK_EMBEDDED,
+                OUT_JACK_EMBEDDED_ID,
+                1,
+                IN_JACK_EXTERNAL_ID,
+                1,
+                0,
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MS_MIDI_OUT_JACK,
+                JACK_EXTERNAL,
+                OUT_JACK_EXTERNAL_ID,
+                1,
+                IN_JACK_EMBEDDED_ID,
+                1,
+                0,
+            ],
+        )?;
+
+        // Host -> device, feeds the embedded IN jack.
+        writer.endpoint(&self.ep_out)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, IN_JACK_EMBEDDED_ID])?;
+
+        // Device -> host, fed by the embedded OUT jack.
+        writer.endpoint(&self.ep_in)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, OUT_JACK_EMBEDDED_ID])?;
+
+        Ok(())
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.ep_out.address() {
+            return;
+        }
+
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        let size = match self.ep_out.read(&mut buf) {
+            Ok(size) => size,
+            Err(_) => return,
+        };
+
+        for packet in buf[..size].chunks_exact(4) {
+            // Empty packets are used as padding.
+            if packet[0] == 0 {
+                continue;
+            }
+            if self.rx_len == RX_QUEUE_SIZE {
+                // Drop the oldest message.
+                self.rx_start = (self.rx_start + 1) % RX_QUEUE_SIZE;
+                self.rx_len -= 1;
+            }
+            let index = (self.rx_start + self.rx_len) % RX_QUEUE_SIZE;
+            self.rx_queue[index].copy_from_slice(packet);
+            self.rx_len += 1;
+        }
+    }
+}
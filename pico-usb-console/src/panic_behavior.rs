@@ -0,0 +1,125 @@
+//! What the panic handler does after printing the panic message.
+//!
+//! By default it just spins. If an LED is registered, it blinks SOS in Morse code, and if a reboot
+//! timeout is set, the chip is reset via the watchdog once it expires.
+
+use rp2040_hal::pac;
+
+// GPIO function select value for software control via SIO.
+const FUNCSEL_SIO: u8 = 5;
+
+// Morse timing unit.
+const UNIT_MS: u32 = 200;
+
+// SOS as a sequence of (LED on, LED off) intervals, in units.
+const SOS_PATTERN: [(u32, u32); 9] = [
+    (1, 1),
+    (1, 1),
+    (1, 3),
+    (3, 1),
+    (3, 1),
+    (3, 3),
+    (1, 1),
+    (1, 1),
+    (1, 7),
+];
+
+struct PanicConfig {
+    led_pin: Option<u8>,
+    reboot_after_ms: Option<u32>,
+    system_clock_freq: u32,
+}
+
+static mut PANIC_CONFIG: PanicConfig = PanicConfig {
+    led_pin: None,
+    reboot_after_ms: None,
+    // Default system clock set by init_clocks_and_plls.
+    system_clock_freq: 125_000_000,
+};
+
+/// Blink SOS on the LED connected to the given GPIO pin after a panic. The pin will be
+/// reconfigured as an output by the panic handler, so it can be used for anything else until then.
+/// `system_clock_freq` is needed to time the blinking.
+pub fn set_panic_led(gpio: u8, system_clock_freq: u32) {
+    assert!(gpio < 30);
+    cortex_m::interrupt::free(|_| unsafe {
+        PANIC_CONFIG.led_pin = Some(gpio);
+        PANIC_CONFIG.system_clock_freq = system_clock_freq;
+    })
+}
+
+/// Reboot the chip via the watchdog the given number of seconds after a panic.
+pub fn set_panic_reboot(after_secs: u32) {
+    cortex_m::interrupt::free(|_| unsafe {
+        PANIC_CONFIG.reboot_after_ms = Some(after_secs.saturating_mul(1000));
+    })
+}
+
+fn delay_ms(ms: u32, system_clock_freq: u32) {
+    cortex_m::asm::delay(ms * (system_clock_freq / 1000));
+}
+
+fn set_led(gpio: u8, on: bool) {
+    let sio = unsafe { &*pac::SIO::ptr() };
+    if on {
+        sio.gpio_out_set.write(|w| unsafe { w.bits(1 << gpio) });
+    } else {
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << gpio) });
+    }
+}
+
+fn init_led(gpio: u8) {
+    let io_bank0 = unsafe { &*pac::IO_BANK0::ptr() };
+    let sio = unsafe { &*pac::SIO::ptr() };
+
+    sio.gpio_oe_set.write(|w| unsafe { w.bits(1 << gpio) });
+    sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << gpio) });
+    io_bank0.gpio[gpio as usize]
+        .gpio_ctrl
+        .write(|w| unsafe { w.funcsel().bits(FUNCSEL_SIO) });
+}
+
+fn reboot() -> ! {
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    watchdog.ctrl.modify(|_, w| w.trigger().set_bit());
+    loop {}
+}
+
+/// Called by the panic handler after the message has been printed.
+pub fn halt() -> ! {
+    let (led_pin, reboot_after_ms, system_clock_freq) = unsafe {
+        (
+            PANIC_CONFIG.led_pin,
+            PANIC_CONFIG.reboot_after_ms,
+            PANIC_CONFIG.system_clock_freq,
+        )
+    };
+
+    let mut elapsed_ms = 0;
+    let mut wait = |ms: u32| {
+        delay_ms(ms, system_clock_freq);
+        elapsed_ms = elapsed_ms.saturating_add(ms);
+        if let Some(reboot_after_ms) = reboot_after_ms {
+            if elapsed_ms >= reboot_after_ms {
+                reboot();
+            }
+        }
+    };
+
+    match led_pin {
+        Some(gpio) => {
+            init_led(gpio);
+            loop {
+                for (on, off) in SOS_PATTERN.iter() {
+                    set_led(gpio, true);
+                    wait(on * UNIT_MS);
+                    set_led(gpio, false);
+                    wait(off * UNIT_MS);
+                }
+            }
+        }
+        None => loop {
+            wait(UNIT_MS);
+        },
+    }
+}
@@ -0,0 +1,172 @@
+//! Reading the 64-bit unique ID of the QSPI flash chip.
+//!
+//! RP2040 itself doesn't have a unique ID, so the Pico SDK uses the one from the flash. Reading
+//! it requires sending a raw command to the flash, which can't be done while the code is executed
+//! from it via XIP. So the command itself is executed from RAM with interrupts disabled, and all
+//! the ROM functions are looked up beforehand.
+//!
+//! Core 1 must not be executing from flash while the ID is read.
+
+use core::arch::asm;
+use core::ptr::read_volatile;
+
+const FLASH_RUID_CMD: u8 = 0x4b;
+const FLASH_RUID_DUMMY_BYTES: usize = 4;
+const FLASH_RUID_DATA_BYTES: usize = 8;
+const FLASH_RUID_TOTAL_BYTES: usize = 1 + FLASH_RUID_DUMMY_BYTES + FLASH_RUID_DATA_BYTES;
+
+const BOOT2_ADDR: *const u32 = 0x1000_0000 as *const u32;
+const BOOT2_SIZE_WORDS: usize = 64;
+
+const IO_QSPI_SS_CTRL: *mut u32 = (0x4001_8000 + 0x0c) as *mut u32;
+const SS_OUTOVER_MASK: u32 = 0b11 << 8;
+const SS_OUTOVER_LOW: u32 = 0b10 << 8;
+const SS_OUTOVER_HIGH: u32 = 0b11 << 8;
+
+const XIP_SSI_SR: *const u32 = (0x1800_0000 + 0x28) as *const u32;
+const XIP_SSI_DR0: *mut u32 = (0x1800_0000 + 0x60) as *mut u32;
+const SSI_SR_TFNF: u32 = 1 << 1;
+const SSI_SR_RFNE: u32 = 1 << 3;
+
+// Pointers to the ROM functions, which have to be resolved while flash is still accessible.
+struct RomFunctions {
+    connect_internal_flash: extern "C" fn(),
+    flash_exit_xip: extern "C" fn(),
+    flash_flush_cache: extern "C" fn(),
+    // Second stage bootloader copied to RAM, used to bring XIP back in the fast mode.
+    boot2: extern "C" fn(),
+}
+
+pub(crate) unsafe fn rom_func(tag: &[u8; 2]) -> extern "C" fn() {
+    type RomTableLookup = extern "C" fn(*const u16, u32) -> usize;
+
+    let table = *(0x14 as *const u16) as *const u16;
+    let lookup: RomTableLookup = core::mem::transmute(*(0x18 as *const u16) as usize);
+    core::mem::transmute(lookup(table, u16::from_le_bytes(*tag) as u32))
+}
+
+/// Read the unique ID of the flash chip.
+pub fn read_unique_id() -> [u8; FLASH_RUID_DATA_BYTES] {
+    let mut boot2 = [0u32; BOOT2_SIZE_WORDS];
+    for (i, word) in boot2.iter_mut().enumerate() {
+        *word = unsafe { read_volatile(BOOT2_ADDR.add(i)) };
+    }
+
+    let mut rx = [0u8; FLASH_RUID_TOTAL_BYTES];
+    let mut tx = [0u8; FLASH_RUID_TOTAL_BYTES];
+    tx[0] = FLASH_RUID_CMD;
+
+    cortex_m::interrupt::free(|_| unsafe {
+        let funcs = RomFunctions {
+            connect_internal_flash: rom_func(b"IF"),
+            flash_exit_xip: rom_func(b"EX"),
+            flash_flush_cache: rom_func(b"FC"),
+            // Set the Thumb bit.
+            boot2: core::mem::transmute((boot2.as_ptr() as usize) + 1),
+        };
+
+        flash_do_cmd(&funcs, tx.as_ptr(), tx.len(), rx.as_mut_ptr(), rx.len());
+    });
+
+    let mut id = [0; FLASH_RUID_DATA_BYTES];
+    id.copy_from_slice(&rx[1 + FLASH_RUID_DUMMY_BYTES..]);
+    id
+}
+
+/// Format the flash unique ID as upper-case hex digits, the same way as Pico SDK does for the
+/// USB serial number.
+pub fn format_unique_id(
+    id: &[u8; FLASH_RUID_DATA_BYTES],
+    out: &mut [u8; 2 * FLASH_RUID_DATA_BYTES],
+) {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    for (i, byte) in id.iter().enumerate() {
+        out[2 * i] = HEX_DIGITS[(byte >> 4) as usize];
+        out[2 * i + 1] = HEX_DIGITS[(byte & 0xf) as usize];
+    }
+}
+
+// Executed from RAM. Must not call any code located in flash in any build profile, which is why
+// the ROM functions are passed in, the registers are accessed by `read_reg` and `write_reg`, and
+// the buffers through raw pointers instead of slices. The counters can't overflow, so the
+// overflow checks of debug builds never jump to the panic handler. `tx_len` must not exceed
+// `rx_len`.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn flash_do_cmd(
+    funcs: &RomFunctions,
+    tx: *const u8,
+    tx_len: usize,
+    rx: *mut u8,
+    rx_len: usize,
+) {
+    (funcs.connect_internal_flash)();
+    (funcs.flash_exit_xip)();
+
+    // Force chip select low.
+    let ss_ctrl = read_reg(IO_QSPI_SS_CTRL);
+    write_reg(
+        IO_QSPI_SS_CTRL,
+        (ss_ctrl & !SS_OUTOVER_MASK) | SS_OUTOVER_LOW,
+    );
+
+    let mut tx_sent = 0;
+    let mut rx_received = 0;
+
+    // Don't overflow the 16-entry RX FIFO.
+    const MAX_IN_FLIGHT: usize = 16 - 2;
+
+    while tx_sent < tx_len || rx_received < rx_len {
+        let status = read_reg(XIP_SSI_SR);
+        let can_put = status & SSI_SR_TFNF != 0;
+        let can_get = status & SSI_SR_RFNE != 0;
+
+        // A byte is only received after one has been sent.
+        if can_put && tx_sent < tx_len && tx_sent - rx_received < MAX_IN_FLIGHT {
+            write_reg(XIP_SSI_DR0, *((tx as usize + tx_sent) as *const u8) as u32);
+            tx_sent += 1;
+        }
+        if can_get && rx_received < rx_len {
+            *((rx as usize + rx_received) as *mut u8) = read_reg(XIP_SSI_DR0) as u8;
+            rx_received += 1;
+        }
+    }
+
+    // Release chip select.
+    let ss_ctrl = read_reg(IO_QSPI_SS_CTRL);
+    write_reg(
+        IO_QSPI_SS_CTRL,
+        (ss_ctrl & !SS_OUTOVER_MASK) | SS_OUTOVER_HIGH,
+    );
+
+    (funcs.flash_flush_cache)();
+    (funcs.boot2)();
+}
+
+// Volatile register accesses for `flash_do_cmd`. Unlike `read_volatile` and `write_volatile`,
+// which aren't inlined without optimizations, these are always a single instruction. They are
+// also placed in RAM in case they are ever emitted out of line.
+#[inline(always)]
+#[link_section = ".data.ram_func"]
+unsafe fn read_reg(addr: *const u32) -> u32 {
+    let value;
+    asm!(
+        "ldr {value}, [{addr}]",
+        addr = in(reg) addr,
+        value = out(reg) value,
+        options(nostack, preserves_flags),
+    );
+    value
+}
+
+#[inline(always)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_reg(addr: *mut u32, value: u32) {
+    asm!(
+        "str {value}, [{addr}]",
+        addr = in(reg) addr,
+        value = in(reg) value,
+        options(nostack, preserves_flags),
+    );
+}
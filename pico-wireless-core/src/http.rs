@@ -0,0 +1,209 @@
+//! Parsing of HTTP/1.1 responses and headers, for the HTTP client and server of pico-wireless.
+
+use crate::protocol::Esp32Error;
+
+#[derive(Debug, Clone)]
+pub enum HttpError {
+    Esp32(Esp32Error),
+    // Only http:// and https:// URLs with a host are supported.
+    InvalidUrl,
+    RequestTooLarge,
+    // The response doesn't fit into the buffer.
+    ResponseTooLarge,
+    MalformedResponse,
+    Timeout,
+    // The body isn't valid JSON for the type, or the serialized value doesn't fit in the buffer.
+    Json,
+}
+
+impl From<Esp32Error> for HttpError {
+    fn from(e: Esp32Error) -> Self {
+        HttpError::Esp32(e)
+    }
+}
+
+impl core::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Status of a response. The body is at the start of the response buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Response {
+    pub status: u16,
+    pub body_len: usize,
+}
+
+/// Parse the status and the headers of the response in `buf[..len]`, and move the body to the
+/// start of the buffer.
+pub fn parse_response(buf: &mut [u8], len: usize) -> Result<Response, HttpError> {
+    let body_start = find(&buf[..len], b"\r\n\r\n").ok_or(HttpError::MalformedResponse)? + 4;
+    let headers = &buf[..body_start];
+
+    // "HTTP/1.1 200 OK"
+    let status = headers
+        .get(9..12)
+        .filter(|_| headers.starts_with(b"HTTP/1."))
+        .and_then(|status| core::str::from_utf8(status).ok())
+        .and_then(|status| status.parse().ok())
+        .ok_or(HttpError::MalformedResponse)?;
+
+    let chunked = header_value(headers, b"transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case(b"chunked"));
+
+    let body_len = if chunked {
+        decode_chunked(buf, body_start, len)?
+    } else {
+        let body_len = len - body_start;
+        match content_length(headers) {
+            Some(expected) if expected > body_len => return Err(HttpError::MalformedResponse),
+            Some(expected) => expected,
+            None => body_len,
+        }
+    };
+
+    buf.copy_within(body_start..body_start + body_len, 0);
+    Ok(Response { status, body_len })
+}
+
+// Decode a chunked body in place. Returns the length of the decoded body, which starts at `start`.
+fn decode_chunked(buf: &mut [u8], start: usize, end: usize) -> Result<usize, HttpError> {
+    let mut read = start;
+    let mut write = start;
+
+    loop {
+        // The peer may close the connection in the middle of the body.
+        let rest = buf.get(read..end).ok_or(HttpError::MalformedResponse)?;
+        let line_end = read + find(rest, b"\r\n").ok_or(HttpError::MalformedResponse)?;
+        // Chunk extensions after ';' are ignored.
+        let size_field = buf[read..line_end]
+            .split(|&byte| byte == b';')
+            .next()
+            .unwrap_or(&[]);
+        let size = core::str::from_utf8(size_field)
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or(HttpError::MalformedResponse)?;
+        read = line_end + 2;
+
+        if size == 0 {
+            return Ok(write - start);
+        }
+        let chunk_end = read
+            .checked_add(size)
+            .filter(|&chunk_end| chunk_end <= end)
+            .ok_or(HttpError::MalformedResponse)?;
+        buf.copy_within(read..chunk_end, write);
+        write += size;
+        // Skip the chunk and the CRLF after it.
+        read = chunk_end
+            .checked_add(2)
+            .ok_or(HttpError::MalformedResponse)?;
+    }
+}
+
+pub fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+/// Value of the header with the given lower-case name, with surrounding whitespace trimmed.
+pub fn header_value<'a>(headers: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    headers.split(|&byte| byte == b'\n').find_map(|line| {
+        let separator = line.iter().position(|&byte| byte == b':')?;
+        if !line[..separator].eq_ignore_ascii_case(name) {
+            return None;
+        }
+        Some(trim(&line[separator + 1..]))
+    })
+}
+
+pub fn content_length(headers: &[u8]) -> Option<usize> {
+    core::str::from_utf8(header_value(headers, b"content-length")?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn trim(mut data: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = data {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        data = rest;
+    }
+    while let [rest @ .., last] = data {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        data = rest;
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_response_with_content_length() {
+        let mut buf = *b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok and more";
+        let len = buf.len();
+        let response = parse_response(&mut buf, len).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(&buf[..response.body_len], b"ok");
+    }
+
+    #[test]
+    fn parses_chunked_response() {
+        let mut buf =
+            *b"HTTP/1.1 200 OK\r\ntransfer-encoding:  Chunked \r\n\r\n2\r\nok\r\n0\r\n\r\n";
+        let len = buf.len();
+        let response = parse_response(&mut buf, len).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(&buf[..response.body_len], b"ok");
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let mut buf = *b"4\r\nWiki\r\n5;ext\r\npedia\r\n0\r\n\r\n";
+        let end = buf.len();
+        assert_eq!(decode_chunked(&mut buf, 0, end).unwrap(), 9);
+        assert_eq!(&buf[..9], b"Wikipedia");
+    }
+
+    #[test]
+    fn rejects_truncated_chunked_body() {
+        let mut buf = *b"4\r\nWiki";
+        let end = buf.len();
+        assert!(matches!(
+            decode_chunked(&mut buf, 0, end),
+            Err(HttpError::MalformedResponse)
+        ));
+
+        let mut buf = *b"4\r\nWiki\r";
+        let end = buf.len();
+        assert!(matches!(
+            decode_chunked(&mut buf, 0, end),
+            Err(HttpError::MalformedResponse)
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_chunk() {
+        let mut buf = *b"ffffffffffffffff\r\nWiki\r\n0\r\n\r\n";
+        let end = buf.len();
+        assert!(matches!(
+            decode_chunked(&mut buf, 0, end),
+            Err(HttpError::MalformedResponse)
+        ));
+
+        let mut buf = *b"ffffffff\r\nWiki\r\n0\r\n\r\n";
+        let end = buf.len();
+        assert!(matches!(
+            decode_chunked(&mut buf, 0, end),
+            Err(HttpError::MalformedResponse)
+        ));
+    }
+}
@@ -0,0 +1,31 @@
+//! Hardware-independent part of pico-wireless: the SPI protocol of the NINA firmware running on
+//! the ESP32, over an abstract transport.
+//!
+//! Can be tested on the host:
+//!
+//! ```text
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+mod buffer;
+pub mod http;
+#[cfg(any(test, feature = "std"))]
+pub mod mock;
+mod nal;
+mod protocol;
+mod stream;
+mod trace;
+mod transcript;
+mod transport;
+
+pub use buffer::BufferError;
+pub use nal::UdpSocket;
+pub use protocol::{
+    AddressSource, CommandClass, ConnectError, ConnectionStatus, DroppedSockets, EncryptionType,
+    Esp32Error, FirmwareVersion, IpV4, LeaseInfo, Listener, NinaProtocol, PinMode, PowerMode,
+    ProtocolMode, RetryPolicy, ScanResult, Socket, SocketHandle, Ssid, TcpState,
+};
+pub use stream::{TcpStream, UdpWriter};
+pub use transcript::{Direction, Transcript};
+pub use transport::{BusError, Transport};
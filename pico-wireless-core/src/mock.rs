@@ -0,0 +1,189 @@
+//! Transport for host-side tests, replaying scripted responses of the module and recording
+//! everything sent to it.
+
+use std::collections::VecDeque;
+
+use crate::transport::{BusError, Transport};
+
+// Returned when no scripted bytes are left, like the idle MISO line.
+const IDLE_BYTE: u8 = 0xFF;
+
+pub struct MockTransport {
+    // Bytes returned by the reads, in order.
+    script: VecDeque<u8>,
+    // Bytes clocked out in each transaction, between a select and a deselect. Reads record the
+    // dummy byte.
+    transactions: Vec<Vec<u8>>,
+    selected: bool,
+    // Bytes have been written in the current transaction. The module only responds in a
+    // transaction of its own, so the reads padding a command get the idle byte.
+    sending: bool,
+    busy: bool,
+    unresponsive: bool,
+    resets: usize,
+    power_downs: usize,
+    // Number of the following transfers that fail.
+    bus_errors: usize,
+    // Fake clock, advanced by a microsecond for every byte transferred.
+    clock_us: u32,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        MockTransport {
+            script: VecDeque::new(),
+            transactions: Vec::new(),
+            selected: false,
+            sending: false,
+            busy: false,
+            unresponsive: false,
+            resets: 0,
+            power_downs: 0,
+            bus_errors: 0,
+            clock_us: 0,
+        }
+    }
+
+    /// Queue raw bytes to be returned by the following reads.
+    pub fn queue_bytes(&mut self, bytes: &[u8]) {
+        self.script.extend(bytes);
+    }
+
+    /// Queue a well-formed response to `cmd` with parameters with 8-bit lengths.
+    pub fn queue_response(&mut self, cmd: u8, params: &[&[u8]]) {
+        self.script.extend([0xE0, cmd | 0x80, params.len() as u8]);
+        for param in params {
+            self.script.push_back(param.len() as u8);
+            self.script.extend(param.iter());
+        }
+        self.script.push_back(0xEE);
+    }
+
+    /// Queue a response to `cmd` with a single parameter with a 16-bit length.
+    pub fn queue_response_data16(&mut self, cmd: u8, data: &[u8]) {
+        self.script.extend([0xE0, cmd | 0x80, 1]);
+        self.script.extend((data.len() as u16).to_be_bytes());
+        self.script.extend(data.iter());
+        self.script.push_back(0xEE);
+    }
+
+    /// Number of scripted bytes that haven't been read yet.
+    pub fn remaining(&self) -> usize {
+        self.script.len()
+    }
+
+    /// Keep the ACK line high, as when the module is processing a command.
+    pub fn set_busy(&mut self, busy: bool) {
+        self.busy = busy;
+    }
+
+    /// Never complete the handshake, as when the module is absent or stuck.
+    pub fn set_unresponsive(&mut self, unresponsive: bool) {
+        self.unresponsive = unresponsive;
+    }
+
+    /// Fail the next `n` transfers without clocking any bytes, as when the SPI peripheral reports
+    /// an error.
+    pub fn fail_transfers(&mut self, n: usize) {
+        self.bus_errors = n;
+    }
+
+    pub fn transactions(&self) -> &[Vec<u8>] {
+        &self.transactions
+    }
+
+    pub fn clear_transactions(&mut self) {
+        self.transactions.clear();
+    }
+
+    pub fn selected(&self) -> bool {
+        self.selected
+    }
+
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    pub fn power_downs(&self) -> usize {
+        self.power_downs
+    }
+
+    fn check_bus(&mut self) -> Result<(), BusError> {
+        if self.bus_errors > 0 {
+            self.bus_errors -= 1;
+            return Err(BusError);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        assert!(self.selected, "SPI transfer without chip select");
+        self.transactions
+            .last_mut()
+            .unwrap()
+            .extend_from_slice(bytes);
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for MockTransport {
+    fn select(&mut self) {
+        self.selected = true;
+        self.sending = false;
+        self.transactions.push(Vec::new());
+    }
+
+    fn deselect(&mut self) {
+        self.selected = false;
+    }
+
+    fn ack(&self) -> bool {
+        self.busy || self.selected
+    }
+
+    fn wait_for_ack(&mut self, high: bool, _timeout_us: u32) -> bool {
+        !self.unresponsive && self.ack() == high
+    }
+
+    fn now_us(&self) -> u32 {
+        self.clock_us
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), BusError> {
+        self.check_bus()?;
+        self.record(data);
+        self.sending = true;
+        self.clock_us = self.clock_us.wrapping_add(data.len() as u32);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), BusError> {
+        self.check_bus()?;
+        for byte in data.iter_mut() {
+            self.record(&[IDLE_BYTE]);
+            self.clock_us = self.clock_us.wrapping_add(1);
+            *byte = if self.sending {
+                IDLE_BYTE
+            } else {
+                self.script.pop_front().unwrap_or(IDLE_BYTE)
+            };
+        }
+        Ok(())
+    }
+
+    // The script is kept, it's what the module sends after booting.
+    fn reset(&mut self) {
+        self.resets += 1;
+        self.busy = false;
+        self.unresponsive = false;
+    }
+
+    fn power_down(&mut self) {
+        self.power_downs += 1;
+    }
+}
@@ -0,0 +1,95 @@
+//! embedded-nal UDP stack over the ESP32 sockets, so that generic SNTP, DNS or CoAP crates can use
+//! it directly.
+
+use embedded_nal::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpClientStack, UdpFullStack};
+
+use crate::protocol::{Esp32Error, IpV4, NinaProtocol, ProtocolMode, Socket};
+use crate::transport::Transport;
+
+pub struct UdpSocket {
+    sock: Socket,
+    remote: Option<(IpV4, u16)>,
+}
+
+fn to_ipv4(addr: SocketAddr) -> Result<(IpV4, u16), Esp32Error> {
+    match addr.ip() {
+        IpAddr::V4(ip) => Ok((IpV4::from_slice(&ip.octets()), addr.port())),
+        IpAddr::V6(_) => Err(Esp32Error::UnsupportedAddress),
+    }
+}
+
+fn to_socket_addr(ip: IpV4, port: u16) -> SocketAddr {
+    let [a, b, c, d] = ip.octets();
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port))
+}
+
+impl<T: Transport> NinaProtocol<T> {
+    fn send_datagram(
+        &mut self,
+        sock: Socket,
+        remote: (IpV4, u16),
+        data: &[u8],
+    ) -> Result<(), Esp32Error> {
+        self.start_client(remote.0, remote.1, sock, ProtocolMode::Udp)?;
+        self.insert_data_buf(sock, data)?;
+        self.send_data_udp(sock)
+    }
+}
+
+impl<T: Transport> UdpClientStack for NinaProtocol<T> {
+    type UdpSocket = UdpSocket;
+    type Error = Esp32Error;
+
+    fn socket(&mut self) -> Result<UdpSocket, Esp32Error> {
+        Ok(UdpSocket {
+            sock: self.get_socket()?,
+            remote: None,
+        })
+    }
+
+    fn connect(&mut self, socket: &mut UdpSocket, remote: SocketAddr) -> Result<(), Esp32Error> {
+        socket.remote = Some(to_ipv4(remote)?);
+        Ok(())
+    }
+
+    fn send(&mut self, socket: &mut UdpSocket, buffer: &[u8]) -> nb::Result<(), Esp32Error> {
+        let remote = socket.remote.ok_or(Esp32Error::NotConnected)?;
+        Ok(self.send_datagram(socket.sock, remote, buffer)?)
+    }
+
+    /// Datagrams longer than the buffer are truncated.
+    fn receive(
+        &mut self,
+        socket: &mut UdpSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, SocketAddr), Esp32Error> {
+        // For UDP sockets, this also fetches the next datagram.
+        if self.available(socket.sock)? == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let size = self.recv(socket.sock, buffer)?;
+        let (ip, port) = self.get_remote_data(socket.sock)?;
+        Ok((size, to_socket_addr(ip, port)))
+    }
+
+    fn close(&mut self, socket: UdpSocket) -> Result<(), Esp32Error> {
+        self.stop_client(socket.sock)
+    }
+}
+
+impl<T: Transport> UdpFullStack for NinaProtocol<T> {
+    fn bind(&mut self, socket: &mut UdpSocket, local_port: u16) -> Result<(), Esp32Error> {
+        self.start_server_udp(local_port, socket.sock)
+    }
+
+    fn send_to(
+        &mut self,
+        socket: &mut UdpSocket,
+        remote: SocketAddr,
+        buffer: &[u8],
+    ) -> nb::Result<(), Esp32Error> {
+        let remote = to_ipv4(remote)?;
+        Ok(self.send_datagram(socket.sock, remote, buffer)?)
+    }
+}
@@ -0,0 +1,3432 @@
+//! Encoding of the commands of the NINA firmware and decoding of its responses.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::blocking::delay::DelayMs;
+use log::{info, warn};
+
+use crate::buffer::{Buffer, BufferError, GenBuffer};
+use crate::trace::SpiTrace;
+use crate::transcript::{Direction, Transcript};
+use crate::transport::{BusError, Transport};
+
+const START_CMD: u8 = 0xE0;
+const END_CMD: u8 = 0xEE;
+const ERR_CMD: u8 = 0xEF;
+const DUMMY_DATA: u8 = 0xFF;
+
+const REPLY_FLAG: u8 = 1 << 7;
+
+// Default limit on waiting for the start of a response, several thousand bytes at 8 MHz.
+const DEFAULT_BYTE_TIMEOUT_US: u32 = 10_000;
+// Default limit on waiting for the ACK line.
+const DEFAULT_HANDSHAKE_TIMEOUT_MS: u32 = 10_000;
+// Bytes clocked out while draining the rest of a response in `resync`, and the number of
+// consecutive dummy bytes taken as the end of the response.
+const MAX_DRAIN_BYTES: usize = 4096;
+const DRAIN_DUMMY_RUN: usize = 16;
+
+// Sizes of the firmware buffers for the certificates and the private key, in PEM.
+const MAX_CLIENT_CERT_SIZE: usize = 1300;
+const MAX_PRIVATE_KEY_SIZE: usize = 1700;
+
+// Largest payload of a single SEND_DATA_TCP command. The firmware receives every command into a
+// 4 KiB buffer, which also holds the framing.
+const MAX_SEND_CHUNK: usize = 4000;
+// Number of attempts to send data while the module doesn't accept any.
+pub(crate) const MAX_SEND_ATTEMPTS: u32 = 100;
+
+// 11 dB ADC attenuation, giving the full 0-3.3 V input range.
+const ADC_ATTENUATION_11DB: u8 = 3;
+
+// TCP state of an established connection, as reported by GET_CLIENT_STATE_TCP.
+const TCP_STATE_ESTABLISHED: u8 = 4;
+
+// Returned by AVAIL_DATA_TCP for a server socket without pending connections, and by GET_SOCKET
+// when all the sockets are in use.
+const NO_SOCKET: u16 = 255;
+// Sockets are tracked in 32-bit masks. The firmware has far fewer.
+const MAX_SOCKETS: usize = 32;
+
+const MAX_SSID_LEN: usize = 32;
+// Maximum number of networks returned by a scan.
+const MAX_SCAN_RESULTS: usize = 16;
+// SSIDs returned by SCAN_NETWORKS, one field per network.
+type SsidBuffer = Buffer<{ MAX_SCAN_RESULTS * MAX_SSID_LEN }, { MAX_SCAN_RESULTS + 1 }>;
+
+// Polling interval bounds for the connection status in `connect`.
+const CONNECT_POLL_MIN_MS: u32 = 10;
+const CONNECT_POLL_MAX_MS: u32 = 500;
+// Polling interval for the received data in `recv_timeout`.
+const RECV_POLL_MS: u32 = 10;
+// Number of attempts to join the requested access point in `connect_bssid`.
+const BSSID_ATTEMPTS: u32 = 3;
+
+// Longest firmware version string, such as "1.7.4".
+const MAX_FW_VERSION_LEN: usize = 16;
+
+// Results of PING other than the round-trip time.
+const PING_DEST_UNREACHABLE: i16 = -1;
+const PING_TIMEOUT: i16 = -2;
+
+// Largest chunk read by a single READ_FILE, whose response has an 8-bit length.
+const MAX_FILE_READ_CHUNK: usize = 255;
+// Largest chunk written by a single WRITE_FILE, whose data parameter has an 8-bit length.
+const MAX_FILE_WRITE_CHUNK: usize = 255;
+
+#[derive(Debug, Clone)]
+pub enum Esp32Error {
+    Unknown,
+    NoStartCmd,
+    // No response to `cmd` has started in time.
+    WaitForByteTimeout {
+        cmd: u8,
+        transcript: Transcript,
+    },
+    // The module has reported an error instead of responding to `cmd`.
+    ErrCmd {
+        cmd: u8,
+        transcript: Transcript,
+    },
+    // A byte of the framing of the response to `cmd` is wrong.
+    UnexpectedByte {
+        cmd: u8,
+        expected: u8,
+        actual: u8,
+        transcript: Transcript,
+    },
+    UnexpectedEncryptionType(u8),
+    UnexpectedStatus(u8),
+    ErrorCode(u8),
+    ResponseBufferError(BufferError),
+    WrongNumberOfResponseParams {
+        cmd: u8,
+        expected: u8,
+        actual: u8,
+        transcript: Transcript,
+    },
+    // Connection hasn't been established in time. Contains the last reported status.
+    ConnectTimeout(ConnectionStatus),
+    // ESP32 gave up connecting to the network.
+    ConnectFailed(ConnectionStatus),
+    // ESP32 couldn't join the network, with the reason reported by the firmware.
+    JoinFailed(ConnectError),
+    // Parameter doesn't fit in the firmware buffer.
+    ParamTooLong,
+    // Only IPv4 is supported by the firmware, and multicast needs a group address.
+    UnsupportedAddress,
+    // Sending on a socket without a remote address.
+    NotConnected,
+    // The hostname couldn't be resolved.
+    HostNotFound,
+    // The protocol mode only works with a hostname, not with an address.
+    HostnameRequired,
+    // All the sockets of the firmware are in use.
+    NoFreeSocket,
+    // The firmware returned a socket which is still in use. It happens when a socket is requested
+    // before the previous one has been connected or bound.
+    SocketInUse,
+    // The module hasn't become ready or hasn't acknowledged the selection in time. It's either
+    // absent or stuck.
+    HandshakeTimeout,
+    // The module has been put to sleep and has to be woken up first.
+    Asleep,
+    // Nothing has been received in time.
+    RecvTimeout,
+    // The module hasn't accepted any data, e.g. because the connection is stalled.
+    SendStalled,
+    // The firmware returned a socket number that the driver can't track.
+    InvalidSocket(u16),
+    // The SPI bus has failed in the middle of an exchange.
+    Bus(BusError),
+}
+
+impl From<BusError> for Esp32Error {
+    fn from(e: BusError) -> Self {
+        Esp32Error::Bus(e)
+    }
+}
+
+impl Esp32Error {
+    /// Whether the error comes from a garbled or lost exchange with the module rather than from
+    /// the command itself, so that repeating the command may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Esp32Error::NoStartCmd
+                | Esp32Error::WaitForByteTimeout { .. }
+                | Esp32Error::ErrCmd { .. }
+                | Esp32Error::UnexpectedByte { .. }
+                | Esp32Error::WrongNumberOfResponseParams { .. }
+                | Esp32Error::ResponseBufferError(_)
+                | Esp32Error::HandshakeTimeout
+                | Esp32Error::Bus(_)
+        )
+    }
+}
+
+impl core::fmt::Display for Esp32Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Groups of commands sharing a retry policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandClass {
+    /// Commands reading the state of the module, such as `get_conn_status` or `current_rssi`.
+    Query = 0,
+    /// Commands changing the settings, such as `set_power_mode` or `digital_write`, which can be
+    /// repeated without changing the outcome.
+    Control = 1,
+    /// Socket commands, such as `send_data_tcp` or `recv`. Repeating them after a lost response
+    /// may send the data twice or lose the received data, so only the protocols that can cope with
+    /// it should enable retries for them.
+    Data = 2,
+}
+
+/// How many times to attempt a command when the exchange with the module fails.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts. Both 0 and 1 mean a single attempt.
+    pub attempts: u8,
+}
+
+impl RetryPolicy {
+    pub const NONE: RetryPolicy = RetryPolicy { attempts: 1 };
+}
+
+// Layout of the parameters of a response.
+enum CmdResponseType {
+    // Any number of parameters with 8-bit lengths, such as the scan results.
+    Normal,
+    // The given number of parameters with 8-bit lengths.
+    Cmd(usize),
+    // A single parameter with an 8-bit length.
+    Data8,
+    // A single parameter with a 16-bit big-endian length, used for the socket data.
+    Data16,
+}
+
+impl CmdResponseType {
+    fn num_params(&self) -> Option<usize> {
+        match self {
+            CmdResponseType::Normal => None,
+            CmdResponseType::Cmd(n) => Some(*n),
+            CmdResponseType::Data8 | CmdResponseType::Data16 => Some(1),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Esp32Command {
+    SetNet = 0x10,
+    SetPassphrase = 0x11,
+    SetKey = 0x12,
+    SetIpConfig = 0x14,
+    SetDnsConfig = 0x15,
+    SetHostname = 0x16,
+    SetPowerMode = 0x17,
+    SetApNet = 0x18,
+    SetApPassphrase = 0x19,
+    SetDebug = 0x1a,
+    GetTemperature = 0x1b,
+    GetDnsConfig = 0x1e,
+    GetReasonCode = 0x1f,
+    GetConnStatus = 0x20,
+    GetIpAddr = 0x21,
+    GetMacAddr = 0x22,
+    GetCurrSsid = 0x23,
+    GetCurrBssid = 0x24,
+    GetCurrRssi = 0x25,
+    GetCurrEnct = 0x26,
+    ScanNetworks = 0x27,
+    StartServerTcp = 0x28,
+    GetStateTcp = 0x29,
+    DataSentTcp = 0x2a,
+    AvailDataTcp = 0x2b,
+    GetDataTcp = 0x2c,
+    StartClientTcp = 0x2d,
+    StopClientTcp = 0x2e,
+    GetClientStateTcp = 0x2f,
+    Disconnect = 0x30,
+    GetIdxRssi = 0x32,
+    GetIdxEnct = 0x33,
+    ReqHostByName = 0x34,
+    GetHostByName = 0x35,
+    StartScanNetworks = 0x36,
+    GetFwVersion = 0x37,
+    SendDataUdp = 0x39,
+    GetRemoteData = 0x3a,
+    GetTime = 0x3b,
+    GetIdxBssid = 0x3c,
+    GetIdxChannel = 0x3d,
+    Ping = 0x3e,
+    GetSocket = 0x3f,
+    SetClientCert = 0x40,
+    SetPk = 0x41,
+    SendDataTcp = 0x44,
+    GetDatabufTcp = 0x45,
+    InsertDataBuf = 0x46,
+    SetEntIdent = 0x4a,
+    SetEntUname = 0x4b,
+    SetEntPasswd = 0x4c,
+    SetEntCaCert = 0x4d,
+    SetEntEnable = 0x4f,
+    SetPinMode = 0x50,
+    SetDigitalWrite = 0x51,
+    SetAnalogWrite = 0x52,
+    GetDigitalRead = 0x53,
+    GetAnalogRead = 0x54,
+    WriteFile = 0x60,
+    ReadFile = 0x61,
+    DeleteFile = 0x62,
+    ExistsFile = 0x63,
+    DownloadFile = 0x64,
+    ApplyOta = 0x65,
+    RenameFile = 0x66,
+    DownloadOta = 0x67,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum EncryptionType {
+    Tkip = 2,
+    Ccmp = 4,
+    Wep = 5,
+    None = 7,
+    Auto = 8,
+    Unknown = 255,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionStatus {
+    Idle = 0,
+    NoSsidAvail = 1,
+    ScanCompleted = 2,
+    Connected = 3,
+    ConnectFailed = 4,
+    ConnectionLost = 5,
+    Disconnected = 6,
+    ApListening = 7,
+    ApConnected = 8,
+    ApFailed = 9,
+    NoShield = 255,
+}
+
+/// Mode of an ESP32 GPIO pin.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum PinMode {
+    Input = 0,
+    Output = 1,
+    InputPullUp = 2,
+}
+
+/// State of a TCP socket in the lwIP stack of the firmware.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TcpState {
+    Closed = 0,
+    Listen = 1,
+    SynSent = 2,
+    SynReceived = 3,
+    Established = 4,
+    FinWait1 = 5,
+    FinWait2 = 6,
+    CloseWait = 7,
+    Closing = 8,
+    LastAck = 9,
+    TimeWait = 10,
+}
+
+impl TcpState {
+    fn from_u8(state: u8) -> Result<Self, Esp32Error> {
+        match state {
+            0 => Ok(TcpState::Closed),
+            1 => Ok(TcpState::Listen),
+            2 => Ok(TcpState::SynSent),
+            3 => Ok(TcpState::SynReceived),
+            4 => Ok(TcpState::Established),
+            5 => Ok(TcpState::FinWait1),
+            6 => Ok(TcpState::FinWait2),
+            7 => Ok(TcpState::CloseWait),
+            8 => Ok(TcpState::Closing),
+            9 => Ok(TcpState::LastAck),
+            10 => Ok(TcpState::TimeWait),
+            _ => Err(Esp32Error::UnexpectedStatus(state)),
+        }
+    }
+}
+
+fn encryption_type_from_u8(response: u8) -> Result<EncryptionType, Esp32Error> {
+    // It sucks, but looks like there is no way to directly convert a number to an enum with
+    // the same value numbers
+    match response {
+        2 => Ok(EncryptionType::Tkip),
+        4 => Ok(EncryptionType::Ccmp),
+        5 => Ok(EncryptionType::Wep),
+        7 => Ok(EncryptionType::None),
+        8 => Ok(EncryptionType::Auto),
+        255 => Ok(EncryptionType::Unknown),
+        _ => Err(Esp32Error::UnexpectedEncryptionType(response)),
+    }
+}
+
+/// WiFi power saving of the ESP32.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerMode {
+    /// The radio is always on. This is the default.
+    Performance = 0,
+    /// The radio sleeps between the beacons of the access point (modem sleep). Cuts the average
+    /// current draw severalfold while idle, at the cost of up to a beacon interval (~100 ms) of
+    /// added latency for incoming packets.
+    LowPower = 1,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum ProtocolMode {
+    Tcp = 0,
+    Udp = 1,
+    /// TLS by mbedTLS, which verifies the certificate against the bundled root CAs.
+    Tls = 2,
+    UdpMulticast = 3,
+    /// TLS by BearSSL. Only works with a hostname, see `connect_secure`.
+    TlsBearSsl = 4,
+}
+
+// Reason codes of the ESP-IDF WiFi driver, reported by GET_REASON_CODE.
+const REASON_AUTH_EXPIRE: u8 = 2;
+const REASON_ASSOC_EXPIRE: u8 = 4;
+const REASON_ASSOC_TOOMANY: u8 = 5;
+const REASON_MIC_FAILURE: u8 = 14;
+const REASON_4WAY_HANDSHAKE_TIMEOUT: u8 = 15;
+const REASON_NO_AP_FOUND: u8 = 201;
+const REASON_AUTH_FAIL: u8 = 202;
+const REASON_ASSOC_FAIL: u8 = 203;
+const REASON_HANDSHAKE_TIMEOUT: u8 = 204;
+
+/// Why joining a network has failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectError {
+    /// No access point with the SSID is in range.
+    NetworkNotFound,
+    /// The passphrase has been rejected. With WPA2 it shows as a timeout of the key exchange, so
+    /// a very weak signal can also cause it.
+    WrongPassword,
+    /// The access point hasn't completed the authentication in time.
+    AuthTimeout,
+    /// The access point has refused the association, e.g. because it has too many stations.
+    AssociationFailed,
+    /// Another access point with the same SSID has been joined instead of the requested one.
+    WrongAccessPoint,
+    /// Other reason code of the ESP-IDF WiFi driver.
+    Other(u8),
+}
+
+impl ConnectError {
+    pub fn from_reason_code(code: u8) -> Self {
+        match code {
+            REASON_NO_AP_FOUND => ConnectError::NetworkNotFound,
+            REASON_AUTH_FAIL
+            | REASON_MIC_FAILURE
+            | REASON_4WAY_HANDSHAKE_TIMEOUT
+            | REASON_HANDSHAKE_TIMEOUT => ConnectError::WrongPassword,
+            REASON_AUTH_EXPIRE => ConnectError::AuthTimeout,
+            REASON_ASSOC_FAIL | REASON_ASSOC_EXPIRE | REASON_ASSOC_TOOMANY => {
+                ConnectError::AssociationFailed
+            }
+            _ => ConnectError::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IpV4([u8; 4]);
+
+impl IpV4 {
+    pub fn from_slice(data: &[u8]) -> Self {
+        let mut addr = [0; 4];
+        addr.clone_from_slice(data);
+        IpV4(addr)
+    }
+
+    pub fn octets(&self) -> [u8; 4] {
+        self.0
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for IpV4 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+/// Where the address of the station comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSource {
+    Dhcp,
+    /// Set with `set_static_ip`.
+    Static,
+}
+
+/// Addressing of the station on the joined network.
+#[derive(Debug, Clone, Copy)]
+pub struct LeaseInfo {
+    pub ip: IpV4,
+    pub netmask: IpV4,
+    pub gateway: IpV4,
+    pub source: AddressSource,
+    /// The servers in use, set with `set_dns` or obtained by DHCP. None if there are none.
+    pub dns: Option<(IpV4, Option<IpV4>)>,
+}
+
+impl fmt::Display for LeaseInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = match self.source {
+            AddressSource::Dhcp => "DHCP",
+            AddressSource::Static => "static",
+        };
+        write!(
+            f,
+            "IP {} Mask {} GW {} ({source})",
+            self.ip, self.netmask, self.gateway
+        )?;
+        match self.dns {
+            Some((dns1, Some(dns2))) => write!(f, " DNS {dns1} {dns2}"),
+            Some((dns1, None)) => write!(f, " DNS {dns1}"),
+            None => write!(f, " no DNS"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Socket(pub(crate) u8);
+
+// Sockets owned by the application, one bit per socket.
+#[derive(Debug, Default)]
+struct SocketPool {
+    in_use: u32,
+}
+
+impl SocketPool {
+    fn acquire(&mut self, sock: Socket) -> Result<(), Esp32Error> {
+        if sock.0 as usize >= MAX_SOCKETS {
+            return Err(Esp32Error::InvalidSocket(sock.0 as u16));
+        }
+        let bit = 1 << sock.0;
+        if self.in_use & bit != 0 {
+            return Err(Esp32Error::SocketInUse);
+        }
+        self.in_use |= bit;
+        Ok(())
+    }
+
+    fn release(&mut self, sock: Socket) {
+        self.in_use &= !1u32.checked_shl(sock.0 as u32).unwrap_or(0);
+    }
+}
+
+// Only used to initialize the array below, each element being a separate atomic.
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// Sockets of the dropped `SocketHandle`s of a driver. They are closed on the next `open_socket`
+/// call, since closing requires access to the driver. Each driver needs its own, usually a static:
+///
+/// ```ignore
+/// static SOCKETS: DroppedSockets = DroppedSockets::new();
+/// let esp32 = NinaProtocol::new(transport, &SOCKETS);
+/// ```
+// Only loads and stores are available on the Cortex-M0+, hence a flag per socket instead of a
+// mask.
+#[derive(Debug)]
+pub struct DroppedSockets {
+    flags: [AtomicBool; MAX_SOCKETS],
+}
+
+impl DroppedSockets {
+    pub const fn new() -> Self {
+        DroppedSockets {
+            flags: [NOT_DROPPED; MAX_SOCKETS],
+        }
+    }
+}
+
+impl Default for DroppedSockets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A socket owned by the application. Dropping the handle returns the socket to the ESP32, so
+/// that sockets aren't leaked after reconnects. Use `close` to close it right away.
+#[derive(Debug)]
+pub struct SocketHandle {
+    sock: Socket,
+    dropped: &'static DroppedSockets,
+}
+
+impl SocketHandle {
+    pub fn socket(&self) -> Socket {
+        self.sock
+    }
+
+    pub fn close<T: Transport>(self, esp32: &mut NinaProtocol<T>) -> Result<(), Esp32Error> {
+        let sock = self.sock;
+        core::mem::forget(self);
+        esp32.stop_client(sock)
+    }
+}
+
+impl Drop for SocketHandle {
+    fn drop(&mut self) {
+        // The socket has been checked by `SocketPool::acquire`.
+        self.dropped.flags[self.sock.0 as usize].store(true, Ordering::Relaxed);
+    }
+}
+
+/// A TCP server socket, listening for connections.
+#[derive(Clone, Copy, Debug)]
+pub struct Listener {
+    sock: Socket,
+}
+
+impl Listener {
+    /// The server socket itself.
+    pub fn socket(&self) -> Socket {
+        self.sock
+    }
+
+    /// Returns the socket of a new connection, or None if there is none pending.
+    pub fn accept<T: Transport>(
+        &self,
+        esp32: &mut NinaProtocol<T>,
+    ) -> Result<Option<Socket>, Esp32Error> {
+        esp32.accept_client_tcp(self.sock)
+    }
+
+    /// Same as `accept`, but also returns the address and the port of the client.
+    pub fn accept_from<T: Transport>(
+        &self,
+        esp32: &mut NinaProtocol<T>,
+    ) -> Result<Option<(Socket, IpV4, u16)>, Esp32Error> {
+        match esp32.accept_client_tcp(self.sock)? {
+            Some(client) => {
+                let (ip, port) = esp32.get_remote_data(client)?;
+                Ok(Some((client, ip, port)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Network name. It can contain arbitrary bytes, though in practice it's almost always UTF-8.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Ssid {
+    data: [u8; MAX_SSID_LEN],
+    len: usize,
+}
+
+impl Ssid {
+    /// Bytes beyond the maximum SSID length of 32 are dropped.
+    pub fn from_slice(data: &[u8]) -> Self {
+        let len = core::cmp::min(data.len(), MAX_SSID_LEN);
+        let mut ssid = Ssid {
+            data: [0; MAX_SSID_LEN],
+            len,
+        };
+        ssid.data[..len].copy_from_slice(&data[..len]);
+        ssid
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn as_str(&self) -> Result<&str, core::str::Utf8Error> {
+        core::str::from_utf8(self.as_bytes())
+    }
+}
+
+impl fmt::Display for Ssid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.as_str() {
+            Ok(ssid) => write!(f, "{}", ssid),
+            Err(_) => write!(f, "{:x?}", self.as_bytes()),
+        }
+    }
+}
+
+impl fmt::Debug for Ssid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// Version of the NINA firmware, such as "1.7.4".
+#[derive(Clone, Copy)]
+pub struct FirmwareVersion {
+    data: [u8; MAX_FW_VERSION_LEN],
+    len: usize,
+}
+
+impl FirmwareVersion {
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.data[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self.as_str())
+    }
+}
+
+/// A network found by `NinaProtocol::scan`.
+#[derive(Clone, Copy, Debug)]
+pub struct ScanResult {
+    pub ssid: Ssid,
+    pub rssi: i32,
+    pub channel: u8,
+    pub encryption: EncryptionType,
+    pub bssid: [u8; 6],
+}
+
+// Sort the results by decreasing RSSI and drop the weaker access points of each SSID. The dropped
+// results are moved to the end as None.
+fn strongest_per_ssid(
+    mut results: [Option<ScanResult>; MAX_SCAN_RESULTS],
+) -> [Option<ScanResult>; MAX_SCAN_RESULTS] {
+    results.sort_unstable_by_key(|result| result.map_or(i32::MAX, |result| -result.rssi));
+
+    let mut len = 0;
+    for i in 0..results.len() {
+        if let Some(result) = results[i] {
+            results[i] = None;
+            if !results[..len]
+                .iter()
+                .flatten()
+                .any(|kept| kept.ssid == result.ssid)
+            {
+                results[len] = Some(result);
+                len += 1;
+            }
+        }
+    }
+    results
+}
+
+// The firmware sends MAC addresses with the bytes in reverse order.
+fn mac_from_slice(data: &[u8]) -> [u8; 6] {
+    let mut mac = [0; 6];
+    for (byte, &received) in mac.iter_mut().zip(data.iter().rev()) {
+        *byte = received;
+    }
+    mac
+}
+
+/// Driver of the ESP32 running the NINA firmware, over any transport.
+pub struct NinaProtocol<T: Transport> {
+    transport: T,
+    command_length: u32,
+    sockets: SocketPool,
+    // A connection started by `try_connect` is waiting for the response.
+    connect_pending: bool,
+    // A scan started by `start_scan` hasn't been reported as complete yet.
+    scan_started: bool,
+    // SSIDs of the scan completed by `scan_complete`, until `scan_results` collects them.
+    scanned_ssids: Option<SsidBuffer>,
+    handshake_timeout_us: u32,
+    byte_timeout_us: u32,
+    // Indexed by CommandClass.
+    retry_policies: [RetryPolicy; 3],
+    // Reset the module when it stops responding.
+    auto_reset: bool,
+    // The module is held in reset by `sleep`.
+    asleep: bool,
+    // Whether the address has been set with `set_static_ip`, which the firmware doesn't report.
+    address_source: AddressSource,
+    dropped_sockets: &'static DroppedSockets,
+    transcript: Transcript,
+    trace: SpiTrace,
+}
+
+impl<T: Transport> NinaProtocol<T> {
+    /// The module is expected to have been reset by the transport. `dropped_sockets` must not be
+    /// shared with another driver.
+    pub fn new(transport: T, dropped_sockets: &'static DroppedSockets) -> Self {
+        NinaProtocol {
+            transport,
+            command_length: 0,
+            sockets: SocketPool::default(),
+            connect_pending: false,
+            scan_started: false,
+            scanned_ssids: None,
+            handshake_timeout_us: DEFAULT_HANDSHAKE_TIMEOUT_MS * 1000,
+            byte_timeout_us: DEFAULT_BYTE_TIMEOUT_US,
+            retry_policies: [RetryPolicy::NONE; 3],
+            auto_reset: false,
+            asleep: false,
+            address_source: AddressSource::Dhcp,
+            dropped_sockets,
+            transcript: Transcript::new(),
+            trace: SpiTrace::new(),
+        }
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Shut the driver down and return the transport, e.g. to give the SPI bus to another device.
+    /// The module is held in reset, so that it stays off the bus, until the transport resets it.
+    pub fn free(mut self) -> T {
+        info!("Releasing ESP32");
+        self.esp_deselect();
+        self.transport.power_down();
+        self.transport
+    }
+
+    fn esp_select(&mut self) {
+        self.transport.select();
+    }
+
+    fn esp_deselect(&mut self) {
+        self.transport.deselect();
+    }
+
+    /// How long to wait for the module to become ready or to acknowledge the selection before
+    /// giving up with `HandshakeTimeout`. Has to cover the longest commands, such as opening a
+    /// TLS connection.
+    pub fn set_handshake_timeout(&mut self, timeout_ms: u32) {
+        self.handshake_timeout_us = timeout_ms.saturating_mul(1000);
+    }
+
+    /// How long to wait for the start of a response once the module has acknowledged the
+    /// selection, before giving up with `WaitForByteTimeout`.
+    pub fn set_byte_timeout(&mut self, timeout_us: u32) {
+        self.byte_timeout_us = timeout_us;
+    }
+
+    /// How many times the commands of the class are attempted when the exchange with the module
+    /// fails. By default, they are attempted once.
+    pub fn set_retry_policy(&mut self, class: CommandClass, policy: RetryPolicy) {
+        self.retry_policies[class as usize] = policy;
+    }
+
+    // Run the command again after the transient errors, as allowed by the policy of its class.
+    // The module has already been resynchronized or reset by `check_protocol` by then.
+    fn retry<R>(
+        &mut self,
+        class: CommandClass,
+        mut command: impl FnMut(&mut Self) -> Result<R, Esp32Error>,
+    ) -> Result<R, Esp32Error> {
+        let attempts = self.retry_policies[class as usize].attempts;
+        let mut attempt = 1;
+        loop {
+            match command(self) {
+                Err(e) if e.is_transient() && attempt < attempts => {
+                    warn!("{class:?} command failed with {e:?}, retrying");
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether to reset the module automatically when it doesn't respond to the handshake. The
+    /// reset drops the network connection and all the sockets, but saves a power cycle if the
+    /// firmware hangs. Disabled by default.
+    pub fn set_auto_reset(&mut self, enabled: bool) {
+        self.auto_reset = enabled;
+    }
+
+    /// Bring the driver and the module back in sync after an error. The rest of a pending response
+    /// is drained, and with `reset` the module is also reset and the network connection has to be
+    /// established again. Checks that the module responds afterwards.
+    ///
+    /// Malformed responses are drained automatically, so this is mainly needed when the commands
+    /// keep failing.
+    pub fn recover(&mut self, reset: bool) -> Result<(), Esp32Error> {
+        if reset {
+            self.reset();
+        } else {
+            self.resync();
+        }
+        self.get_conn_status().map(|_| ())
+    }
+
+    /// Put the module to sleep by holding it in reset, cutting its current draw from ~80 mA to
+    /// almost nothing. The network connection and all the sockets are lost, and the commands fail
+    /// with `Asleep` until `wake` is called.
+    pub fn sleep(&mut self) {
+        info!("Putting ESP32 to sleep");
+        self.esp_deselect();
+        self.transport.power_down();
+        self.clear_state();
+        self.asleep = true;
+    }
+
+    /// Boot the module after `sleep` and check that it responds. The network has to be joined
+    /// again.
+    pub fn wake(&mut self) -> Result<(), Esp32Error> {
+        self.asleep = false;
+        self.recover(true)
+    }
+
+    // Reset the module through the transport. The sockets don't survive the reset.
+    fn reset(&mut self) {
+        info!("Resetting ESP32");
+        self.esp_deselect();
+        self.transport.reset();
+        self.clear_state();
+    }
+
+    fn clear_state(&mut self) {
+        self.command_length = 0;
+        self.connect_pending = false;
+        self.scan_started = false;
+        self.scanned_ssids = None;
+        self.sockets = SocketPool::default();
+        self.address_source = AddressSource::Dhcp;
+        for dropped in self.dropped_sockets.flags.iter() {
+            dropped.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // After a malformed response the module may still be in the middle of sending it, and the
+    // next command would read the rest of it. Clock out the remaining bytes until the module
+    // sends only dummy data, and end the transaction.
+    fn resync(&mut self) {
+        self.esp_deselect();
+        self.command_length = 0;
+        self.connect_pending = false;
+
+        if self.wait_for_esp_select().is_err() {
+            return;
+        }
+        let mut dummy_run = 0;
+        for _ in 0..MAX_DRAIN_BYTES {
+            match self.read_byte() {
+                Ok(DUMMY_DATA) => {
+                    dummy_run += 1;
+                    if dummy_run == DRAIN_DUMMY_RUN {
+                        break;
+                    }
+                }
+                Ok(_) => dummy_run = 0,
+                // Tried again after the next error.
+                Err(_) => break,
+            }
+        }
+        self.esp_deselect();
+    }
+
+    // Called with the result of every exchange with the module, to recover from protocol errors
+    // before they break the next commands.
+    fn check_protocol<R>(&mut self, result: Result<R, Esp32Error>) -> Result<R, Esp32Error> {
+        match result {
+            Err(
+                Esp32Error::NoStartCmd
+                | Esp32Error::WaitForByteTimeout { .. }
+                | Esp32Error::ErrCmd { .. }
+                | Esp32Error::UnexpectedByte { .. }
+                | Esp32Error::WrongNumberOfResponseParams { .. }
+                | Esp32Error::ResponseBufferError(_)
+                | Esp32Error::Bus(_),
+            ) => {
+                info!("Protocol error, resynchronizing with ESP32");
+                self.resync();
+            }
+            Err(Esp32Error::HandshakeTimeout) if self.auto_reset => self.reset(),
+            _ => {}
+        }
+        result
+    }
+
+    fn wait_for_esp_ready(&mut self) -> Result<(), Esp32Error> {
+        self.wait_for_ack_level(false)
+    }
+
+    fn wait_for_esp_ack(&mut self) -> Result<(), Esp32Error> {
+        self.wait_for_ack_level(true)
+    }
+
+    fn wait_for_ack_level(&mut self, high: bool) -> Result<(), Esp32Error> {
+        if self.transport.wait_for_ack(high, self.handshake_timeout_us) {
+            Ok(())
+        } else {
+            Err(Esp32Error::HandshakeTimeout)
+        }
+    }
+
+    fn wait_for_esp_select(&mut self) -> Result<(), Esp32Error> {
+        self.wait_for_esp_ready()?;
+        self.esp_select();
+        let ack = self.wait_for_esp_ack();
+        if ack.is_err() {
+            self.esp_deselect();
+        }
+        ack
+    }
+
+    // All the bytes go through these methods, so that they end up in the transcript and the trace.
+    fn write(&mut self, data: &[u8]) -> Result<(), Esp32Error> {
+        self.transcript.record(Direction::Sent, data);
+        self.transport.write(data).map_err(Esp32Error::Bus)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Esp32Error> {
+        self.transcript.record(Direction::Sent, &[byte]);
+        self.transport.write_byte(byte).map_err(Esp32Error::Bus)
+    }
+
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), Esp32Error> {
+        self.transport.read_bytes(data)?;
+        self.transcript.record(Direction::Received, data);
+        self.trace.response_bytes(data);
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Esp32Error> {
+        let byte = self.transport.read_byte()?;
+        self.transcript.record(Direction::Received, &[byte]);
+        self.trace.response_bytes(&[byte]);
+        Ok(byte)
+    }
+
+    // The skipped bytes aren't recorded.
+    fn skip_bytes(&mut self, n: usize) -> Result<(), Esp32Error> {
+        self.transport.skip_bytes(n).map_err(Esp32Error::Bus)
+    }
+
+    fn read_and_check_byte(&mut self, cmd: Esp32Command, expected: u8) -> Result<(), Esp32Error> {
+        let actual = self.read_byte()?;
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(Esp32Error::UnexpectedByte {
+                cmd: cmd as u8,
+                expected,
+                actual,
+                transcript: self.transcript,
+            })
+        }
+    }
+
+    fn wait_for_byte(&mut self, cmd: Esp32Command, expected: u8) -> Result<(), Esp32Error> {
+        let start_us = self.transport.now_us();
+        while self.transport.now_us().wrapping_sub(start_us) < self.byte_timeout_us {
+            let b = self.read_byte()?;
+            if b == expected {
+                return Ok(());
+            } else if b == ERR_CMD {
+                return Err(Esp32Error::ErrCmd {
+                    cmd: cmd as u8,
+                    transcript: self.transcript,
+                });
+            }
+        }
+        Err(Esp32Error::WaitForByteTimeout {
+            cmd: cmd as u8,
+            transcript: self.transcript,
+        })
+    }
+
+    fn start_cmd(&mut self, cmd: Esp32Command, num_param: u8) -> Result<(), Esp32Error> {
+        if self.asleep {
+            return Err(Esp32Error::Asleep);
+        }
+        let selected = self.wait_for_esp_select();
+        self.check_protocol(selected)?;
+
+        self.trace.command(cmd, cmd as u8, num_param);
+        let sent = self.write(&[START_CMD, (cmd as u8) & !REPLY_FLAG, num_param]);
+        self.command_length += 3;
+
+        self.check_protocol(sent)
+    }
+
+    fn send_param(&mut self, param: &[u8]) -> Result<(), Esp32Error> {
+        self.send_param_vectored(&[param])
+    }
+
+    // Parameter made of several slices, sent without copying them into one buffer.
+    fn send_param_vectored(&mut self, parts: &[&[u8]]) -> Result<(), Esp32Error> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if len > u8::MAX as usize {
+            return self.abort_cmd();
+        }
+        let sent = self.write_byte(len as u8).and_then(|()| {
+            for part in parts {
+                self.trace.param(part);
+                self.write(part)?;
+            }
+            Ok(())
+        });
+        self.command_length += len as u32 + 1;
+        self.check_protocol(sent)
+    }
+
+    // Parameter with a 16-bit length, used by the commands starting from 0x40.
+    fn send_buffer(&mut self, param: &[u8]) -> Result<(), Esp32Error> {
+        self.send_buffer_vectored(&[param])
+    }
+
+    fn send_buffer_vectored(&mut self, parts: &[&[u8]]) -> Result<(), Esp32Error> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if len > u16::MAX as usize {
+            return self.abort_cmd();
+        }
+        let sent = self.write(&(len as u16).to_be_bytes()).and_then(|()| {
+            for part in parts {
+                self.trace.param(part);
+                self.write(part)?;
+            }
+            Ok(())
+        });
+        self.command_length += len as u32 + 2;
+        self.check_protocol(sent)
+    }
+
+    // Give up on a command whose parameter doesn't fit its length field. The part sent so far is
+    // dropped by deselecting the module, and whatever it responds with is drained.
+    fn abort_cmd(&mut self) -> Result<(), Esp32Error> {
+        self.resync();
+        Err(Esp32Error::ParamTooLong)
+    }
+
+    fn end_cmd(&mut self) -> Result<(), Esp32Error> {
+        let sent = self.write_byte(END_CMD).and_then(|()| {
+            self.command_length += 1;
+            while self.command_length % 4 != 0 {
+                self.read_byte()?;
+                self.command_length += 1;
+            }
+            Ok(())
+        });
+
+        self.command_length = 0;
+        self.esp_deselect();
+        self.check_protocol(sent)
+    }
+
+    fn get_response_impl(
+        &mut self,
+        cmd: Esp32Command,
+        buffer: &mut dyn GenBuffer,
+        response_type: CmdResponseType,
+    ) -> Result<(), Esp32Error> {
+        let num_params = self.read_response_header(cmd, &response_type)?;
+
+        for _ in 0..num_params {
+            let field_size = self.read_param_len(&response_type)?;
+            let field = buffer
+                .add_field(field_size)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+            self.read_bytes(field)?;
+        }
+
+        self.read_and_check_byte(cmd, END_CMD)
+    }
+
+    // Read the response up to the number of parameters, which is returned.
+    fn read_response_header(
+        &mut self,
+        cmd: Esp32Command,
+        response_type: &CmdResponseType,
+    ) -> Result<u8, Esp32Error> {
+        self.wait_for_byte(cmd, START_CMD)?;
+        // The trace starts from the start byte, after the idle bytes.
+        self.trace.start_response();
+        self.trace.response_bytes(&[START_CMD]);
+        self.read_and_check_byte(cmd, cmd as u8 | REPLY_FLAG)?;
+
+        let num_params = self.read_byte()?;
+        match response_type.num_params() {
+            Some(expected) if num_params as usize != expected => {
+                Err(Esp32Error::WrongNumberOfResponseParams {
+                    cmd: cmd as u8,
+                    expected: expected as u8,
+                    actual: num_params,
+                    transcript: self.transcript,
+                })
+            }
+            _ => Ok(num_params),
+        }
+    }
+
+    fn read_param_len(&mut self, response_type: &CmdResponseType) -> Result<usize, Esp32Error> {
+        match response_type {
+            CmdResponseType::Data16 => {
+                let mut len = [0; 2];
+                self.read_bytes(&mut len)?;
+                Ok(u16::from_be_bytes(len) as usize)
+            }
+            _ => Ok(self.read_byte()? as usize),
+        }
+    }
+
+    fn get_response(
+        &mut self,
+        cmd: Esp32Command,
+        buffer: &mut dyn GenBuffer,
+        response_type: CmdResponseType,
+    ) -> Result<(), Esp32Error> {
+        self.trace.start_response();
+        let response = self
+            .wait_for_esp_select()
+            .and_then(|()| self.get_response_impl(cmd, buffer, response_type));
+        self.esp_deselect();
+        self.trace.end_response(cmd);
+
+        self.check_protocol(response)
+    }
+
+    /// Whether the module is ready to accept a command or to return a response. When it isn't,
+    /// the blocking methods wait for it.
+    pub fn is_ready(&self) -> bool {
+        !self.transport.ack()
+    }
+
+    // Same as get_response, but returns WouldBlock instead of waiting while the module is still
+    // processing the command.
+    fn poll_response(
+        &mut self,
+        cmd: Esp32Command,
+        buffer: &mut dyn GenBuffer,
+        response_type: CmdResponseType,
+    ) -> nb::Result<(), Esp32Error> {
+        if !self.is_ready() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.get_response(cmd, buffer, response_type)
+            .map_err(nb::Error::Other)
+    }
+
+    // Response with a single parameter with a 16-bit length. Returns the number of bytes copied
+    // to `data`, the rest of the parameter is skipped.
+    fn get_response_data16(
+        &mut self,
+        cmd: Esp32Command,
+        data: &mut [u8],
+    ) -> Result<usize, Esp32Error> {
+        self.trace.start_response();
+        let response = self
+            .wait_for_esp_select()
+            .and_then(|()| self.get_response_data16_impl(cmd, data));
+        self.esp_deselect();
+        self.trace.end_response(cmd);
+
+        self.check_protocol(response)
+    }
+
+    fn get_response_data16_impl(
+        &mut self,
+        cmd: Esp32Command,
+        data: &mut [u8],
+    ) -> Result<usize, Esp32Error> {
+        self.read_response_header(cmd, &CmdResponseType::Data16)?;
+
+        let len = self.read_param_len(&CmdResponseType::Data16)?;
+        let size = core::cmp::min(len, data.len());
+        self.read_bytes(&mut data[..size])?;
+        if len > size {
+            // Skipped to stay in sync with the module, but the data is lost.
+            let dropped = len - size;
+            warn!("Dropped {dropped} bytes of a response that didn't fit");
+            self.skip_bytes(dropped)?;
+        }
+
+        self.read_and_check_byte(cmd, END_CMD)?;
+        Ok(size)
+    }
+
+    fn get_response_u16(&mut self, cmd: Esp32Command) -> Result<u16, Esp32Error> {
+        let mut buffer: Buffer<2, 2> = Buffer::new();
+        self.get_response(cmd, &mut buffer, CmdResponseType::Data8)?;
+        let field = buffer
+            .field_as_slice_fixed(0, 2)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+        Ok(u16::from_le_bytes([field[0], field[1]]))
+    }
+
+    fn get_response_u8(&mut self, cmd: Esp32Command) -> Result<u8, Esp32Error> {
+        let mut buffer: Buffer<1, 2> = Buffer::new();
+        self.get_response(cmd, &mut buffer, CmdResponseType::Data8)?;
+        buffer
+            .field_as_u8(0)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))
+    }
+
+    fn get_response_i32(&mut self, cmd: Esp32Command) -> Result<i32, Esp32Error> {
+        let mut buffer: Buffer<4, 2> = Buffer::new();
+        self.get_response(cmd, &mut buffer, CmdResponseType::Data8)?;
+        buffer
+            .field_as_i32(0)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))
+    }
+
+    fn get_response_mac(&mut self, cmd: Esp32Command) -> Result<[u8; 6], Esp32Error> {
+        let mut buffer: Buffer<6, 2> = Buffer::new();
+        self.get_response(cmd, &mut buffer, CmdResponseType::Data8)?;
+        let mac_slice = buffer
+            .field_as_slice_fixed(0, 6)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+        Ok(mac_from_slice(mac_slice))
+    }
+
+    fn check_response_status(&mut self, command: Esp32Command) -> Result<(), Esp32Error> {
+        let status = self.get_response_u8(command)?;
+
+        if status == 1 {
+            Ok(())
+        } else {
+            Err(Esp32Error::ErrorCode(status))
+        }
+    }
+
+    /// Enable or disable the debug output of the firmware on the ESP32 UART.
+    pub fn set_debug(&mut self, enabled: bool) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetDebug, 1)?;
+            esp32.send_param(&[enabled as u8])?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetDebug)
+        })
+    }
+
+    pub fn firmware_version(&mut self) -> Result<FirmwareVersion, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetFwVersion, 0)?;
+            esp32.end_cmd()?;
+
+            let mut buffer: Buffer<MAX_FW_VERSION_LEN, 2> = Buffer::new();
+            esp32.get_response(
+                Esp32Command::GetFwVersion,
+                &mut buffer,
+                CmdResponseType::Data8,
+            )?;
+            let version = buffer
+                .field_as_slice(0)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+            // The string is sent with its terminating NUL.
+            let len = version
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(version.len());
+
+            let mut data = [0; MAX_FW_VERSION_LEN];
+            data[..len].copy_from_slice(&version[..len]);
+            Ok(FirmwareVersion { data, len })
+        })
+    }
+
+    /// Set the WiFi power saving mode. It applies to the station interface and persists until
+    /// the module is reset.
+    pub fn set_power_mode(&mut self, mode: PowerMode) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetPowerMode, 1)?;
+            esp32.send_param(&[mode as u8])?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetPowerMode)
+        })
+    }
+
+    /// Reading of the ESP32 internal temperature sensor in °C. It is very coarse and measures
+    /// the chip temperature, which is usually well above the ambient one.
+    pub fn temperature(&mut self) -> Result<f32, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetTemperature, 0)?;
+            esp32.end_cmd()?;
+
+            let mut buffer: Buffer<4, 2> = Buffer::new();
+            esp32.get_response(
+                Esp32Command::GetTemperature,
+                &mut buffer,
+                CmdResponseType::Data8,
+            )?;
+            let field = buffer
+                .field_as_slice_fixed(0, 4)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+            Ok(f32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+        })
+    }
+
+    pub fn analog_write(&mut self, pin: u8, value: u8) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetAnalogWrite, 2)?;
+            esp32.send_param(&[pin])?;
+            esp32.send_param(&[value])?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetAnalogWrite)
+        })
+    }
+
+    pub fn pin_mode(&mut self, pin: u8, mode: PinMode) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetPinMode, 2)?;
+            esp32.send_param(&[pin])?;
+            esp32.send_param(&[mode as u8])?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetPinMode)
+        })
+    }
+
+    pub fn digital_write(&mut self, pin: u8, high: bool) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetDigitalWrite, 2)?;
+            esp32.send_param(&[pin])?;
+            esp32.send_param(&[high as u8])?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetDigitalWrite)
+        })
+    }
+
+    pub fn digital_read(&mut self, pin: u8) -> Result<bool, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetDigitalRead, 1)?;
+            esp32.send_param(&[pin])?;
+            esp32.end_cmd()?;
+
+            Ok(esp32.get_response_u8(Esp32Command::GetDigitalRead)? != 0)
+        })
+    }
+
+    /// Raw 12-bit ADC reading, covering the 0-3.3 V range. Only the pins connected to ADC1 can
+    /// be read while WiFi is running.
+    pub fn analog_read(&mut self, pin: u8) -> Result<u16, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetAnalogRead, 2)?;
+            esp32.send_param(&[pin])?;
+            esp32.send_param(&[ADC_ATTENUATION_11DB])?;
+            esp32.end_cmd()?;
+
+            esp32.get_response_u16(Esp32Command::GetAnalogRead)
+        })
+    }
+
+    fn scan_networks(&mut self, ssids: &mut dyn GenBuffer) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::ScanNetworks, 0)?;
+        self.end_cmd()?;
+
+        self.get_response(Esp32Command::ScanNetworks, ssids, CmdResponseType::Normal)
+    }
+
+    /// Scan for networks, blocking for the duration of the scan. The details of all the found
+    /// networks are requested before returning.
+    pub fn scan(&mut self) -> Result<impl Iterator<Item = ScanResult>, Esp32Error> {
+        Ok(self.scan_and_collect()?.into_iter().flatten())
+    }
+
+    /// Start a scan, to be polled with `scan_complete` and collected with `scan_results`. Doesn't
+    /// block, but the firmware only scans when the found networks are fetched, which is done by
+    /// `scan_complete`.
+    pub fn start_scan(&mut self) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::StartScanNetworks, 0)?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::StartScanNetworks)?;
+        self.scan_started = true;
+        self.scanned_ssids = None;
+        Ok(())
+    }
+
+    /// Whether the scan started with `start_scan` has finished. Also true if no scan has been
+    /// started. Once the module reports the ScanCompleted status, or Connected, which a connected
+    /// module keeps reporting during a scan, the found networks are fetched and kept for
+    /// `scan_results`. The firmware runs the scan while they are fetched, so such a call blocks
+    /// for the duration of the scan. While connected, the scan is complete once it has found any
+    /// networks.
+    pub fn scan_complete(&mut self) -> Result<bool, Esp32Error> {
+        if !self.scan_started {
+            return Ok(true);
+        }
+        let status = self.get_conn_status()?;
+        if !matches!(
+            status,
+            ConnectionStatus::ScanCompleted | ConnectionStatus::Connected
+        ) {
+            return Ok(false);
+        }
+
+        let mut ssids = SsidBuffer::new();
+        self.scan_networks(&mut ssids)?;
+        if status == ConnectionStatus::Connected && ssids.len() == 0 {
+            return Ok(false);
+        }
+        self.scan_started = false;
+        self.scanned_ssids = Some(ssids);
+        Ok(true)
+    }
+
+    /// Scan for networks, and return one entry per SSID, for the access point with the strongest
+    /// signal, from the strongest network. At most `max` networks are returned.
+    pub fn scan_nearby(
+        &mut self,
+        max: usize,
+    ) -> Result<impl Iterator<Item = ScanResult>, Esp32Error> {
+        let results = self.scan_and_collect()?;
+        Ok(strongest_per_ssid(results).into_iter().flatten().take(max))
+    }
+
+    /// The networks found by the scan completed by `scan_complete`. Only their details are
+    /// requested, without scanning again. Without such a scan, or once its networks have been
+    /// collected, scans like `scan`, blocking for the duration of the scan.
+    pub fn scan_results(&mut self) -> Result<impl Iterator<Item = ScanResult>, Esp32Error> {
+        let results = match self.scanned_ssids.take() {
+            Some(ssids) => self.collect_scan_results(&ssids)?,
+            None => self.scan_and_collect()?,
+        };
+        Ok(results.into_iter().flatten())
+    }
+
+    fn scan_and_collect(&mut self) -> Result<[Option<ScanResult>; MAX_SCAN_RESULTS], Esp32Error> {
+        self.scanned_ssids = None;
+        let mut ssids = SsidBuffer::new();
+        self.scan_networks(&mut ssids)?;
+        self.collect_scan_results(&ssids)
+    }
+
+    // Details of the networks, which the firmware keeps from the last scan.
+    fn collect_scan_results(
+        &mut self,
+        ssids: &SsidBuffer,
+    ) -> Result<[Option<ScanResult>; MAX_SCAN_RESULTS], Esp32Error> {
+        let mut results = [None; MAX_SCAN_RESULTS];
+        for (idx, result) in results.iter_mut().enumerate().take(ssids.len()) {
+            let ssid = ssids
+                .field_as_slice(idx)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+            let idx = idx as u8;
+            *result = Some(ScanResult {
+                ssid: Ssid::from_slice(ssid),
+                rssi: self.get_rssi(idx)?,
+                channel: self.get_channel(idx)?,
+                encryption: self.get_encryption_type(idx)?,
+                bssid: self.get_bssid(idx)?,
+            });
+        }
+
+        Ok(results)
+    }
+
+    pub fn get_channel(&mut self, idx: u8) -> Result<u8, Esp32Error> {
+        self.start_cmd(Esp32Command::GetIdxChannel, 1)?;
+        self.send_param(&[idx])?;
+        self.end_cmd()?;
+
+        self.get_response_u8(Esp32Command::GetIdxChannel)
+    }
+
+    pub fn get_rssi(&mut self, idx: u8) -> Result<i32, Esp32Error> {
+        self.start_cmd(Esp32Command::GetIdxRssi, 1)?;
+        self.send_param(&[idx])?;
+        self.end_cmd()?;
+
+        self.get_response_i32(Esp32Command::GetIdxRssi)
+    }
+
+    pub fn get_bssid(&mut self, idx: u8) -> Result<[u8; 6], Esp32Error> {
+        self.start_cmd(Esp32Command::GetIdxBssid, 1)?;
+        self.send_param(&[idx])?;
+        self.end_cmd()?;
+
+        self.get_response_mac(Esp32Command::GetIdxBssid)
+    }
+
+    pub fn get_encryption_type(&mut self, idx: u8) -> Result<EncryptionType, Esp32Error> {
+        self.start_cmd(Esp32Command::GetIdxEnct, 1)?;
+        self.send_param(&[idx])?;
+        self.end_cmd()?;
+
+        let response = self.get_response_u8(Esp32Command::GetIdxEnct)?;
+        encryption_type_from_u8(response)
+    }
+
+    /// Encryption of the network the module is connected to.
+    pub fn current_encryption_type(&mut self) -> Result<EncryptionType, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetCurrEnct, 1)?;
+            esp32.send_param(&[DUMMY_DATA])?;
+            esp32.end_cmd()?;
+
+            let response = esp32.get_response_u8(Esp32Command::GetCurrEnct)?;
+            encryption_type_from_u8(response)
+        })
+    }
+
+    /// Join an open network.
+    pub fn set_network(&mut self, ssid: &str) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::SetNet, 1)?;
+        self.send_param(ssid.as_bytes())?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetNet)
+    }
+
+    pub fn wifi_set_passphrase(&mut self, ssid: &str, passphrase: &str) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::SetPassphrase, 2)?;
+        self.send_param(ssid.as_bytes())?;
+        self.send_param(passphrase.as_bytes())?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetPassphrase)
+    }
+
+    /// Join a WEP network with the key at `key_idx`, 0 to 3. The key is 5 or 13 bytes long.
+    pub fn set_wep_key(&mut self, ssid: &str, key_idx: u8, key: &[u8]) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::SetKey, 3)?;
+        self.send_param(ssid.as_bytes())?;
+        self.send_param(&[key_idx])?;
+        self.send_param(key)?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetKey)
+    }
+
+    /// Join a network and wait until the connection is established, polling the status with an
+    /// increasing interval. An empty passphrase means an open network.
+    ///
+    /// Hidden networks can be joined as well, since the module probes for the SSID instead of
+    /// waiting for its beacons.
+    pub fn connect(
+        &mut self,
+        ssid: &str,
+        passphrase: &str,
+        timeout_ms: u32,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), Esp32Error> {
+        if passphrase.is_empty() {
+            self.set_network(ssid)?;
+        } else {
+            self.wifi_set_passphrase(ssid, passphrase)?;
+        }
+        self.wait_for_connection(timeout_ms, delay)
+    }
+
+    /// Same as `connect`, but only accepts the access point with the given BSSID, for networks
+    /// served by several access points. The firmware can't be told which one to join, so the
+    /// module is disconnected and tries again when it picks another one, which usually works if
+    /// the requested access point has the strongest signal. Fails with `WrongAccessPoint` after a
+    /// few attempts.
+    pub fn connect_bssid(
+        &mut self,
+        ssid: &str,
+        passphrase: &str,
+        bssid: [u8; 6],
+        timeout_ms: u32,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), Esp32Error> {
+        for _ in 0..BSSID_ATTEMPTS {
+            self.connect(ssid, passphrase, timeout_ms, delay)?;
+            if self.current_bssid()? == bssid {
+                return Ok(());
+            }
+            info!("Joined another access point of {ssid}, retrying");
+            self.disconnect()?;
+        }
+        Err(Esp32Error::JoinFailed(ConnectError::WrongAccessPoint))
+    }
+
+    /// Leave the network.
+    pub fn disconnect(&mut self) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::Disconnect, 1)?;
+        self.send_param(&[DUMMY_DATA])?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::Disconnect)
+    }
+
+    fn wait_for_connection(
+        &mut self,
+        timeout_ms: u32,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), Esp32Error> {
+        let mut elapsed_ms = 0;
+        let mut poll_ms = CONNECT_POLL_MIN_MS;
+
+        loop {
+            let status = self.get_conn_status()?;
+            match status {
+                ConnectionStatus::Connected => return Ok(()),
+                ConnectionStatus::ConnectFailed | ConnectionStatus::NoSsidAvail => {
+                    return Err(Esp32Error::JoinFailed(self.connect_error(status)?))
+                }
+                ConnectionStatus::NoShield => return Err(Esp32Error::ConnectFailed(status)),
+                _ => {}
+            }
+
+            if elapsed_ms >= timeout_ms {
+                return Err(Esp32Error::ConnectTimeout(status));
+            }
+
+            let wait_ms = core::cmp::min(poll_ms, timeout_ms - elapsed_ms);
+            delay.delay_ms(wait_ms);
+            elapsed_ms += wait_ms;
+            poll_ms = core::cmp::min(2 * poll_ms, CONNECT_POLL_MAX_MS);
+        }
+    }
+
+    /// Reason code of the ESP-IDF WiFi driver for the last disconnection or failed attempt to
+    /// join a network. See `ConnectError`.
+    pub fn reason_code(&mut self) -> Result<u8, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetReasonCode, 0)?;
+            esp32.end_cmd()?;
+
+            esp32.get_response_u8(Esp32Command::GetReasonCode)
+        })
+    }
+
+    /// Why joining a network has failed, given the status in which it ended.
+    pub fn connect_error(&mut self, status: ConnectionStatus) -> Result<ConnectError, Esp32Error> {
+        if status == ConnectionStatus::NoSsidAvail {
+            return Ok(ConnectError::NetworkNotFound);
+        }
+        Ok(ConnectError::from_reason_code(self.reason_code()?))
+    }
+
+    /// Outer EAP identity for WPA2-Enterprise networks.
+    ///
+    /// To join an enterprise network, set the identity, username, password and optionally the CA
+    /// certificate, call `enable_enterprise`, then join the network with `set_network`.
+    pub fn set_enterprise_identity(&mut self, identity: &str) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::SetEntIdent, 1)?;
+        self.send_param(identity.as_bytes())?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetEntIdent)
+    }
+
+    pub fn set_enterprise_username(&mut self, username: &str) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::SetEntUname, 1)?;
+        self.send_param(username.as_bytes())?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetEntUname)
+    }
+
+    pub fn set_enterprise_password(&mut self, password: &str) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::SetEntPasswd, 1)?;
+        self.send_param(password.as_bytes())?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetEntPasswd)
+    }
+
+    /// CA certificate in PEM format used to verify the authentication server. Without it, the
+    /// server isn't verified.
+    pub fn set_enterprise_ca_cert(&mut self, cert: &[u8]) -> Result<(), Esp32Error> {
+        if cert.len() > MAX_CLIENT_CERT_SIZE {
+            return Err(Esp32Error::ParamTooLong);
+        }
+
+        self.start_cmd(Esp32Command::SetEntCaCert, 1)?;
+        self.send_buffer(cert)?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetEntCaCert)
+    }
+
+    /// Switch the station to WPA2-Enterprise authentication with the credentials set before.
+    pub fn enable_enterprise(&mut self) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::SetEntEnable, 0)?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetEntEnable)
+    }
+
+    /// Use a fixed address instead of DHCP. Has to be called before joining a network.
+    pub fn set_static_ip(
+        &mut self,
+        ip: IpV4,
+        gateway: IpV4,
+        netmask: IpV4,
+    ) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetIpConfig, 4)?;
+            // Number of valid addresses.
+            esp32.send_param(&[3])?;
+            esp32.send_param(ip.as_bytes())?;
+            esp32.send_param(gateway.as_bytes())?;
+            esp32.send_param(netmask.as_bytes())?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetIpConfig)
+        })?;
+        self.address_source = AddressSource::Static;
+        Ok(())
+    }
+
+    /// DNS servers to use instead of the ones obtained by DHCP.
+    pub fn set_dns(&mut self, dns1: IpV4, dns2: Option<IpV4>) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetDnsConfig, 3)?;
+            // Number of valid addresses.
+            esp32.send_param(&[1 + dns2.is_some() as u8])?;
+            esp32.send_param(dns1.as_bytes())?;
+            esp32.send_param(dns2.unwrap_or(IpV4([0; 4])).as_bytes())?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetDnsConfig)
+        })
+    }
+
+    /// DNS servers in use, set with `set_dns` or obtained by DHCP. A missing server is 0.0.0.0.
+    pub fn get_dns(&mut self) -> Result<(IpV4, IpV4), Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetDnsConfig, 0)?;
+            esp32.end_cmd()?;
+
+            let mut buffer = Buffer::<8, 3>::new();
+            esp32.get_response(
+                Esp32Command::GetDnsConfig,
+                &mut buffer,
+                CmdResponseType::Cmd(2),
+            )?;
+
+            let dns1 = buffer
+                .field_as_slice_fixed(0, 4)
+                .map_err(Esp32Error::ResponseBufferError)?;
+            let dns2 = buffer
+                .field_as_slice_fixed(1, 4)
+                .map_err(Esp32Error::ResponseBufferError)?;
+            Ok((IpV4::from_slice(dns1), IpV4::from_slice(dns2)))
+        })
+    }
+
+    /// Name sent to the DHCP server. Has to be called before joining a network.
+    pub fn set_hostname(&mut self, hostname: &str) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Control, |esp32| {
+            esp32.start_cmd(Esp32Command::SetHostname, 1)?;
+            esp32.send_param(hostname.as_bytes())?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SetHostname)
+        })
+    }
+
+    /// Start an access point. With an empty passphrase the network is open.
+    pub fn start_ap(
+        &mut self,
+        ssid: &str,
+        passphrase: &str,
+        channel: u8,
+    ) -> Result<(), Esp32Error> {
+        if passphrase.is_empty() {
+            self.start_cmd(Esp32Command::SetApNet, 2)?;
+            self.send_param(ssid.as_bytes())?;
+            self.send_param(&[channel])?;
+            self.end_cmd()?;
+
+            self.check_response_status(Esp32Command::SetApNet)
+        } else {
+            self.start_cmd(Esp32Command::SetApPassphrase, 3)?;
+            self.send_param(ssid.as_bytes())?;
+            self.send_param(passphrase.as_bytes())?;
+            self.send_param(&[channel])?;
+            self.end_cmd()?;
+
+            self.check_response_status(Esp32Command::SetApPassphrase)
+        }
+    }
+
+    /// Status of the access point started by `start_ap`: `ApListening` while no station is
+    /// connected, `ApConnected` once one is, or `ApFailed`.
+    pub fn ap_status(&mut self) -> Result<ConnectionStatus, Esp32Error> {
+        self.get_conn_status()
+    }
+
+    pub fn get_conn_status(&mut self) -> Result<ConnectionStatus, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetConnStatus, 0)?;
+            esp32.end_cmd()?;
+
+            let status = esp32.get_response_u8(Esp32Command::GetConnStatus)?;
+
+            match status {
+                0 => Ok(ConnectionStatus::Idle),
+                1 => Ok(ConnectionStatus::NoSsidAvail),
+                2 => Ok(ConnectionStatus::ScanCompleted),
+                3 => Ok(ConnectionStatus::Connected),
+                4 => Ok(ConnectionStatus::ConnectFailed),
+                5 => Ok(ConnectionStatus::ConnectionLost),
+                6 => Ok(ConnectionStatus::Disconnected),
+                7 => Ok(ConnectionStatus::ApListening),
+                8 => Ok(ConnectionStatus::ApConnected),
+                9 => Ok(ConnectionStatus::ApFailed),
+                255 => Ok(ConnectionStatus::NoShield),
+                _ => Err(Esp32Error::UnexpectedStatus(status)),
+            }
+        })
+    }
+
+    pub fn mac_address(&mut self) -> Result<[u8; 6], Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetMacAddr, 1)?;
+            esp32.send_param(&[DUMMY_DATA])?;
+            esp32.end_cmd()?;
+
+            esp32.get_response_mac(Esp32Command::GetMacAddr)
+        })
+    }
+
+    /// Current unix time in seconds, which the firmware gets by SNTP once it is connected to a
+    /// network. Returns 0 until the time has been synchronized.
+    pub fn get_time(&mut self) -> Result<u32, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetTime, 1)?;
+            esp32.send_param(&[DUMMY_DATA])?;
+            esp32.end_cmd()?;
+
+            let mut buffer: Buffer<4, 2> = Buffer::new();
+            esp32.get_response(Esp32Command::GetTime, &mut buffer, CmdResponseType::Data8)?;
+            let field = buffer
+                .field_as_slice_fixed(0, 4)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+            Ok(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+        })
+    }
+
+    /// SSID of the network the module is connected to.
+    pub fn current_ssid(&mut self) -> Result<Ssid, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetCurrSsid, 1)?;
+            esp32.send_param(&[DUMMY_DATA])?;
+            esp32.end_cmd()?;
+
+            let mut buffer: Buffer<MAX_SSID_LEN, 2> = Buffer::new();
+            esp32.get_response(
+                Esp32Command::GetCurrSsid,
+                &mut buffer,
+                CmdResponseType::Data8,
+            )?;
+            let ssid = buffer
+                .field_as_slice(0)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+            Ok(Ssid::from_slice(ssid))
+        })
+    }
+
+    /// MAC address of the access point the module is connected to.
+    pub fn current_bssid(&mut self) -> Result<[u8; 6], Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetCurrBssid, 1)?;
+            esp32.send_param(&[DUMMY_DATA])?;
+            esp32.end_cmd()?;
+
+            esp32.get_response_mac(Esp32Command::GetCurrBssid)
+        })
+    }
+
+    /// Signal strength of the current connection in dBm.
+    pub fn current_rssi(&mut self) -> Result<i32, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetCurrRssi, 1)?;
+            esp32.send_param(&[DUMMY_DATA])?;
+            esp32.end_cmd()?;
+
+            esp32.get_response_i32(Esp32Command::GetCurrRssi)
+        })
+    }
+
+    /// Channel of the current connection. The firmware doesn't report it directly, so this runs a
+    /// scan and looks up the access point by its BSSID. Returns None if it hasn't been found.
+    pub fn current_channel(&mut self) -> Result<Option<u8>, Esp32Error> {
+        let bssid = self.current_bssid()?;
+
+        Ok(self
+            .scan()?
+            .find(|network| network.bssid == bssid)
+            .map(|network| network.channel))
+    }
+
+    pub fn get_network_data(&mut self) -> Result<(IpV4, IpV4, IpV4), Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetIpAddr, 0)?;
+            esp32.end_cmd()?;
+
+            let mut buffer = Buffer::<12, 4>::new();
+            esp32.get_response(
+                Esp32Command::GetIpAddr,
+                &mut buffer,
+                CmdResponseType::Cmd(3),
+            )?;
+
+            let addr_slice = buffer
+                .field_as_slice_fixed(0, 4)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+            let mask_slice = buffer
+                .field_as_slice_fixed(1, 4)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+            let gateway_slice = buffer
+                .field_as_slice_fixed(2, 4)
+                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+            Ok((
+                IpV4::from_slice(addr_slice),
+                IpV4::from_slice(mask_slice),
+                IpV4::from_slice(gateway_slice),
+            ))
+        })
+    }
+
+    /// Address, netmask and gateway of the station, with where they come from and the DNS
+    /// servers in use. The configuration is lost when the module is reset.
+    pub fn lease_info(&mut self) -> Result<LeaseInfo, Esp32Error> {
+        let (ip, netmask, gateway) = self.get_network_data()?;
+        let (dns1, dns2) = self.get_dns()?;
+        let configured = |addr: IpV4| (addr.0 != [0; 4]).then_some(addr);
+        Ok(LeaseInfo {
+            ip,
+            netmask,
+            gateway,
+            source: self.address_source,
+            dns: configured(dns1).map(|dns1| (dns1, configured(dns2))),
+        })
+    }
+
+    /// Resolve a hostname using the DNS server of the network.
+    pub fn resolve(&mut self, hostname: &str) -> Result<IpV4, Esp32Error> {
+        self.start_cmd(Esp32Command::ReqHostByName, 1)?;
+        self.send_param(hostname.as_bytes())?;
+        self.end_cmd()?;
+
+        if self.get_response_u8(Esp32Command::ReqHostByName)? != 1 {
+            return Err(Esp32Error::HostNotFound);
+        }
+
+        self.start_cmd(Esp32Command::GetHostByName, 0)?;
+        self.end_cmd()?;
+
+        let mut buffer: Buffer<4, 2> = Buffer::new();
+        self.get_response(
+            Esp32Command::GetHostByName,
+            &mut buffer,
+            CmdResponseType::Data8,
+        )?;
+        let ip = buffer
+            .field_as_slice_fixed(0, 4)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+        Ok(IpV4::from_slice(ip))
+    }
+
+    /// Send an ICMP echo request with the given TTL and wait for the reply. Returns the round-trip
+    /// time in milliseconds, or None if there was no reply.
+    pub fn ping(&mut self, ip: IpV4, ttl: u8) -> Result<Option<u16>, Esp32Error> {
+        self.start_cmd(Esp32Command::Ping, 2)?;
+        self.send_param(ip.as_bytes())?;
+        self.send_param(&[ttl])?;
+        self.end_cmd()?;
+
+        match self.get_response_u16(Esp32Command::Ping)? as i16 {
+            time_ms if time_ms >= 0 => Ok(Some(time_ms as u16)),
+            PING_DEST_UNREACHABLE | PING_TIMEOUT => Ok(None),
+            code => Err(Esp32Error::ErrorCode(code as u8)),
+        }
+    }
+
+    /// Get a free socket. It's only marked as used by the firmware once it is connected or bound,
+    /// so requesting another socket before that fails with `SocketInUse`.
+    pub fn get_socket(&mut self) -> Result<Socket, Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::GetSocket, 0)?;
+            esp32.end_cmd()?;
+
+            let socket_id = esp32.get_response_u8(Esp32Command::GetSocket)?;
+            if socket_id as u16 == NO_SOCKET {
+                return Err(Esp32Error::NoFreeSocket);
+            }
+
+            let sock = Socket(socket_id);
+            esp32.sockets.acquire(sock)?;
+            Ok(sock)
+        })
+    }
+
+    /// Number of sockets that have been obtained from the firmware and not closed yet.
+    pub fn sockets_in_use(&self) -> u32 {
+        self.sockets.in_use.count_ones()
+    }
+
+    /// Get a free socket, wrapped in a handle which closes it when dropped.
+    pub fn open_socket(&mut self) -> Result<SocketHandle, Esp32Error> {
+        self.close_dropped_sockets()?;
+        let sock = self.get_socket()?;
+        Ok(SocketHandle {
+            sock,
+            dropped: self.dropped_sockets,
+        })
+    }
+
+    fn close_dropped_sockets(&mut self) -> Result<(), Esp32Error> {
+        for (sock, dropped) in self.dropped_sockets.flags.iter().enumerate() {
+            if dropped.load(Ordering::Relaxed) {
+                dropped.store(false, Ordering::Relaxed);
+                self.stop_client(Socket(sock as u8))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn start_client(
+        &mut self,
+        ip: IpV4,
+        port: u16,
+        sock: Socket,
+        mode: ProtocolMode,
+    ) -> Result<(), Esp32Error> {
+        if let ProtocolMode::TlsBearSsl = mode {
+            return Err(Esp32Error::HostnameRequired);
+        }
+
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::StartClientTcp, 4)?;
+            esp32.send_param(ip.as_bytes())?;
+            // The firmware expects the port in network byte order.
+            esp32.send_param(&port.to_be_bytes())?;
+            esp32.send_param(&[sock.0])?;
+            esp32.send_param(&[mode as u8])?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::StartClientTcp)
+        })
+    }
+
+    /// Open a TCP connection to the given host, resolving its address.
+    pub fn connect_tcp(&mut self, hostname: &str, port: u16) -> Result<SocketHandle, Esp32Error> {
+        let sock = self.open_socket()?;
+        self.start_client_host(hostname, port, sock.socket(), ProtocolMode::Tcp)?;
+        Ok(sock)
+    }
+
+    /// Whether the TCP connection on the socket is established. Turns false once the peer has
+    /// closed it.
+    pub fn socket_connected(&mut self, sock: Socket) -> Result<bool, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.start_cmd(Esp32Command::GetClientStateTcp, 1)?;
+            esp32.send_param(&[sock.0])?;
+            esp32.end_cmd()?;
+
+            Ok(esp32.get_response_u8(Esp32Command::GetClientStateTcp)? == TCP_STATE_ESTABLISHED)
+        })
+    }
+
+    /// Open a TLS connection to the given host. The hostname is used both to resolve the address
+    /// and for SNI and the verification of the server certificate.
+    ///
+    /// The NINA firmware verifies the certificate against the root CA bundle built into its
+    /// flash image. There is no command to upload additional root certificates, so servers with
+    /// private CAs need a firmware with a custom bundle.
+    pub fn connect_tls(&mut self, hostname: &str, port: u16) -> Result<SocketHandle, Esp32Error> {
+        let sock = self.open_socket()?;
+        self.start_client_host(hostname, port, sock.socket(), ProtocolMode::Tls)?;
+        Ok(sock)
+    }
+
+    /// Open a TLS connection using BearSSL instead of mbedTLS. Some firmware builds only verify
+    /// the server certificate on this path, so it's the safer choice when the firmware is not
+    /// known. The hostname can't be empty, since BearSSL needs it for SNI and for the
+    /// verification, and there is no way to connect to a bare address.
+    ///
+    /// BearSSL does the handshake in software while the module holds the ACK line, which can take
+    /// several seconds, so the handshake timeout shouldn't be lowered when using it. Firmware
+    /// without BearSSL fails with `ErrorCode(0)`, like for a failed handshake.
+    pub fn connect_secure(
+        &mut self,
+        hostname: &str,
+        port: u16,
+    ) -> Result<SocketHandle, Esp32Error> {
+        if hostname.is_empty() {
+            return Err(Esp32Error::HostnameRequired);
+        }
+        let sock = self.open_socket()?;
+        self.start_client_host(hostname, port, sock.socket(), ProtocolMode::TlsBearSsl)?;
+        Ok(sock)
+    }
+
+    /// Set the client certificate for the TLS connections requiring mutual authentication, in PEM
+    /// format. Used by the connections opened afterwards, together with `set_private_key`.
+    pub fn set_client_cert(&mut self, cert: &[u8]) -> Result<(), Esp32Error> {
+        if cert.len() > MAX_CLIENT_CERT_SIZE {
+            return Err(Esp32Error::ParamTooLong);
+        }
+
+        self.start_cmd(Esp32Command::SetClientCert, 1)?;
+        self.send_buffer(cert)?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetClientCert)
+    }
+
+    /// Set the private key of the client certificate, in PEM format.
+    pub fn set_private_key(&mut self, key: &[u8]) -> Result<(), Esp32Error> {
+        if key.len() > MAX_PRIVATE_KEY_SIZE {
+            return Err(Esp32Error::ParamTooLong);
+        }
+
+        self.start_cmd(Esp32Command::SetPk, 1)?;
+        self.send_buffer(key)?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::SetPk)
+    }
+
+    // Same as start_client, but with the address resolved by the firmware.
+    fn start_client_host(
+        &mut self,
+        hostname: &str,
+        port: u16,
+        sock: Socket,
+        mode: ProtocolMode,
+    ) -> Result<(), Esp32Error> {
+        self.send_start_client_host(hostname, port, sock, mode)?;
+        self.check_response_status(Esp32Command::StartClientTcp)
+    }
+
+    /// Non-blocking version of `connect_tcp`, `connect_tls` and `connect_secure` for a socket from
+    /// `get_socket`.
+    /// The module doesn't accept other commands until the connection is established or fails,
+    /// which takes up to several seconds, so this has to be called with the same arguments until
+    /// it returns something other than `WouldBlock` before using the module for anything else.
+    pub fn try_connect(
+        &mut self,
+        hostname: &str,
+        port: u16,
+        sock: Socket,
+        mode: ProtocolMode,
+    ) -> nb::Result<(), Esp32Error> {
+        if !self.connect_pending {
+            if !self.is_ready() {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.send_start_client_host(hostname, port, sock, mode)?;
+            self.connect_pending = true;
+        }
+
+        let mut buffer: Buffer<1, 2> = Buffer::new();
+        let response = self.poll_response(
+            Esp32Command::StartClientTcp,
+            &mut buffer,
+            CmdResponseType::Data8,
+        );
+        if let Err(nb::Error::WouldBlock) = response {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.connect_pending = false;
+        response?;
+
+        match buffer.field_as_u8(0) {
+            Ok(1) => Ok(()),
+            Ok(status) => Err(nb::Error::Other(Esp32Error::ErrorCode(status))),
+            Err(e) => Err(nb::Error::Other(Esp32Error::ResponseBufferError(e))),
+        }
+    }
+
+    fn send_start_client_host(
+        &mut self,
+        hostname: &str,
+        port: u16,
+        sock: Socket,
+        mode: ProtocolMode,
+    ) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::StartClientTcp, 5)?;
+        self.send_param(hostname.as_bytes())?;
+        // The address is ignored when the hostname is given.
+        self.send_param(&[0; 4])?;
+        self.send_param(&port.to_be_bytes())?;
+        self.send_param(&[sock.0])?;
+        self.send_param(&[mode as u8])?;
+        self.end_cmd()?;
+
+        Ok(())
+    }
+
+    pub fn insert_data_buf(&mut self, sock: Socket, buf: &[u8]) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::InsertDataBuf, 2)?;
+            esp32.send_buffer(&[sock.0])?;
+            esp32.send_buffer(buf)?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::InsertDataBuf)
+        })
+    }
+
+    /// Like `insert_data_buf`, with the data made of several slices, e.g. a header and a payload,
+    /// that don't have to be copied into one buffer first.
+    pub fn insert_data_vectored(&mut self, sock: Socket, bufs: &[&[u8]]) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::InsertDataBuf, 2)?;
+            esp32.send_buffer(&[sock.0])?;
+            esp32.send_buffer_vectored(bufs)?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::InsertDataBuf)
+        })
+    }
+
+    /// Address and port of the peer of a connection, or the sender of the last received datagram.
+    pub fn get_remote_data(&mut self, sock: Socket) -> Result<(IpV4, u16), Esp32Error> {
+        self.start_cmd(Esp32Command::GetRemoteData, 1)?;
+        self.send_param(&[sock.0])?;
+        self.end_cmd()?;
+
+        let mut buffer: Buffer<6, 3> = Buffer::new();
+        self.get_response(
+            Esp32Command::GetRemoteData,
+            &mut buffer,
+            CmdResponseType::Cmd(2),
+        )?;
+        let ip = buffer
+            .field_as_slice_fixed(0, 4)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+        let port = buffer
+            .field_as_slice_fixed(1, 2)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+        Ok((IpV4::from_slice(ip), u16::from_be_bytes([port[0], port[1]])))
+    }
+
+    /// Bind a UDP socket to a local port, to receive datagrams on it.
+    pub fn start_server_udp(&mut self, port: u16, sock: Socket) -> Result<(), Esp32Error> {
+        self.start_server_tcp(port, sock, ProtocolMode::Udp)
+    }
+
+    /// Join a multicast group and receive the datagrams sent to it on the given port.
+    pub fn start_multicast_udp(
+        &mut self,
+        group: IpV4,
+        port: u16,
+        sock: Socket,
+    ) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::StartServerTcp, 4)?;
+        self.send_param(group.as_bytes())?;
+        self.send_param(&port.to_be_bytes())?;
+        self.send_param(&[sock.0])?;
+        self.send_param(&[ProtocolMode::UdpMulticast as u8])?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::StartServerTcp)
+    }
+
+    pub fn send_data_udp(&mut self, sock: Socket) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::SendDataUdp, 1)?;
+            esp32.send_param(&[sock.0])?;
+            esp32.end_cmd()?;
+
+            esp32.check_response_status(Esp32Command::SendDataUdp)
+        })
+    }
+
+    /// Listen for TCP connections on the given port.
+    pub fn start_server(&mut self, port: u16) -> Result<Listener, Esp32Error> {
+        let sock = self.get_socket()?;
+        self.start_server_tcp(port, sock, ProtocolMode::Tcp)?;
+        Ok(Listener { sock })
+    }
+
+    fn start_server_tcp(
+        &mut self,
+        port: u16,
+        sock: Socket,
+        mode: ProtocolMode,
+    ) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::StartServerTcp, 3)?;
+        self.send_param(&port.to_be_bytes())?;
+        self.send_param(&[sock.0])?;
+        self.send_param(&[mode as u8])?;
+        self.end_cmd()?;
+
+        self.check_response_status(Esp32Command::StartServerTcp)
+    }
+
+    /// State of a server socket, `Listen` while it accepts connections.
+    pub fn server_state(&mut self, sock: Socket) -> Result<TcpState, Esp32Error> {
+        self.start_cmd(Esp32Command::GetStateTcp, 1)?;
+        self.send_param(&[sock.0])?;
+        self.end_cmd()?;
+
+        TcpState::from_u8(self.get_response_u8(Esp32Command::GetStateTcp)?)
+    }
+
+    // For a client socket, the number of received bytes. For a server socket, the socket of a
+    // newly accepted connection or NO_SOCKET.
+    fn avail_data_tcp(&mut self, sock: Socket) -> Result<u16, Esp32Error> {
+        self.start_cmd(Esp32Command::AvailDataTcp, 1)?;
+        self.send_param(&[sock.0])?;
+        self.end_cmd()?;
+
+        self.get_response_u16(Esp32Command::AvailDataTcp)
+    }
+
+    /// Number of received bytes that can be read from a connected socket.
+    pub fn available(&mut self, sock: Socket) -> Result<usize, Esp32Error> {
+        self.retry(CommandClass::Query, |esp32| {
+            esp32.avail_data_tcp(sock).map(|size| size as usize)
+        })
+    }
+
+    /// The next received byte, without consuming it. Only meaningful if `available` is non-zero.
+    pub fn peek_byte(&mut self, sock: Socket) -> Result<u8, Esp32Error> {
+        self.start_cmd(Esp32Command::GetDataTcp, 2)?;
+        self.send_param(&[sock.0])?;
+        // Peek instead of reading.
+        self.send_param(&[1])?;
+        self.end_cmd()?;
+
+        self.get_response_u8(Esp32Command::GetDataTcp)
+    }
+
+    /// Whether the data sent on the socket has been acknowledged by the peer.
+    pub fn data_sent(&mut self, sock: Socket) -> Result<bool, Esp32Error> {
+        self.start_cmd(Esp32Command::DataSentTcp, 1)?;
+        self.send_param(&[sock.0])?;
+        self.end_cmd()?;
+
+        Ok(self.get_response_u8(Esp32Command::DataSentTcp)? != 0)
+    }
+
+    fn accept_client_tcp(&mut self, server: Socket) -> Result<Option<Socket>, Esp32Error> {
+        let client = self.avail_data_tcp(server)?;
+        if client == NO_SOCKET {
+            return Ok(None);
+        }
+
+        if client as usize >= MAX_SOCKETS {
+            return Err(Esp32Error::InvalidSocket(client));
+        }
+        let client = Socket(client as u8);
+        // The firmware only reuses the sockets of accepted connections after they are stopped.
+        self.sockets.acquire(client)?;
+        Ok(Some(client))
+    }
+
+    /// Read the received data from a connected socket without waiting for more to arrive.
+    /// Returns the number of bytes read, which is 0 if nothing is available.
+    pub fn recv(&mut self, sock: Socket, buf: &mut [u8]) -> Result<usize, Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            let size = core::cmp::min(buf.len(), u16::MAX as usize) as u16;
+
+            esp32.start_cmd(Esp32Command::GetDatabufTcp, 2)?;
+            esp32.send_buffer(&[sock.0])?;
+            esp32.send_buffer(&size.to_le_bytes())?;
+            esp32.end_cmd()?;
+
+            esp32.get_response_data16(Esp32Command::GetDatabufTcp, &mut buf[..size as usize])
+        })
+    }
+
+    /// Whether `recv` would return something: data has been received or the peer has closed the
+    /// connection, in which case it returns 0.
+    pub fn poll_readable(&mut self, sock: Socket) -> Result<bool, Esp32Error> {
+        Ok(self.available(sock)? > 0 || !self.socket_connected(sock)?)
+    }
+
+    /// Same as `recv`, but waits up to `timeout_ms` for the data to arrive, and fails with
+    /// `RecvTimeout` if it doesn't. Returns 0 if the peer has closed the connection.
+    pub fn recv_timeout(
+        &mut self,
+        sock: Socket,
+        buf: &mut [u8],
+        timeout_ms: u32,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<usize, Esp32Error> {
+        let mut elapsed_ms = 0;
+        while !self.poll_readable(sock)? {
+            if elapsed_ms >= timeout_ms {
+                return Err(Esp32Error::RecvTimeout);
+            }
+            let wait_ms = core::cmp::min(RECV_POLL_MS, timeout_ms - elapsed_ms);
+            delay.delay_ms(wait_ms);
+            elapsed_ms += wait_ms;
+        }
+        self.recv(sock, buf)
+    }
+
+    /// Non-blocking version of `recv`. Returns `WouldBlock` if nothing has been received, or if
+    /// the module is busy.
+    pub fn try_recv(&mut self, sock: Socket, buf: &mut [u8]) -> nb::Result<usize, Esp32Error> {
+        if self.connect_pending || !self.is_ready() {
+            return Err(nb::Error::WouldBlock);
+        }
+        if self.available(sock)? == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(self.recv(sock, buf)?)
+    }
+
+    /// Send data on a connected socket. Returns the number of bytes accepted by the module.
+    pub fn send_data_tcp(&mut self, sock: Socket, data: &[u8]) -> Result<usize, Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::SendDataTcp, 2)?;
+            esp32.send_buffer(&[sock.0])?;
+            esp32.send_buffer(data)?;
+            esp32.end_cmd()?;
+
+            esp32
+                .get_response_u16(Esp32Command::SendDataTcp)
+                .map(|size| size as usize)
+        })
+    }
+
+    /// Like `send_data_tcp`, with the data made of several slices that don't have to be copied
+    /// into one buffer first.
+    pub fn send_data_tcp_vectored(
+        &mut self,
+        sock: Socket,
+        bufs: &[&[u8]],
+    ) -> Result<usize, Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::SendDataTcp, 2)?;
+            esp32.send_buffer(&[sock.0])?;
+            esp32.send_buffer_vectored(bufs)?;
+            esp32.end_cmd()?;
+
+            esp32
+                .get_response_u16(Esp32Command::SendDataTcp)
+                .map(|size| size as usize)
+        })
+    }
+
+    /// Send a buffer of any size on a connected socket, splitting it into several commands.
+    /// Returns the number of bytes accepted by the module, which is less than `data.len()` only
+    /// if the module has stopped accepting data, e.g. because the connection is stalled.
+    pub fn send_all(&mut self, sock: Socket, data: &[u8]) -> Result<usize, Esp32Error> {
+        let mut sent = 0;
+        let mut attempts = 0;
+
+        while sent < data.len() && attempts < MAX_SEND_ATTEMPTS {
+            let end = core::cmp::min(data.len(), sent + MAX_SEND_CHUNK);
+            let written = self.send_data_tcp(sock, &data[sent..end])?;
+            if written == 0 {
+                attempts += 1;
+            } else {
+                sent += written;
+                attempts = 0;
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Close a client connection or stop a server.
+    pub fn stop_client(&mut self, sock: Socket) -> Result<(), Esp32Error> {
+        self.retry(CommandClass::Data, |esp32| {
+            esp32.start_cmd(Esp32Command::StopClientTcp, 1)?;
+            esp32.send_param(&[sock.0])?;
+            esp32.end_cmd()?;
+
+            esp32.sockets.release(sock);
+            esp32.check_response_status(Esp32Command::StopClientTcp)
+        })
+    }
+}
+
+/// Files in the flash of the module, and firmware updates. These commands are only supported by
+/// the Arduino builds of the NINA firmware, 1.4.0 and later.
+impl<T: Transport> NinaProtocol<T> {
+    // Header shared by the file commands.
+    fn start_file_cmd(
+        &mut self,
+        cmd: Esp32Command,
+        name: &str,
+        offset: u32,
+        len: u32,
+        num_param: u8,
+    ) -> Result<(), Esp32Error> {
+        self.start_cmd(cmd, num_param)?;
+        self.send_param(&offset.to_le_bytes())?;
+        self.send_param(&len.to_le_bytes())?;
+        self.send_param(name.as_bytes())?;
+        Ok(())
+    }
+
+    /// Write `data` to the file at `offset`, creating the file if needed. At most 255 bytes can be
+    /// written at a time.
+    pub fn write_file(&mut self, name: &str, offset: u32, data: &[u8]) -> Result<(), Esp32Error> {
+        if data.len() > MAX_FILE_WRITE_CHUNK {
+            return Err(Esp32Error::ParamTooLong);
+        }
+
+        self.start_file_cmd(Esp32Command::WriteFile, name, offset, data.len() as u32, 4)?;
+        self.send_param(data)?;
+        self.end_cmd()?;
+
+        // The firmware doesn't report whether the write has succeeded.
+        let mut buffer: Buffer<4, 2> = Buffer::new();
+        self.get_response(Esp32Command::WriteFile, &mut buffer, CmdResponseType::Data8)
+    }
+
+    /// Read the file from `offset` into `buf`, at most 255 bytes at a time. Returns the number of
+    /// bytes read.
+    pub fn read_file(
+        &mut self,
+        name: &str,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, Esp32Error> {
+        let len = core::cmp::min(buf.len(), MAX_FILE_READ_CHUNK);
+        self.start_file_cmd(Esp32Command::ReadFile, name, offset, len as u32, 3)?;
+        self.end_cmd()?;
+
+        let mut buffer: Buffer<MAX_FILE_READ_CHUNK, 2> = Buffer::new();
+        self.get_response(Esp32Command::ReadFile, &mut buffer, CmdResponseType::Data8)?;
+        let data = buffer
+            .field_as_slice(0)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+        let size = core::cmp::min(data.len(), len);
+        buf[..size].copy_from_slice(&data[..size]);
+
+        Ok(size)
+    }
+
+    pub fn delete_file(&mut self, name: &str) -> Result<(), Esp32Error> {
+        self.start_file_cmd(Esp32Command::DeleteFile, name, 0, 0, 3)?;
+        self.end_cmd()?;
+
+        let mut buffer: Buffer<4, 2> = Buffer::new();
+        self.get_response(
+            Esp32Command::DeleteFile,
+            &mut buffer,
+            CmdResponseType::Data8,
+        )
+    }
+
+    /// Size of the file, or None if it doesn't exist. Empty files are reported as missing.
+    pub fn file_size(&mut self, name: &str) -> Result<Option<u32>, Esp32Error> {
+        self.start_file_cmd(Esp32Command::ExistsFile, name, 0, 4, 3)?;
+        self.end_cmd()?;
+
+        let mut buffer: Buffer<4, 2> = Buffer::new();
+        self.get_response(
+            Esp32Command::ExistsFile,
+            &mut buffer,
+            CmdResponseType::Data8,
+        )?;
+        let field = buffer
+            .field_as_slice_fixed(0, 4)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+        match u32::from_le_bytes([field[0], field[1], field[2], field[3]]) {
+            0 => Ok(None),
+            size => Ok(Some(size)),
+        }
+    }
+
+    pub fn rename_file(&mut self, old_name: &str, new_name: &str) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::RenameFile, 2)?;
+        self.send_param(old_name.as_bytes())?;
+        self.send_param(new_name.as_bytes())?;
+        self.end_cmd()?;
+
+        // The errno of the rename.
+        let mut buffer: Buffer<4, 2> = Buffer::new();
+        self.get_response(
+            Esp32Command::RenameFile,
+            &mut buffer,
+            CmdResponseType::Data8,
+        )?;
+        let field = buffer
+            .field_as_slice_fixed(0, 4)
+            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
+
+        match field[0] {
+            0 => Ok(()),
+            errno => Err(Esp32Error::ErrorCode(errno)),
+        }
+    }
+
+    /// Download `url` over HTTP into a file. Returns the status reported by the firmware. The
+    /// module is busy until the download is complete, so the handshake timeout has to cover it.
+    pub fn download_file(&mut self, url: &str, name: &str) -> Result<u8, Esp32Error> {
+        self.start_cmd(Esp32Command::DownloadFile, 2)?;
+        self.send_param(url.as_bytes())?;
+        self.send_param(name.as_bytes())?;
+        self.end_cmd()?;
+
+        self.get_response_u8(Esp32Command::DownloadFile)
+    }
+
+    /// Download a firmware image from `url` into the OTA partition. Returns the status reported by
+    /// the firmware. The new firmware is started by `apply_ota`.
+    pub fn download_ota(&mut self, url: &str) -> Result<u8, Esp32Error> {
+        self.start_cmd(Esp32Command::DownloadOta, 1)?;
+        self.send_param(url.as_bytes())?;
+        self.end_cmd()?;
+
+        self.get_response_u8(Esp32Command::DownloadOta)
+    }
+
+    /// Restart the module into the firmware downloaded by `download_ota`. There is no response,
+    /// and like after a reset the network connection and all the sockets are lost.
+    pub fn apply_ota(&mut self) -> Result<(), Esp32Error> {
+        self.start_cmd(Esp32Command::ApplyOta, 0)?;
+        self.end_cmd()?;
+
+        self.clear_state();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransport;
+
+    fn esp32() -> NinaProtocol<MockTransport> {
+        NinaProtocol::new(MockTransport::new(), Box::leak(Box::default()))
+    }
+
+    struct NoDelay;
+
+    impl DelayMs<u32> for NoDelay {
+        fn delay_ms(&mut self, _ms: u32) {}
+    }
+
+    #[test]
+    fn frames_command_and_pads_to_4_bytes() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::SetNet as u8, &[&[1]]);
+
+        esp32.set_network("ab").unwrap();
+
+        let command = &esp32.transport().transactions()[0];
+        assert_eq!(command, &[0xE0, 0x10, 1, 2, b'a', b'b', 0xEE, 0xFF]);
+        assert!(!esp32.transport().selected());
+    }
+
+    #[test]
+    fn doesnt_pad_aligned_command() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[3]]);
+
+        assert_eq!(
+            esp32.get_conn_status().unwrap(),
+            ConnectionStatus::Connected
+        );
+        assert_eq!(esp32.transport().transactions()[0], [0xE0, 0x20, 0, 0xEE]);
+    }
+
+    #[test]
+    fn pads_command_with_16_bit_params() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response_data16(Esp32Command::GetDatabufTcp as u8, b"hello");
+
+        let mut buf = [0; 8];
+        assert_eq!(esp32.recv(Socket(3), &mut buf).unwrap(), 5);
+
+        let command = &esp32.transport().transactions()[0];
+        assert_eq!(command, &[0xE0, 0x45, 2, 0, 1, 3, 0, 2, 8, 0, 0xEE, 0xFF]);
+        assert_eq!(command.len() % 4, 0);
+    }
+
+    #[test]
+    fn sends_port_in_network_order() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::StartClientTcp as u8, &[&[1]]);
+
+        esp32
+            .start_client(IpV4([10, 0, 0, 1]), 0x1234, Socket(2), ProtocolMode::Udp)
+            .unwrap();
+
+        assert_eq!(
+            esp32.transport().transactions()[0],
+            [0xE0, 0x2d, 4, 4, 10, 0, 0, 1, 2, 0x12, 0x34, 1, 2, 1, 1, 0xEE]
+        );
+    }
+
+    #[test]
+    fn requires_hostname_for_bearssl() {
+        let mut esp32 = esp32();
+
+        assert!(matches!(
+            esp32.start_client(
+                IpV4([10, 0, 0, 1]),
+                443,
+                Socket(0),
+                ProtocolMode::TlsBearSsl
+            ),
+            Err(Esp32Error::HostnameRequired)
+        ));
+        assert!(matches!(
+            esp32.connect_secure("", 443),
+            Err(Esp32Error::HostnameRequired)
+        ));
+        assert!(esp32.transport().transactions().is_empty());
+    }
+
+    #[test]
+    fn checks_reply_flag() {
+        let mut esp32 = esp32();
+        // The command without the reply flag.
+        esp32.transport().queue_bytes(&[0xE0, 0x20, 1, 1, 3, 0xEE]);
+
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::UnexpectedByte {
+                cmd: 0x20,
+                expected: 0xA0,
+                actual: 0x20,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn skips_bytes_before_start() {
+        let mut esp32 = esp32();
+        esp32.transport().queue_bytes(&[0xFF, 0x00, 0xFF]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[6]]);
+
+        assert_eq!(
+            esp32.get_conn_status().unwrap(),
+            ConnectionStatus::Disconnected
+        );
+    }
+
+    #[test]
+    fn reports_error_response() {
+        let mut esp32 = esp32();
+        esp32.transport().queue_bytes(&[0xEF]);
+
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::ErrCmd { cmd: 0x20, .. })
+        ));
+    }
+
+    #[cfg(feature = "transcript")]
+    #[test]
+    fn attaches_transcript_to_errors() {
+        let mut esp32 = esp32();
+        esp32.transport().queue_bytes(&[0xEF]);
+
+        let transcript = match esp32.get_conn_status() {
+            Err(Esp32Error::ErrCmd { transcript, .. }) => transcript,
+            result => panic!("Unexpected result {result:?}"),
+        };
+        let recorded: Vec<(Direction, u8)> = transcript.iter().collect();
+        assert_eq!(recorded[0], (Direction::Sent, 0xE0));
+        assert_eq!(recorded.last(), Some(&(Direction::Received, 0xEF)));
+    }
+
+    #[test]
+    fn times_out_without_response() {
+        let mut esp32 = esp32();
+
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::WaitForByteTimeout { cmd: 0x20, .. })
+        ));
+    }
+
+    #[test]
+    fn times_out_waiting_for_response() {
+        let mut esp32 = esp32();
+        esp32.set_byte_timeout(100);
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::WaitForByteTimeout { .. })
+        ));
+        // The mock clock advances by a microsecond per byte.
+        assert!(esp32.transport().transactions()[1].len() <= 101);
+    }
+
+    #[test]
+    fn retries_query_after_garbled_response() {
+        let mut esp32 = esp32();
+        esp32.set_retry_policy(CommandClass::Query, RetryPolicy { attempts: 2 });
+        esp32.transport().queue_bytes(&[0xE0, 0x20, 1, 1, 3, 0xEE]);
+        // Ends the drain after the error.
+        esp32
+            .transport()
+            .queue_bytes(&[DUMMY_DATA; DRAIN_DUMMY_RUN]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[3]]);
+
+        assert_eq!(
+            esp32.get_conn_status().unwrap(),
+            ConnectionStatus::Connected
+        );
+        assert_eq!(esp32.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn doesnt_retry_data_by_default() {
+        let mut esp32 = esp32();
+        esp32.set_retry_policy(CommandClass::Query, RetryPolicy { attempts: 3 });
+        let cmd = Esp32Command::SendDataTcp as u8;
+        esp32
+            .transport()
+            .queue_bytes(&[0xE0, cmd, 1, 2, 5, 0, 0xEE]);
+        esp32
+            .transport()
+            .queue_bytes(&[DUMMY_DATA; DRAIN_DUMMY_RUN]);
+        esp32
+            .transport()
+            .queue_response(cmd, &[&5u16.to_le_bytes()]);
+
+        assert!(esp32.send_data_tcp(Socket(0), b"hello").is_err());
+        assert!(esp32.transport().remaining() > 0);
+    }
+
+    #[test]
+    fn checks_number_of_params() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[3], &[3]]);
+
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::WrongNumberOfResponseParams {
+                expected: 1,
+                actual: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn checks_end_byte() {
+        let mut esp32 = esp32();
+        esp32.transport().queue_bytes(&[0xE0, 0xA0, 1, 1, 3, 0x00]);
+
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::UnexpectedByte {
+                expected: 0xEE,
+                actual: 0x00,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn trims_firmware_version() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetFwVersion as u8, &[b"1.7.4\0"]);
+
+        assert_eq!(esp32.firmware_version().unwrap().as_str(), "1.7.4");
+    }
+
+    #[test]
+    fn reports_ping_timeout() {
+        let mut esp32 = esp32();
+        let cmd = Esp32Command::Ping as u8;
+        esp32
+            .transport()
+            .queue_response(cmd, &[&12u16.to_le_bytes()]);
+        esp32
+            .transport()
+            .queue_response(cmd, &[&PING_TIMEOUT.to_le_bytes()]);
+
+        let ip = IpV4([192, 168, 0, 1]);
+        assert_eq!(esp32.ping(ip, 64).unwrap(), Some(12));
+        assert_eq!(esp32.ping(ip, 64).unwrap(), None);
+    }
+
+    #[test]
+    fn sends_file_data_with_length() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::WriteFile as u8, &[&[0]]);
+
+        esp32.write_file("a", 0, b"xyz").unwrap();
+
+        let command = &esp32.transport().transactions()[0];
+        assert_eq!(
+            command,
+            &[0xE0, 0x60, 4, 4, 0, 0, 0, 0, 4, 3, 0, 0, 0, 1, b'a', 3, b'x', b'y', b'z', 0xEE]
+        );
+    }
+
+    #[test]
+    fn rejects_long_file_write() {
+        let mut esp32 = esp32();
+        assert!(matches!(
+            esp32.write_file("a", 0, &[0; 256]),
+            Err(Esp32Error::ParamTooLong)
+        ));
+        assert!(esp32.transport().transactions().is_empty());
+    }
+
+    #[test]
+    fn rejects_long_param() {
+        let mut esp32 = esp32();
+        let ssid = core::str::from_utf8(&[b'a'; 256]).unwrap();
+        assert!(matches!(
+            esp32.set_network(ssid),
+            Err(Esp32Error::ParamTooLong)
+        ));
+        assert!(!esp32.transport().selected());
+    }
+
+    #[test]
+    fn rejects_unknown_status() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[42]]);
+
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::UnexpectedStatus(42))
+        ));
+    }
+
+    #[test]
+    fn resyncs_after_protocol_error() {
+        let mut esp32 = esp32();
+        // Truncated response followed by garbage.
+        esp32
+            .transport()
+            .queue_bytes(&[0xE0, 0x21, 1, 1, 3, 0xEE, 0x12, 0x34]);
+        assert!(esp32.get_conn_status().is_err());
+        assert_eq!(esp32.transport().remaining(), 0);
+        assert!(!esp32.transport().selected());
+
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[3]]);
+        assert_eq!(
+            esp32.get_conn_status().unwrap(),
+            ConnectionStatus::Connected
+        );
+    }
+
+    #[test]
+    fn waits_for_scan_results_while_connected() {
+        let mut esp32 = esp32();
+        let transport = esp32.transport();
+        transport.queue_response(Esp32Command::StartScanNetworks as u8, &[&[1]]);
+        for ssids in [&[][..], &[&b"net"[..]][..]] {
+            transport.queue_response(Esp32Command::GetConnStatus as u8, &[&[3]]);
+            transport.queue_response(Esp32Command::ScanNetworks as u8, ssids);
+        }
+
+        transport.queue_response(Esp32Command::GetIdxRssi as u8, &[&(-40i32).to_le_bytes()]);
+        transport.queue_response(Esp32Command::GetIdxChannel as u8, &[&[6]]);
+        transport.queue_response(Esp32Command::GetIdxEnct as u8, &[&[4]]);
+        transport.queue_response(Esp32Command::GetIdxBssid as u8, &[&[1, 2, 3, 4, 5, 6]]);
+
+        esp32.start_scan().unwrap();
+        assert!(!esp32.scan_complete().unwrap());
+        assert!(esp32.scan_complete().unwrap());
+
+        // The networks found by the completed scan are collected without scanning again.
+        let results: Vec<_> = esp32.scan_results().unwrap().collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].ssid.as_bytes(), b"net");
+        assert_eq!(results[0].rssi, -40);
+        assert_eq!(esp32.transport().remaining(), 0);
+        let scans = esp32
+            .transport()
+            .transactions()
+            .iter()
+            .filter(|transaction| transaction[..2] == [0xE0, Esp32Command::ScanNetworks as u8])
+            .count();
+        assert_eq!(scans, 2);
+    }
+
+    #[test]
+    fn recovers_from_bus_error() {
+        let mut esp32 = esp32();
+        esp32.transport().fail_transfers(1);
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::Bus(BusError))
+        ));
+        assert!(!esp32.transport().selected());
+
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[3]]);
+        assert_eq!(
+            esp32.get_conn_status().unwrap(),
+            ConnectionStatus::Connected
+        );
+    }
+
+    #[test]
+    fn truncates_data16_response() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response_data16(Esp32Command::GetDatabufTcp as u8, b"hello, world");
+
+        let mut buf = [0; 5];
+        assert_eq!(esp32.recv(Socket(0), &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        assert_eq!(esp32.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn times_out_waiting_for_data() {
+        let mut esp32 = esp32();
+        for _ in 0..3 {
+            esp32
+                .transport()
+                .queue_response(Esp32Command::AvailDataTcp as u8, &[&[0, 0]]);
+            esp32
+                .transport()
+                .queue_response(Esp32Command::GetClientStateTcp as u8, &[&[4]]);
+        }
+
+        let mut buf = [0; 4];
+        assert!(matches!(
+            esp32.recv_timeout(Socket(0), &mut buf, 2 * RECV_POLL_MS, &mut NoDelay),
+            Err(Esp32Error::RecvTimeout)
+        ));
+        assert_eq!(esp32.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn returns_eof_when_peer_closes() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::AvailDataTcp as u8, &[&[0, 0]]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetClientStateTcp as u8, &[&[0]]);
+        esp32
+            .transport()
+            .queue_response_data16(Esp32Command::GetDatabufTcp as u8, &[]);
+
+        let mut buf = [0; 4];
+        assert_eq!(
+            esp32
+                .recv_timeout(Socket(0), &mut buf, 1000, &mut NoDelay)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn reads_long_data16_param() {
+        let mut esp32 = esp32();
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        esp32
+            .transport()
+            .queue_response_data16(Esp32Command::GetDatabufTcp as u8, &data);
+
+        let mut buffer: Buffer<300, 2> = Buffer::new();
+        esp32
+            .get_response(
+                Esp32Command::GetDatabufTcp,
+                &mut buffer,
+                CmdResponseType::Data16,
+            )
+            .unwrap();
+        assert_eq!(buffer.field_as_slice(0).unwrap(), &data[..]);
+        assert_eq!(esp32.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn reports_handshake_timeout() {
+        let mut esp32 = esp32();
+        esp32.transport().set_unresponsive(true);
+
+        assert!(matches!(
+            esp32.get_conn_status(),
+            Err(Esp32Error::HandshakeTimeout)
+        ));
+        assert!(!esp32.transport().selected());
+        assert_eq!(esp32.transport().resets(), 0);
+    }
+
+    #[test]
+    fn resets_unresponsive_module() {
+        let mut esp32 = esp32();
+        esp32.set_auto_reset(true);
+        esp32.transport().set_unresponsive(true);
+
+        assert!(esp32.get_conn_status().is_err());
+        assert_eq!(esp32.transport().resets(), 1);
+    }
+
+    #[test]
+    fn sleeps_and_wakes() {
+        let mut esp32 = esp32();
+        esp32.sleep();
+        assert_eq!(esp32.transport().power_downs(), 1);
+        assert!(matches!(esp32.get_conn_status(), Err(Esp32Error::Asleep)));
+        assert!(esp32.transport().transactions().is_empty());
+
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[0]]);
+        esp32.wake().unwrap();
+        assert_eq!(esp32.transport().resets(), 1);
+        assert_eq!(esp32.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn holds_module_in_reset_when_freed() {
+        let esp32 = esp32();
+        let transport = esp32.free();
+        assert_eq!(transport.power_downs(), 1);
+        assert!(!transport.selected());
+    }
+
+    #[test]
+    fn reports_join_failure_reason() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetConnStatus as u8, &[&[4]]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetReasonCode as u8, &[&[15]]);
+
+        assert!(matches!(
+            esp32.wait_for_connection(1000, &mut NoDelay),
+            Err(Esp32Error::JoinFailed(ConnectError::WrongPassword))
+        ));
+    }
+
+    #[test]
+    fn tracks_sockets() {
+        let mut esp32 = esp32();
+        let cmd = Esp32Command::GetSocket as u8;
+        esp32.transport().queue_response(cmd, &[&[0]]);
+        esp32.transport().queue_response(cmd, &[&[0]]);
+        esp32.transport().queue_response(cmd, &[&[255]]);
+        esp32.transport().queue_response(cmd, &[&[40]]);
+
+        assert_eq!(esp32.get_socket().unwrap().0, 0);
+        assert!(matches!(esp32.get_socket(), Err(Esp32Error::SocketInUse)));
+        assert!(matches!(esp32.get_socket(), Err(Esp32Error::NoFreeSocket)));
+        assert!(matches!(
+            esp32.get_socket(),
+            Err(Esp32Error::InvalidSocket(40))
+        ));
+        assert_eq!(esp32.sockets_in_use(), 1);
+    }
+
+    #[test]
+    fn closes_dropped_sockets_of_own_driver_only() {
+        let cmd = Esp32Command::GetSocket as u8;
+        let mut first = esp32();
+        let mut second = esp32();
+        first.transport().queue_response(cmd, &[&[0]]);
+        second.transport().queue_response(cmd, &[&[1]]);
+
+        drop(first.open_socket().unwrap());
+        assert_eq!(second.open_socket().unwrap().socket().0, 1);
+        // Only GET_SOCKET, without stopping the socket dropped on the other driver.
+        assert_eq!(second.transport().transactions().len(), 2);
+    }
+
+    #[test]
+    fn splits_large_send() {
+        let mut esp32 = esp32();
+        let cmd = Esp32Command::SendDataTcp as u8;
+        esp32
+            .transport()
+            .queue_response(cmd, &[&4000u16.to_le_bytes()]);
+        esp32
+            .transport()
+            .queue_response(cmd, &[&1000u16.to_le_bytes()]);
+
+        let data = [0x55; 5000];
+        assert_eq!(esp32.send_all(Socket(0), &data).unwrap(), 5000);
+
+        let transactions = esp32.transport().transactions();
+        assert_eq!(transactions.len(), 4);
+        assert_eq!(&transactions[0][6..8], &[0x0f, 0xa0]);
+        assert_eq!(&transactions[2][6..8], &[0x03, 0xe8]);
+    }
+
+    #[test]
+    fn reports_partial_send() {
+        let mut esp32 = esp32();
+        let cmd = Esp32Command::SendDataTcp as u8;
+        esp32
+            .transport()
+            .queue_response(cmd, &[&3u16.to_le_bytes()]);
+        for _ in 0..MAX_SEND_ATTEMPTS {
+            esp32
+                .transport()
+                .queue_response(cmd, &[&0u16.to_le_bytes()]);
+        }
+
+        assert_eq!(esp32.send_all(Socket(0), b"hello").unwrap(), 3);
+        assert_eq!(esp32.transport().remaining(), 0);
+    }
+
+    #[test]
+    fn doesnt_block_while_busy() {
+        let mut esp32 = esp32();
+        esp32.transport().set_busy(true);
+
+        assert!(!esp32.is_ready());
+        let mut buf = [0; 4];
+        assert!(matches!(
+            esp32.try_recv(Socket(0), &mut buf),
+            Err(nb::Error::WouldBlock)
+        ));
+        assert!(esp32.transport().transactions().is_empty());
+    }
+
+    #[test]
+    fn reads_dns_servers() {
+        let mut esp32 = esp32();
+        esp32.transport().queue_response(
+            Esp32Command::GetDnsConfig as u8,
+            &[&[192, 168, 1, 1], &[0; 4]],
+        );
+
+        let (dns1, dns2) = esp32.get_dns().unwrap();
+        assert_eq!(dns1.octets(), [192, 168, 1, 1]);
+        assert_eq!(dns2.octets(), [0; 4]);
+        assert_eq!(esp32.transport().transactions()[0], [0xE0, 0x1E, 0, 0xEE]);
+    }
+
+    #[test]
+    fn reports_static_lease() {
+        let mut esp32 = esp32();
+        let ip = IpV4([192, 168, 1, 20]);
+        let netmask = IpV4([255, 255, 255, 0]);
+        let gateway = IpV4([192, 168, 1, 1]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::SetIpConfig as u8, &[&[1]]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::SetDnsConfig as u8, &[&[1]]);
+        esp32.transport().queue_response(
+            Esp32Command::GetIpAddr as u8,
+            &[&ip.0, &netmask.0, &gateway.0],
+        );
+        esp32
+            .transport()
+            .queue_response(Esp32Command::GetDnsConfig as u8, &[&[1, 1, 1, 1], &[0; 4]]);
+
+        esp32.set_static_ip(ip, gateway, netmask).unwrap();
+        esp32.set_dns(IpV4([1, 1, 1, 1]), None).unwrap();
+        let lease = esp32.lease_info().unwrap();
+        assert_eq!(lease.source, AddressSource::Static);
+        assert_eq!(lease.gateway.octets(), gateway.0);
+        let (dns1, dns2) = lease.dns.unwrap();
+        assert_eq!(dns1.octets(), [1, 1, 1, 1]);
+        assert!(dns2.is_none());
+
+        esp32.sleep();
+        assert_eq!(esp32.address_source, AddressSource::Dhcp);
+    }
+
+    #[test]
+    fn reports_dhcp_dns_servers() {
+        let mut esp32 = esp32();
+        esp32.transport().queue_response(
+            Esp32Command::GetIpAddr as u8,
+            &[&[192, 168, 1, 20], &[255, 255, 255, 0], &[192, 168, 1, 1]],
+        );
+        esp32.transport().queue_response(
+            Esp32Command::GetDnsConfig as u8,
+            &[&[192, 168, 1, 1], &[8, 8, 8, 8]],
+        );
+
+        let lease = esp32.lease_info().unwrap();
+        assert_eq!(lease.source, AddressSource::Dhcp);
+        let (dns1, dns2) = lease.dns.unwrap();
+        assert_eq!(dns1.octets(), [192, 168, 1, 1]);
+        assert_eq!(dns2.unwrap().octets(), [8, 8, 8, 8]);
+    }
+
+    #[test]
+    fn sends_vectored_data() {
+        let mut esp32 = esp32();
+        esp32
+            .transport()
+            .queue_response(Esp32Command::InsertDataBuf as u8, &[&[1]]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::InsertDataBuf as u8, &[&[1]]);
+
+        esp32
+            .insert_data_vectored(Socket(2), &[b"head", b"", b"payload"])
+            .unwrap();
+        esp32.insert_data_buf(Socket(2), b"headpayload").unwrap();
+
+        let transactions = esp32.transport().transactions();
+        let mut expected = vec![0xE0, 0x46, 2, 0, 1, 2, 0, 11];
+        expected.extend_from_slice(b"headpayload");
+        expected.push(0xEE);
+        assert_eq!(transactions[0], expected);
+        assert_eq!(transactions[2], expected);
+    }
+
+    #[test]
+    fn keeps_strongest_access_point_per_ssid() {
+        let network = |ssid: &[u8], rssi| {
+            Some(ScanResult {
+                ssid: Ssid::from_slice(ssid),
+                rssi,
+                channel: 1,
+                encryption: EncryptionType::Auto,
+                bssid: [0; 6],
+            })
+        };
+        let mut results = [None; MAX_SCAN_RESULTS];
+        results[..4].copy_from_slice(&[
+            network(b"home", -70),
+            network(b"lab", -60),
+            network(b"home", -50),
+            network(b"lab", -80),
+        ]);
+
+        let results = strongest_per_ssid(results);
+        let kept: Vec<_> = results
+            .iter()
+            .flatten()
+            .map(|result| (result.ssid.as_bytes(), result.rssi))
+            .collect();
+        assert_eq!(kept, [(&b"home"[..], -50), (&b"lab"[..], -60)]);
+    }
+}
@@ -0,0 +1,244 @@
+//! Blocking byte stream over a connected TCP socket, implementing the embedded-io traits and
+//! `fmt::Write`, so that text protocols can be written with `write!` straight to the network and
+//! generic embedded-io code can read from it. `UdpWriter` does the same for the datagrams sent on
+//! a UDP socket.
+
+use core::fmt;
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_io::{ErrorKind, ErrorType, Read, Write};
+
+use crate::protocol::{Esp32Error, NinaProtocol, Socket};
+use crate::transport::Transport;
+
+const DEFAULT_READ_TIMEOUT_MS: u32 = 5000;
+
+/// A connected socket together with the driver and a delay, for the duration of a conversation
+/// over it. The socket stays open when the stream is dropped.
+pub struct TcpStream<'a, T: Transport, D: DelayMs<u32>> {
+    esp32: &'a mut NinaProtocol<T>,
+    sock: Socket,
+    delay: &'a mut D,
+    read_timeout_ms: u32,
+}
+
+impl<'a, T: Transport, D: DelayMs<u32>> TcpStream<'a, T, D> {
+    pub fn new(esp32: &'a mut NinaProtocol<T>, sock: Socket, delay: &'a mut D) -> Self {
+        TcpStream {
+            esp32,
+            sock,
+            delay,
+            read_timeout_ms: DEFAULT_READ_TIMEOUT_MS,
+        }
+    }
+
+    /// Time `read` waits for data before failing with `RecvTimeout`.
+    pub fn set_read_timeout(&mut self, timeout_ms: u32) {
+        self.read_timeout_ms = timeout_ms;
+    }
+
+    pub fn socket(&self) -> Socket {
+        self.sock
+    }
+}
+
+impl embedded_io::Error for Esp32Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Esp32Error::RecvTimeout | Esp32Error::SendStalled => ErrorKind::TimedOut,
+            Esp32Error::NotConnected => ErrorKind::NotConnected,
+            Esp32Error::HostNotFound => ErrorKind::NotFound,
+            Esp32Error::ParamTooLong
+            | Esp32Error::UnsupportedAddress
+            | Esp32Error::HostnameRequired => ErrorKind::InvalidInput,
+            Esp32Error::NoFreeSocket => ErrorKind::OutOfMemory,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl<'a, T: Transport, D: DelayMs<u32>> ErrorType for TcpStream<'a, T, D> {
+    type Error = Esp32Error;
+}
+
+impl<'a, T: Transport, D: DelayMs<u32>> Read for TcpStream<'a, T, D> {
+    /// Waits for data up to the read timeout. Returns 0 once the peer has closed the connection.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Esp32Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.esp32
+            .recv_timeout(self.sock, buf, self.read_timeout_ms, self.delay)
+    }
+}
+
+impl<'a, T: Transport, D: DelayMs<u32>> Write for TcpStream<'a, T, D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Esp32Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.esp32.send_all(self.sock, buf)? {
+            0 => Err(Esp32Error::SendStalled),
+            sent => Ok(sent),
+        }
+    }
+
+    // The module sends the data right away.
+    fn flush(&mut self) -> Result<(), Esp32Error> {
+        Ok(())
+    }
+}
+
+impl<'a, T: Transport, D: DelayMs<u32>> fmt::Write for TcpStream<'a, T, D> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.esp32.send_all(self.sock, s.as_bytes()) {
+            Ok(sent) if sent == s.len() => Ok(()),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// Datagram built by writing to it, which is sent by `flush`. The data is buffered by the module,
+/// so the datagram isn't limited by the memory of the Pico, but has to fit in the MTU. It is sent
+/// to the destination set by `NinaProtocol::start_client`. Nothing is sent when the writer is
+/// dropped without `flush`.
+pub struct UdpWriter<'a, T: Transport> {
+    esp32: &'a mut NinaProtocol<T>,
+    sock: Socket,
+    // Something has been written since the last flush.
+    pending: bool,
+}
+
+impl<'a, T: Transport> UdpWriter<'a, T> {
+    pub fn new(esp32: &'a mut NinaProtocol<T>, sock: Socket) -> Self {
+        UdpWriter {
+            esp32,
+            sock,
+            pending: false,
+        }
+    }
+
+    pub fn socket(&self) -> Socket {
+        self.sock
+    }
+}
+
+impl<'a, T: Transport> ErrorType for UdpWriter<'a, T> {
+    type Error = Esp32Error;
+}
+
+impl<'a, T: Transport> Write for UdpWriter<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Esp32Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.esp32.insert_data_buf(self.sock, buf)?;
+        self.pending = true;
+        Ok(buf.len())
+    }
+
+    /// Send the datagram written since the last flush. Does nothing if nothing has been written.
+    fn flush(&mut self) -> Result<(), Esp32Error> {
+        if self.pending {
+            self.esp32.send_data_udp(self.sock)?;
+            self.pending = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: Transport> fmt::Write for UdpWriter<'a, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Write::write(self, s.as_bytes())
+            .map(|_| ())
+            .map_err(|_| fmt::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransport;
+    use crate::protocol::{Esp32Command, MAX_SEND_ATTEMPTS};
+
+    struct NoDelay;
+
+    impl DelayMs<u32> for NoDelay {
+        fn delay_ms(&mut self, _ms: u32) {}
+    }
+
+    #[test]
+    fn writes_formatted_text() {
+        let mut esp32 = NinaProtocol::new(MockTransport::new(), Box::leak(Box::default()));
+        let cmd = Esp32Command::SendDataTcp as u8;
+        esp32
+            .transport()
+            .queue_response(cmd, &[&4u16.to_le_bytes()]);
+
+        let mut delay = NoDelay;
+        let mut stream = TcpStream::new(&mut esp32, Socket(0), &mut delay);
+        fmt::Write::write_fmt(&mut stream, format_args!("HELO")).unwrap();
+
+        let command = &esp32.transport().transactions()[0];
+        assert_eq!(&command[8..12], b"HELO");
+    }
+
+    #[test]
+    fn fails_write_when_stalled() {
+        let mut esp32 = NinaProtocol::new(MockTransport::new(), Box::leak(Box::default()));
+        let cmd = Esp32Command::SendDataTcp as u8;
+        for _ in 0..MAX_SEND_ATTEMPTS {
+            esp32
+                .transport()
+                .queue_response(cmd, &[&0u16.to_le_bytes()]);
+        }
+
+        let mut delay = NoDelay;
+        let mut stream = TcpStream::new(&mut esp32, Socket(0), &mut delay);
+        assert!(matches!(
+            stream.write(b"data"),
+            Err(Esp32Error::SendStalled)
+        ));
+    }
+
+    #[test]
+    fn reads_received_data() {
+        let mut esp32 = NinaProtocol::new(MockTransport::new(), Box::leak(Box::default()));
+        esp32
+            .transport()
+            .queue_response(Esp32Command::AvailDataTcp as u8, &[&6u16.to_le_bytes()]);
+        esp32
+            .transport()
+            .queue_response_data16(Esp32Command::GetDatabufTcp as u8, b"250 OK");
+
+        let mut delay = NoDelay;
+        let mut stream = TcpStream::new(&mut esp32, Socket(0), &mut delay);
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"250 OK");
+    }
+
+    #[test]
+    fn sends_datagram_on_flush() {
+        let mut esp32 = NinaProtocol::new(MockTransport::new(), Box::leak(Box::default()));
+        esp32
+            .transport()
+            .queue_response(Esp32Command::InsertDataBuf as u8, &[&[1]]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::InsertDataBuf as u8, &[&[1]]);
+        esp32
+            .transport()
+            .queue_response(Esp32Command::SendDataUdp as u8, &[&[1]]);
+
+        let mut writer = UdpWriter::new(&mut esp32, Socket(0));
+        writer.write_all(b"t=").unwrap();
+        writer.write_all(b"21").unwrap();
+        writer.flush().unwrap();
+        // Nothing is pending after the datagram has been sent.
+        writer.flush().unwrap();
+
+        assert_eq!(esp32.transport().remaining(), 0);
+        assert_eq!(esp32.transport().transactions().len(), 6);
+    }
+}
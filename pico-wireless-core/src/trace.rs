@@ -0,0 +1,105 @@
+//! Logging of every command sent to the module and of the raw response, with the `trace-spi`
+//! feature. The command is logged as it is sent, one line per parameter, while the response is
+//! collected and logged in a single line once it has been read:
+//!
+//! ```text
+//! GetConnStatus (0x20), 0 params
+//! GetConnStatus < e0 a0 01 01 03 ee
+//! ```
+//!
+//! Without the feature, `SpiTrace` takes no space and logs nothing.
+
+use core::fmt;
+
+#[cfg(feature = "trace-spi")]
+use log::trace;
+
+// Longest response logged in full. The rest is only counted.
+#[cfg(feature = "trace-spi")]
+const MAX_TRACED_RESPONSE: usize = 64;
+
+pub(crate) struct SpiTrace {
+    #[cfg(feature = "trace-spi")]
+    response: [u8; MAX_TRACED_RESPONSE],
+    // Total number of response bytes, including the ones that didn't fit.
+    #[cfg(feature = "trace-spi")]
+    len: usize,
+}
+
+impl SpiTrace {
+    pub(crate) const fn new() -> Self {
+        SpiTrace {
+            #[cfg(feature = "trace-spi")]
+            response: [0; MAX_TRACED_RESPONSE],
+            #[cfg(feature = "trace-spi")]
+            len: 0,
+        }
+    }
+
+    #[cfg(feature = "trace-spi")]
+    pub(crate) fn command(&self, cmd: impl fmt::Debug, opcode: u8, num_param: u8) {
+        trace!("{cmd:?} ({opcode:#04x}), {num_param} params");
+    }
+
+    #[cfg(not(feature = "trace-spi"))]
+    pub(crate) fn command(&self, _cmd: impl fmt::Debug, _opcode: u8, _num_param: u8) {}
+
+    #[cfg(feature = "trace-spi")]
+    pub(crate) fn param(&self, param: &[u8]) {
+        trace!("  {}", Hex(param));
+    }
+
+    #[cfg(not(feature = "trace-spi"))]
+    pub(crate) fn param(&self, _param: &[u8]) {}
+
+    #[cfg(feature = "trace-spi")]
+    pub(crate) fn start_response(&mut self) {
+        self.len = 0;
+    }
+
+    #[cfg(not(feature = "trace-spi"))]
+    pub(crate) fn start_response(&mut self) {}
+
+    #[cfg(feature = "trace-spi")]
+    pub(crate) fn response_bytes(&mut self, bytes: &[u8]) {
+        if self.len < MAX_TRACED_RESPONSE {
+            let n = core::cmp::min(bytes.len(), MAX_TRACED_RESPONSE - self.len);
+            self.response[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        }
+        self.len += bytes.len();
+    }
+
+    #[cfg(not(feature = "trace-spi"))]
+    pub(crate) fn response_bytes(&mut self, _bytes: &[u8]) {}
+
+    #[cfg(feature = "trace-spi")]
+    pub(crate) fn end_response(&self, cmd: impl fmt::Debug) {
+        let traced = core::cmp::min(self.len, MAX_TRACED_RESPONSE);
+        let hex = Hex(&self.response[..traced]);
+        if self.len > traced {
+            trace!("{cmd:?} < {hex} (+{} bytes)", self.len - traced);
+        } else {
+            trace!("{cmd:?} < {hex}");
+        }
+    }
+
+    #[cfg(not(feature = "trace-spi"))]
+    pub(crate) fn end_response(&self, _cmd: impl fmt::Debug) {}
+}
+
+// Bytes as space-separated hex.
+#[cfg(feature = "trace-spi")]
+struct Hex<'a>(&'a [u8]);
+
+#[cfg(feature = "trace-spi")]
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! The last bytes exchanged with the module, attached to the protocol errors so that a failure in
+//! the field shows what the module has actually sent. They are only recorded with the `transcript`
+//! feature. Without it, `Transcript` is always empty and takes no space.
+
+use core::fmt;
+
+// Number of bytes kept, from the newest one.
+#[cfg(feature = "transcript")]
+const TRANSCRIPT_LEN: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Copy)]
+pub struct Transcript {
+    #[cfg(feature = "transcript")]
+    bytes: [(Direction, u8); TRANSCRIPT_LEN],
+    // Total number of recorded bytes. The next one goes to `count % TRANSCRIPT_LEN`.
+    #[cfg(feature = "transcript")]
+    count: usize,
+}
+
+impl Transcript {
+    pub(crate) const fn new() -> Self {
+        Transcript {
+            #[cfg(feature = "transcript")]
+            bytes: [(Direction::Sent, 0); TRANSCRIPT_LEN],
+            #[cfg(feature = "transcript")]
+            count: 0,
+        }
+    }
+
+    #[cfg(feature = "transcript")]
+    pub(crate) fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        // Only the tail of a long transfer would be kept anyway.
+        let skipped = bytes.len().saturating_sub(TRANSCRIPT_LEN);
+        self.count += skipped;
+        for &byte in &bytes[skipped..] {
+            self.bytes[self.count % TRANSCRIPT_LEN] = (direction, byte);
+            self.count += 1;
+        }
+    }
+
+    #[cfg(not(feature = "transcript"))]
+    pub(crate) fn record(&mut self, _direction: Direction, _bytes: &[u8]) {}
+
+    /// The recorded bytes, from the oldest one.
+    #[cfg(feature = "transcript")]
+    pub fn iter(&self) -> impl Iterator<Item = (Direction, u8)> + '_ {
+        let len = core::cmp::min(self.count, TRANSCRIPT_LEN);
+        let start = self.count - len;
+        (start..self.count).map(move |i| self.bytes[i % TRANSCRIPT_LEN])
+    }
+
+    /// The recorded bytes, from the oldest one.
+    #[cfg(not(feature = "transcript"))]
+    pub fn iter(&self) -> impl Iterator<Item = (Direction, u8)> + '_ {
+        core::iter::empty()
+    }
+}
+
+/// Hex bytes, with `>` before the sent ones and `<` before the received ones, e.g.
+/// `[> e0 20 00 ee < e0 a0 01 01 03 ee]`.
+impl fmt::Debug for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        let mut last_direction = None;
+        for (i, (direction, byte)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            if last_direction != Some(direction) {
+                match direction {
+                    Direction::Sent => write!(f, "> ")?,
+                    Direction::Received => write!(f, "< ")?,
+                }
+                last_direction = Some(direction);
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(all(test, feature = "transcript"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_last_bytes() {
+        let mut transcript = Transcript::new();
+        transcript.record(Direction::Sent, &[0xE0, 0x20, 0x00, 0xEE]);
+        let response: Vec<u8> = (0..TRANSCRIPT_LEN as u8).collect();
+        transcript.record(Direction::Received, &response[..TRANSCRIPT_LEN - 2]);
+        transcript.record(Direction::Received, &response[TRANSCRIPT_LEN - 2..]);
+
+        let recorded: Vec<(Direction, u8)> = transcript.iter().collect();
+        assert_eq!(recorded.len(), TRANSCRIPT_LEN);
+        assert_eq!(recorded[0], (Direction::Received, 0));
+        assert_eq!(recorded[TRANSCRIPT_LEN - 1].1, TRANSCRIPT_LEN as u8 - 1);
+    }
+
+    #[test]
+    fn formats_directions() {
+        let mut transcript = Transcript::new();
+        transcript.record(Direction::Sent, &[0xE0, 0x20]);
+        transcript.record(Direction::Received, &[0xEF]);
+
+        assert_eq!(format!("{transcript:?}"), "[> e0 20 < ef]");
+    }
+}
@@ -0,0 +1,56 @@
+//! The physical link to the module, abstracted so that the protocol can run over different SPI
+//! implementations and over a scripted fake in the tests.
+
+/// The SPI bus has failed in the middle of a transfer, so some bytes may not have been sent or
+/// received.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusError;
+
+/// SPI bus together with the chip select, ACK and reset lines of the module.
+pub trait Transport {
+    /// Pull the chip select low.
+    fn select(&mut self);
+
+    fn deselect(&mut self);
+
+    /// Level of the ACK line, also called BUSY or READY. It's low when the module is ready to
+    /// receive a command or to send a response, and goes high once the module has noticed the
+    /// selection.
+    fn ack(&self) -> bool;
+
+    /// Wait up to `timeout_us` for the ACK line to reach the given level. Returns whether it has.
+    fn wait_for_ack(&mut self, high: bool, timeout_us: u32) -> bool;
+
+    /// Microseconds from an arbitrary point, wrapping around. Used for the timeouts.
+    fn now_us(&self) -> u32;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), BusError>;
+
+    /// Read `data.len()` bytes, sending dummy bytes.
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), BusError>;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), BusError> {
+        self.write(&[byte])
+    }
+
+    fn read_byte(&mut self) -> Result<u8, BusError> {
+        let mut byte = [0];
+        self.read_bytes(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn skip_bytes(&mut self, n: usize) -> Result<(), BusError> {
+        for _ in 0..n {
+            self.read_byte()?;
+        }
+        Ok(())
+    }
+
+    /// Pulse the reset line with GPIO0 high, so that the module boots the firmware, and wait
+    /// until it has booted.
+    fn reset(&mut self);
+
+    /// Hold the module in reset, which cuts its current draw to almost nothing. It's brought back
+    /// by `reset`.
+    fn power_down(&mut self);
+}
@@ -0,0 +1,142 @@
+//! Sleeping with WFI while waiting for the ACK line of the ESP32, instead of spinning on it. A
+//! level interrupt on the ACK pin wakes the core once the line reaches the awaited level, and
+//! TIMER alarm 3 wakes it up at the deadline.
+//!
+//! With the `async` feature, the same interrupt wakes the task waiting for the module to become
+//! ready, see `wake_on_level`.
+//!
+//! Defines the IO_IRQ_BANK0 and TIMER_IRQ_3 handlers, so the application can't use GPIO
+//! interrupts on bank 0 or the last timer alarm for anything else.
+
+#[cfg(feature = "async")]
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(feature = "async")]
+use core::task::Waker;
+
+#[cfg(feature = "async")]
+use cortex_m::interrupt::Mutex;
+use rp2040_hal::pac::{self, interrupt};
+
+// GPIO connected to the ACK line, set by `init`.
+static ACK_GPIO: AtomicU8 = AtomicU8::new(0);
+
+const ALARM_BIT: u32 = 1 << 3;
+
+// Task waiting for the ACK line, woken by the level interrupt.
+#[cfg(feature = "async")]
+static ACK_WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+// Each of the PROC0_INTE registers has 4 bits for each of 8 GPIOs: level low, level high, edge
+// low, edge high.
+fn level_low_bit() -> u32 {
+    1 << ((ACK_GPIO.load(Ordering::Relaxed) % 8) * 4)
+}
+
+fn level_high_bit() -> u32 {
+    level_low_bit() << 1
+}
+
+fn level_bit(high: bool) -> u32 {
+    if high {
+        level_high_bit()
+    } else {
+        level_low_bit()
+    }
+}
+
+// Modify the PROC0_INTE register containing the bits of the ACK GPIO.
+fn modify_inte(f: impl FnOnce(u32) -> u32) {
+    let io_bank0 = unsafe { &*pac::IO_BANK0::ptr() };
+    match ACK_GPIO.load(Ordering::Relaxed) / 8 {
+        0 => io_bank0
+            .proc0_inte0
+            .modify(|r, w| unsafe { w.bits(f(r.bits())) }),
+        1 => io_bank0
+            .proc0_inte1
+            .modify(|r, w| unsafe { w.bits(f(r.bits())) }),
+        2 => io_bank0
+            .proc0_inte2
+            .modify(|r, w| unsafe { w.bits(f(r.bits())) }),
+        _ => io_bank0
+            .proc0_inte3
+            .modify(|r, w| unsafe { w.bits(f(r.bits())) }),
+    }
+}
+
+pub(crate) fn init(ack_gpio: u8) {
+    ACK_GPIO.store(ack_gpio, Ordering::Relaxed);
+    disable_level_interrupts();
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer
+        .inte
+        .modify(|r, w| unsafe { w.bits(r.bits() | ALARM_BIT) });
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0);
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_3);
+    }
+}
+
+/// Sleep until `is_reached` returns true or the lower 32 bits of TIMER pass `deadline_us`. Any
+/// other interrupt also wakes the core up, in which case the condition is checked again.
+pub(crate) fn wait_for_level(high: bool, deadline_us: u32, is_reached: impl Fn() -> bool) {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+
+    while !is_reached() {
+        let now_us = timer.timerawl.read().bits();
+        if deadline_us.wrapping_sub(now_us) as i32 <= 0 {
+            break;
+        }
+
+        cortex_m::interrupt::free(|_| {
+            // Checking again with the interrupts masked, so that the edge can't be missed between
+            // the check and WFI.
+            if is_reached() {
+                return;
+            }
+            timer.alarm3.write(|w| unsafe { w.bits(deadline_us) });
+            modify_inte(|bits| bits | level_bit(high));
+            // Wakes up on a pending interrupt even when they are masked. The handler runs once
+            // they are unmasked at the end of the critical section.
+            cortex_m::asm::wfi();
+        });
+    }
+
+    // Disarm the alarm, so that it doesn't fire after the wait.
+    timer.armed.write(|w| unsafe { w.bits(ALARM_BIT) });
+}
+
+/// Wake the task once the ACK line reaches the given level. If it already has, the interrupt fires
+/// right away. Only the last registered task is woken.
+#[cfg(feature = "async")]
+pub(crate) fn wake_on_level(high: bool, waker: &Waker) {
+    cortex_m::interrupt::free(|cs| {
+        *ACK_WAKER.borrow(cs).borrow_mut() = Some(waker.clone());
+        modify_inte(|bits| bits | level_bit(high));
+    });
+}
+
+fn disable_level_interrupts() {
+    modify_inte(|bits| bits & !(level_low_bit() | level_high_bit()));
+}
+
+// Level interrupts can't be cleared, so the handler disables them until the next wait.
+#[allow(non_snake_case)]
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    disable_level_interrupts();
+    #[cfg(feature = "async")]
+    {
+        let waker = cortex_m::interrupt::free(|cs| ACK_WAKER.borrow(cs).borrow_mut().take());
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn TIMER_IRQ_3() {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.intr.write(|w| unsafe { w.bits(ALARM_BIT) });
+}
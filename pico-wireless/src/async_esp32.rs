@@ -0,0 +1,226 @@
+//! Async wrapper over the driver, for running WiFi operations on an executor together with other
+//! tasks. Waiting for the module to become ready, for a connection to be established and for data
+//! to arrive is done by awaiting instead of spinning, so other tasks can run in the meantime.
+//!
+//! The futures don't depend on a particular executor. A future waiting for the module to become
+//! ready is woken by the interrupt on the ACK line (see `ack_interrupt`), and one waiting for the
+//! connection status or for data, which the module doesn't signal, is woken by TIMER alarm 2
+//! after `POLL_INTERVAL_US`. Between the wakeups the executor can sleep.
+//!
+//! Defines the TIMER_IRQ_2 handler, so the application can't use the third timer alarm for
+//! anything else.
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use cortex_m::interrupt::Mutex;
+use rp2040_hal::pac::{self, interrupt};
+
+use crate::ack_interrupt;
+use crate::pico_wireless::{
+    ConnectionStatus, Esp32, Esp32Error, ProtocolMode, Socket, SocketHandle,
+};
+use crate::spi_bus::SpiBus;
+
+// Interval between the checks of the connection status and of the received data.
+const POLL_INTERVAL_US: u32 = 10_000;
+
+const ALARM_BIT: u32 = 1 << 2;
+
+// Task waiting for the alarm. `AsyncEsp32` takes `&mut self`, so only one future waits at a time.
+static TIMER_WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+pub struct AsyncEsp32<S: SpiBus> {
+    esp32: Esp32<S>,
+}
+
+impl<S: SpiBus> AsyncEsp32<S> {
+    pub fn new(esp32: Esp32<S>) -> Self {
+        let timer = unsafe { &*pac::TIMER::ptr() };
+        timer
+            .inte
+            .modify(|r, w| unsafe { w.bits(r.bits() | ALARM_BIT) });
+        unsafe { pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_2) };
+
+        AsyncEsp32 { esp32 }
+    }
+
+    /// The blocking driver, for the operations which don't have async versions.
+    pub fn inner(&mut self) -> &mut Esp32<S> {
+        &mut self.esp32
+    }
+
+    pub fn into_inner(self) -> Esp32<S> {
+        self.esp32
+    }
+
+    /// Wait until the module is ready to accept a command.
+    pub async fn ready(&self) {
+        Ready(&self.esp32).await
+    }
+
+    /// Join a network and wait until the connection is established. An empty passphrase means an
+    /// open network.
+    pub async fn join(&mut self, ssid: &str, passphrase: &str) -> Result<(), Esp32Error> {
+        self.ready().await;
+        if passphrase.is_empty() {
+            self.esp32.set_network(ssid)?;
+        } else {
+            self.esp32.wifi_set_passphrase(ssid, passphrase)?;
+        }
+
+        loop {
+            self.ready().await;
+            let status = self.esp32.get_conn_status()?;
+            match status {
+                ConnectionStatus::Connected => return Ok(()),
+                ConnectionStatus::ConnectFailed | ConnectionStatus::NoSsidAvail => {
+                    let error = self.esp32.connect_error(status)?;
+                    return Err(Esp32Error::JoinFailed(error));
+                }
+                ConnectionStatus::NoShield => return Err(Esp32Error::ConnectFailed(status)),
+                _ => sleep(POLL_INTERVAL_US).await,
+            }
+        }
+    }
+
+    /// Open a TCP or TLS connection to the given host.
+    pub async fn connect(
+        &mut self,
+        hostname: &str,
+        port: u16,
+        mode: ProtocolMode,
+    ) -> Result<SocketHandle, Esp32Error> {
+        self.ready().await;
+        let sock = self.esp32.open_socket()?;
+        poll_nb(&mut self.esp32, |esp32| {
+            esp32.try_connect(hostname, port, sock.socket(), mode)
+        })
+        .await?;
+        Ok(sock)
+    }
+
+    /// Wait until some data is received and read it. Returns the number of bytes read.
+    pub async fn recv(&mut self, sock: Socket, buf: &mut [u8]) -> Result<usize, Esp32Error> {
+        poll_nb(&mut self.esp32, |esp32| esp32.try_recv(sock, buf)).await
+    }
+
+    /// Returns the number of bytes accepted by the module.
+    pub async fn send(&mut self, sock: Socket, data: &[u8]) -> Result<usize, Esp32Error> {
+        self.ready().await;
+        self.esp32.send_data_tcp(sock, data)
+    }
+}
+
+// Await an nb operation, retrying it until it stops returning WouldBlock: once the module is
+// ready if it's busy, otherwise after the poll interval.
+fn poll_nb<S, T, E, F>(esp32: &mut Esp32<S>, f: F) -> PollNb<'_, S, F>
+where
+    S: SpiBus,
+    F: FnMut(&mut Esp32<S>) -> nb::Result<T, E>,
+{
+    PollNb { esp32, f }
+}
+
+struct PollNb<'a, S: SpiBus, F> {
+    esp32: &'a mut Esp32<S>,
+    f: F,
+}
+
+// The closure is never pinned, only called through a mutable reference.
+impl<S: SpiBus, F> Unpin for PollNb<'_, S, F> {}
+
+impl<S, T, E, F> Future for PollNb<'_, S, F>
+where
+    S: SpiBus,
+    F: FnMut(&mut Esp32<S>) -> nb::Result<T, E>,
+{
+    type Output = Result<T, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, E>> {
+        let this = self.get_mut();
+        match (this.f)(this.esp32) {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(nb::Error::Other(e)) => Poll::Ready(Err(e)),
+            Err(nb::Error::WouldBlock) => {
+                if this.esp32.is_ready() {
+                    wake_at(now_us().wrapping_add(POLL_INTERVAL_US), cx.waker());
+                } else {
+                    ack_interrupt::wake_on_level(false, cx.waker());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct Ready<'a, S: SpiBus>(&'a Esp32<S>);
+
+impl<S: SpiBus> Future for Ready<'_, S> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0.is_ready() {
+            Poll::Ready(())
+        } else {
+            ack_interrupt::wake_on_level(false, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+fn sleep(duration_us: u32) -> Sleep {
+    Sleep {
+        deadline_us: now_us().wrapping_add(duration_us),
+    }
+}
+
+struct Sleep {
+    deadline_us: u32,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.deadline_us.wrapping_sub(now_us()) as i32 <= 0 {
+            Poll::Ready(())
+        } else {
+            wake_at(self.deadline_us, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+// Lower 32 bits of TIMER, which the alarms are compared with.
+fn now_us() -> u32 {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.timerawl.read().bits()
+}
+
+// Wake the task when the lower 32 bits of TIMER reach `deadline_us`.
+fn wake_at(deadline_us: u32, waker: &Waker) {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    cortex_m::interrupt::free(|cs| {
+        *TIMER_WAKER.borrow(cs).borrow_mut() = Some(waker.clone());
+        timer.alarm2.write(|w| unsafe { w.bits(deadline_us) });
+    });
+    // The alarm only fires when the timer matches it, so a deadline that has passed in the
+    // meantime would be missed.
+    if deadline_us.wrapping_sub(now_us()) as i32 <= 0 {
+        waker.wake_by_ref();
+    }
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn TIMER_IRQ_2() {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.intr.write(|w| unsafe { w.bits(ALARM_BIT) });
+    let waker = cortex_m::interrupt::free(|cs| TIMER_WAKER.borrow(cs).borrow_mut().take());
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
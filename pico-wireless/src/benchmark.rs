@@ -0,0 +1,187 @@
+//! Measurement of the command latency and the bulk transfer rate at several SPI clock rates, to
+//! find how fast the wiring of a particular board can be clocked. The default 8 MHz is safe with
+//! short wires, but the ESP32 accepts up to 30 MHz on a good connection:
+//!
+//! ```ignore
+//! let report = benchmark::run(&mut esp32, &benchmark::DEFAULT_RATES, system_clock_freq)?;
+//! if let Some(baudrate) = report.fastest_stable() {
+//!     esp32.transport().set_baudrate(baudrate, system_clock_freq);
+//! }
+//! ```
+//!
+//! The module doesn't need to be connected to a network. The bulk data is written to a UDP
+//! socket that is closed without sending it.
+
+use log::{info, warn};
+
+use crate::blocking_spi::{Spi, SpiDevice};
+use crate::pico_wireless::{
+    now_us, Esp32, Esp32Error, IpV4, ProtocolMode, Socket, DEFAULT_BAUDRATE,
+};
+
+pub const DEFAULT_RATES: [u32; 6] = [
+    4_000_000, 8_000_000, 12_000_000, 16_000_000, 20_000_000, 24_000_000,
+];
+
+// Most rates in a single run.
+pub const MAX_RATES: usize = 8;
+
+// Commands timed at each rate. All of them have to return the expected response for the rate to
+// be considered stable.
+const LATENCY_ROUNDS: u32 = 32;
+const THROUGHPUT_ROUNDS: u32 = 16;
+// Fits in the UDP buffer of the firmware.
+const THROUGHPUT_CHUNK: usize = 1024;
+// Discard service on the loopback address, never actually reached.
+const SINK_PORT: u16 = 9;
+
+/// Measurements at one clock rate.
+#[derive(Debug, Clone, Copy)]
+pub struct RateResult {
+    /// The rate the SPI peripheral has actually been set to, which is the requested one rounded
+    /// down to a divisor of the system clock.
+    pub baudrate: u32,
+    /// All the commands have returned the expected responses.
+    pub stable: bool,
+    /// Average round trip of a short command. Only meaningful for the stable rates.
+    pub latency_us: u32,
+    /// Payload bytes per second written to the module. Only meaningful for the stable rates.
+    pub throughput: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchmarkReport {
+    results: [Option<RateResult>; MAX_RATES],
+}
+
+impl BenchmarkReport {
+    /// Results in the order of the requested rates.
+    pub fn results(&self) -> impl Iterator<Item = &RateResult> {
+        self.results.iter().flatten()
+    }
+
+    /// The highest rate at which the module has been working reliably.
+    pub fn fastest_stable(&self) -> Option<u32> {
+        self.results()
+            .filter(|result| result.stable)
+            .map(|result| result.baudrate)
+            .max()
+    }
+}
+
+/// Measure the module at each of the `rates`, up to `MAX_RATES` of them. Afterwards the clock is
+/// set back to `DEFAULT_BAUDRATE`, so that the result can be applied with
+/// `SpiTransport::set_baudrate`.
+///
+/// Fails if the module doesn't respond even at the default rate.
+pub fn run<D: SpiDevice>(
+    esp32: &mut Esp32<Spi<D>>,
+    rates: &[u32],
+    system_clock_freq: u32,
+) -> Result<BenchmarkReport, Esp32Error> {
+    esp32
+        .transport()
+        .set_baudrate(DEFAULT_BAUDRATE, system_clock_freq);
+    let expected_mac = esp32.mac_address()?;
+
+    let mut report = BenchmarkReport {
+        results: [None; MAX_RATES],
+    };
+    for (slot, &rate) in report.results.iter_mut().zip(rates) {
+        let baudrate = esp32.transport().set_baudrate(rate, system_clock_freq);
+        let result = measure(esp32, baudrate, expected_mac);
+        info!(
+            "{baudrate} Hz: stable {}, latency {} us, throughput {} B/s",
+            result.stable, result.latency_us, result.throughput
+        );
+        *slot = Some(result);
+
+        if !result.stable {
+            // Errors at a high rate may leave the module out of sync. A command at the default
+            // rate resynchronizes it.
+            esp32
+                .transport()
+                .set_baudrate(DEFAULT_BAUDRATE, system_clock_freq);
+            esp32.mac_address().ok();
+        }
+    }
+
+    esp32
+        .transport()
+        .set_baudrate(DEFAULT_BAUDRATE, system_clock_freq);
+    Ok(report)
+}
+
+fn measure<D: SpiDevice>(
+    esp32: &mut Esp32<Spi<D>>,
+    baudrate: u32,
+    expected_mac: [u8; 6],
+) -> RateResult {
+    let mut result = RateResult {
+        baudrate,
+        stable: false,
+        latency_us: 0,
+        throughput: 0,
+    };
+
+    let start_us = now_us();
+    for _ in 0..LATENCY_ROUNDS {
+        match esp32.mac_address() {
+            Ok(mac) if mac == expected_mac => {}
+            Ok(mac) => {
+                warn!("Garbled response at {baudrate} Hz: {mac:02x?}");
+                return result;
+            }
+            Err(e) => {
+                warn!("Error at {baudrate} Hz: {e:?}");
+                return result;
+            }
+        }
+    }
+    result.latency_us = now_us().wrapping_sub(start_us) / LATENCY_ROUNDS;
+
+    match measure_throughput(esp32) {
+        Ok(throughput) => {
+            result.throughput = throughput;
+            result.stable = true;
+        }
+        Err(e) => warn!("Error writing data at {baudrate} Hz: {e:?}"),
+    }
+    result
+}
+
+// Only the data commands are timed. The socket is reopened between them outside of the timed
+// part, so that the firmware never has to hold more than a chunk.
+fn measure_throughput<D: SpiDevice>(esp32: &mut Esp32<Spi<D>>) -> Result<u32, Esp32Error> {
+    let mut chunk = [0; THROUGHPUT_CHUNK];
+    for (i, byte) in chunk.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let sock = esp32.get_socket()?;
+    let elapsed_us = write_chunks(esp32, sock, &chunk);
+    esp32.stop_client(sock).ok();
+    let elapsed_us = elapsed_us?;
+
+    let bytes = (THROUGHPUT_ROUNDS as usize * THROUGHPUT_CHUNK) as u64;
+    Ok((bytes * 1_000_000 / core::cmp::max(elapsed_us, 1) as u64) as u32)
+}
+
+// Returns the time spent in the data commands.
+fn write_chunks<D: SpiDevice>(
+    esp32: &mut Esp32<Spi<D>>,
+    sock: Socket,
+    chunk: &[u8],
+) -> Result<u32, Esp32Error> {
+    let sink = IpV4::from_slice(&[127, 0, 0, 1]);
+    let mut elapsed_us = 0;
+    for round in 0..THROUGHPUT_ROUNDS {
+        if round > 0 {
+            esp32.stop_client(sock)?;
+        }
+        esp32.start_client(sink, SINK_PORT, sock, ProtocolMode::Udp)?;
+        let start_us = now_us();
+        esp32.insert_data_buf(sock, chunk)?;
+        elapsed_us += now_us().wrapping_sub(start_us);
+    }
+    Ok(elapsed_us)
+}
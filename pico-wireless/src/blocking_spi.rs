@@ -1,7 +1,18 @@
+use core::convert::Infallible;
 use core::ops::Deref;
+use embedded_hal::blocking::spi;
+use embedded_hal::spi::FullDuplex;
 use rp2040_hal::pac;
 use log::info;
 
+use crate::dma;
+#[cfg(feature = "spi-interrupt")]
+use crate::spi_interrupt;
+
+// Transfers of at least this many bytes go through DMA, if it's enabled. Below that, setting up
+// the channels takes longer than the transfer.
+const DMA_THRESHOLD: usize = 32;
+
 pub trait Resettable {
     fn reset(&self, resets: &mut pac::RESETS);
     fn unreset(&self, resets: &mut pac::RESETS);
@@ -29,10 +40,27 @@ impl Resettable for pac::SPI1 {
     }
 }
 
-pub trait SpiDevice: Deref<Target = pac::spi0::RegisterBlock> + Resettable {}
+pub trait SpiDevice: Deref<Target = pac::spi0::RegisterBlock> + Resettable {
+    const INDEX: usize;
+    const IRQ: pac::Interrupt;
+    // DREQ signals pacing the DMA.
+    const TX_DREQ: u8;
+    const RX_DREQ: u8;
+}
 
-impl SpiDevice for pac::SPI0 {}
-impl SpiDevice for pac::SPI1 {}
+impl SpiDevice for pac::SPI0 {
+    const INDEX: usize = 0;
+    const IRQ: pac::Interrupt = pac::Interrupt::SPI0_IRQ;
+    const TX_DREQ: u8 = 16;
+    const RX_DREQ: u8 = 17;
+}
+
+impl SpiDevice for pac::SPI1 {
+    const INDEX: usize = 1;
+    const IRQ: pac::Interrupt = pac::Interrupt::SPI1_IRQ;
+    const TX_DREQ: u8 = 18;
+    const RX_DREQ: u8 = 19;
+}
 
 #[derive(Clone, Copy)]
 pub enum Mode {
@@ -62,9 +90,31 @@ impl Mode {
     }
 }
 
+/// Frame format of the SSP. Only the Motorola format uses the clock polarity and phase of the
+/// mode.
+#[derive(Clone, Copy)]
+pub enum FrameFormat {
+    Motorola = 0,
+    TiSynchronous = 1,
+    /// Half-duplex National Microwire.
+    Microwire = 2,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
 pub struct Spi<D: SpiDevice> {
     device: D,
     dummy_data: u8,
+    // Frame size set by `set_format`.
+    data_bits: u8,
+    // The SSP only shifts the MSB first, so with LsbFirst the frames are reversed on the way.
+    bit_order: BitOrder,
+    // TX and RX DMA channels.
+    dma_channels: Option<(u8, u8)>,
 }
 
 impl<D: SpiDevice> Spi<D> {
@@ -72,10 +122,28 @@ impl<D: SpiDevice> Spi<D> {
         Spi {
             device,
             dummy_data: 0,
+            data_bits: 8,
+            bit_order: BitOrder::MsbFirst,
+            dma_channels: None,
         }
     }
 
+    /// Initialize with 8-bit frames in mode 0, which is what the ESP32 uses. Returns the actual
+    /// baudrate.
     pub fn init(&mut self, resets: &mut pac::RESETS, baudrate: u32, system_clock_freq: u32) -> u32 {
+        self.init_with_format(resets, baudrate, system_clock_freq, 8, Mode::Mode0)
+    }
+
+    /// Initialize with the frame size and the mode of the device, see `set_format`. Returns the
+    /// actual baudrate.
+    pub fn init_with_format(
+        &mut self,
+        resets: &mut pac::RESETS,
+        baudrate: u32,
+        system_clock_freq: u32,
+        data_bits: u8,
+        mode: Mode,
+    ) -> u32 {
         info!("device.reset");
         self.device.reset(resets);
         info!("device.unreset");
@@ -85,8 +153,7 @@ impl<D: SpiDevice> Spi<D> {
         let actual_baudrate = self.set_baudrate(baudrate, system_clock_freq);
         info!("actual baudrate: {actual_baudrate}");
 
-        // Use internal enum for format.
-        self.set_format(8, Mode::Mode0);
+        self.set_format(data_bits, mode);
 
         // Enable DREQ signals -- harmless if DMA is not listening
         self.device
@@ -99,11 +166,127 @@ impl<D: SpiDevice> Spi<D> {
         actual_baudrate
     }
 
+    /// Return the peripheral. It's left enabled, with its configuration.
+    pub fn free(self) -> D {
+        self.device
+    }
+
     pub fn set_dummy_data(&mut self, byte: u8) {
         self.dummy_data = byte;
     }
 
-    fn set_baudrate(&mut self, baudrate: u32, system_clock_freq: u32) -> u32 {
+    /// Use the given pair of DMA channels for the transfers of `DMA_THRESHOLD` bytes or more, so
+    /// that bulk data moves at the SPI clock rate. The channels must not be used by anything else.
+    pub fn enable_dma(&mut self, resets: &mut pac::RESETS, tx_channel: u8, rx_channel: u8) {
+        assert!(tx_channel != rx_channel);
+        assert!(tx_channel < dma::NUM_CHANNELS && rx_channel < dma::NUM_CHANNELS);
+        dma::init(resets);
+        self.dma_channels = Some((tx_channel, rx_channel));
+    }
+
+    // DMA can't reverse the bits, so it isn't used for LSB-first frames.
+    fn dma_channels_for(&self, len: usize) -> Option<(u8, u8)> {
+        if len >= DMA_THRESHOLD && self.bit_order == BitOrder::MsbFirst {
+            self.dma_channels
+        } else {
+            None
+        }
+    }
+
+    fn enabled_dma_channels(&self) -> (u8, u8) {
+        assert!(self.bit_order == BitOrder::MsbFirst, "DMA only sends the MSB first");
+        self.dma_channels.expect("DMA isn't enabled, see Spi::enable_dma")
+    }
+
+    /// Send `data` with DMA whatever its size, discarding the received bytes. Panics if DMA
+    /// hasn't been enabled with `enable_dma`.
+    pub fn write_dma(&mut self, data: &[u8]) {
+        let channels = self.enabled_dma_channels();
+        let mut discarded = 0u8;
+        self.run_dma(channels, data.as_ptr(), true, &mut discarded, false, data.len());
+    }
+
+    /// Fill `data` with DMA whatever its size, sending the dummy byte. Panics if DMA hasn't been
+    /// enabled with `enable_dma`.
+    pub fn read_dma(&mut self, data: &mut [u8]) {
+        let channels = self.enabled_dma_channels();
+        let dummy = self.dummy_data;
+        self.run_dma(channels, &dummy, false, data.as_mut_ptr(), true, data.len());
+    }
+
+    /// Same as `transfer`, with DMA whatever the size. Panics if DMA hasn't been enabled with
+    /// `enable_dma`.
+    pub fn transfer_dma(&mut self, data: &mut [u8]) {
+        let channels = self.enabled_dma_channels();
+        let ptr = data.as_mut_ptr();
+        self.run_dma(channels, ptr, true, ptr, true, data.len());
+    }
+
+    /// Start sending the bytes of `data` and replacing them with the received ones, and return
+    /// without waiting, so that the CPU can do something else in the meantime. The buffer is
+    /// given back by `DmaTransfer::wait`. Panics if DMA hasn't been enabled with `enable_dma`.
+    pub fn start_transfer_dma(&mut self, data: &'static mut [u8]) -> DmaTransfer<'_, D> {
+        let channels = self.enabled_dma_channels();
+        let ptr = data.as_mut_ptr();
+        self.start_dma(channels, ptr, true, ptr, true, data.len());
+        DmaTransfer {
+            spi: self,
+            channels,
+            data: Some(data),
+        }
+    }
+
+    // Send `len` bytes from `src` and store the received ones in `dst`. The addresses are only
+    // incremented with the corresponding flags, otherwise the same byte is sent or overwritten.
+    fn run_dma(
+        &mut self,
+        channels: (u8, u8),
+        src: *const u8,
+        incr_src: bool,
+        dst: *mut u8,
+        incr_dst: bool,
+        len: usize,
+    ) {
+        self.start_dma(channels, src, incr_src, dst, incr_dst, len);
+        dma::wait_pair(channels.0, channels.1);
+        while self._is_busy() {}
+    }
+
+    fn start_dma(
+        &mut self,
+        (tx_channel, rx_channel): (u8, u8),
+        src: *const u8,
+        incr_src: bool,
+        dst: *mut u8,
+        incr_dst: bool,
+        len: usize,
+    ) {
+        // Stale bytes would shift the received data.
+        self._discard_received();
+
+        let data_reg = &self.device.sspdr as *const _ as u32;
+        let tx = dma::Channel {
+            channel: tx_channel,
+            read_addr: src as u32,
+            incr_read: incr_src,
+            write_addr: data_reg,
+            incr_write: false,
+            dreq: D::TX_DREQ,
+        };
+        let rx = dma::Channel {
+            channel: rx_channel,
+            read_addr: data_reg,
+            incr_read: false,
+            write_addr: dst as u32,
+            incr_write: incr_dst,
+            dreq: D::RX_DREQ,
+        };
+        dma::start_pair(&tx, &rx, len as u32);
+    }
+
+    /// Set the clock to the highest rate not above `baudrate`. Returns the actual rate. The bus
+    /// has to be idle.
+    pub fn set_baudrate(&mut self, baudrate: u32, system_clock_freq: u32) -> u32 {
         let prescale = if 3 * 256 * baudrate as u64 > system_clock_freq as u64 {
             2
         } else {
@@ -123,7 +306,40 @@ impl<D: SpiDevice> Spi<D> {
         system_clock_freq as u32 / ((prescale as u32) * (1 + postdiv as u32))
     }
 
-    fn set_format(&mut self, data_bits: u8, mode: Mode) {
+    /// Select the Motorola SPI, TI synchronous serial or Microwire frame format. The bus has to be
+    /// idle.
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.device
+            .sspcr0
+            .modify(|_, w| unsafe { w.frf().bits(format as u8) });
+    }
+
+    /// Send and receive the frames from the MSB or from the LSB. The SSP only shifts the MSB
+    /// first, so the driver reverses the bits of the LSB-first frames, and can't move them with
+    /// DMA: the `_dma` methods panic.
+    pub fn set_bit_order(&mut self, order: BitOrder) {
+        self.bit_order = order;
+    }
+
+    fn order_byte(&self, byte: u8) -> u8 {
+        match self.bit_order {
+            BitOrder::MsbFirst => byte,
+            BitOrder::LsbFirst => byte.reverse_bits(),
+        }
+    }
+
+    fn order_word(&self, word: u16) -> u16 {
+        match self.bit_order {
+            BitOrder::MsbFirst => word,
+            BitOrder::LsbFirst => word.reverse_bits() >> (16 - self.data_bits),
+        }
+    }
+
+    /// Set the frame size, from 4 to 16 bits, and the mode. The byte transfers need 8-bit frames,
+    /// the other sizes are sent with `write_u16` and `read_u16`. The bus has to be idle.
+    pub fn set_format(&mut self, data_bits: u8, mode: Mode) {
+        assert!((4..=16).contains(&data_bits));
+        self.data_bits = data_bits;
         self.device.sspcr0.modify(|_, w| unsafe {
             w.dss()
                 .bits(data_bits - 1)
@@ -134,6 +350,13 @@ impl<D: SpiDevice> Spi<D> {
         });
     }
 
+    /// Change the clock polarity and phase, keeping the frame size. The bus has to be idle.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.device
+            .sspcr0
+            .modify(|_, w| w.spo().bit(mode.cpol()).sph().bit(mode.cpha()));
+    }
+
     fn _is_writable(&self) -> bool {
         self.device.sspsr.read().tnf().bit_is_set()
     }
@@ -147,21 +370,26 @@ impl<D: SpiDevice> Spi<D> {
     }
 
     fn _write(&self, data: u8) {
+        self._write_word(self.order_byte(data) as u16);
+    }
+
+    fn _write_word(&self, data: u16) {
         while !self._is_writable() {}
-        self.device
-            .sspdr
-            .write(|w| unsafe { w.data().bits(data as u16) });
+        self.device.sspdr.write(|w| unsafe { w.data().bits(data) });
     }
 
     fn _write_and_drain(&self, data: u8) {
         self._write(data);
+        self._drain();
+    }
 
-        while self._is_readable() {
-            self.device.sspdr.read();
-        }
-
+    fn _drain(&self) {
+        self._discard_received();
         while self._is_busy() {}
+        self._discard_received();
+    }
 
+    fn _discard_received(&self) {
         while self._is_readable() {
             self.device.sspdr.read();
         }
@@ -169,7 +397,25 @@ impl<D: SpiDevice> Spi<D> {
 
     // Internal. Doesn't check that the device is readable.
     fn _read(&self) -> u8 {
-        self.device.sspdr.read().data().bits() as u8
+        self.order_byte(self._read_word() as u8)
+    }
+
+    fn _read_word(&self) -> u16 {
+        self.device.sspdr.read().data().bits()
+    }
+
+    /// Send a frame of any size set by `set_format`, in the low bits of `word`.
+    pub fn write_u16(&mut self, word: u16) {
+        self._write_word(self.order_word(word));
+        self._drain();
+    }
+
+    /// Receive a frame of any size set by `set_format`, in the low bits, sending the dummy byte
+    /// repeated in each byte of the frame.
+    pub fn read_u16(&mut self) -> u16 {
+        self._write_word(u16::from_le_bytes([self.dummy_data; 2]));
+        while !self._is_readable() {}
+        self.order_word(self._read_word())
     }
 
     pub fn write_byte(&mut self, byte: u8) {
@@ -177,10 +423,22 @@ impl<D: SpiDevice> Spi<D> {
     }
 
     pub fn write(&mut self, data: &[u8]) {
-        while !self._is_writable() {}
-        for byte in data.iter() {
-            self.write_byte(*byte);
+        if let Some(channels) = self.dma_channels_for(data.len()) {
+            let mut discarded = 0u8;
+            self.run_dma(channels, data.as_ptr(), true, &mut discarded, false, data.len());
+            return;
         }
+
+        // Keep the TX FIFO full instead of waiting for each byte to go out, and discard the
+        // received bytes as they arrive, so that the RX FIFO doesn't overflow.
+        for &byte in data {
+            while !self._is_writable() {
+                self._discard_received();
+            }
+            self._write(byte);
+            self._discard_received();
+        }
+        self._drain();
     }
 
     pub fn read_byte(&mut self) -> u8 {
@@ -190,14 +448,175 @@ impl<D: SpiDevice> Spi<D> {
     }
 
     pub fn read_bytes(&mut self, data: &mut [u8]) {
+        if let Some(channels) = self.dma_channels_for(data.len()) {
+            let dummy = self.dummy_data;
+            self.run_dma(channels, &dummy, false, data.as_mut_ptr(), true, data.len());
+            return;
+        }
+
         for byte in data.iter_mut() {
             *byte = self.read_byte()
         }
     }
 
+    /// Send the bytes of `data`, replacing them with the received ones.
+    pub fn transfer(&mut self, data: &mut [u8]) {
+        if let Some(channels) = self.dma_channels_for(data.len()) {
+            // Each byte is sent before the one received in its place arrives.
+            let ptr = data.as_mut_ptr();
+            self.run_dma(channels, ptr, true, ptr, true, data.len());
+            return;
+        }
+
+        for byte in data.iter_mut() {
+            self._write(*byte);
+            while !self._is_readable() {}
+            *byte = self._read();
+        }
+    }
+
+    /// Send `cmd`, then fill `resp` while sending the dummy byte, e.g. to read the registers of a
+    /// sensor. The bytes received while `cmd` is sent are discarded.
+    pub fn write_then_read(&mut self, cmd: &[u8], resp: &mut [u8]) {
+        self.write(cmd);
+        self.read_bytes(resp);
+    }
+
+    /// Put a byte into the TX FIFO without waiting. Each byte sent this way receives one, which
+    /// has to be taken with `try_read`.
+    pub fn try_write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        if !self._is_writable() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self._write(byte);
+        Ok(())
+    }
+
+    /// Take a received byte from the RX FIFO without waiting.
+    pub fn try_read(&mut self) -> nb::Result<u8, Infallible> {
+        if !self._is_readable() {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(self._read())
+    }
+
+    /// Switch to the interrupt-driven transfers of `spi_interrupt`. The blocking transfers must
+    /// not be used while bytes are pending.
+    #[cfg(feature = "spi-interrupt")]
+    pub fn enable_interrupts(&mut self) {
+        spi_interrupt::enable(&self.device, D::IRQ);
+    }
+
+    /// Queue bytes to be sent by the interrupt handler. Returns how many of them fit into the
+    /// queue.
+    #[cfg(feature = "spi-interrupt")]
+    pub fn queue_write(&mut self, data: &[u8]) -> usize {
+        let tx = &spi_interrupt::STATES[D::INDEX].tx;
+        let queued = data
+            .iter()
+            .take_while(|&&byte| tx.push(self.order_byte(byte)))
+            .count();
+        spi_interrupt::kick(&self.device);
+        queued
+    }
+
+    /// Take the next byte received in response to the queued ones without waiting.
+    #[cfg(feature = "spi-interrupt")]
+    pub fn try_read_queued(&mut self) -> nb::Result<u8, Infallible> {
+        let state = &spi_interrupt::STATES[D::INDEX];
+        let byte = self.order_byte(state.rx.pop().ok_or(nb::Error::WouldBlock)?);
+        if state.tx.len() > 0 {
+            // The handler may have stopped sending for lack of room for the responses.
+            spi_interrupt::kick(&self.device);
+        }
+        Ok(byte)
+    }
+
+    /// Number of queued bytes whose responses haven't been taken yet. Zero once the transfer is
+    /// complete.
+    #[cfg(feature = "spi-interrupt")]
+    pub fn pending(&self) -> usize {
+        spi_interrupt::STATES[D::INDEX].pending()
+    }
+
     pub fn skip_bytes(&mut self, n: usize) {
+        if let Some(channels) = self.dma_channels_for(n) {
+            let dummy = self.dummy_data;
+            let mut discarded = 0u8;
+            self.run_dma(channels, &dummy, false, &mut discarded, false, n);
+            return;
+        }
+
         for _ in 0..n {
             self.read_byte();
         }
     }
 }
+
+/// A transfer started by `Spi::start_transfer_dma`. The bus can't be used until it has finished.
+/// Dropping it waits for the transfer to finish.
+pub struct DmaTransfer<'a, D: SpiDevice> {
+    spi: &'a mut Spi<D>,
+    channels: (u8, u8),
+    // Taken by `wait`.
+    data: Option<&'static mut [u8]>,
+}
+
+impl<'a, D: SpiDevice> DmaTransfer<'a, D> {
+    pub fn is_done(&self) -> bool {
+        let (tx_channel, rx_channel) = self.channels;
+        !dma::is_busy(tx_channel) && !dma::is_busy(rx_channel)
+    }
+
+    /// Wait for the transfer to finish and return the buffer with the received bytes.
+    pub fn wait(mut self) -> &'static mut [u8] {
+        self.finish();
+        self.data.take().unwrap()
+    }
+
+    fn finish(&mut self) {
+        dma::wait_pair(self.channels.0, self.channels.1);
+        while self.spi._is_busy() {}
+    }
+}
+
+impl<'a, D: SpiDevice> Drop for DmaTransfer<'a, D> {
+    fn drop(&mut self) {
+        if self.data.is_some() {
+            self.finish();
+        }
+    }
+}
+
+// The embedded-hal traits, so that the driver can be passed to the device drivers written against
+// them. The transfers can't fail.
+
+impl<D: SpiDevice> spi::Write<u8> for Spi<D> {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        Spi::write(self, words);
+        Ok(())
+    }
+}
+
+impl<D: SpiDevice> spi::Transfer<u8> for Spi<D> {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        Spi::transfer(self, words);
+        Ok(words)
+    }
+}
+
+impl<D: SpiDevice> FullDuplex<u8> for Spi<D> {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.try_read()
+    }
+
+    fn send(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        self.try_write(word)
+    }
+}
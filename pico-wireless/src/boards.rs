@@ -0,0 +1,172 @@
+//! Wiring of the common boards carrying the ESP32, so that bringing up the driver takes a single
+//! call. Each preset takes exactly the pins the board uses, in any mode, so wiring mistakes don't
+//! compile. It switches the SPI pins to the SPI function, configures the control lines and resets
+//! the module. Since the pins can only be taken once, each preset also keeps the `DroppedSockets`
+//! of its driver in a static of its own.
+//!
+//! Boards wired differently can use `SpiTransport::new` directly.
+
+use rp2040_hal::gpio::pin::bank0::{
+    Gpio10, Gpio11, Gpio12, Gpio13, Gpio14, Gpio16, Gpio18, Gpio19, Gpio2, Gpio20, Gpio3, Gpio7,
+    Gpio8, Gpio9,
+};
+use rp2040_hal::gpio::pin::{FunctionSpi, Pin, PinMode, ValidPinMode};
+use rp2040_hal::pac;
+
+use crate::blocking_spi::Spi;
+use crate::pico_wireless::{DroppedSockets, Esp32, SpiTransport, DEFAULT_BAUDRATE};
+use crate::shared_spi::{SharedSpi, SpiClient};
+
+/// Pimoroni Pico Wireless Pack on a Pico: SPI0 on GPIO 16 (MISO), 18 (SCK) and 19 (MOSI), CS on
+/// GPIO 7, GPIO0 on GPIO 2, ACK on GPIO 10 and RESETN on GPIO 11.
+#[allow(clippy::too_many_arguments)]
+pub fn pimoroni_pico_wireless(
+    resets: &mut pac::RESETS,
+    spi: pac::SPI0,
+    miso: Pin<Gpio16, impl PinMode + ValidPinMode<Gpio16>>,
+    sck: Pin<Gpio18, impl PinMode + ValidPinMode<Gpio18>>,
+    mosi: Pin<Gpio19, impl PinMode + ValidPinMode<Gpio19>>,
+    cs: Pin<Gpio7, impl PinMode + ValidPinMode<Gpio7>>,
+    gpio0: Pin<Gpio2, impl PinMode + ValidPinMode<Gpio2>>,
+    ack: Pin<Gpio10, impl PinMode + ValidPinMode<Gpio10>>,
+    resetn: Pin<Gpio11, impl PinMode + ValidPinMode<Gpio11>>,
+    delay: &mut cortex_m::delay::Delay,
+    system_clock_freq: u32,
+) -> Esp32<Spi<pac::SPI0>> {
+    let _ = miso.into_mode::<FunctionSpi>();
+    let _ = sck.into_mode::<FunctionSpi>();
+    let _ = mosi.into_mode::<FunctionSpi>();
+
+    let spi = SpiTransport::init_spi(resets, spi, system_clock_freq);
+    static DROPPED_SOCKETS: DroppedSockets = DroppedSockets::new();
+    Esp32::new(
+        SpiTransport::init(
+            resets,
+            spi,
+            cs.into(),
+            ack.into(),
+            Some(gpio0.into()),
+            resetn.into(),
+            delay,
+        ),
+        &DROPPED_SOCKETS,
+    )
+}
+
+/// SPI0 bus of the Pico Wireless Pack, for using the micro-SD card along with the ESP32. See
+/// `pimoroni_pico_wireless_shared` and `sd`.
+pub fn pimoroni_pico_wireless_bus(
+    resets: &mut pac::RESETS,
+    spi: pac::SPI0,
+    miso: Pin<Gpio16, impl PinMode + ValidPinMode<Gpio16>>,
+    sck: Pin<Gpio18, impl PinMode + ValidPinMode<Gpio18>>,
+    mosi: Pin<Gpio19, impl PinMode + ValidPinMode<Gpio19>>,
+    system_clock_freq: u32,
+) -> SharedSpi<pac::SPI0> {
+    let _ = miso.into_mode::<FunctionSpi>();
+    let _ = sck.into_mode::<FunctionSpi>();
+    let _ = mosi.into_mode::<FunctionSpi>();
+
+    let spi = SpiTransport::init_spi(resets, spi, system_clock_freq);
+    SharedSpi::new(spi, system_clock_freq)
+}
+
+/// Same as `pimoroni_pico_wireless`, but on the bus shared with the micro-SD card.
+pub fn pimoroni_pico_wireless_shared<'a>(
+    resets: &mut pac::RESETS,
+    bus: &'a SharedSpi<pac::SPI0>,
+    cs: Pin<Gpio7, impl PinMode + ValidPinMode<Gpio7>>,
+    gpio0: Pin<Gpio2, impl PinMode + ValidPinMode<Gpio2>>,
+    ack: Pin<Gpio10, impl PinMode + ValidPinMode<Gpio10>>,
+    resetn: Pin<Gpio11, impl PinMode + ValidPinMode<Gpio11>>,
+    delay: &mut cortex_m::delay::Delay,
+) -> Esp32<SpiClient<'a, pac::SPI0>> {
+    static DROPPED_SOCKETS: DroppedSockets = DroppedSockets::new();
+    Esp32::new(
+        SpiTransport::init(
+            resets,
+            bus.client(DEFAULT_BAUDRATE),
+            cs.into(),
+            ack.into(),
+            Some(gpio0.into()),
+            resetn.into(),
+            delay,
+        ),
+        &DROPPED_SOCKETS,
+    )
+}
+
+/// Adafruit AirLift FeatherWing on a Feather RP2040: SPI0 on GPIO 20 (MISO), 18 (SCK) and 19
+/// (MOSI), CS on D13 (GPIO 13), BUSY on D11 (GPIO 11) and RESET on D12 (GPIO 12).
+///
+/// GPIO0 of the ESP32 isn't connected unless the jumper to D10 is soldered, and the board pulls it
+/// up so that the module always boots the firmware. With the jumper, it's only needed for
+/// `passthrough` firmware updates.
+#[allow(clippy::too_many_arguments)]
+pub fn airlift_featherwing(
+    resets: &mut pac::RESETS,
+    spi: pac::SPI0,
+    miso: Pin<Gpio20, impl PinMode + ValidPinMode<Gpio20>>,
+    sck: Pin<Gpio18, impl PinMode + ValidPinMode<Gpio18>>,
+    mosi: Pin<Gpio19, impl PinMode + ValidPinMode<Gpio19>>,
+    cs: Pin<Gpio13, impl PinMode + ValidPinMode<Gpio13>>,
+    busy: Pin<Gpio11, impl PinMode + ValidPinMode<Gpio11>>,
+    reset: Pin<Gpio12, impl PinMode + ValidPinMode<Gpio12>>,
+    delay: &mut cortex_m::delay::Delay,
+    system_clock_freq: u32,
+) -> Esp32<Spi<pac::SPI0>> {
+    let _ = miso.into_mode::<FunctionSpi>();
+    let _ = sck.into_mode::<FunctionSpi>();
+    let _ = mosi.into_mode::<FunctionSpi>();
+
+    let spi = SpiTransport::init_spi(resets, spi, system_clock_freq);
+    static DROPPED_SOCKETS: DroppedSockets = DroppedSockets::new();
+    Esp32::new(
+        SpiTransport::init(
+            resets,
+            spi,
+            cs.into(),
+            busy.into(),
+            None,
+            reset.into(),
+            delay,
+        ),
+        &DROPPED_SOCKETS,
+    )
+}
+
+/// u-blox NINA-W102 of the Arduino Nano RP2040 Connect: SPI1 on GPIO 8 (MISO), 14 (SCK) and 11
+/// (MOSI), CS on GPIO 9, GPIO0 on GPIO 2, ACK on GPIO 10 and RESETN on GPIO 3.
+#[allow(clippy::too_many_arguments)]
+pub fn arduino_nano_rp2040_connect(
+    resets: &mut pac::RESETS,
+    spi: pac::SPI1,
+    miso: Pin<Gpio8, impl PinMode + ValidPinMode<Gpio8>>,
+    sck: Pin<Gpio14, impl PinMode + ValidPinMode<Gpio14>>,
+    mosi: Pin<Gpio11, impl PinMode + ValidPinMode<Gpio11>>,
+    cs: Pin<Gpio9, impl PinMode + ValidPinMode<Gpio9>>,
+    gpio0: Pin<Gpio2, impl PinMode + ValidPinMode<Gpio2>>,
+    ack: Pin<Gpio10, impl PinMode + ValidPinMode<Gpio10>>,
+    resetn: Pin<Gpio3, impl PinMode + ValidPinMode<Gpio3>>,
+    delay: &mut cortex_m::delay::Delay,
+    system_clock_freq: u32,
+) -> Esp32<Spi<pac::SPI1>> {
+    let _ = miso.into_mode::<FunctionSpi>();
+    let _ = sck.into_mode::<FunctionSpi>();
+    let _ = mosi.into_mode::<FunctionSpi>();
+
+    let spi = SpiTransport::init_spi(resets, spi, system_clock_freq);
+    static DROPPED_SOCKETS: DroppedSockets = DroppedSockets::new();
+    Esp32::new(
+        SpiTransport::init(
+            resets,
+            spi,
+            cs.into(),
+            ack.into(),
+            Some(gpio0.into()),
+            resetn.into(),
+            delay,
+        ),
+        &DROPPED_SOCKETS,
+    )
+}
@@ -0,0 +1,160 @@
+//! WiFi credentials saved in the last sector of the flash, so that the device rejoins its network
+//! after a power cycle without the SSID compiled into the binary. The sector is excluded from the
+//! program in memory.x.
+//!
+//! While the flash is erased and programmed it can't be read, so the code doing it runs from RAM
+//! with the interrupts disabled. It takes up to a few tens of milliseconds, during which the USB
+//! console doesn't respond either.
+
+use crate::provisioning::Credentials;
+
+// Size of the flash of the Pico. Has to match memory.x.
+const FLASH_SIZE: u32 = 2 * 1024 * 1024;
+const SECTOR_SIZE: u32 = 4096;
+const PAGE_SIZE: usize = 256;
+// Offset of the store from the start of the flash.
+const STORE_OFFSET: u32 = FLASH_SIZE - SECTOR_SIZE;
+// Where the flash is mapped for reading.
+const XIP_BASE: u32 = 0x1000_0000;
+// Erases a 4 KiB sector.
+const SECTOR_ERASE_CMD: u8 = 0x20;
+// Size of the second stage boot loader at the start of the flash, in words.
+const BOOT2_WORDS: usize = 64;
+
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSPHRASE_LEN: usize = 64;
+
+// The record consists of the magic, the lengths of the SSID and the passphrase, both of them
+// padded to their maximum lengths and a checksum of everything before it.
+const MAGIC: [u8; 4] = *b"WIFI";
+const SSID_OFFSET: usize = 6;
+const PASSPHRASE_OFFSET: usize = SSID_OFFSET + MAX_SSID_LEN;
+const CHECKSUM_OFFSET: usize = PASSPHRASE_OFFSET + MAX_PASSPHRASE_LEN;
+const RECORD_SIZE: usize = CHECKSUM_OFFSET + 4;
+
+/// Save the credentials, replacing the previous ones.
+pub fn save(credentials: &Credentials) {
+    let (ssid, passphrase) = (credentials.ssid(), credentials.passphrase());
+
+    // Erased flash reads as 0xFF.
+    let mut page = [0xFF; PAGE_SIZE];
+    page[..4].copy_from_slice(&MAGIC);
+    page[4] = ssid.len() as u8;
+    page[5] = passphrase.len() as u8;
+    page[SSID_OFFSET..SSID_OFFSET + ssid.len()].copy_from_slice(ssid.as_bytes());
+    page[PASSPHRASE_OFFSET..PASSPHRASE_OFFSET + passphrase.len()]
+        .copy_from_slice(passphrase.as_bytes());
+    let checksum = checksum(&page[..CHECKSUM_OFFSET]);
+    page[CHECKSUM_OFFSET..RECORD_SIZE].copy_from_slice(&checksum.to_le_bytes());
+
+    write_sector(Some(&page));
+}
+
+/// The saved credentials, or None if nothing has been saved or the record is corrupted, e.g.
+/// because the power was lost while saving it.
+pub fn load() -> Option<Credentials> {
+    let record =
+        unsafe { core::slice::from_raw_parts((XIP_BASE + STORE_OFFSET) as *const u8, RECORD_SIZE) };
+
+    let stored_checksum = u32::from_le_bytes([
+        record[CHECKSUM_OFFSET],
+        record[CHECKSUM_OFFSET + 1],
+        record[CHECKSUM_OFFSET + 2],
+        record[CHECKSUM_OFFSET + 3],
+    ]);
+    if record[..4] != MAGIC || checksum(&record[..CHECKSUM_OFFSET]) != stored_checksum {
+        return None;
+    }
+
+    let (ssid_len, passphrase_len) = (record[4] as usize, record[5] as usize);
+    if ssid_len > MAX_SSID_LEN || passphrase_len > MAX_PASSPHRASE_LEN {
+        return None;
+    }
+    let ssid = core::str::from_utf8(&record[SSID_OFFSET..SSID_OFFSET + ssid_len]).ok()?;
+    let passphrase =
+        core::str::from_utf8(&record[PASSPHRASE_OFFSET..PASSPHRASE_OFFSET + passphrase_len])
+            .ok()?;
+    Credentials::new(ssid, passphrase).ok()
+}
+
+/// Forget the saved credentials.
+pub fn erase() {
+    write_sector(None);
+}
+
+// FNV-1a
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+// Erase the sector of the store and program its first page, if given.
+fn write_sector(page: Option<&[u8; PAGE_SIZE]>) {
+    let rom = RomFunctions::lookup();
+
+    // boot2 restores the fast read mode after the programming. It's copied to RAM, since the
+    // flash can't be read at that point.
+    let mut boot2 = [0u32; BOOT2_WORDS];
+    unsafe {
+        core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), BOOT2_WORDS);
+    }
+
+    let page = page.map_or(core::ptr::null(), |page| page.as_ptr());
+    cortex_m::interrupt::free(|_| unsafe { program_sector(&rom, &boot2, page) });
+}
+
+// Flash functions of the boot ROM.
+struct RomFunctions {
+    connect_internal_flash: extern "C" fn(),
+    flash_exit_xip: extern "C" fn(),
+    flash_range_erase: extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: extern "C" fn(),
+}
+
+impl RomFunctions {
+    fn lookup() -> Self {
+        unsafe {
+            RomFunctions {
+                connect_internal_flash: core::mem::transmute(rom_function(b"IF")),
+                flash_exit_xip: core::mem::transmute(rom_function(b"EX")),
+                flash_range_erase: core::mem::transmute(rom_function(b"RE")),
+                flash_range_program: core::mem::transmute(rom_function(b"RP")),
+                flash_flush_cache: core::mem::transmute(rom_function(b"FC")),
+            }
+        }
+    }
+}
+
+// Address of a ROM function by its two-letter tag. See section 2.8.3 of the RP2040 datasheet.
+unsafe fn rom_function(tag: &[u8; 2]) -> usize {
+    // 16-bit pointers to the function table and to the lookup function.
+    let table = *(0x14 as *const u16) as *const u16;
+    let lookup: extern "C" fn(*const u16, u32) -> usize =
+        core::mem::transmute(*(0x18 as *const u16) as usize);
+    lookup(table, u16::from_le_bytes(*tag) as u32)
+}
+
+// Runs from RAM and calls only the ROM and boot2, since the flash can't be read until boot2 has
+// returned.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn program_sector(rom: &RomFunctions, boot2: &[u32; BOOT2_WORDS], page: *const u8) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(
+        STORE_OFFSET,
+        SECTOR_SIZE as usize,
+        SECTOR_SIZE,
+        SECTOR_ERASE_CMD,
+    );
+    if !page.is_null() {
+        (rom.flash_range_program)(STORE_OFFSET, page, PAGE_SIZE);
+    }
+    (rom.flash_flush_cache)();
+
+    // Thumb code, hence the lowest bit.
+    let boot2_entry: extern "C" fn() = core::mem::transmute(boot2.as_ptr() as usize + 1);
+    boot2_entry();
+}
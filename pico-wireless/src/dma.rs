@@ -0,0 +1,75 @@
+//! Minimal use of the DMA channels for the bulk SPI transfers: a pair of channels, one feeding
+//! the TX FIFO and one emptying the RX FIFO, paced by the DREQ signals of the peripheral.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use rp2040_hal::pac;
+
+// Fields of the CTRL register. DATA_SIZE is left at 0, which means bytes.
+const CTRL_EN: u32 = 1 << 0;
+const CTRL_INCR_READ: u32 = 1 << 4;
+const CTRL_INCR_WRITE: u32 = 1 << 5;
+const CTRL_CHAIN_TO_SHIFT: u32 = 11;
+const CTRL_TREQ_SEL_SHIFT: u32 = 15;
+const CTRL_BUSY: u32 = 1 << 24;
+
+pub(crate) const NUM_CHANNELS: u8 = 12;
+
+/// One channel of a transfer.
+pub(crate) struct Channel {
+    pub(crate) channel: u8,
+    pub(crate) read_addr: u32,
+    pub(crate) incr_read: bool,
+    pub(crate) write_addr: u32,
+    pub(crate) incr_write: bool,
+    pub(crate) dreq: u8,
+}
+
+pub(crate) fn init(resets: &mut pac::RESETS) {
+    resets.reset.modify(|_, w| w.dma().clear_bit());
+    while resets.reset_done.read().dma().bit_is_clear() {}
+}
+
+/// Start both channels at once to transfer `count` bytes each. The buffers behind the addresses
+/// have to stay alive until `wait_pair` returns.
+pub(crate) fn start_pair(a: &Channel, b: &Channel, count: u32) {
+    let dma = unsafe { &*pac::DMA::ptr() };
+
+    for channel in [a, b] {
+        let ch = &dma.ch[channel.channel as usize];
+        let mut ctrl = CTRL_EN
+            // Chaining to itself disables chaining.
+            | (channel.channel as u32) << CTRL_CHAIN_TO_SHIFT
+            | (channel.dreq as u32) << CTRL_TREQ_SEL_SHIFT;
+        if channel.incr_read {
+            ctrl |= CTRL_INCR_READ;
+        }
+        if channel.incr_write {
+            ctrl |= CTRL_INCR_WRITE;
+        }
+        ch.ch_read_addr
+            .write(|w| unsafe { w.bits(channel.read_addr) });
+        ch.ch_write_addr
+            .write(|w| unsafe { w.bits(channel.write_addr) });
+        ch.ch_trans_count.write(|w| unsafe { w.bits(count) });
+        // Not the trigger alias, the channels are started together below.
+        ch.ch_al1_ctrl.write(|w| unsafe { w.bits(ctrl) });
+    }
+
+    // The buffers must be written before the DMA reads them.
+    compiler_fence(Ordering::SeqCst);
+    dma.multi_chan_trigger
+        .write(|w| unsafe { w.bits(1 << a.channel | 1 << b.channel) });
+}
+
+pub(crate) fn is_busy(channel: u8) -> bool {
+    let dma = unsafe { &*pac::DMA::ptr() };
+    dma.ch[channel as usize].ch_ctrl_trig.read().bits() & CTRL_BUSY != 0
+}
+
+/// Wait until both channels have finished.
+pub(crate) fn wait_pair(a: u8, b: u8) {
+    while is_busy(a) || is_busy(b) {}
+    // And the results read only after it has finished.
+    compiler_fence(Ordering::SeqCst);
+}
@@ -0,0 +1,279 @@
+//! Minimal HTTP/1.1 client. Each request opens a new connection, which is closed once the whole
+//! response is received.
+
+use core::fmt::Write as _;
+
+use pico_wireless_core::http::parse_response;
+pub(crate) use pico_wireless_core::http::{content_length, find, header_value};
+pub use pico_wireless_core::http::{HttpError, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::pico_wireless::{Esp32, Socket, SocketHandle};
+use crate::spi_bus::SpiBus;
+
+const RESPONSE_TIMEOUT_MS: u32 = 10_000;
+const POLL_INTERVAL_MS: u32 = 10;
+const MAX_REQUEST_HEADER_SIZE: usize = 512;
+pub const MAX_JSON_BODY_SIZE: usize = 1024;
+
+pub(crate) struct Url<'a> {
+    pub tls: bool,
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+}
+
+fn parse_url(url: &str) -> Result<Url, HttpError> {
+    parse_url_with_schemes(url, "http://", "https://")
+}
+
+/// Parse a URL with the given prefixes of the plain and the TLS schemes, such as "ws://" and
+/// "wss://". The default ports are the same as for HTTP.
+pub(crate) fn parse_url_with_schemes<'a>(
+    url: &'a str,
+    plain: &str,
+    tls: &str,
+) -> Result<Url<'a>, HttpError> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix(plain) {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix(tls) {
+        (true, rest)
+    } else {
+        return Err(HttpError::InvalidUrl);
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| HttpError::InvalidUrl)?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        return Err(HttpError::InvalidUrl);
+    }
+
+    Ok(Url {
+        tls,
+        host,
+        port,
+        path,
+    })
+}
+
+// Request header being formatted.
+pub(crate) struct HeaderWriter {
+    data: [u8; MAX_REQUEST_HEADER_SIZE],
+    len: usize,
+}
+
+impl HeaderWriter {
+    pub fn new() -> Self {
+        HeaderWriter {
+            data: [0; MAX_REQUEST_HEADER_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl core::fmt::Write for HeaderWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > MAX_REQUEST_HEADER_SIZE {
+            return Err(core::fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Send a GET request and read the response into `response_buf`.
+pub fn get<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    url: &str,
+    response_buf: &mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<Response, HttpError> {
+    request(esp32, "GET", url, None, response_buf, delay)
+}
+
+/// Send a POST request with the given body and read the response into `response_buf`.
+pub fn post<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    url: &str,
+    content_type: &str,
+    body: &[u8],
+    response_buf: &mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<Response, HttpError> {
+    request(
+        esp32,
+        "POST",
+        url,
+        Some((content_type, body)),
+        response_buf,
+        delay,
+    )
+}
+
+/// Send a GET request and deserialize the JSON body of the response, which is read into
+/// `response_buf`. Strings in `T` can borrow from the buffer. Returns the status as well, since
+/// error responses may have a JSON body too.
+pub fn get_json<'b, T: Deserialize<'b>, S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    url: &str,
+    response_buf: &'b mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<(u16, T), HttpError> {
+    let response = get(esp32, url, response_buf, delay)?;
+    let response_buf: &'b [u8] = response_buf;
+    Ok((
+        response.status,
+        parse_json(&response_buf[..response.body_len])?,
+    ))
+}
+
+/// Send a POST request with `body` serialized as JSON, and deserialize the JSON body of the
+/// response like `get_json`. The serialized body has to fit in `MAX_JSON_BODY_SIZE`.
+pub fn post_json<'b, B: Serialize, T: Deserialize<'b>, S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    url: &str,
+    body: &B,
+    response_buf: &'b mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<(u16, T), HttpError> {
+    let mut json = [0; MAX_JSON_BODY_SIZE];
+    let len = serde_json_core::to_slice(body, &mut json).map_err(|_| HttpError::Json)?;
+
+    let response = post(
+        esp32,
+        url,
+        "application/json",
+        &json[..len],
+        response_buf,
+        delay,
+    )?;
+    let response_buf: &'b [u8] = response_buf;
+    Ok((
+        response.status,
+        parse_json(&response_buf[..response.body_len])?,
+    ))
+}
+
+pub(crate) fn parse_json<'b, T: Deserialize<'b>>(body: &'b [u8]) -> Result<T, HttpError> {
+    serde_json_core::from_slice(body)
+        .map(|(value, _)| value)
+        .map_err(|_| HttpError::Json)
+}
+
+fn request<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    method: &str,
+    url: &str,
+    body: Option<(&str, &[u8])>,
+    response_buf: &mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<Response, HttpError> {
+    let url = parse_url(url)?;
+
+    let mut header = HeaderWriter::new();
+    write!(
+        &mut header,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        method, url.path, url.host
+    )
+    .map_err(|_| HttpError::RequestTooLarge)?;
+    if let Some((content_type, body)) = body {
+        write!(
+            &mut header,
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            content_type,
+            body.len()
+        )
+        .map_err(|_| HttpError::RequestTooLarge)?;
+    }
+    header
+        .write_str("\r\n")
+        .map_err(|_| HttpError::RequestTooLarge)?;
+
+    let sock = if url.tls {
+        esp32.connect_tls(url.host, url.port)?
+    } else {
+        esp32.connect_tcp(url.host, url.port)?
+    };
+
+    let response = exchange(esp32, &sock, header.as_bytes(), body, response_buf, delay);
+    sock.close(esp32)?;
+    response
+}
+
+fn exchange<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    sock: &SocketHandle,
+    header: &[u8],
+    body: Option<(&str, &[u8])>,
+    response_buf: &mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<Response, HttpError> {
+    let sock = sock.socket();
+    send_all(esp32, sock, header)?;
+    if let Some((_, body)) = body {
+        send_all(esp32, sock, body)?;
+    }
+
+    let len = read_response(esp32, sock, response_buf, delay)?;
+    parse_response(response_buf, len)
+}
+
+fn send_all<S: SpiBus>(esp32: &mut Esp32<S>, sock: Socket, data: &[u8]) -> Result<(), HttpError> {
+    if esp32.send_all(sock, data)? < data.len() {
+        return Err(HttpError::Timeout);
+    }
+    Ok(())
+}
+
+// Read until the body is complete according to Content-Length, or until the server closes the
+// connection. Returns the number of bytes read.
+fn read_response<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    sock: Socket,
+    buf: &mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<usize, HttpError> {
+    let mut len = 0;
+    let mut idle_ms = 0;
+
+    loop {
+        if let Some(body_start) = find(&buf[..len], b"\r\n\r\n").map(|pos| pos + 4) {
+            if let Some(body_len) = content_length(&buf[..body_start]) {
+                if len >= body_start.saturating_add(body_len) {
+                    return Ok(len);
+                }
+            }
+        }
+
+        if esp32.available(sock)? == 0 {
+            if !esp32.socket_connected(sock)? {
+                return Ok(len);
+            }
+            if idle_ms >= RESPONSE_TIMEOUT_MS {
+                return Err(HttpError::Timeout);
+            }
+            delay.delay_ms(POLL_INTERVAL_MS);
+            idle_ms += POLL_INTERVAL_MS;
+            continue;
+        }
+
+        if len == buf.len() {
+            return Err(HttpError::ResponseTooLarge);
+        }
+        len += esp32.recv(sock, &mut buf[len..])?;
+        idle_ms = 0;
+    }
+}
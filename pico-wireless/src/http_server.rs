@@ -0,0 +1,345 @@
+//! Minimal HTTP/1.1 server, for a status or configuration page on the LAN. Handlers are registered
+//! for the paths and fill in a response, which is sent once the handler returns:
+//!
+//! ```ignore
+//! let mut status = |_: &Request, response: &mut Response| {
+//!     write!(response, "uptime {} s", uptime_s()).ok();
+//! };
+//! let mut server = HttpServer::new(80);
+//! server.on("/status", &mut status)?;
+//! loop {
+//!     server.poll(&mut esp32, &mut delay)?;
+//! }
+//! ```
+//!
+//! Each connection serves a single request and is closed after the response. The request and the
+//! response have to fit in `MAX_REQUEST_SIZE` and `MAX_RESPONSE_SIZE`.
+
+use core::fmt::Write as _;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::http::{find, header_value, parse_json, HeaderWriter, HttpError};
+use crate::pico_wireless::{Esp32, Esp32Error, Listener, Socket};
+use crate::provisioning::{read_request, url_decode};
+use crate::spi_bus::SpiBus;
+
+pub const MAX_ROUTES: usize = 16;
+pub const MAX_REQUEST_SIZE: usize = 1024;
+pub const MAX_RESPONSE_SIZE: usize = 2048;
+
+/// A parsed request. The path doesn't include the query.
+pub struct Request<'r> {
+    pub method: &'r str,
+    pub path: &'r str,
+    pub query: Option<&'r str>,
+    headers: &'r [u8],
+    pub body: &'r [u8],
+}
+
+impl<'r> Request<'r> {
+    /// Value of the header, with the name compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&'r [u8]> {
+        header_value(self.headers, name.as_bytes())
+    }
+
+    /// Decode the named parameter of the query or, failing that, of a urlencoded form in the
+    /// body into `out`. Returns the length of the value, or None if there is no such parameter or
+    /// it doesn't fit.
+    pub fn param(&self, name: &str, out: &mut [u8]) -> Option<usize> {
+        let query = self.query.map(str::as_bytes).unwrap_or(&[]);
+        find_param(query, name.as_bytes())
+            .or_else(|| find_param(self.body, name.as_bytes()))
+            .and_then(|value| url_decode(value, out))
+    }
+
+    /// Deserialize the JSON body. Strings in `T` can borrow from the request.
+    pub fn json<T: Deserialize<'r>>(&self) -> Result<T, HttpError> {
+        parse_json(self.body)
+    }
+}
+
+fn find_param<'a>(params: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    params.split(|&byte| byte == b'&').find_map(|param| {
+        let separator = param.iter().position(|&byte| byte == b'=')?;
+        if &param[..separator] == name {
+            Some(&param[separator + 1..])
+        } else {
+            None
+        }
+    })
+}
+
+/// Response filled in by a handler. By default it is "200 OK" with an empty text/plain body.
+pub struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: [u8; MAX_RESPONSE_SIZE],
+    len: usize,
+    // Part of the body didn't fit.
+    truncated: bool,
+}
+
+impl Response {
+    fn new() -> Self {
+        Response {
+            status: 200,
+            content_type: "text/plain",
+            body: [0; MAX_RESPONSE_SIZE],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    pub fn set_content_type(&mut self, content_type: &'static str) {
+        self.content_type = content_type;
+    }
+
+    /// Append to the body. Returns false if it didn't fit, in which case the client gets a 500
+    /// instead.
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        let end = self.len + data.len();
+        if end > MAX_RESPONSE_SIZE {
+            self.truncated = true;
+            return false;
+        }
+        self.body[self.len..end].copy_from_slice(data);
+        self.len = end;
+        true
+    }
+
+    /// Replace the body with `value` serialized as JSON. Returns false if it didn't fit, in which
+    /// case the client gets a 500 instead.
+    pub fn json<T: Serialize>(&mut self, value: &T) -> bool {
+        self.content_type = "application/json";
+        match serde_json_core::to_slice(value, &mut self.body) {
+            Ok(len) => {
+                self.len = len;
+                true
+            }
+            Err(_) => {
+                self.truncated = true;
+                false
+            }
+        }
+    }
+}
+
+impl core::fmt::Write for Response {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.write(s.as_bytes()) {
+            Ok(())
+        } else {
+            Err(core::fmt::Error)
+        }
+    }
+}
+
+struct Route<'a> {
+    path: &'a str,
+    handler: &'a mut dyn FnMut(&Request, &mut Response),
+}
+
+pub struct HttpServer<'a> {
+    port: u16,
+    // Started on the first poll.
+    listener: Option<Listener>,
+    routes: [Option<Route<'a>>; MAX_ROUTES],
+    num_routes: usize,
+}
+
+impl<'a> HttpServer<'a> {
+    /// Nothing is sent to the module until the first `poll`.
+    pub fn new(port: u16) -> Self {
+        HttpServer {
+            port,
+            listener: None,
+            routes: Default::default(),
+            num_routes: 0,
+        }
+    }
+
+    /// Serve the requests for `path` with `handler`, for any method. The handler can check
+    /// `Request::method` itself. Fails with `ParamTooLong` if there are already `MAX_ROUTES`.
+    pub fn on(
+        &mut self,
+        path: &'a str,
+        handler: &'a mut dyn FnMut(&Request, &mut Response),
+    ) -> Result<(), Esp32Error> {
+        if self.num_routes == MAX_ROUTES {
+            return Err(Esp32Error::ParamTooLong);
+        }
+        self.routes[self.num_routes] = Some(Route { path, handler });
+        self.num_routes += 1;
+        Ok(())
+    }
+
+    /// Serve a pending connection, if any. Returns whether a request has been served. Blocks
+    /// while the request is being received, up to a few seconds for a slow client.
+    pub fn poll<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Result<bool, HttpError> {
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => {
+                let listener = esp32.start_server(self.port)?;
+                info!("Serving HTTP on port {}", self.port);
+                &*self.listener.insert(listener)
+            }
+        };
+
+        let client = match listener.accept(esp32)? {
+            Some(client) => client,
+            None => return Ok(false),
+        };
+
+        let served = self.serve(esp32, client, delay);
+        esp32.stop_client(client)?;
+        served.map(|()| true)
+    }
+
+    fn serve<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        client: Socket,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Result<(), HttpError> {
+        let mut buf = [0; MAX_REQUEST_SIZE];
+        let len = read_request(esp32, client, &mut buf, delay)?;
+
+        let mut response = Response::new();
+        match parse_request(&buf[..len]) {
+            Ok(request) => {
+                info!("{} {}", request.method, request.path);
+                let route = self
+                    .routes
+                    .iter_mut()
+                    .flatten()
+                    .find(|route| route.path == request.path);
+                match route {
+                    Some(route) => (route.handler)(&request, &mut response),
+                    None => response.set_status(404),
+                }
+            }
+            Err(RequestError::Malformed) => {
+                warn!("Malformed request");
+                response.set_status(400);
+            }
+            Err(RequestError::TooLarge) => {
+                warn!("Request doesn't fit in {MAX_REQUEST_SIZE} bytes");
+                response.set_status(413);
+            }
+        }
+
+        if response.truncated {
+            warn!("Response doesn't fit in {MAX_RESPONSE_SIZE} bytes");
+            response = Response::new();
+            response.set_status(500);
+        }
+        send_response(esp32, client, &response)
+    }
+}
+
+enum RequestError {
+    Malformed,
+    // The body announced by Content-Length doesn't fit in MAX_REQUEST_SIZE.
+    TooLarge,
+}
+
+// The request line, the headers and, with Content-Length, the whole body have to be there.
+fn parse_request(request: &[u8]) -> Result<Request, RequestError> {
+    let headers_end = find(request, b"\r\n\r\n").ok_or(RequestError::Malformed)? + 4;
+    let headers = &request[..headers_end];
+    let body_len = match header_value(headers, b"content-length") {
+        Some(value) => core::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or(RequestError::Malformed)?,
+        None => 0,
+    };
+    let body_end = headers_end
+        .checked_add(body_len)
+        .filter(|&body_end| body_end <= MAX_REQUEST_SIZE)
+        .ok_or(RequestError::TooLarge)?;
+    let body = request
+        .get(headers_end..body_end)
+        .ok_or(RequestError::Malformed)?;
+
+    parse_head(headers, body).ok_or(RequestError::Malformed)
+}
+
+fn parse_head<'a>(headers: &'a [u8], body: &'a [u8]) -> Option<Request<'a>> {
+    let line_end = find(headers, b"\r\n")?;
+    let line = core::str::from_utf8(&headers[..line_end]).ok()?;
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+
+    Some(Request {
+        method,
+        path,
+        query,
+        headers: &headers[line_end + 2..],
+        body,
+    })
+}
+
+fn send_response<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    client: Socket,
+    response: &Response,
+) -> Result<(), HttpError> {
+    let mut header = HeaderWriter::new();
+    write!(
+        &mut header,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.len
+    )
+    .map_err(|_| HttpError::RequestTooLarge)?;
+
+    let body = &response.body[..response.len];
+    if esp32.send_all(client, header.as_bytes())? < header.as_bytes().len()
+        || esp32.send_all(client, body)? < body.len()
+    {
+        return Err(HttpError::Timeout);
+    }
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
@@ -0,0 +1,195 @@
+//! Long-lived TCP connections that survive NAT routers. Routers drop the mappings of connections
+//! that have been idle for a while, often after a few minutes, without telling either end: the
+//! connection looks open, but nothing arrives any more. The NINA firmware doesn't expose the TCP
+//! keepalive of lwIP, so it's done on the driver side instead:
+//!
+//! - keepalive: after `keepalive_interval_ms` without sending anything, a payload that the
+//!   protocol ignores, such as an empty line, is sent to keep the mapping alive;
+//! - idle timeout: after `idle_timeout_ms` without receiving anything, the connection is presumed
+//!   dead, and is closed and opened again.
+//!
+//! The connection is also opened again when the peer closes it. Like `WifiManager`,
+//! `PersistentConnection` doesn't block and has no clock of its own: `poll` takes the current time
+//! in milliseconds and has to be called regularly from the application loop.
+
+use log::{info, warn};
+
+use crate::pico_wireless::{Esp32, Esp32Error, Socket};
+use crate::sockets::TcpSocket;
+use crate::spi_bus::SpiBus;
+
+/// Keepalive and idle timeout of a connection. Both are disabled by default.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketTimeouts {
+    /// Send `keepalive_payload` after this long without sending anything.
+    pub keepalive_interval_ms: Option<u32>,
+    pub keepalive_payload: &'static [u8],
+    /// Reconnect after this long without receiving anything.
+    pub idle_timeout_ms: Option<u32>,
+}
+
+impl Default for SocketTimeouts {
+    fn default() -> Self {
+        SocketTimeouts {
+            keepalive_interval_ms: None,
+            keepalive_payload: b"\r\n",
+            idle_timeout_ms: None,
+        }
+    }
+}
+
+/// A TCP or TLS connection to a server that is opened again whenever it is lost.
+pub struct PersistentConnection<'a> {
+    hostname: &'a str,
+    port: u16,
+    tls: bool,
+    timeouts: SocketTimeouts,
+    // None until the first poll, and after the connection has been lost until it's opened again.
+    socket: Option<TcpSocket>,
+    last_sent_ms: u32,
+    last_received_ms: u32,
+    reconnects: u32,
+}
+
+impl<'a> PersistentConnection<'a> {
+    /// Nothing is sent to the module until the first `poll`.
+    pub fn new(hostname: &'a str, port: u16, tls: bool, timeouts: SocketTimeouts) -> Self {
+        PersistentConnection {
+            hostname,
+            port,
+            tls,
+            timeouts,
+            socket: None,
+            last_sent_ms: 0,
+            last_received_ms: 0,
+            reconnects: 0,
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: SocketTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn timeouts(&self) -> SocketTimeouts {
+        self.timeouts
+    }
+
+    /// The socket of the open connection.
+    pub fn socket(&self) -> Option<Socket> {
+        self.socket.as_ref().map(|socket| socket.socket())
+    }
+
+    /// Number of times the connection has been opened again.
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    /// Open the connection if needed, and apply the keepalive and the idle timeout. If the
+    /// connection can't be opened, the next `poll` tries again.
+    pub fn poll<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, now_ms: u32) -> Result<(), Esp32Error> {
+        let socket = match &self.socket {
+            Some(socket) => socket,
+            None => return self.open(esp32, now_ms),
+        };
+
+        if !socket.is_connected(esp32)? {
+            info!("{}:{} closed the connection", self.hostname, self.port);
+            return self.reopen(esp32, now_ms);
+        }
+
+        if let Some(idle_timeout_ms) = self.timeouts.idle_timeout_ms {
+            if now_ms.wrapping_sub(self.last_received_ms) >= idle_timeout_ms {
+                warn!(
+                    "Nothing received from {}:{} for {idle_timeout_ms} ms, reconnecting",
+                    self.hostname, self.port
+                );
+                return self.reopen(esp32, now_ms);
+            }
+        }
+
+        if let Some(keepalive_interval_ms) = self.timeouts.keepalive_interval_ms {
+            if now_ms.wrapping_sub(self.last_sent_ms) >= keepalive_interval_ms {
+                let payload = self.timeouts.keepalive_payload;
+                self.send(esp32, payload, now_ms)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send the whole buffer. Fails with `NotConnected` if the connection isn't open. A
+    /// connection that fails is closed, and opened again by the next `poll`.
+    pub fn send<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        data: &[u8],
+        now_ms: u32,
+    ) -> Result<(), Esp32Error> {
+        let socket = self.socket.as_ref().ok_or(Esp32Error::NotConnected)?;
+        let sent = match socket.send_all(esp32, data) {
+            Ok(sent) if sent == data.len() => Ok(()),
+            Ok(_) => Err(Esp32Error::SendStalled),
+            Err(e) => Err(e),
+        };
+        match sent {
+            Ok(()) => self.last_sent_ms = now_ms,
+            Err(_) => self.drop_socket(esp32),
+        }
+        sent
+    }
+
+    /// Read the received data without waiting. Returns the number of bytes read. Fails with
+    /// `NotConnected` if the connection isn't open.
+    pub fn recv<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        buf: &mut [u8],
+        now_ms: u32,
+    ) -> Result<usize, Esp32Error> {
+        let socket = self.socket.as_ref().ok_or(Esp32Error::NotConnected)?;
+        match socket.recv(esp32, buf) {
+            Ok(size) => {
+                if size > 0 {
+                    self.last_received_ms = now_ms;
+                }
+                Ok(size)
+            }
+            Err(e) => {
+                self.drop_socket(esp32);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn close<S: SpiBus>(&mut self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        match self.socket.take() {
+            Some(socket) => socket.close(esp32),
+            None => Ok(()),
+        }
+    }
+
+    fn open<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, now_ms: u32) -> Result<(), Esp32Error> {
+        let socket = if self.tls {
+            TcpSocket::connect_tls(esp32, self.hostname, self.port)?
+        } else {
+            TcpSocket::connect(esp32, self.hostname, self.port)?
+        };
+        info!("Connected to {}:{}", self.hostname, self.port);
+        self.socket = Some(socket);
+        self.last_sent_ms = now_ms;
+        self.last_received_ms = now_ms;
+        Ok(())
+    }
+
+    fn reopen<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, now_ms: u32) -> Result<(), Esp32Error> {
+        self.drop_socket(esp32);
+        self.reconnects = self.reconnects.wrapping_add(1);
+        self.open(esp32, now_ms)
+    }
+
+    fn drop_socket<S: SpiBus>(&mut self, esp32: &mut Esp32<S>) {
+        if let Some(socket) = self.socket.take() {
+            socket.close(esp32).ok();
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! RGB LED of the Pico Wireless Pack, driven by the ESP32 PWM on its GPIO 25, 26 and 27.
+
+use crate::pico_wireless::{Esp32, Esp32Error};
+use crate::spi_bus::SpiBus;
+
+// ESP32 pins of the LED channels.
+const LED_R: u8 = 25;
+const LED_G: u8 = 26;
+const LED_B: u8 = 27;
+
+/// The LED has a common anode, so the channels are lit while the pins are low. The levels are
+/// gamma-corrected, so that the perceived brightness grows evenly with them.
+pub struct Led {
+    gamma: bool,
+}
+
+impl Led {
+    pub const fn new() -> Self {
+        Led { gamma: true }
+    }
+
+    /// Use the levels as PWM duty cycles, e.g. to replay colors picked for another LED driver.
+    pub fn set_gamma_correction(&mut self, enabled: bool) {
+        self.gamma = enabled;
+    }
+
+    pub fn set_rgb<S: SpiBus>(
+        &self,
+        esp32: &mut Esp32<S>,
+        r: u8,
+        g: u8,
+        b: u8,
+    ) -> Result<(), Esp32Error> {
+        esp32.analog_write(LED_R, self.duty(r))?;
+        esp32.analog_write(LED_G, self.duty(g))?;
+        esp32.analog_write(LED_B, self.duty(b))
+    }
+
+    pub fn off<S: SpiBus>(&self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        self.set_rgb(esp32, 0, 0, 0)
+    }
+
+    fn duty(&self, level: u8) -> u8 {
+        let level = if self.gamma { gamma(level) } else { level };
+        255 - level
+    }
+}
+
+impl Default for Led {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Gamma of 2, close enough to the usual 2.2 for an LED. Rounded up, so that the lowest levels
+// still light it.
+fn gamma(level: u8) -> u8 {
+    let level = level as u32;
+    ((level * level + 254) / 255) as u8
+}
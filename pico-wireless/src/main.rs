@@ -4,14 +4,44 @@
 use embedded_hal::digital::v2::OutputPin;
 use embedded_time::fixed_point::FixedPoint as _;
 use log::info;
-use rp2040_hal::{self as hal, clocks::Clock as _, gpio, pac, sio::Sio, watchdog::Watchdog};
+use rp2040_hal::{self as hal, clocks::Clock as _, pac, sio::Sio, watchdog::Watchdog};
 
+#[cfg(feature = "ack-interrupt")]
+mod ack_interrupt;
+#[cfg(feature = "async")]
+mod async_esp32;
+mod benchmark;
 mod blocking_spi;
-mod buffer;
+mod boards;
+mod credentials_store;
+mod dma;
+mod http;
+mod http_server;
+mod keepalive;
+mod led;
+mod mdns;
+mod mqtt;
+mod ntp;
+mod passthrough;
+mod ping_watchdog;
 mod pico_wireless;
-
-use buffer::{Buffer, GenBuffer};
-use pico_wireless::{ConnectionStatus, IpV4, ProtocolMode};
+mod provisioning;
+mod rssi_monitor;
+mod sd;
+mod shared_esp32;
+mod shared_spi;
+mod sockets;
+#[cfg(feature = "spi-interrupt")]
+mod spi_interrupt;
+mod spi_bus;
+mod telemetry;
+mod udp_log;
+mod websocket;
+mod wifi_manager;
+mod wps;
+
+use led::Led;
+use pico_wireless::{ConnectionStatus, IpV4, ProtocolMode, ScanResult};
 
 #[link_section = ".boot2"]
 #[used]
@@ -20,10 +50,6 @@ pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
 // External high-speed crystal on the pico board is 12Mhz
 pub const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
 
-const ESP_LED_R: u8 = 25;
-const ESP_LED_G: u8 = 26;
-const ESP_LED_B: u8 = 27;
-
 #[cortex_m_rt::entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
@@ -81,23 +107,18 @@ fn main() -> ! {
     );
     let mut led_pin = pins.gpio25.into_push_pull_output();
 
-    let cs = pins.gpio7.into_push_pull_output();
-    let gpio2 = pins.gpio2.into_push_pull_output();
-    let resetn = pins.gpio11.into_push_pull_output();
-    let ack = pins.gpio10.into_pull_down_input();
-    let _ = pins.gpio16.into_mode::<gpio::FunctionSpi>();
-    let _ = pins.gpio18.into_mode::<gpio::FunctionSpi>();
-    let _ = pins.gpio19.into_mode::<gpio::FunctionSpi>();
-
     info!("Creating ESP32 inteface");
 
-    let mut esp32 = pico_wireless::Esp32::new(
+    let mut esp32 = boards::pimoroni_pico_wireless(
         &mut pac.RESETS,
         pac.SPI0,
-        cs,
-        ack,
-        gpio2,
-        resetn,
+        pins.gpio16,
+        pins.gpio18,
+        pins.gpio19,
+        pins.gpio7,
+        pins.gpio2,
+        pins.gpio10,
+        pins.gpio11,
         &mut delay,
         clocks.system_clock.freq().integer(),
     );
@@ -105,18 +126,18 @@ fn main() -> ! {
     show_networks(&mut esp32);
     esp32.wifi_set_passphrase("", "").unwrap();
 
+    let led = Led::new();
     let mut sock = None;
 
     loop {
         led_pin.set_high().unwrap();
-        esp32.analog_write(ESP_LED_R, 255).unwrap();
-        esp32.analog_write(ESP_LED_B, 0).unwrap();
+        led.set_rgb(&mut esp32, 0, 0, 255).unwrap();
         delay.delay_ms(500);
 
         let status = esp32.get_conn_status().unwrap();
         if status == ConnectionStatus::Connected {
-            let (ip, mask, gateway) = esp32.get_network_data().unwrap();
-            info!("IP {ip} Mask {mask} GW {gateway}");
+            let lease = esp32.lease_info().unwrap();
+            info!("{lease}");
 
             if sock.is_none() {
                 sock = Some(esp32.get_socket().unwrap());
@@ -140,23 +161,23 @@ fn main() -> ! {
         }
 
         led_pin.set_low().unwrap();
-        esp32.analog_write(ESP_LED_R, 0).unwrap();
-        esp32.analog_write(ESP_LED_B, 255).unwrap();
+        led.set_rgb(&mut esp32, 255, 0, 0).unwrap();
         delay.delay_ms(500);
     }
 }
 
-fn show_networks(esp32: &mut pico_wireless::Esp32) {
-    let mut buffer: Buffer<256, 17> = Buffer::new();
-    esp32.scan_networks(&mut buffer).unwrap();
-    info!("Found {} networks:", buffer.len());
-
-    for i in 0..buffer.len() {
-        let ssid = buffer.field_as_str(i).unwrap();
-        let channel = esp32.get_channel(i as u8).unwrap();
-        let rssi = esp32.get_rssi(i as u8).unwrap();
-        let enc = esp32.get_encryption_type(i as u8).unwrap();
-        info!("{ssid} Ch{channel} RSSI: {rssi} {enc:?}");
+fn show_networks(esp32: &mut pico_wireless::Esp32<blocking_spi::Spi<pac::SPI0>>) {
+    info!("Found networks:");
+
+    for network in esp32.scan().unwrap() {
+        let ScanResult {
+            ssid,
+            channel,
+            rssi,
+            encryption,
+            ..
+        } = network;
+        info!("{ssid} Ch{channel} RSSI: {rssi} {encryption:?}");
     }
 
     info!("");
@@ -0,0 +1,372 @@
+//! mDNS responder (RFC 6762) answering for `<hostname>.local`, with an optional DNS-SD service
+//! (RFC 6763), so that the board can be found on the LAN without knowing its address.
+//!
+//! All the answers are sent to the multicast group. Legacy unicast queries, sent from ports other
+//! than 5353, aren't answered, since their senders expect a unicast reply with the query ID.
+
+use crate::pico_wireless::{Esp32, Esp32Error, IpV4, ProtocolMode, SocketHandle};
+use crate::spi_bus::SpiBus;
+
+const MDNS_GROUP: [u8; 4] = [224, 0, 0, 251];
+const MDNS_PORT: u16 = 5353;
+// Enough for any query and reply with a single service.
+const PACKET_SIZE: usize = 512;
+const MAX_NAME_LEN: usize = 255;
+// Limit on the number of compression pointers in a name, to avoid loops.
+const MAX_POINTERS: usize = 16;
+const HEADER_SIZE: usize = 12;
+const TTL_SECS: u32 = 120;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+// Set in the answers for the records that only this device owns.
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+// Set in the questions asking for a unicast reply. Ignored, since all replies are multicast.
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+
+const LOCAL: &[u8] = b"local";
+const SERVICES: &[u8] = b"_services._dns-sd._udp";
+
+/// Service advertised with DNS-SD, such as `_http._tcp` on port 80. The instance name is the
+/// hostname.
+#[derive(Debug, Clone, Copy)]
+pub struct Service<'a> {
+    pub service_type: &'a str,
+    pub port: u16,
+    /// Key-value pairs of the TXT record, such as "path=/".
+    pub txt: &'a [&'a str],
+}
+
+pub struct MdnsResponder<'a> {
+    sock: SocketHandle,
+    hostname: &'a str,
+    service: Option<Service<'a>>,
+}
+
+// Records to include in a reply.
+#[derive(Default)]
+struct Records {
+    a: bool,
+    ptr: bool,
+    srv: bool,
+    txt: bool,
+    services: bool,
+}
+
+impl Records {
+    fn any(&self) -> bool {
+        self.a || self.ptr || self.srv || self.txt || self.services
+    }
+}
+
+impl<'a> MdnsResponder<'a> {
+    /// Join the mDNS group and announce the host and the service. `hostname` is the name without
+    /// the ".local" suffix.
+    pub fn new<S: SpiBus>(
+        esp32: &mut Esp32<S>,
+        hostname: &'a str,
+        service: Option<Service<'a>>,
+    ) -> Result<Self, Esp32Error> {
+        if hostname.is_empty() || hostname.len() > 63 {
+            return Err(Esp32Error::ParamTooLong);
+        }
+
+        let sock = esp32.open_socket()?;
+        esp32.start_multicast_udp(IpV4::from_slice(&MDNS_GROUP), MDNS_PORT, sock.socket())?;
+
+        let responder = MdnsResponder {
+            sock,
+            hostname,
+            service,
+        };
+        responder.announce(esp32)?;
+        Ok(responder)
+    }
+
+    /// Send all the records unsolicited. Useful after the address has changed.
+    pub fn announce<S: SpiBus>(&self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        let records = Records {
+            a: true,
+            ptr: self.service.is_some(),
+            srv: self.service.is_some(),
+            txt: self.service.is_some(),
+            services: false,
+        };
+        self.reply(esp32, &records)
+    }
+
+    /// Answer the queries received since the last call. Has to be called regularly.
+    pub fn poll<S: SpiBus>(&self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        let sock = self.sock.socket();
+        let mut packet = [0; PACKET_SIZE];
+
+        // For UDP sockets, this also fetches the next datagram.
+        while esp32.available(sock)? != 0 {
+            let size = esp32.recv(sock, &mut packet)?;
+            let (_, port) = esp32.get_remote_data(sock)?;
+            if port != MDNS_PORT {
+                continue;
+            }
+
+            if let Some(records) = self.parse_query(&packet[..size]) {
+                if records.any() {
+                    self.reply(esp32, &records)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn close<S: SpiBus>(self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        self.sock.close(esp32)
+    }
+
+    // Find the records asked for by the query. Returns None for malformed packets and responses.
+    fn parse_query(&self, packet: &[u8]) -> Option<Records> {
+        let header = packet.get(..HEADER_SIZE)?;
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        if flags & FLAG_RESPONSE != 0 {
+            return None;
+        }
+        let questions = u16::from_be_bytes([header[4], header[5]]);
+
+        let hostname = self.hostname.as_bytes();
+        let mut records = Records::default();
+        let mut offset = HEADER_SIZE;
+        let mut name = [0; MAX_NAME_LEN];
+
+        for _ in 0..questions {
+            let (name_len, next) = read_name(packet, offset, &mut name)?;
+            let name = &name[..name_len];
+            let fields = packet.get(next..next + 4)?;
+            let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+            let qclass = u16::from_be_bytes([fields[2], fields[3]]) & !CLASS_UNICAST_RESPONSE;
+            offset = next + 4;
+
+            if qclass != CLASS_IN {
+                continue;
+            }
+            let asks = |record_type| qtype == record_type || qtype == TYPE_ANY;
+
+            if name_eq(name, &[hostname, LOCAL]) && asks(TYPE_A) {
+                records.a = true;
+            }
+            if let Some(service) = &self.service {
+                let service_type = service.service_type.as_bytes();
+                if name_eq(name, &[service_type, LOCAL]) && asks(TYPE_PTR) {
+                    // The other records are needed to connect, so send them right away.
+                    records.ptr = true;
+                    records.srv = true;
+                    records.txt = true;
+                    records.a = true;
+                }
+                if name_eq(name, &[hostname, service_type, LOCAL]) {
+                    records.srv |= asks(TYPE_SRV);
+                    records.txt |= asks(TYPE_TXT);
+                }
+                if name_eq(name, &[SERVICES, LOCAL]) && asks(TYPE_PTR) {
+                    records.services = true;
+                }
+            }
+        }
+
+        Some(records)
+    }
+
+    fn reply<S: SpiBus>(&self, esp32: &mut Esp32<S>, records: &Records) -> Result<(), Esp32Error> {
+        let (ip, _, _) = esp32.get_network_data()?;
+
+        let mut writer = PacketWriter {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        };
+        let size = self
+            .write_reply(&mut writer, ip, records)
+            .ok_or(Esp32Error::ParamTooLong)?;
+
+        let sock = self.sock.socket();
+        esp32.start_client(
+            IpV4::from_slice(&MDNS_GROUP),
+            MDNS_PORT,
+            sock,
+            ProtocolMode::Udp,
+        )?;
+        esp32.insert_data_buf(sock, &writer.data[..size])?;
+        esp32.send_data_udp(sock)
+    }
+
+    fn write_reply(&self, writer: &mut PacketWriter, ip: IpV4, records: &Records) -> Option<usize> {
+        let hostname = self.hostname.as_bytes();
+        let mut answers: u16 = 0;
+
+        // The header is filled in at the end, once the number of answers is known.
+        writer.len = HEADER_SIZE;
+
+        if records.a {
+            writer.record(&[hostname, LOCAL], TYPE_A, CLASS_IN | CLASS_CACHE_FLUSH)?;
+            writer.rdata(&ip.octets())?;
+            answers += 1;
+        }
+
+        if let Some(service) = &self.service {
+            let service_type = service.service_type.as_bytes();
+
+            if records.services {
+                writer.record(&[SERVICES, LOCAL], TYPE_PTR, CLASS_IN)?;
+                let start = writer.start_rdata()?;
+                writer.name(&[service_type, LOCAL])?;
+                writer.end_rdata(start);
+                answers += 1;
+            }
+            if records.ptr {
+                writer.record(&[service_type, LOCAL], TYPE_PTR, CLASS_IN)?;
+                let start = writer.start_rdata()?;
+                writer.name(&[hostname, service_type, LOCAL])?;
+                writer.end_rdata(start);
+                answers += 1;
+            }
+            if records.srv {
+                let instance: &[&[u8]] = &[hostname, service_type, LOCAL];
+                writer.record(instance, TYPE_SRV, CLASS_IN | CLASS_CACHE_FLUSH)?;
+                let start = writer.start_rdata()?;
+                // Priority and weight.
+                writer.bytes(&[0, 0, 0, 0])?;
+                writer.bytes(&service.port.to_be_bytes())?;
+                writer.name(&[hostname, LOCAL])?;
+                writer.end_rdata(start);
+                answers += 1;
+            }
+            if records.txt {
+                let instance: &[&[u8]] = &[hostname, service_type, LOCAL];
+                writer.record(instance, TYPE_TXT, CLASS_IN | CLASS_CACHE_FLUSH)?;
+                let start = writer.start_rdata()?;
+                if service.txt.is_empty() {
+                    // TXT records can't be empty.
+                    writer.bytes(&[0])?;
+                }
+                for entry in service.txt {
+                    writer.bytes(&[u8::try_from(entry.len()).ok()?])?;
+                    writer.bytes(entry.as_bytes())?;
+                }
+                writer.end_rdata(start);
+                answers += 1;
+            }
+        }
+
+        let flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE;
+        writer.data[2..4].copy_from_slice(&flags.to_be_bytes());
+        writer.data[6..8].copy_from_slice(&answers.to_be_bytes());
+        Some(writer.len)
+    }
+}
+
+struct PacketWriter {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl PacketWriter {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.len + bytes.len();
+        self.data.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(())
+    }
+
+    // Write a name made of the given parts, each of which may contain several dot-separated
+    // labels. Names aren't compressed.
+    fn name(&mut self, parts: &[&[u8]]) -> Option<()> {
+        for label in labels(parts) {
+            self.bytes(&[u8::try_from(label.len()).ok()?])?;
+            self.bytes(label)?;
+        }
+        self.bytes(&[0])
+    }
+
+    // Write everything up to the rdata length.
+    fn record(&mut self, name: &[&[u8]], record_type: u16, class: u16) -> Option<()> {
+        self.name(name)?;
+        self.bytes(&record_type.to_be_bytes())?;
+        self.bytes(&class.to_be_bytes())?;
+        self.bytes(&TTL_SECS.to_be_bytes())
+    }
+
+    fn rdata(&mut self, rdata: &[u8]) -> Option<()> {
+        self.bytes(&(rdata.len() as u16).to_be_bytes())?;
+        self.bytes(rdata)
+    }
+
+    // Reserve space for the rdata length, which is filled in by `end_rdata`.
+    fn start_rdata(&mut self) -> Option<usize> {
+        self.bytes(&[0, 0])?;
+        Some(self.len)
+    }
+
+    fn end_rdata(&mut self, start: usize) {
+        let len = (self.len - start) as u16;
+        self.data[start - 2..start].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn labels<'a>(parts: &'a [&'a [u8]]) -> impl Iterator<Item = &'a [u8]> {
+    parts
+        .iter()
+        .flat_map(|part| part.split(|&byte| byte == b'.'))
+}
+
+// Case-insensitive comparison of a dotted name with the given parts.
+fn name_eq(name: &[u8], parts: &[&[u8]]) -> bool {
+    let mut expected = labels(parts);
+    for label in name.split(|&byte| byte == b'.') {
+        match expected.next() {
+            Some(expected) if label.eq_ignore_ascii_case(expected) => {}
+            _ => return false,
+        }
+    }
+    expected.next().is_none()
+}
+
+// Read a possibly compressed name into `out` in the dotted form. Returns its length and the
+// offset after the name in the packet.
+fn read_name(packet: &[u8], mut offset: usize, out: &mut [u8]) -> Option<(usize, usize)> {
+    let mut len = 0;
+    // Offset after the name, known once the first pointer is met.
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let label_len = *packet.get(offset)? as usize;
+        if label_len == 0 {
+            return Some((len, end.unwrap_or(offset + 1)));
+        }
+
+        if label_len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            let low = *packet.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = (label_len & 0x3f) << 8 | low;
+            continue;
+        }
+
+        let label = packet.get(offset + 1..offset + 1 + label_len)?;
+        if len > 0 {
+            *out.get_mut(len)? = b'.';
+            len += 1;
+        }
+        out.get_mut(len..len + label_len)?.copy_from_slice(label);
+        len += label_len;
+        offset += 1 + label_len;
+    }
+}
@@ -0,0 +1,448 @@
+//! MQTT 3.1.1 client over an ESP32 TCP socket, supporting QoS 0 and 1.
+//!
+//! The client doesn't have its own clock: `poll` takes the current time in milliseconds, so that
+//! any timer can drive the keepalive. Only one QoS 1 message can be unacknowledged at a time.
+
+use crate::pico_wireless::{Esp32, Esp32Error, SocketHandle};
+use crate::spi_bus::SpiBus;
+
+const MAX_PACKET_SIZE: usize = 512;
+const CONNACK_TIMEOUT_MS: u32 = 5000;
+const POLL_INTERVAL_MS: u32 = 10;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+const PROTOCOL_LEVEL_3_1_1: u8 = 4;
+const CONNECT_CLEAN_SESSION: u8 = 0x02;
+const CONNECT_PASSWORD: u8 = 0x40;
+const CONNECT_USERNAME: u8 = 0x80;
+const SUBACK_FAILURE: u8 = 0x80;
+
+#[derive(Debug, Clone)]
+pub enum MqttError {
+    Esp32(Esp32Error),
+    // CONNACK with a non-zero return code.
+    ConnectionRefused(u8),
+    SubscribeFailed,
+    Timeout,
+    Disconnected,
+    PacketTooLarge,
+    MalformedPacket,
+    // A QoS 1 message is still waiting for PUBACK.
+    Busy,
+}
+
+impl From<Esp32Error> for MqttError {
+    fn from(e: Esp32Error) -> Self {
+        MqttError::Esp32(e)
+    }
+}
+
+impl core::fmt::Display for MqttError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+pub struct ConnectOptions<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+    pub keep_alive_secs: u16,
+}
+
+impl<'a> ConnectOptions<'a> {
+    pub fn new(client_id: &'a str) -> Self {
+        ConnectOptions {
+            client_id,
+            username: None,
+            password: None,
+            keep_alive_secs: 60,
+        }
+    }
+}
+
+pub struct MqttClient {
+    sock: SocketHandle,
+    keep_alive_ms: u32,
+    last_sent_ms: u32,
+    // Time when PINGREQ was sent, while waiting for PINGRESP.
+    ping_sent_ms: Option<u32>,
+    next_packet_id: u16,
+    unacked: Option<u16>,
+    connected: bool,
+    rx: [u8; MAX_PACKET_SIZE],
+    rx_len: usize,
+}
+
+// Packet being assembled for sending.
+struct PacketWriter {
+    data: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl PacketWriter {
+    fn new() -> Self {
+        PacketWriter {
+            data: [0; MAX_PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), MqttError> {
+        let end = self.len + bytes.len();
+        if end > MAX_PACKET_SIZE {
+            return Err(MqttError::PacketTooLarge);
+        }
+        self.data[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), MqttError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    // String or binary data prefixed with its length.
+    fn prefixed(&mut self, bytes: &[u8]) -> Result<(), MqttError> {
+        if bytes.len() > u16::MAX as usize {
+            return Err(MqttError::PacketTooLarge);
+        }
+        self.u16(bytes.len() as u16)?;
+        self.bytes(bytes)
+    }
+
+    // The fixed header is written in front of the already written body.
+    fn finish(&mut self, header: u8) -> Result<&[u8], MqttError> {
+        let mut fixed = [header, 0, 0, 0, 0];
+        let mut fixed_len = 1;
+        let mut remaining = self.len;
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            fixed[fixed_len] = byte;
+            fixed_len += 1;
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        if self.len + fixed_len > MAX_PACKET_SIZE {
+            return Err(MqttError::PacketTooLarge);
+        }
+        self.data.copy_within(..self.len, fixed_len);
+        self.data[..fixed_len].copy_from_slice(&fixed[..fixed_len]);
+        self.len += fixed_len;
+        Ok(&self.data[..self.len])
+    }
+}
+
+// Returns the size of the fixed header and the remaining length, or None if the header is
+// incomplete.
+fn decode_fixed_header(data: &[u8]) -> Result<Option<(usize, usize)>, MqttError> {
+    let mut remaining = 0;
+    for i in 1..5 {
+        let byte = match data.get(i) {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
+        remaining |= ((byte & 0x7f) as usize) << (7 * (i - 1));
+        if byte & 0x80 == 0 {
+            return Ok(Some((i + 1, remaining)));
+        }
+    }
+    Err(MqttError::MalformedPacket)
+}
+
+impl MqttClient {
+    /// Connect to a broker and wait for it to accept the connection. `now_ms` is the current time
+    /// in the same units as the one passed to `poll`.
+    pub fn connect<S: SpiBus>(
+        esp32: &mut Esp32<S>,
+        host: &str,
+        port: u16,
+        options: &ConnectOptions,
+        now_ms: u32,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Result<Self, MqttError> {
+        let sock = esp32.connect_tcp(host, port)?;
+        let mut client = MqttClient {
+            sock,
+            keep_alive_ms: options.keep_alive_secs as u32 * 1000,
+            last_sent_ms: now_ms,
+            ping_sent_ms: None,
+            next_packet_id: 1,
+            unacked: None,
+            connected: false,
+            rx: [0; MAX_PACKET_SIZE],
+            rx_len: 0,
+        };
+
+        let mut flags = CONNECT_CLEAN_SESSION;
+        if options.username.is_some() {
+            flags |= CONNECT_USERNAME;
+        }
+        if options.password.is_some() {
+            flags |= CONNECT_PASSWORD;
+        }
+
+        let mut packet = PacketWriter::new();
+        packet.prefixed(b"MQTT")?;
+        packet.bytes(&[PROTOCOL_LEVEL_3_1_1, flags])?;
+        packet.u16(options.keep_alive_secs)?;
+        packet.prefixed(options.client_id.as_bytes())?;
+        if let Some(username) = options.username {
+            packet.prefixed(username.as_bytes())?;
+        }
+        if let Some(password) = options.password {
+            packet.prefixed(password)?;
+        }
+        client.send_packet(esp32, packet.finish(CONNECT)?, now_ms)?;
+
+        let mut elapsed_ms = 0;
+        while !client.connected {
+            if elapsed_ms >= CONNACK_TIMEOUT_MS {
+                return Err(MqttError::Timeout);
+            }
+            client.receive(esp32, now_ms, &mut |_, _| {})?;
+            delay.delay_ms(POLL_INTERVAL_MS);
+            elapsed_ms += POLL_INTERVAL_MS;
+        }
+
+        Ok(client)
+    }
+
+    /// Publish a message. For QoS 1, returns the packet ID, which stays in `unacked` until the
+    /// broker acknowledges it.
+    pub fn publish<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        now_ms: u32,
+    ) -> Result<Option<u16>, MqttError> {
+        if qos == QoS::AtLeastOnce && self.unacked.is_some() {
+            return Err(MqttError::Busy);
+        }
+
+        let mut packet = PacketWriter::new();
+        packet.prefixed(topic.as_bytes())?;
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => {
+                let packet_id = self.packet_id();
+                packet.u16(packet_id)?;
+                Some(packet_id)
+            }
+        };
+        packet.bytes(payload)?;
+
+        let header = PUBLISH | ((qos as u8) << 1) | retain as u8;
+        self.send_packet(esp32, packet.finish(header)?, now_ms)?;
+        self.unacked = packet_id;
+        Ok(packet_id)
+    }
+
+    /// Subscribe to a topic filter. Messages are delivered to the callback passed to `poll`.
+    pub fn subscribe<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        topic_filter: &str,
+        qos: QoS,
+        now_ms: u32,
+    ) -> Result<(), MqttError> {
+        let mut packet = PacketWriter::new();
+        let packet_id = self.packet_id();
+        packet.u16(packet_id)?;
+        packet.prefixed(topic_filter.as_bytes())?;
+        packet.bytes(&[qos as u8])?;
+        self.send_packet(esp32, packet.finish(SUBSCRIBE)?, now_ms)
+    }
+
+    /// ID of the QoS 1 message waiting for acknowledgement.
+    pub fn unacked(&self) -> Option<u16> {
+        self.unacked
+    }
+
+    /// Process the received packets and send the keepalive pings. Should be called from the main
+    /// loop more often than the keepalive interval.
+    pub fn poll<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        now_ms: u32,
+        on_message: &mut dyn FnMut(&str, &[u8]),
+    ) -> Result<(), MqttError> {
+        if !esp32.socket_connected(self.sock.socket())? {
+            return Err(MqttError::Disconnected);
+        }
+
+        self.receive(esp32, now_ms, on_message)?;
+
+        if self.keep_alive_ms == 0 {
+            return Ok(());
+        }
+        match self.ping_sent_ms {
+            Some(sent_ms) if now_ms.wrapping_sub(sent_ms) >= self.keep_alive_ms => {
+                Err(MqttError::Timeout)
+            }
+            Some(_) => Ok(()),
+            None if now_ms.wrapping_sub(self.last_sent_ms) >= self.keep_alive_ms / 2 => {
+                let mut packet = PacketWriter::new();
+                self.send_packet(esp32, packet.finish(PINGREQ)?, now_ms)?;
+                self.ping_sent_ms = Some(now_ms);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn disconnect<S: SpiBus>(mut self, esp32: &mut Esp32<S>) -> Result<(), MqttError> {
+        let mut packet = PacketWriter::new();
+        let now_ms = self.last_sent_ms;
+        self.send_packet(esp32, packet.finish(DISCONNECT)?, now_ms)?;
+        Ok(self.sock.close(esp32)?)
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        // Packet ID 0 is not allowed.
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        packet_id
+    }
+
+    fn send_packet<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        packet: &[u8],
+        now_ms: u32,
+    ) -> Result<(), MqttError> {
+        if esp32.send_all(self.sock.socket(), packet)? < packet.len() {
+            return Err(MqttError::Disconnected);
+        }
+
+        self.last_sent_ms = now_ms;
+        Ok(())
+    }
+
+    fn receive<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        now_ms: u32,
+        on_message: &mut dyn FnMut(&str, &[u8]),
+    ) -> Result<(), MqttError> {
+        while esp32.available(self.sock.socket())? > 0 {
+            if self.rx_len == MAX_PACKET_SIZE {
+                return Err(MqttError::PacketTooLarge);
+            }
+            self.rx_len += esp32.recv(self.sock.socket(), &mut self.rx[self.rx_len..])?;
+
+            while let Some((header_len, remaining)) = decode_fixed_header(&self.rx[..self.rx_len])?
+            {
+                let packet_len = header_len + remaining;
+                if packet_len > MAX_PACKET_SIZE {
+                    return Err(MqttError::PacketTooLarge);
+                }
+                if self.rx_len < packet_len {
+                    break;
+                }
+
+                let mut packet = [0; MAX_PACKET_SIZE];
+                packet[..packet_len].copy_from_slice(&self.rx[..packet_len]);
+                self.rx.copy_within(packet_len..self.rx_len, 0);
+                self.rx_len -= packet_len;
+
+                self.handle_packet(
+                    esp32,
+                    packet[0],
+                    &packet[header_len..packet_len],
+                    now_ms,
+                    on_message,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_packet<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        header: u8,
+        body: &[u8],
+        now_ms: u32,
+        on_message: &mut dyn FnMut(&str, &[u8]),
+    ) -> Result<(), MqttError> {
+        match header & 0xf0 {
+            CONNACK => {
+                let return_code = *body.get(1).ok_or(MqttError::MalformedPacket)?;
+                if return_code != 0 {
+                    return Err(MqttError::ConnectionRefused(return_code));
+                }
+                self.connected = true;
+            }
+            PUBLISH => {
+                let qos = (header >> 1) & 0x03;
+                let topic_len = read_u16(body, 0)? as usize;
+                let topic = body
+                    .get(2..2 + topic_len)
+                    .and_then(|topic| core::str::from_utf8(topic).ok())
+                    .ok_or(MqttError::MalformedPacket)?;
+                let mut payload_start = 2 + topic_len;
+
+                let packet_id = if qos > 0 {
+                    payload_start += 2;
+                    Some(read_u16(body, 2 + topic_len)?)
+                } else {
+                    None
+                };
+
+                on_message(topic, &body[payload_start..]);
+
+                // QoS 2 isn't requested in subscriptions, so the broker doesn't send it.
+                if let Some(packet_id) = packet_id {
+                    let mut packet = PacketWriter::new();
+                    packet.u16(packet_id)?;
+                    self.send_packet(esp32, packet.finish(PUBACK)?, now_ms)?;
+                }
+            }
+            PUBACK => {
+                if self.unacked == Some(read_u16(body, 0)?) {
+                    self.unacked = None;
+                }
+            }
+            SUBACK => {
+                if body.get(2) == Some(&SUBACK_FAILURE) {
+                    return Err(MqttError::SubscribeFailed);
+                }
+            }
+            PINGRESP => self.ping_sent_ms = None,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, MqttError> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(MqttError::MalformedPacket),
+    }
+}
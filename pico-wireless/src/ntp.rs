@@ -0,0 +1,178 @@
+//! SNTP client (RFC 4330). A single request gives the offset between the TIMER counter and the
+//! unix time, which is then tracked by `Clock` without talking to the server again.
+
+use rp2040_hal::Timer;
+
+use crate::pico_wireless::{Esp32, Esp32Error, IpV4, ProtocolMode, Socket};
+use crate::spi_bus::SpiBus;
+
+const NTP_PORT: u16 = 123;
+// Local port to which the replies are sent.
+const LOCAL_PORT: u16 = 2390;
+const PACKET_SIZE: usize = 48;
+const RESPONSE_TIMEOUT_MS: u32 = 2000;
+const POLL_INTERVAL_MS: u32 = 10;
+
+// Seconds between 1900-01-01, the NTP epoch, and 1970-01-01.
+const UNIX_EPOCH_NTP_SECS: u64 = 2_208_988_800;
+// Client mode, protocol version 4, no leap second warning.
+const REQUEST_HEADER: u8 = 0b00_100_011;
+const MODE_SERVER: u8 = 4;
+
+#[derive(Debug, Clone)]
+pub enum NtpError {
+    Esp32(Esp32Error),
+    Timeout,
+    // The reply is too short, isn't a server reply, or is a kiss-o'-death packet.
+    InvalidResponse,
+}
+
+impl From<Esp32Error> for NtpError {
+    fn from(e: Esp32Error) -> Self {
+        NtpError::Esp32(e)
+    }
+}
+
+impl core::fmt::Display for NtpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Unix time, tracked using the TIMER counter after a synchronization.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    // Unix time in microseconds when the TIMER counter was zero.
+    offset_us: u64,
+}
+
+impl Clock {
+    /// Query the NTP server and compute the offset of the TIMER counter.
+    pub fn sync<S: SpiBus>(
+        esp32: &mut Esp32<S>,
+        server: &str,
+        timer: &Timer,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Result<Self, NtpError> {
+        let (unix_us, counter) = query(esp32, server, timer, delay)?;
+        Ok(Clock {
+            offset_us: unix_us - counter,
+        })
+    }
+
+    /// Current unix time in microseconds.
+    pub fn now_us(&self, timer: &Timer) -> u64 {
+        self.offset_us + timer.get_counter()
+    }
+
+    /// Current unix time in seconds.
+    pub fn now(&self, timer: &Timer) -> u64 {
+        self.now_us(timer) / 1_000_000
+    }
+}
+
+/// Query the NTP server once. Returns the current unix time in seconds.
+pub fn request_time<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    server: &str,
+    timer: &Timer,
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<u64, NtpError> {
+    let (unix_us, _) = query(esp32, server, timer, delay)?;
+    Ok(unix_us / 1_000_000)
+}
+
+// Returns the unix time in microseconds and the TIMER counter at the moment the reply arrived.
+fn query<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    server: &str,
+    timer: &Timer,
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<(u64, u64), NtpError> {
+    let server = esp32.resolve(server)?;
+    let sock = esp32.open_socket()?;
+
+    let mut request = [0; PACKET_SIZE];
+    request[0] = REQUEST_HEADER;
+
+    let reply = exchange(esp32, sock.socket(), server, &request, timer, delay);
+    sock.close(esp32)?;
+    let (reply, sent_at, received_at) = reply?;
+
+    let mode = reply[0] & 0b111;
+    let stratum = reply[1];
+    if mode != MODE_SERVER || stratum == 0 {
+        return Err(NtpError::InvalidResponse);
+    }
+
+    let server_received_us = timestamp_us(&reply[32..40]).ok_or(NtpError::InvalidResponse)?;
+    let server_sent_us = timestamp_us(&reply[40..48]).ok_or(NtpError::InvalidResponse)?;
+
+    // Half of the round trip, not counting the time spent by the server.
+    let server_time_us = server_sent_us.saturating_sub(server_received_us);
+    let network_delay_us = (received_at - sent_at).saturating_sub(server_time_us) / 2;
+
+    Ok((server_sent_us + network_delay_us, received_at))
+}
+
+// Send the request and wait for the reply. Returns the reply and the TIMER counter when the
+// request was sent and when the reply arrived.
+fn exchange<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    sock: Socket,
+    server: IpV4,
+    request: &[u8],
+    timer: &Timer,
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<([u8; PACKET_SIZE], u64, u64), NtpError> {
+    esp32.start_server_udp(LOCAL_PORT, sock)?;
+    esp32.start_client(server, NTP_PORT, sock, ProtocolMode::Udp)?;
+    esp32.insert_data_buf(sock, request)?;
+    let sent_at = timer.get_counter();
+    esp32.send_data_udp(sock)?;
+
+    let mut reply = [0; PACKET_SIZE];
+    let size = receive(esp32, sock, &mut reply, delay)?;
+    let received_at = timer.get_counter();
+    if size < PACKET_SIZE {
+        return Err(NtpError::InvalidResponse);
+    }
+    Ok((reply, sent_at, received_at))
+}
+
+fn receive<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    sock: Socket,
+    buf: &mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<usize, NtpError> {
+    let mut elapsed_ms = 0;
+    // For UDP sockets, this also fetches the next datagram.
+    while esp32.available(sock)? == 0 {
+        if elapsed_ms >= RESPONSE_TIMEOUT_MS {
+            return Err(NtpError::Timeout);
+        }
+        delay.delay_ms(POLL_INTERVAL_MS);
+        elapsed_ms += POLL_INTERVAL_MS;
+    }
+    Ok(esp32.recv(sock, buf)?)
+}
+
+// Convert an NTP timestamp to unix time in microseconds. Returns None for the zero timestamp,
+// which means that the time is unknown.
+fn timestamp_us(data: &[u8]) -> Option<u64> {
+    let secs = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as u64;
+    let fraction = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as u64;
+    if secs == 0 && fraction == 0 {
+        return None;
+    }
+
+    // The seconds counter wraps in 2036. Times from before 1968 belong to the next era.
+    let secs = if secs < 1 << 31 {
+        secs + (1 << 32)
+    } else {
+        secs
+    };
+
+    Some((secs - UNIX_EPOCH_NTP_SECS) * 1_000_000 + ((fraction * 1_000_000) >> 32))
+}
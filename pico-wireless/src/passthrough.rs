@@ -0,0 +1,135 @@
+//! Bridge between the USB console and the UART of the ESP32, to update the NINA firmware with
+//! esptool through the Pico.
+//!
+//! The control lines of the USB serial port drive the boot pins, the same way as on the usual
+//! ESP32 development boards: RTS holds the ESP32 in reset and DTR pulls GPIO0 low. This is what
+//! esptool's default reset sequence expects, so
+//!
+//!     esptool.py --port /dev/ttyACM0 write_flash 0 NINA_W102.bin
+//!
+//! enters the bootloader by itself. The UART settings follow the line coding set by the host,
+//! including the baud rate changes done by esptool.
+//!
+//! The ESP32 UART has to be wired to the UART of the Pico passed to `run`.
+
+use embedded_hal::digital::v2::OutputPin as _;
+use embedded_time::rate::{Baud, Hertz};
+use pico_usb_console::{LineState, ParityType, StopBits};
+use rp2040_hal::{
+    gpio::DynPin,
+    uart::{self, Enabled, UartConfig, UartDevice, UartPeripheral},
+};
+use usb_device::UsbError;
+
+const BUF_SIZE: usize = 64;
+const DEFAULT_BAUD_RATE: u32 = 115_200;
+const MIN_BAUD_RATE: u32 = 1200;
+
+/// Bridge the USB console to the ESP32 UART until the Pico is reset. The console is used for the
+/// raw data, so logging is disabled.
+pub fn run<D: UartDevice>(
+    mut uart: UartPeripheral<Enabled, D>,
+    gpio0: impl Into<DynPin>,
+    resetn: impl Into<DynPin>,
+    peripheral_freq: Hertz,
+) -> ! {
+    let (mut gpio0, mut resetn): (DynPin, DynPin) = (gpio0.into(), resetn.into());
+    gpio0.into_push_pull_output();
+    resetn.into_push_pull_output();
+    log::set_max_level(log::LevelFilter::Off);
+    // esptool toggles DTR, which would otherwise inject the banner into the data.
+    pico_usb_console::set_reconnect_banner("");
+    let console = pico_usb_console::get_console();
+
+    let mut line_state: Option<LineState> = None;
+    let mut to_esp32 = [0; BUF_SIZE];
+    // Bytes in `to_esp32` that haven't been written to the UART yet.
+    let mut pending = 0..0;
+    let mut from_esp32 = [0; BUF_SIZE];
+
+    loop {
+        let new_line_state = pico_usb_console::line_state();
+        if new_line_state != line_state {
+            if let Some(new_state) = new_line_state {
+                resetn.set_state((!new_state.rts).into()).unwrap();
+                gpio0.set_state((!new_state.dtr).into()).unwrap();
+
+                let format_changed = line_state.map_or(true, |old| {
+                    (old.data_rate, old.data_bits, old.parity, old.stop_bits)
+                        != (
+                            new_state.data_rate,
+                            new_state.data_bits,
+                            new_state.parity,
+                            new_state.stop_bits,
+                        )
+                });
+                if format_changed {
+                    uart = reconfigure(uart, &new_state, peripheral_freq);
+                }
+            }
+            line_state = new_line_state;
+        }
+
+        if pending.is_empty() {
+            if let Ok(size) = console.read_bytes(&mut to_esp32) {
+                pending = 0..size;
+            }
+        }
+        if let Ok(rest) = uart.write_raw(&to_esp32[pending.clone()]) {
+            pending.start = pending.end - rest.len();
+        }
+
+        if let Ok(size) = uart.read_raw(&mut from_esp32) {
+            write_to_host(&from_esp32[..size]);
+        }
+    }
+}
+
+// Unlike `write_all_bytes`, doesn't drop the data while DTR is low, since esptool keeps it low
+// after the reset.
+fn write_to_host(data: &[u8]) {
+    let console = pico_usb_console::get_console();
+    let mut remaining = data;
+    while !remaining.is_empty() {
+        match console.write_bytes(remaining) {
+            Ok(size) => remaining = &remaining[size..],
+            Err(UsbError::WouldBlock) => {}
+            // Not configured by the host.
+            Err(_) => return,
+        }
+    }
+}
+
+// Apply the line coding requested by the host. Unsupported settings, such as 1.5 stop bits, are
+// replaced with the closest ones, and baud rates below MIN_BAUD_RATE with the esptool default.
+fn reconfigure<D: UartDevice>(
+    uart: UartPeripheral<Enabled, D>,
+    line_state: &LineState,
+    peripheral_freq: Hertz,
+) -> UartPeripheral<Enabled, D> {
+    let config = UartConfig {
+        baudrate: Baud(if line_state.data_rate < MIN_BAUD_RATE {
+            DEFAULT_BAUD_RATE
+        } else {
+            line_state.data_rate
+        }),
+        data_bits: match line_state.data_bits {
+            5 => uart::DataBits::Five,
+            6 => uart::DataBits::Six,
+            7 => uart::DataBits::Seven,
+            _ => uart::DataBits::Eight,
+        },
+        stop_bits: match line_state.stop_bits {
+            StopBits::One => uart::StopBits::One,
+            StopBits::OnePointFive | StopBits::Two => uart::StopBits::Two,
+        },
+        parity: match line_state.parity {
+            ParityType::Odd => Some(uart::Parity::Odd),
+            ParityType::Event => Some(uart::Parity::Even),
+            _ => None,
+        },
+    };
+
+    // Only fails for the baud rates that are too low for the peripheral clock.
+    uart.disable().enable(config, peripheral_freq).unwrap()
+}
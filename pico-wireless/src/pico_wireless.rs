@@ -1,37 +1,49 @@
-use core::fmt;
 use embedded_hal::digital::v2::{InputPin as _, OutputPin as _};
 use log::info;
-use rp2040_hal::{
-    gpio::{
-        pin,
-        pin::bank0::{Gpio10, Gpio11, Gpio12, Gpio2, Gpio7},
-        pin::PinId,
-        Pin,
-    },
-    pac,
+use rp2040_hal::{gpio::DynPin, pac};
+
+pub use pico_wireless_core::{
+    BusError, CommandClass, ConnectError, ConnectionStatus, DroppedSockets, EncryptionType,
+    Esp32Error, IpV4, Listener, NinaProtocol, ProtocolMode, RetryPolicy, ScanResult, Socket,
+    SocketHandle, TcpStream, Transport, UdpWriter,
 };
 
-use crate::blocking_spi::Spi;
-use crate::buffer::{Buffer, BufferError, GenBuffer};
+#[cfg(feature = "ack-interrupt")]
+use crate::ack_interrupt;
+use crate::blocking_spi::{Spi, SpiDevice};
+use crate::spi_bus::SpiBus;
+
+// Time the module takes to boot after a reset.
+const RESET_BOOT_MS: u32 = 750;
 
-const START_CMD: u8 = 0xE0;
-const END_CMD: u8 = 0xEE;
-const ERR_CMD: u8 = 0xEF;
-const DUMMY_DATA: u8 = 0xFF;
+/// SPI clock set by `SpiTransport::new`. It works with any wiring, but good connections can be
+/// clocked faster, see `benchmark`.
+pub const DEFAULT_BAUDRATE: u32 = 8_000_000;
 
-const REPLY_FLAG: u8 = 1 << 7;
+/// Driver of the ESP32 on the Pico. The SPI bus is either the SPI driver of this crate on SPI0 or
+/// SPI1, or any embedded-hal SPI implementation wrapped in `HalSpi`.
+pub type Esp32<S> = NinaProtocol<SpiTransport<S>>;
 
-const BYTE_TIMEOUT: u32 = 5000;
+// Microseconds since boot, wrapping every ~71 minutes.
+pub(crate) fn now_us() -> u32 {
+    unsafe { (*pac::TIMER::ptr()).timerawl.read().bits() }
+}
 
+fn spin_ms(ms: u32) {
+    let start_us = now_us();
+    while now_us().wrapping_sub(start_us) < ms * 1000 {}
+}
+
+/// A push button connected to the ground, such as button A of the Pico Wireless Pack on GPIO 12.
 pub struct ButtonA {
-    pin: Pin<pin::bank0::Gpio12, pin::PullUpInput>,
+    pin: DynPin,
 }
 
 impl ButtonA {
-    pub fn new(pin: Pin<Gpio12, <Gpio12 as PinId>::Reset>) -> Self {
-        ButtonA {
-            pin: pin.into_pull_up_input(),
-        }
+    pub fn new(pin: impl Into<DynPin>) -> Self {
+        let mut pin: DynPin = pin.into();
+        pin.into_pull_up_input();
+        ButtonA { pin }
     }
 
     pub fn pressed(&self) -> bool {
@@ -39,447 +51,218 @@ impl ButtonA {
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum Esp32Error {
-    Unknown,
-    NoStartCmd,
-    WaitForByteTimeout,
-    ErrCmd,
-    UnexpectedByte,
-    UnexpectedEncryptionType(u8),
-    UnexpectedStatus(u8),
-    ErrorCode(u8),
-    ResponseBufferError(BufferError),
-    WrongNumberOfResponseParams,
-}
-
-impl core::fmt::Display for Esp32Error {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
-
-enum CmdResponseType {
-    Normal,
-    Cmd,
-    Data8,
-    Data16,
-}
-
-#[repr(u8)]
-enum Esp32Command {
-    SetPassphrase = 0x11,
-    GetConnStatus = 0x20,
-    GetIpAddr = 0x21,
-    ScanNetworks = 0x27,
-    StartClientTcp = 0x2d,
-    StopClientTcp = 0x2e,
-    GetIdxRssi = 0x32,
-    GetIdxEnct = 0x33,
-    SendDataUdp = 0x39,
-    GetIdxBssid = 0x3c,
-    GetIdxChannel = 0x3d,
-    GetSocket = 0x3f,
-    InsertDataBuf = 0x46,
-    SetAnalogWrite = 0x52,
+/// GPIO pins of the ESP32 returned by `SpiTransport::free`, in the modes set by the transport.
+pub struct Esp32Pins {
+    pub cs: DynPin,
+    pub ack: DynPin,
+    pub gpio2: Option<DynPin>,
+    pub resetn: DynPin,
 }
 
-#[repr(u8)]
-#[derive(Debug, Clone, Copy)]
-pub enum EncryptionType {
-    Tkip = 2,
-    Ccmp = 4,
-    Wep = 5,
-    None = 7,
-    Auto = 8,
-    Unknown = 255,
+/// Connection to the ESP32 over an SPI bus and GPIO pins of the Pico. See `boards` for the
+/// wiring of the common boards.
+pub struct SpiTransport<S: SpiBus> {
+    spi: S,
+    cs: DynPin,
+    // Not connected on some boards, which pull GPIO0 up themselves.
+    gpio2: Option<DynPin>,
+    ack: DynPin,
+    resetn: DynPin,
 }
 
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum ConnectionStatus {
-    Idle = 0,
-    NoSsidAvail = 1,
-    ScanCompleted = 2,
-    Connected = 3,
-    ConnectFailed = 4,
-    ConnectionLost = 5,
-    Disconnected = 6,
-    ApListening = 7,
-    ApConnected = 8,
-    ApFailed = 9,
-    NoShield = 255,
-}
-
-#[repr(u8)]
-#[derive(Clone, Copy, Debug)]
-pub enum ProtocolMode {
-    Tcp = 0,
-    Udp = 1,
-    Tls = 2,
-    UdpMulticast = 3,
-    TlsBearSsl = 4,
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct IpV4([u8; 4]);
+impl<D: SpiDevice> SpiTransport<Spi<D>> {
+    /// Takes the chip select, ACK (also called BUSY or READY), GPIO0 and RESETN lines of the
+    /// ESP32, in any mode. On the Pico Wireless Pack they are GPIO 7, 10, 2 and 11, and on the
+    /// Adafruit AirLift boards they are wired to whichever pins the carrier uses. The SPI pins
+    /// have to be switched to the SPI function by the caller.
+    ///
+    /// Resets the module.
+    pub fn new(
+        resets: &mut pac::RESETS,
+        spi_device: D,
+        cs: impl Into<DynPin>,
+        ack: impl Into<DynPin>,
+        gpio2: impl Into<DynPin>,
+        resetn: impl Into<DynPin>,
+        delay: &mut cortex_m::delay::Delay,
+        system_clock_freq: u32,
+    ) -> Self {
+        let spi = Self::init_spi(resets, spi_device, system_clock_freq);
+        SpiTransport::with_spi(resets, spi, cs, ack, gpio2, resetn, delay)
+    }
 
-impl IpV4 {
-    pub fn from_slice(data: &[u8]) -> Self {
-        let mut addr = [0; 4];
-        addr.clone_from_slice(data);
-        IpV4(addr)
+    pub(crate) fn init_spi(
+        resets: &mut pac::RESETS,
+        spi_device: D,
+        system_clock_freq: u32,
+    ) -> Spi<D> {
+        let mut spi = Spi::new(spi_device);
+        spi.init(resets, DEFAULT_BAUDRATE, system_clock_freq);
+        spi.set_dummy_data(0xFF);
+        spi
     }
 
-    fn as_bytes(&self) -> &[u8] {
-        &self.0
+    /// Change the SPI clock between commands. Returns the actual rate.
+    pub fn set_baudrate(&mut self, baudrate: u32, system_clock_freq: u32) -> u32 {
+        self.spi.set_baudrate(baudrate, system_clock_freq)
     }
-}
 
-impl fmt::Display for IpV4 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    /// Move the bulk socket data with DMA, using the given channels. See `Spi::enable_dma`.
+    pub fn enable_dma(&mut self, resets: &mut pac::RESETS, tx_channel: u8, rx_channel: u8) {
+        self.spi.enable_dma(resets, tx_channel, rx_channel);
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct Socket(u8);
-
-pub struct Esp32 {
-    spi: Spi<pac::SPI0>,
-    cs: Pin<Gpio7, pin::PushPullOutput>,
-    gpio2: Pin<Gpio2, pin::PushPullOutput>,
-    ack: Pin<Gpio10, pin::PullDownInput>,
-    command_length: u32,
-}
+impl<S: SpiBus> SpiTransport<S> {
+    /// Same as `new`, but over an SPI bus that has already been configured: mode 0, at most
+    /// 8 MHz.
+    pub fn with_spi(
+        resets: &mut pac::RESETS,
+        spi: S,
+        cs: impl Into<DynPin>,
+        ack: impl Into<DynPin>,
+        gpio2: impl Into<DynPin>,
+        resetn: impl Into<DynPin>,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Self {
+        SpiTransport::init(
+            resets,
+            spi,
+            cs.into(),
+            ack.into(),
+            Some(gpio2.into()),
+            resetn.into(),
+            delay,
+        )
+    }
 
-impl Esp32 {
-    pub fn new(
+    pub(crate) fn init(
         resets: &mut pac::RESETS,
-        spi_device: pac::SPI0,
-        mut cs: Pin<Gpio7, pin::PushPullOutput>,
-        ack: Pin<Gpio10, pin::PullDownInput>,
-        mut gpio2: Pin<Gpio2, pin::PushPullOutput>,
-        mut resetn: Pin<Gpio11, pin::PushPullOutput>,
+        spi: S,
+        mut cs: DynPin,
+        mut ack: DynPin,
+        mut gpio2: Option<DynPin>,
+        mut resetn: DynPin,
         delay: &mut cortex_m::delay::Delay,
-        system_clock_freq: u32,
     ) -> Self {
-        let mut spi = Spi::new(spi_device);
-        spi.init(resets, 8_000_000, system_clock_freq);
-        spi.set_dummy_data(0xFF);
+        cs.into_push_pull_output();
+        ack.into_pull_down_input();
+        if let Some(gpio2) = &mut gpio2 {
+            gpio2.into_push_pull_output();
+            gpio2.set_high().unwrap();
+        }
+        resetn.into_push_pull_output();
 
         cs.set_high().unwrap();
 
+        // TIMER is used for the handshake timeouts.
+        resets.reset.modify(|_, w| w.timer().clear_bit());
+        while resets.reset_done.read().timer().bit_is_clear() {}
+
+        #[cfg(feature = "ack-interrupt")]
+        ack_interrupt::init(ack.id().num);
+
         // Reset
         info!("Resetting ESP32");
-        gpio2.set_high().unwrap();
         cs.set_high().unwrap();
         resetn.set_low().unwrap();
         delay.delay_ms(10);
         resetn.set_high().unwrap();
-        delay.delay_ms(750);
+        delay.delay_ms(RESET_BOOT_MS);
 
-        Esp32 {
+        SpiTransport {
             spi,
             cs,
             ack,
             gpio2,
-            command_length: 0,
+            resetn,
         }
     }
 
-    fn esp_select(&mut self) {
-        self.cs.set_low().unwrap();
-    }
-
-    fn esp_deselect(&mut self) {
-        self.cs.set_high().unwrap();
-    }
-
-    fn wait_for_esp_ready(&self) {
-        while self.ack.is_high().unwrap() {}
+    /// Return the SPI bus and the pins, after `NinaProtocol::free` has put the module in reset:
+    ///
+    /// ```ignore
+    /// let (spi, pins) = esp32.free().free();
+    /// ```
+    ///
+    /// RESETN is still driven low, so the module stays in reset until the pin is reconfigured.
+    pub fn free(self) -> (S, Esp32Pins) {
+        let pins = Esp32Pins {
+            cs: self.cs,
+            ack: self.ack,
+            gpio2: self.gpio2,
+            resetn: self.resetn,
+        };
+        (self.spi, pins)
     }
+}
 
-    fn wait_for_esp_ack(&self) {
-        while self.ack.is_low().unwrap() {}
-    }
-
-    fn wait_for_esp_select(&mut self) {
-        self.wait_for_esp_ready();
-        self.esp_select();
-        self.wait_for_esp_ack();
-    }
-
-    fn read_and_check_byte(&mut self, expected: u8) -> Result<(), Esp32Error> {
-        // info!("read_and_check_byte({expected})");
-        let b = self.spi.read_byte();
-        if b == expected {
-            Ok(())
-        } else {
-            Err(Esp32Error::UnexpectedByte)
-        }
-    }
-
-    fn wait_for_byte(&mut self, expected: u8) -> Result<(), Esp32Error> {
-        for _ in 0..BYTE_TIMEOUT {
-            let b = self.spi.read_byte();
-            if b == expected {
-                return Ok(());
-            } else if b == ERR_CMD {
-                return Err(Esp32Error::ErrCmd);
-            }
-        }
-        Err(Esp32Error::WaitForByteTimeout)
-    }
-
-    fn start_cmd(&mut self, cmd: Esp32Command, num_param: u8) {
-        self.wait_for_esp_select();
-
-        self.spi
-            .write(&[START_CMD, (cmd as u8) & !REPLY_FLAG, num_param]);
-        self.command_length += 3;
+impl<S: SpiBus> Transport for SpiTransport<S> {
+    fn select(&mut self) {
+        self.cs.set_low().unwrap();
     }
 
-    fn send_param(&mut self, param: &[u8]) {
-        assert!(param.len() < 256);
-        self.spi.write_byte(param.len() as u8);
-        self.spi.write(param);
-        self.command_length += param.len() as u32 + 1;
+    fn deselect(&mut self) {
+        self.cs.set_high().unwrap();
     }
 
-    fn send_buffer(&mut self, param: &[u8]) {
-        self.spi.write_byte((param.len() / 256) as u8);
-        self.spi.write_byte((param.len() % 256) as u8);
-        self.spi.write(param);
-        self.command_length += param.len() as u32 + 1;
+    fn ack(&self) -> bool {
+        self.ack.is_high().unwrap()
     }
 
-    fn end_cmd(&mut self) {
-        self.spi.write_byte(END_CMD);
-        self.command_length += 1;
+    fn wait_for_ack(&mut self, high: bool, timeout_us: u32) -> bool {
+        let level_reached = || self.ack.is_high().unwrap() == high;
+        let start_us = now_us();
 
-        while self.command_length % 4 != 0 {
-            self.spi.read_byte();
-            self.command_length += 1;
-        }
+        #[cfg(feature = "ack-interrupt")]
+        ack_interrupt::wait_for_level(high, start_us.wrapping_add(timeout_us), level_reached);
+        #[cfg(not(feature = "ack-interrupt"))]
+        while !level_reached() && now_us().wrapping_sub(start_us) < timeout_us {}
 
-        self.command_length = 0;
-        self.esp_deselect();
+        level_reached()
     }
 
-    fn get_response_impl(
-        &mut self,
-        cmd: Esp32Command,
-        buffer: &mut dyn GenBuffer,
-        expected_num_params: Option<usize>,
-    ) -> Result<(), Esp32Error> {
-        self.wait_for_byte(START_CMD)?;
-        self.read_and_check_byte(cmd as u8 | REPLY_FLAG)?;
-
-        let num_params = self.spi.read_byte();
-
-        if expected_num_params.is_some() && num_params as usize != expected_num_params.unwrap() {
-            return Err(Esp32Error::WrongNumberOfResponseParams);
-        }
-
-        for _ in 0..num_params {
-            let field_size = self.spi.read_byte();
-            let field = buffer
-                .add_field(field_size as usize)
-                .map_err(|e| Esp32Error::ResponseBufferError(e))?;
-            self.spi.read_bytes(field);
-        }
-
-        self.read_and_check_byte(END_CMD)
-    }
-
-    fn get_response(
-        &mut self,
-        cmd: Esp32Command,
-        buffer: &mut dyn GenBuffer,
-        expected_num_params: Option<usize>,
-    ) -> Result<(), Esp32Error> {
-        self.wait_for_esp_select();
-        let response = self.get_response_impl(cmd, buffer, expected_num_params);
-        self.esp_deselect();
-
-        response
-    }
-
-    fn get_response_u8(&mut self, cmd: Esp32Command) -> Result<u8, Esp32Error> {
-        let mut buffer: Buffer<1, 2> = Buffer::new();
-        self.get_response(cmd, &mut buffer, Some(1))?;
-        buffer
-            .field_as_u8(0)
-            .map_err(|e| Esp32Error::ResponseBufferError(e))
+    fn now_us(&self) -> u32 {
+        now_us()
     }
 
-    fn get_response_i32(&mut self, cmd: Esp32Command) -> Result<i32, Esp32Error> {
-        let mut buffer: Buffer<4, 2> = Buffer::new();
-        self.get_response(cmd, &mut buffer, Some(1))?;
-        buffer
-            .field_as_i32(0)
-            .map_err(|e| Esp32Error::ResponseBufferError(e))
-    }
-
-    fn check_response_status(&mut self, command: Esp32Command) -> Result<(), Esp32Error> {
-        let status = self.get_response_u8(command)?;
-
-        if status == 1 {
-            Ok(())
-        } else {
-            Err(Esp32Error::ErrorCode(status))
-        }
-
+    fn write(&mut self, data: &[u8]) -> Result<(), BusError> {
+        self.spi.write(data)
     }
 
-    pub fn analog_write(&mut self, pin: u8, value: u8) -> Result<(), Esp32Error> {
-        self.start_cmd(Esp32Command::SetAnalogWrite, 2);
-        self.send_param(&[pin]);
-        self.send_param(&[value]);
-        self.end_cmd();
-
-        self.check_response_status(Esp32Command::SetAnalogWrite)
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), BusError> {
+        self.spi.read_bytes(data)
     }
 
-    pub fn scan_networks(&mut self, ssids: &mut dyn GenBuffer) -> Result<(), Esp32Error> {
-        self.start_cmd(Esp32Command::ScanNetworks, 0);
-        self.end_cmd();
-
-        self.get_response(Esp32Command::ScanNetworks, ssids, None)
+    fn write_byte(&mut self, byte: u8) -> Result<(), BusError> {
+        self.spi.write_byte(byte)
     }
 
-    pub fn get_channel(&mut self, idx: u8) -> Result<u8, Esp32Error> {
-        self.start_cmd(Esp32Command::GetIdxChannel, 1);
-        self.send_param(&[idx]);
-        self.end_cmd();
-
-        self.get_response_u8(Esp32Command::GetIdxChannel)
+    fn read_byte(&mut self) -> Result<u8, BusError> {
+        self.spi.read_byte()
     }
 
-    pub fn get_rssi(&mut self, idx: u8) -> Result<i32, Esp32Error> {
-        self.start_cmd(Esp32Command::GetIdxRssi, 1);
-        self.send_param(&[idx]);
-        self.end_cmd();
-
-        self.get_response_i32(Esp32Command::GetIdxRssi)
+    fn skip_bytes(&mut self, n: usize) -> Result<(), BusError> {
+        self.spi.skip_bytes(n)
     }
 
-    pub fn get_encryption_type(&mut self, idx: u8) -> Result<EncryptionType, Esp32Error> {
-        self.start_cmd(Esp32Command::GetIdxEnct, 1);
-        self.send_param(&[idx]);
-        self.end_cmd();
-
-        let response = self.get_response_u8(Esp32Command::GetIdxEnct)?;
-
-        // It sucks, but looks like there is no way to directly convert a number to an enum with
-        // the same value numbers
-        match response {
-            2 => Ok(EncryptionType::Tkip),
-            4 => Ok(EncryptionType::Ccmp),
-            5 => Ok(EncryptionType::Wep),
-            7 => Ok(EncryptionType::None),
-            8 => Ok(EncryptionType::Auto),
-            255 => Ok(EncryptionType::Unknown),
-            _ => Err(Esp32Error::UnexpectedEncryptionType(response)),
+    fn reset(&mut self) {
+        if let Some(gpio2) = &mut self.gpio2 {
+            gpio2.set_high().unwrap();
         }
+        self.cs.set_high().unwrap();
+        self.resetn.set_low().unwrap();
+        spin_ms(10);
+        self.resetn.set_high().unwrap();
+        spin_ms(RESET_BOOT_MS);
     }
 
-    pub fn wifi_set_passphrase(&mut self, ssid: &str, passphrase: &str) -> Result<(), Esp32Error> {
-        self.start_cmd(Esp32Command::SetPassphrase, 2);
-        self.send_param(ssid.as_bytes());
-        self.send_param(passphrase.as_bytes());
-        self.end_cmd();
-
-        self.check_response_status(Esp32Command::SetPassphrase)
-    }
-
-    pub fn get_conn_status(&mut self) -> Result<ConnectionStatus, Esp32Error> {
-        self.start_cmd(Esp32Command::GetConnStatus, 0);
-        self.end_cmd();
-
-        let status = self.get_response_u8(Esp32Command::GetConnStatus)?;
-
-        match status {
-            0 => Ok(ConnectionStatus::Idle),
-            1 => Ok(ConnectionStatus::NoSsidAvail),
-            2 => Ok(ConnectionStatus::ScanCompleted),
-            3 => Ok(ConnectionStatus::Connected),
-            4 => Ok(ConnectionStatus::ConnectFailed),
-            5 => Ok(ConnectionStatus::ConnectionLost),
-            6 => Ok(ConnectionStatus::Disconnected),
-            7 => Ok(ConnectionStatus::ApListening),
-            8 => Ok(ConnectionStatus::ApConnected),
-            9 => Ok(ConnectionStatus::ApFailed),
-            255 => Ok(ConnectionStatus::NoShield),
-            _ => Err(Esp32Error::UnexpectedStatus(status)),
+    fn power_down(&mut self) {
+        // GPIO0 stays high, so that the module boots the firmware when it's released.
+        if let Some(gpio2) = &mut self.gpio2 {
+            gpio2.set_high().unwrap();
         }
-    }
-
-    pub fn get_network_data(&mut self) -> Result<(IpV4, IpV4, IpV4), Esp32Error> {
-        self.start_cmd(Esp32Command::GetIpAddr, 0);
-        self.end_cmd();
-
-        let mut buffer = Buffer::<12, 4>::new();
-        self.get_response(Esp32Command::GetIpAddr, &mut buffer, Some(3))?;
-
-        let addr_slice = buffer
-            .field_as_slice_fixed(0, 4)
-            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
-        let mask_slice = buffer
-            .field_as_slice_fixed(1, 4)
-            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
-        let gateway_slice = buffer
-            .field_as_slice_fixed(2, 4)
-            .map_err(|e| Esp32Error::ResponseBufferError(e))?;
-
-        Ok((
-            IpV4::from_slice(addr_slice),
-            IpV4::from_slice(mask_slice),
-            IpV4::from_slice(gateway_slice),
-        ))
-    }
-
-    pub fn get_socket(&mut self) -> Result<Socket, Esp32Error> {
-        self.start_cmd(Esp32Command::GetSocket, 0);
-        self.end_cmd();
-
-        let socket_id = self.get_response_u8(Esp32Command::GetSocket)?;
-
-        Ok(Socket(socket_id))
-    }
-
-    pub fn start_client(
-        &mut self,
-        ip: IpV4,
-        port: u16,
-        sock: Socket,
-        mode: ProtocolMode,
-    ) -> Result<(), Esp32Error> {
-        self.start_cmd(Esp32Command::StartClientTcp, 4);
-        self.send_param(ip.as_bytes());
-        self.send_param(&port.to_ne_bytes());
-        self.send_param(&[sock.0]);
-        self.send_param(&[mode as u8]);
-        self.end_cmd();
-
-        self.check_response_status(Esp32Command::StartClientTcp)
-    }
-
-    pub fn insert_data_buf(&mut self, sock: Socket, buf: &[u8]) -> Result<(), Esp32Error> {
-        self.start_cmd(Esp32Command::InsertDataBuf, 2);
-        self.send_param(&[sock.0]);
-        self.send_buffer(buf);
-        self.end_cmd();
-
-        self.check_response_status(Esp32Command::InsertDataBuf)
-    }
-
-    pub fn send_data_udp(&mut self, sock: Socket) -> Result<(), Esp32Error> {
-        self.start_cmd(Esp32Command::SendDataUdp, 1);
-        self.send_param(&[sock.0]);
-        self.end_cmd();
-
-        self.check_response_status(Esp32Command::SendDataUdp)
+        self.cs.set_high().unwrap();
+        self.resetn.set_low().unwrap();
     }
 }
@@ -0,0 +1,155 @@
+//! Recovering from link failures on headless devices. `PingWatchdog` pings the gateway every
+//! interval, and after a few pings in a row have failed, escalates through the recovery steps:
+//!
+//! 1. leave the network, so that `WifiManager` joins it again;
+//! 2. reset the ESP32, for a module that is stuck or a firmware that has lost its network stack;
+//! 3. optionally, reboot the Pico, for everything else.
+//!
+//! ```ignore
+//! let mut watchdog = PingWatchdog::new(30_000);
+//! watchdog.set_reboot(true);
+//! loop {
+//!     wifi.poll(&mut esp32, now_ms())?;
+//!     watchdog.poll(&mut esp32, now_ms());
+//! }
+//! ```
+//!
+//! The watchdog doesn't join the network itself: it relies on `WifiManager` noticing that the
+//! connection has been lost and joining again. After each step it waits for `RECOVERY_GRACE_MS`
+//! before pinging again, and a successful ping starts the escalation from the beginning. Like
+//! `WifiManager`, it doesn't block and has no clock of its own: `poll` takes the current time in
+//! milliseconds and has to be called regularly from the application loop.
+
+use log::{info, warn};
+
+use crate::pico_wireless::{ConnectionStatus, Esp32, Esp32Error};
+use crate::spi_bus::SpiBus;
+
+const DEFAULT_MAX_FAILURES: u32 = 3;
+// Time given to a recovery step, e.g. to join the network again, before the next ping.
+const RECOVERY_GRACE_MS: u32 = 30_000;
+const PING_TTL: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    Reconnect,
+    ResetModule,
+    Reboot,
+}
+
+pub struct PingWatchdog {
+    interval_ms: u32,
+    max_failures: u32,
+    reboot: bool,
+    // None until the first ping, which is sent on the first poll.
+    last_ping_ms: Option<u32>,
+    // Time until the next ping, longer after a recovery step.
+    wait_ms: u32,
+    // Consecutive failed pings since the last recovery step.
+    failures: u32,
+    // Last step taken since the last successful ping.
+    step: Option<Recovery>,
+    on_recovery: Option<fn(Recovery)>,
+}
+
+impl PingWatchdog {
+    /// Ping the gateway every `interval_ms`. The Pico isn't rebooted by default.
+    pub fn new(interval_ms: u32) -> Self {
+        PingWatchdog {
+            interval_ms,
+            max_failures: DEFAULT_MAX_FAILURES,
+            reboot: false,
+            last_ping_ms: None,
+            wait_ms: interval_ms,
+            failures: 0,
+            step: None,
+            on_recovery: None,
+        }
+    }
+
+    /// Number of failed pings in a row that trigger the next recovery step.
+    pub fn set_max_failures(&mut self, max_failures: u32) {
+        self.max_failures = core::cmp::max(max_failures, 1);
+    }
+
+    /// Reboot the Pico when resetting the ESP32 hasn't helped. Otherwise the ESP32 is reset again.
+    pub fn set_reboot(&mut self, reboot: bool) {
+        self.reboot = reboot;
+    }
+
+    /// Register a function that will be called from `poll` before each recovery step, e.g. to
+    /// save the state of the application before a reboot.
+    pub fn set_callback(&mut self, callback: Option<fn(Recovery)>) {
+        self.on_recovery = callback;
+    }
+
+    /// Ping the gateway if a ping is due, and take the next recovery step if too many pings have
+    /// failed. Returns the step that has been taken, if any. A failure to talk to the module
+    /// counts as a failed ping.
+    pub fn poll<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, now_ms: u32) -> Option<Recovery> {
+        if let Some(last_ping_ms) = self.last_ping_ms {
+            if now_ms.wrapping_sub(last_ping_ms) < self.wait_ms {
+                return None;
+            }
+        }
+        self.last_ping_ms = Some(now_ms);
+        self.wait_ms = self.interval_ms;
+
+        match ping_gateway(esp32) {
+            Ok(Some(_)) => {
+                if self.step.is_some() {
+                    info!("Gateway reachable again");
+                }
+                self.failures = 0;
+                self.step = None;
+                return None;
+            }
+            Ok(None) => warn!("No reply from the gateway"),
+            Err(e) => warn!("Couldn't ping the gateway: {e}"),
+        }
+
+        self.failures += 1;
+        if self.failures < self.max_failures {
+            return None;
+        }
+        self.failures = 0;
+
+        let step = match self.step {
+            None => Recovery::Reconnect,
+            Some(Recovery::Reconnect) => Recovery::ResetModule,
+            Some(_) if self.reboot => Recovery::Reboot,
+            Some(_) => Recovery::ResetModule,
+        };
+        self.step = Some(step);
+        self.wait_ms = RECOVERY_GRACE_MS;
+        if let Some(callback) = self.on_recovery {
+            callback(step);
+        }
+
+        match step {
+            Recovery::Reconnect => {
+                warn!("Gateway unreachable, reconnecting");
+                esp32.disconnect().ok();
+            }
+            Recovery::ResetModule => {
+                warn!("Gateway still unreachable, resetting the ESP32");
+                esp32.recover(true).ok();
+            }
+            Recovery::Reboot => {
+                warn!("Gateway still unreachable, rebooting");
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+        Some(step)
+    }
+}
+
+// Round-trip time of a ping to the gateway, or None if there was no reply or the module isn't
+// connected.
+fn ping_gateway<S: SpiBus>(esp32: &mut Esp32<S>) -> Result<Option<u16>, Esp32Error> {
+    if esp32.get_conn_status()? != ConnectionStatus::Connected {
+        return Ok(None);
+    }
+    let (_, _, gateway) = esp32.get_network_data()?;
+    esp32.ping(gateway, PING_TTL)
+}
@@ -0,0 +1,292 @@
+//! Setting up the WiFi credentials from a phone or a laptop. The ESP32 starts an open access
+//! point and serves a form asking for the network name and password, then joins that network.
+//!
+//! There is no DNS server redirecting all names to the access point, so the OS doesn't pop up the
+//! form by itself: the user has to open http://192.168.4.1/, the default address of the NINA
+//! access point. Any path serves the form.
+
+use log::{info, warn};
+
+use crate::http::{content_length, find};
+use crate::pico_wireless::{ConnectionStatus, Esp32, Esp32Error, Socket};
+use crate::spi_bus::SpiBus;
+
+const HTTP_PORT: u16 = 80;
+const AP_CHANNEL: u8 = 1;
+const AP_START_TIMEOUT_MS: u32 = 5000;
+const CONNECT_TIMEOUT_MS: u32 = 20_000;
+// Time given to the client to send the whole request.
+const REQUEST_TIMEOUT_MS: u32 = 5000;
+const POLL_INTERVAL_MS: u32 = 10;
+const REQUEST_BUF_SIZE: usize = 1024;
+
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSPHRASE_LEN: usize = 64;
+
+const FORM_PAGE: &str = "HTTP/1.1 200 OK\r\n\
+    Content-Type: text/html\r\n\
+    Connection: close\r\n\
+    \r\n\
+    <!DOCTYPE html><html><head>\
+    <meta name=\"viewport\" content=\"width=device-width\">\
+    <title>WiFi setup</title></head><body>\
+    <h1>WiFi setup</h1>\
+    <form method=\"post\" action=\"/\">\
+    <p>Network <input name=\"ssid\" maxlength=\"32\"></p>\
+    <p>Password <input name=\"pass\" type=\"password\" maxlength=\"64\"></p>\
+    <p><input type=\"submit\" value=\"Connect\"></p>\
+    </form></body></html>";
+
+const CONNECTING_PAGE: &str = "HTTP/1.1 200 OK\r\n\
+    Content-Type: text/html\r\n\
+    Connection: close\r\n\
+    \r\n\
+    <!DOCTYPE html><html><head><title>WiFi setup</title></head><body>\
+    <p>Connecting. If the network doesn't show up within a minute, join this access point \
+    again and check the password.</p>\
+    </body></html>";
+
+const BAD_REQUEST_PAGE: &str = "HTTP/1.1 400 Bad Request\r\n\
+    Content-Type: text/plain\r\n\
+    Connection: close\r\n\
+    \r\n\
+    Invalid network name or password\r\n";
+
+/// Network name and password entered by the user.
+#[derive(Clone, Copy)]
+pub struct Credentials {
+    ssid: [u8; MAX_SSID_LEN],
+    ssid_len: usize,
+    passphrase: [u8; MAX_PASSPHRASE_LEN],
+    passphrase_len: usize,
+}
+
+impl Credentials {
+    /// An empty passphrase means an open network.
+    pub fn new(ssid: &str, passphrase: &str) -> Result<Self, Esp32Error> {
+        if ssid.len() > MAX_SSID_LEN || passphrase.len() > MAX_PASSPHRASE_LEN {
+            return Err(Esp32Error::ParamTooLong);
+        }
+
+        let mut credentials = Credentials {
+            ssid: [0; MAX_SSID_LEN],
+            ssid_len: ssid.len(),
+            passphrase: [0; MAX_PASSPHRASE_LEN],
+            passphrase_len: passphrase.len(),
+        };
+        credentials.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
+        credentials.passphrase[..passphrase.len()].copy_from_slice(passphrase.as_bytes());
+        Ok(credentials)
+    }
+
+    pub fn ssid(&self) -> &str {
+        core::str::from_utf8(&self.ssid[..self.ssid_len]).unwrap_or("")
+    }
+
+    pub fn passphrase(&self) -> &str {
+        core::str::from_utf8(&self.passphrase[..self.passphrase_len]).unwrap_or("")
+    }
+}
+
+/// Run the provisioning flow until the module has joined a network. `ap_ssid` is the name of the
+/// temporary access point. Returns the credentials of the joined network, so that the caller can
+/// save them with `credentials_store::save`.
+pub fn provision<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    ap_ssid: &str,
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<Credentials, Esp32Error> {
+    loop {
+        let credentials = serve_form(esp32, ap_ssid, delay)?;
+
+        info!("Joining {}", credentials.ssid());
+        match esp32.connect(
+            credentials.ssid(),
+            credentials.passphrase(),
+            CONNECT_TIMEOUT_MS,
+            delay,
+        ) {
+            Ok(()) => return Ok(credentials),
+            Err(Esp32Error::JoinFailed(error)) => {
+                warn!("Couldn't join {}: {:?}", credentials.ssid(), error);
+            }
+            Err(Esp32Error::ConnectTimeout(status)) => {
+                warn!("Timed out joining {}: {:?}", credentials.ssid(), status);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Start the access point and serve the form until valid credentials are submitted.
+fn serve_form<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    ap_ssid: &str,
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<Credentials, Esp32Error> {
+    start_ap(esp32, ap_ssid, delay)?;
+
+    let server = esp32.start_server(HTTP_PORT)?;
+    info!("Serving the WiFi setup form at http://192.168.4.1/ on {ap_ssid}");
+
+    let mut request = [0; REQUEST_BUF_SIZE];
+    loop {
+        let client = match server.accept_from(esp32)? {
+            Some((client, ip, port)) => {
+                info!("Connection from {ip}:{port}");
+                client
+            }
+            None => {
+                delay.delay_ms(POLL_INTERVAL_MS);
+                continue;
+            }
+        };
+
+        let size = read_request(esp32, client, &mut request, delay)?;
+        let request = &request[..size];
+
+        let credentials = if request.starts_with(b"POST ") {
+            parse_credentials(request)
+        } else {
+            None
+        };
+
+        let page = match (request.starts_with(b"POST "), credentials) {
+            (true, Some(_)) => CONNECTING_PAGE,
+            (true, None) => BAD_REQUEST_PAGE,
+            (false, _) => FORM_PAGE,
+        };
+        // The credentials are used even if the page hasn't reached the browser.
+        let sent = esp32.send_all(client, page.as_bytes())?;
+        if sent < page.len() {
+            warn!("Sent only {sent} of {} bytes of the page", page.len());
+        }
+        esp32.stop_client(client)?;
+
+        if let Some(credentials) = credentials {
+            esp32.stop_client(server.socket())?;
+            return Ok(credentials);
+        }
+    }
+}
+
+fn start_ap<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    ap_ssid: &str,
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<(), Esp32Error> {
+    esp32.start_ap(ap_ssid, "", AP_CHANNEL)?;
+
+    let mut elapsed_ms = 0;
+    loop {
+        let status = esp32.ap_status()?;
+        match status {
+            ConnectionStatus::ApListening | ConnectionStatus::ApConnected => return Ok(()),
+            ConnectionStatus::ApFailed => return Err(Esp32Error::ConnectFailed(status)),
+            _ if elapsed_ms >= AP_START_TIMEOUT_MS => {
+                return Err(Esp32Error::ConnectTimeout(status))
+            }
+            _ => {}
+        }
+        delay.delay_ms(POLL_INTERVAL_MS);
+        elapsed_ms += POLL_INTERVAL_MS;
+    }
+}
+
+// Read the request headers and the body, if Content-Length is given. Returns the number of read
+// bytes, which may be a truncated request if the client is too slow or the request is too long.
+pub(crate) fn read_request<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    client: Socket,
+    buf: &mut [u8],
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<usize, Esp32Error> {
+    let mut len = 0;
+    let mut elapsed_ms = 0;
+
+    while len < buf.len() {
+        if let Some(body_start) = find(&buf[..len], b"\r\n\r\n").map(|pos| pos + 4) {
+            let body_len = content_length(&buf[..body_start]).unwrap_or(0);
+            if len >= body_start.saturating_add(body_len) {
+                break;
+            }
+        }
+
+        if esp32.available(client)? == 0 {
+            if elapsed_ms >= REQUEST_TIMEOUT_MS {
+                break;
+            }
+            delay.delay_ms(POLL_INTERVAL_MS);
+            elapsed_ms += POLL_INTERVAL_MS;
+            continue;
+        }
+
+        len += esp32.recv(client, &mut buf[len..])?;
+    }
+
+    Ok(len)
+}
+
+// Parse the "ssid" and "pass" fields of a urlencoded form.
+fn parse_credentials(request: &[u8]) -> Option<Credentials> {
+    let body_start = find(request, b"\r\n\r\n")? + 4;
+    let body = &request[body_start..];
+
+    let mut credentials = Credentials {
+        ssid: [0; MAX_SSID_LEN],
+        ssid_len: 0,
+        passphrase: [0; MAX_PASSPHRASE_LEN],
+        passphrase_len: 0,
+    };
+    let mut has_ssid = false;
+
+    for field in body.split(|&byte| byte == b'&') {
+        let separator = field.iter().position(|&byte| byte == b'=')?;
+        let (name, value) = (&field[..separator], &field[separator + 1..]);
+
+        match name {
+            b"ssid" => {
+                credentials.ssid_len = url_decode(value, &mut credentials.ssid)?;
+                has_ssid = credentials.ssid_len > 0;
+            }
+            b"pass" => {
+                credentials.passphrase_len = url_decode(value, &mut credentials.passphrase)?;
+            }
+            _ => {}
+        }
+    }
+
+    // WPA passphrases have at least 8 characters, an empty one means an open network.
+    let passphrase_valid = credentials.passphrase_len == 0 || credentials.passphrase_len >= 8;
+    let valid_utf8 = core::str::from_utf8(&credentials.ssid[..credentials.ssid_len]).is_ok()
+        && core::str::from_utf8(&credentials.passphrase[..credentials.passphrase_len]).is_ok();
+
+    if has_ssid && passphrase_valid && valid_utf8 {
+        Some(credentials)
+    } else {
+        None
+    }
+}
+
+// Decode a urlencoded value. Returns None if it is malformed or doesn't fit in `out`.
+pub(crate) fn url_decode(value: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut i = 0;
+
+    while i < value.len() {
+        let byte = match value[i] {
+            b'+' => b' ',
+            b'%' => {
+                let hex = value.get(i + 1..i + 3)?;
+                i += 2;
+                u8::from_str_radix(core::str::from_utf8(hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        };
+        *out.get_mut(len)? = byte;
+        len += 1;
+        i += 1;
+    }
+
+    Some(len)
+}
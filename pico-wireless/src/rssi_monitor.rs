@@ -0,0 +1,154 @@
+//! Watching the signal strength of the joined network. `RssiMonitor` samples the RSSI every
+//! interval, keeps the minimum, average and maximum, and calls a function when the signal drops
+//! below a threshold, so that the application can e.g. send its reports less often:
+//!
+//! ```ignore
+//! let mut monitor = RssiMonitor::new(5000, -80);
+//! monitor.set_callback(Some(|weak| telemetry_slow_down(weak)));
+//! loop {
+//!     wifi.poll(&mut esp32, now_ms())?;
+//!     monitor.poll(&mut esp32, now_ms())?;
+//! }
+//! ```
+//!
+//! Like `WifiManager`, the monitor doesn't block and has no clock of its own: `poll` takes the
+//! current time in milliseconds and has to be called regularly from the application loop. Nothing
+//! is sampled while the module isn't connected.
+
+use log::info;
+
+use crate::pico_wireless::{ConnectionStatus, Esp32, Esp32Error};
+use crate::spi_bus::SpiBus;
+
+// Margin above the threshold the signal has to recover to before it's reported as good again, so
+// that a signal hovering around the threshold doesn't trigger the callback on every sample.
+const HYSTERESIS_DB: i32 = 3;
+
+/// Signal strength in dBm over the samples since the statistics were last reset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RssiStats {
+    pub min: i32,
+    pub avg: i32,
+    pub max: i32,
+    pub samples: u32,
+}
+
+pub struct RssiMonitor {
+    interval_ms: u32,
+    threshold: i32,
+    // None until the first sample, which is taken on the first poll.
+    last_sample_ms: Option<u32>,
+    last: Option<i32>,
+    min: i32,
+    max: i32,
+    sum: i64,
+    samples: u32,
+    weak: bool,
+    on_change: Option<fn(bool)>,
+}
+
+impl RssiMonitor {
+    /// Sample every `interval_ms`, reporting the signal as weak below `threshold` dBm.
+    pub fn new(interval_ms: u32, threshold: i32) -> Self {
+        RssiMonitor {
+            interval_ms,
+            threshold,
+            last_sample_ms: None,
+            last: None,
+            min: i32::MAX,
+            max: i32::MIN,
+            sum: 0,
+            samples: 0,
+            weak: false,
+            on_change: None,
+        }
+    }
+
+    /// Register a function that will be called from `poll` with true when the signal drops below
+    /// the threshold and with false when it recovers.
+    pub fn set_callback(&mut self, callback: Option<fn(bool)>) {
+        self.on_change = callback;
+    }
+
+    pub fn set_threshold(&mut self, threshold: i32) {
+        self.threshold = threshold;
+    }
+
+    pub fn set_interval(&mut self, interval_ms: u32) {
+        self.interval_ms = interval_ms;
+    }
+
+    /// The latest sample in dBm.
+    pub fn last(&self) -> Option<i32> {
+        self.last
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// None if nothing has been sampled since the last reset.
+    pub fn stats(&self) -> Option<RssiStats> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some(RssiStats {
+            min: self.min,
+            avg: (self.sum / self.samples as i64) as i32,
+            max: self.max,
+            samples: self.samples,
+        })
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.min = i32::MAX;
+        self.max = i32::MIN;
+        self.sum = 0;
+        self.samples = 0;
+    }
+
+    /// Take a sample if one is due. Returns the new sample, if any.
+    pub fn poll<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        now_ms: u32,
+    ) -> Result<Option<i32>, Esp32Error> {
+        if let Some(last_sample_ms) = self.last_sample_ms {
+            if now_ms.wrapping_sub(last_sample_ms) < self.interval_ms {
+                return Ok(None);
+            }
+        }
+        self.last_sample_ms = Some(now_ms);
+
+        if esp32.get_conn_status()? != ConnectionStatus::Connected {
+            return Ok(None);
+        }
+        let rssi = esp32.current_rssi()?;
+        self.record(rssi);
+        Ok(Some(rssi))
+    }
+
+    fn record(&mut self, rssi: i32) {
+        self.last = Some(rssi);
+        self.min = core::cmp::min(self.min, rssi);
+        self.max = core::cmp::max(self.max, rssi);
+        self.sum += rssi as i64;
+        self.samples += 1;
+
+        let weak = if self.weak {
+            rssi < self.threshold + HYSTERESIS_DB
+        } else {
+            rssi < self.threshold
+        };
+        if weak != self.weak {
+            info!(
+                "Signal {}: {rssi} dBm",
+                if weak { "weak" } else { "recovered" }
+            );
+            self.weak = weak;
+            if let Some(callback) = self.on_change {
+                callback(weak);
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Micro-SD card on the SPI bus shared with the ESP32, with FAT filesystems through
+//! embedded-sdmmc. On the Pico Wireless Pack the card is on SPI0 next to the ESP32, with CS on
+//! GPIO 22:
+//!
+//! ```ignore
+//! let bus = boards::pimoroni_pico_wireless_bus(&mut pac.RESETS, pac.SPI0, pins.gpio16,
+//!     pins.gpio18, pins.gpio19, system_clock_freq);
+//! let mut esp32 = boards::pimoroni_pico_wireless_shared(&mut pac.RESETS, &bus, pins.gpio7,
+//!     pins.gpio2, pins.gpio10, pins.gpio11, &mut delay);
+//! let mut card = sd::open(&bus, pins.gpio22)?;
+//! sd::append(&mut card, "LOG.TXT", b"started\n")?;
+//! ```
+//!
+//! The helpers work with the files in the root directory of the first partition, with 8.3 names.
+//! Anything else can be done with the `VolumeManager` API directly.
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::OutputPin as _;
+use embedded_sdmmc::{
+    File, Mode, SdCardError, TimeSource, Timestamp, Volume, VolumeIdx, VolumeManager,
+};
+use rp2040_hal::gpio::DynPin;
+
+use crate::blocking_spi::SpiDevice;
+use crate::pico_wireless::{now_us, Esp32, Esp32Error, Socket};
+use crate::shared_spi::{SharedSpi, SpiClient};
+use crate::spi_bus::SpiBus;
+
+// The card has to be initialized at 100-400 kHz, and then accepts up to 25 MHz.
+const INIT_BAUDRATE: u32 = 400_000;
+const BAUDRATE: u32 = 16_000_000;
+
+// Size of the chunks in which the files are read, one block of the card.
+const CHUNK_SIZE: usize = 512;
+
+/// The card with its filesystems.
+pub type Card<'a, D> =
+    VolumeManager<embedded_sdmmc::SdCard<SpiClient<'a, D>, DynPin, TimerDelay>, FixedTime>;
+
+#[derive(Debug)]
+pub enum SdError {
+    Card(embedded_sdmmc::Error<SdCardError>),
+    Esp32(Esp32Error),
+}
+
+impl From<embedded_sdmmc::Error<SdCardError>> for SdError {
+    fn from(e: embedded_sdmmc::Error<SdCardError>) -> Self {
+        SdError::Card(e)
+    }
+}
+
+impl From<SdCardError> for SdError {
+    fn from(e: SdCardError) -> Self {
+        SdError::Card(embedded_sdmmc::Error::DeviceError(e))
+    }
+}
+
+impl From<Esp32Error> for SdError {
+    fn from(e: Esp32Error) -> Self {
+        SdError::Esp32(e)
+    }
+}
+
+impl core::fmt::Display for SdError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Delay for the card driver, on the TIMER.
+pub struct TimerDelay;
+
+impl DelayUs<u8> for TimerDelay {
+    fn delay_us(&mut self, us: u8) {
+        let start_us = now_us();
+        while now_us().wrapping_sub(start_us) < us as u32 {}
+    }
+}
+
+/// Timestamp of the created and modified files. The Pico has no real-time clock, so it is fixed
+/// to 2022-01-01.
+pub struct FixedTime;
+
+impl TimeSource for FixedTime {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 52,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        }
+    }
+}
+
+/// Initialize the card with its chip select on `cs`, in any mode. Fails if there is no card.
+pub fn open<D: SpiDevice>(
+    bus: &SharedSpi<D>,
+    cs: impl Into<DynPin>,
+) -> Result<Card<'_, D>, SdError> {
+    let mut cs: DynPin = cs.into();
+    cs.into_push_pull_output();
+    cs.set_high().unwrap();
+
+    let card = embedded_sdmmc::SdCard::new(bus.client(INIT_BAUDRATE), cs, TimerDelay);
+    // The card is initialized by the first command.
+    card.num_bytes()?;
+    card.spi(|spi| spi.set_baudrate(BAUDRATE));
+
+    Ok(VolumeManager::new(card, FixedTime))
+}
+
+/// Append `data` to the file, creating it if needed.
+pub fn append<D: SpiDevice>(card: &mut Card<D>, name: &str, data: &[u8]) -> Result<(), SdError> {
+    let mut volume = card.get_volume(VolumeIdx(0))?;
+    let root = card.open_root_dir(&volume)?;
+    let file = card.open_file_in_dir(&mut volume, &root, name, Mode::ReadWriteCreateOrAppend);
+    card.close_dir(&volume, root);
+
+    let mut file = file?;
+    let written = card.write(&mut volume, &mut file, data);
+    card.close_file(&volume, file)?;
+    written?;
+    Ok(())
+}
+
+/// Send the contents of the file on a connected TCP socket. Returns the size of the file.
+pub fn send_file<D: SpiDevice, S: SpiBus>(
+    card: &mut Card<D>,
+    name: &str,
+    esp32: &mut Esp32<S>,
+    sock: Socket,
+) -> Result<u32, SdError> {
+    let mut volume = card.get_volume(VolumeIdx(0))?;
+    let root = card.open_root_dir(&volume)?;
+    let file = card.open_file_in_dir(&mut volume, &root, name, Mode::ReadOnly);
+    card.close_dir(&volume, root);
+
+    let mut file = file?;
+    let sent = send_contents(card, &volume, &mut file, esp32, sock);
+    let size = file.length();
+    card.close_file(&volume, file)?;
+    sent.map(|()| size)
+}
+
+fn send_contents<D: SpiDevice, S: SpiBus>(
+    card: &mut Card<D>,
+    volume: &Volume,
+    file: &mut File,
+    esp32: &mut Esp32<S>,
+    sock: Socket,
+) -> Result<(), SdError> {
+    let mut chunk = [0; CHUNK_SIZE];
+    while !file.eof() {
+        let len = card.read(volume, file, &mut chunk)?;
+        if esp32.send_all(sock, &chunk[..len])? < len {
+            return Err(Esp32Error::SendStalled.into());
+        }
+    }
+    Ok(())
+}
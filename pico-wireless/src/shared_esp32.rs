@@ -0,0 +1,82 @@
+//! Using the ESP32 from several contexts, e.g. sockets from the main loop and `WifiManager` from a
+//! timer interrupt. A command takes several SPI transactions, and a command sent from an interrupt
+//! handler in the middle of another one corrupts both. `SharedEsp32` hands the driver to one
+//! context at a time:
+//!
+//! ```ignore
+//! static ESP32: SharedEsp32<Spi<SPI0>> = SharedEsp32::new();
+//!
+//! ESP32.put(esp32);
+//! loop {
+//!     ESP32.lock(|esp32| socket.send(esp32, b"hello"))?;
+//! }
+//!
+//! #[interrupt]
+//! fn TIMER_IRQ_0() {
+//!     // Skipped if the interrupt came in the middle of a command, tried again on the next tick.
+//!     ESP32.try_lock(|esp32| WIFI.poll(esp32, now_ms()));
+//! }
+//! ```
+//!
+//! The driver is moved out for the duration of the closure, so the interrupts are only masked
+//! while it changes hands, and not during the commands.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+
+use crate::pico_wireless::Esp32;
+use crate::spi_bus::SpiBus;
+
+pub struct SharedEsp32<S: SpiBus> {
+    // None while the driver is in use, or before it has been put.
+    esp32: Mutex<RefCell<Option<Esp32<S>>>>,
+}
+
+impl<S: SpiBus> SharedEsp32<S> {
+    /// Empty, so that it can be a static. The driver is given with `put`.
+    pub const fn new() -> Self {
+        SharedEsp32 {
+            esp32: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Give the driver, replacing the previous one.
+    pub fn put(&self, esp32: Esp32<S>) {
+        cortex_m::interrupt::free(|cs| *self.esp32.borrow(cs).borrow_mut() = Some(esp32));
+    }
+
+    /// Take the driver back, e.g. to put the module to sleep. Returns None if it is in use.
+    pub fn take(&self) -> Option<Esp32<S>> {
+        cortex_m::interrupt::free(|cs| self.esp32.borrow(cs).borrow_mut().take())
+    }
+
+    /// Run `f` with the driver. Returns None without running it if the driver is in use in
+    /// another context, e.g. by the code that the interrupt handler has interrupted, or hasn't
+    /// been put. Can be called from interrupt handlers.
+    pub fn try_lock<R>(&self, f: impl FnOnce(&mut Esp32<S>) -> R) -> Option<R> {
+        let mut esp32 = self.take()?;
+        let result = f(&mut esp32);
+        self.put(esp32);
+        Some(result)
+    }
+
+    /// Run `f` with the driver, waiting for it to be released by another context. Must not be
+    /// called from an interrupt handler: the code it has interrupted can't release the driver
+    /// until the handler returns, so it would wait forever.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut Esp32<S>) -> R) -> R {
+        loop {
+            if let Some(mut esp32) = self.take() {
+                let result = f(&mut esp32);
+                self.put(esp32);
+                return result;
+            }
+        }
+    }
+}
+
+impl<S: SpiBus> Default for SharedEsp32<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,112 @@
+//! SPI bus shared by the ESP32 and other devices on their own chip selects, such as the micro-SD
+//! card of the Pico Wireless Pack. Each device gets a `SpiClient` with its own clock rate, which
+//! is applied to the peripheral whenever the bus passes to another client.
+//!
+//! The bus is only borrowed for the duration of a transfer. The ESP32 driver completes every
+//! exchange before returning, and keeps its chip select high in between, so the devices can be
+//! used in any order from the same thread. It isn't meant to be shared with interrupt handlers.
+
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+
+use embedded_hal::blocking::spi;
+use pico_wireless_core::BusError;
+
+use crate::blocking_spi::{Spi, SpiDevice};
+use crate::spi_bus::SpiBus;
+
+pub struct SharedSpi<D: SpiDevice> {
+    spi: RefCell<Spi<D>>,
+    // Rate requested by the client that has used the bus last.
+    baudrate: Cell<u32>,
+    system_clock_freq: u32,
+}
+
+impl<D: SpiDevice> SharedSpi<D> {
+    /// Takes an initialized bus, clocked at any rate.
+    pub fn new(spi: Spi<D>, system_clock_freq: u32) -> Self {
+        SharedSpi {
+            spi: RefCell::new(spi),
+            baudrate: Cell::new(0),
+            system_clock_freq,
+        }
+    }
+
+    pub fn client(&self, baudrate: u32) -> SpiClient<'_, D> {
+        SpiClient {
+            bus: self,
+            baudrate,
+        }
+    }
+
+    pub fn free(self) -> Spi<D> {
+        self.spi.into_inner()
+    }
+}
+
+/// Access to the shared bus for one device.
+pub struct SpiClient<'a, D: SpiDevice> {
+    bus: &'a SharedSpi<D>,
+    baudrate: u32,
+}
+
+impl<'a, D: SpiDevice> SpiClient<'a, D> {
+    /// Takes effect from the next transfer.
+    pub fn set_baudrate(&mut self, baudrate: u32) {
+        self.baudrate = baudrate;
+    }
+
+    /// See `Spi::write_then_read`.
+    pub fn write_then_read(&mut self, cmd: &[u8], resp: &mut [u8]) {
+        self.with_spi(|spi| spi.write_then_read(cmd, resp));
+    }
+
+    fn with_spi<R>(&mut self, f: impl FnOnce(&mut Spi<D>) -> R) -> R {
+        let mut spi = self.bus.spi.borrow_mut();
+        if self.bus.baudrate.get() != self.baudrate {
+            spi.set_baudrate(self.baudrate, self.bus.system_clock_freq);
+            self.bus.baudrate.set(self.baudrate);
+        }
+        f(&mut spi)
+    }
+}
+
+impl<'a, D: SpiDevice> SpiBus for SpiClient<'a, D> {
+    fn write(&mut self, data: &[u8]) -> Result<(), BusError> {
+        self.with_spi(|spi| SpiBus::write(spi, data))
+    }
+
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), BusError> {
+        self.with_spi(|spi| SpiBus::read_bytes(spi, data))
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), BusError> {
+        self.with_spi(|spi| SpiBus::write_byte(spi, byte))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, BusError> {
+        self.with_spi(|spi| SpiBus::read_byte(spi))
+    }
+
+    fn skip_bytes(&mut self, n: usize) -> Result<(), BusError> {
+        self.with_spi(|spi| SpiBus::skip_bytes(spi, n))
+    }
+}
+
+impl<'a, D: SpiDevice> spi::Write<u8> for SpiClient<'a, D> {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        self.with_spi(|spi| spi.write(words));
+        Ok(())
+    }
+}
+
+impl<'a, D: SpiDevice> spi::Transfer<u8> for SpiClient<'a, D> {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        self.with_spi(|spi| spi.transfer(words));
+        Ok(words)
+    }
+}
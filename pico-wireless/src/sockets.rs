@@ -0,0 +1,190 @@
+//! Sockets typed by protocol, so that only the operations that make sense for a TCP connection or
+//! a UDP socket are available. Like `SocketHandle`, which they wrap, they are returned to the
+//! ESP32 when dropped.
+
+use crate::pico_wireless::{
+    Esp32, Esp32Error, IpV4, ProtocolMode, Socket, SocketHandle, SpiTransport, TcpStream, UdpWriter,
+};
+use crate::spi_bus::SpiBus;
+
+/// A TCP or TLS connection.
+#[derive(Debug)]
+pub struct TcpSocket {
+    handle: SocketHandle,
+}
+
+impl TcpSocket {
+    pub fn connect<S: SpiBus>(
+        esp32: &mut Esp32<S>,
+        hostname: &str,
+        port: u16,
+    ) -> Result<Self, Esp32Error> {
+        Ok(TcpSocket {
+            handle: esp32.connect_tcp(hostname, port)?,
+        })
+    }
+
+    pub fn connect_tls<S: SpiBus>(
+        esp32: &mut Esp32<S>,
+        hostname: &str,
+        port: u16,
+    ) -> Result<Self, Esp32Error> {
+        Ok(TcpSocket {
+            handle: esp32.connect_tls(hostname, port)?,
+        })
+    }
+
+    pub fn socket(&self) -> Socket {
+        self.handle.socket()
+    }
+
+    /// Turns false once the peer has closed the connection.
+    pub fn is_connected<S: SpiBus>(&self, esp32: &mut Esp32<S>) -> Result<bool, Esp32Error> {
+        esp32.socket_connected(self.socket())
+    }
+
+    /// Address and port of the other end of the connection.
+    pub fn peer_addr<S: SpiBus>(&self, esp32: &mut Esp32<S>) -> Result<(IpV4, u16), Esp32Error> {
+        esp32.get_remote_data(self.socket())
+    }
+
+    pub fn available<S: SpiBus>(&self, esp32: &mut Esp32<S>) -> Result<usize, Esp32Error> {
+        esp32.available(self.socket())
+    }
+
+    /// Read the received data without waiting. Returns the number of bytes read.
+    pub fn recv<S: SpiBus>(
+        &self,
+        esp32: &mut Esp32<S>,
+        buf: &mut [u8],
+    ) -> Result<usize, Esp32Error> {
+        esp32.recv(self.socket(), buf)
+    }
+
+    /// Wait up to `timeout_ms` for data and read it. Returns 0 once the peer has closed the
+    /// connection.
+    pub fn recv_timeout<S: SpiBus>(
+        &self,
+        esp32: &mut Esp32<S>,
+        buf: &mut [u8],
+        timeout_ms: u32,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Result<usize, Esp32Error> {
+        esp32.recv_timeout(self.socket(), buf, timeout_ms, delay)
+    }
+
+    /// Whether `recv` would return data or report the end of the connection.
+    pub fn poll_readable<S: SpiBus>(&self, esp32: &mut Esp32<S>) -> Result<bool, Esp32Error> {
+        esp32.poll_readable(self.socket())
+    }
+
+    /// Returns the number of bytes accepted by the module.
+    pub fn send<S: SpiBus>(&self, esp32: &mut Esp32<S>, data: &[u8]) -> Result<usize, Esp32Error> {
+        esp32.send_data_tcp(self.socket(), data)
+    }
+
+    /// Send the whole buffer, in several commands if needed. Returns the number of bytes accepted,
+    /// which is less than `data.len()` only if the connection has stalled.
+    pub fn send_all<S: SpiBus>(
+        &self,
+        esp32: &mut Esp32<S>,
+        data: &[u8],
+    ) -> Result<usize, Esp32Error> {
+        esp32.send_all(self.socket(), data)
+    }
+
+    /// Blocking stream over the connection, implementing `embedded_io::Read` and `Write` and
+    /// `core::fmt::Write`.
+    pub fn stream<'a, S: SpiBus>(
+        &self,
+        esp32: &'a mut Esp32<S>,
+        delay: &'a mut cortex_m::delay::Delay,
+    ) -> TcpStream<'a, SpiTransport<S>, cortex_m::delay::Delay> {
+        TcpStream::new(esp32, self.socket(), delay)
+    }
+
+    pub fn close<S: SpiBus>(self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        self.handle.close(esp32)
+    }
+}
+
+/// A UDP socket bound to a local port.
+#[derive(Debug)]
+pub struct UdpSocket {
+    handle: SocketHandle,
+}
+
+impl UdpSocket {
+    pub fn bind<S: SpiBus>(esp32: &mut Esp32<S>, port: u16) -> Result<Self, Esp32Error> {
+        let handle = esp32.open_socket()?;
+        esp32.start_server_udp(port, handle.socket())?;
+        Ok(UdpSocket { handle })
+    }
+
+    /// Join a multicast group, such as 239.255.255.250 for SSDP, to receive the datagrams sent to
+    /// it on the given port with `recv_from`.
+    pub fn join_multicast<S: SpiBus>(
+        esp32: &mut Esp32<S>,
+        group: IpV4,
+        port: u16,
+    ) -> Result<Self, Esp32Error> {
+        if !(224..240).contains(&group.octets()[0]) {
+            return Err(Esp32Error::UnsupportedAddress);
+        }
+        let handle = esp32.open_socket()?;
+        esp32.start_multicast_udp(group, port, handle.socket())?;
+        Ok(UdpSocket { handle })
+    }
+
+    pub fn socket(&self) -> Socket {
+        self.handle.socket()
+    }
+
+    pub fn send_to<S: SpiBus>(
+        &self,
+        esp32: &mut Esp32<S>,
+        ip: IpV4,
+        port: u16,
+        data: &[u8],
+    ) -> Result<(), Esp32Error> {
+        let sock = self.socket();
+        esp32.start_client(ip, port, sock, ProtocolMode::Udp)?;
+        esp32.insert_data_buf(sock, data)?;
+        esp32.send_data_udp(sock)
+    }
+
+    /// Writer of datagrams to the destination, implementing `embedded_io::Write` and
+    /// `core::fmt::Write`. Each `flush` sends the data written since the previous one.
+    pub fn writer<'a, S: SpiBus>(
+        &self,
+        esp32: &'a mut Esp32<S>,
+        ip: IpV4,
+        port: u16,
+    ) -> Result<UdpWriter<'a, SpiTransport<S>>, Esp32Error> {
+        let sock = self.socket();
+        esp32.start_client(ip, port, sock, ProtocolMode::Udp)?;
+        Ok(UdpWriter::new(esp32, sock))
+    }
+
+    /// Receive the next datagram without waiting. Returns its size and its sender. Datagrams
+    /// longer than the buffer are truncated.
+    pub fn recv_from<S: SpiBus>(
+        &self,
+        esp32: &mut Esp32<S>,
+        buf: &mut [u8],
+    ) -> Result<Option<(usize, IpV4, u16)>, Esp32Error> {
+        let sock = self.socket();
+        // For UDP sockets, this also fetches the next datagram.
+        if esp32.available(sock)? == 0 {
+            return Ok(None);
+        }
+
+        let size = esp32.recv(sock, buf)?;
+        let (ip, port) = esp32.get_remote_data(sock)?;
+        Ok(Some((size, ip, port)))
+    }
+
+    pub fn close<S: SpiBus>(self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        self.handle.close(esp32)
+    }
+}
@@ -0,0 +1,114 @@
+//! SPI bus used by the ESP32 driver. It is implemented for the SPI driver of this crate and, via
+//! `HalSpi`, for any embedded-hal SPI implementation, such as the rp2040-hal SPI, a PIO SPI or a
+//! proxy of a shared bus.
+
+use embedded_hal::blocking::spi;
+use log::warn;
+use pico_wireless_core::BusError;
+
+use crate::blocking_spi::{Spi, SpiDevice};
+
+// Sent while reading. The ESP32 ignores it.
+const DUMMY_DATA: u8 = 0xFF;
+
+// Size of the chunks in which `HalSpi` skips data.
+const SKIP_CHUNK_SIZE: usize = 32;
+
+/// Blocking byte-oriented SPI master. The chip select is driven by the ESP32 driver. A failed
+/// transfer makes the driver resynchronize with the module, like a garbled response.
+pub trait SpiBus {
+    fn write(&mut self, data: &[u8]) -> Result<(), BusError>;
+
+    /// Read `data.len()` bytes, sending dummy bytes.
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), BusError>;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), BusError> {
+        self.write(&[byte])
+    }
+
+    fn read_byte(&mut self) -> Result<u8, BusError> {
+        let mut byte = [0];
+        self.read_bytes(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn skip_bytes(&mut self, n: usize) -> Result<(), BusError> {
+        for _ in 0..n {
+            self.read_byte()?;
+        }
+        Ok(())
+    }
+}
+
+// The SPI peripheral of the RP2040 reports no errors.
+impl<D: SpiDevice> SpiBus for Spi<D> {
+    fn write(&mut self, data: &[u8]) -> Result<(), BusError> {
+        Spi::write(self, data);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), BusError> {
+        Spi::read_bytes(self, data);
+        Ok(())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), BusError> {
+        Spi::write_byte(self, byte);
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<u8, BusError> {
+        Ok(Spi::read_byte(self))
+    }
+
+    fn skip_bytes(&mut self, n: usize) -> Result<(), BusError> {
+        Spi::skip_bytes(self, n);
+        Ok(())
+    }
+}
+
+/// Adapter for an embedded-hal SPI implementation, configured for mode 0 and at most 8 MHz.
+/// Transfer errors are logged and reported as `Esp32Error::Bus`.
+pub struct HalSpi<S> {
+    spi: S,
+}
+
+impl<S> HalSpi<S> {
+    pub fn new(spi: S) -> Self {
+        HalSpi { spi }
+    }
+
+    pub fn free(self) -> S {
+        self.spi
+    }
+}
+
+impl<S, E> SpiBus for HalSpi<S>
+where
+    S: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+    E: core::fmt::Debug,
+{
+    fn write(&mut self, data: &[u8]) -> Result<(), BusError> {
+        self.spi.write(data).map_err(bus_error)
+    }
+
+    fn read_bytes(&mut self, data: &mut [u8]) -> Result<(), BusError> {
+        data.fill(DUMMY_DATA);
+        self.spi.transfer(data).map(|_| ()).map_err(bus_error)
+    }
+
+    fn skip_bytes(&mut self, mut n: usize) -> Result<(), BusError> {
+        let mut chunk = [0; SKIP_CHUNK_SIZE];
+        while n > 0 {
+            let size = core::cmp::min(n, SKIP_CHUNK_SIZE);
+            self.read_bytes(&mut chunk[..size])?;
+            n -= size;
+        }
+        Ok(())
+    }
+}
+
+fn bus_error<E: core::fmt::Debug>(e: E) -> BusError {
+    warn!("SPI error: {e:?}");
+    BusError
+}
@@ -0,0 +1,153 @@
+//! Interrupt-driven transfers, so that a cooperative scheduler can keep running while the bytes
+//! go out. The bytes queued by `Spi::queue_write` are moved to the TX FIFO by the SSPINTR handler,
+//! which also moves the received bytes from the RX FIFO to a software queue, from which
+//! `Spi::try_read_queued` takes them. Each byte sent receives one, like with the blocking
+//! transfers, and the bytes are only sent once their responses fit into the queue, so nothing is
+//! lost when the application is slow to read them.
+//!
+//! Defines the SPI0_IRQ and SPI1_IRQ handlers, so the application can't use them for anything
+//! else.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use rp2040_hal::pac::{self, interrupt};
+
+pub(crate) const QUEUE_SIZE: usize = 256;
+
+// Bits of SSPIMSC and SSPICR.
+const RTIM: u32 = 1 << 1;
+const RXIM: u32 = 1 << 2;
+const TXIM: u32 = 1 << 3;
+
+// Queue with a single producer and a single consumer, one of them being the interrupt handler.
+// Only loads and stores are available on the Cortex-M0+, which is enough for each index being
+// written by one side only.
+pub(crate) struct Queue {
+    data: UnsafeCell<[u8; QUEUE_SIZE]>,
+    // Written by the consumer.
+    head: AtomicUsize,
+    // Written by the producer.
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for Queue {}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            data: UnsafeCell::new([0; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub(crate) fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == QUEUE_SIZE {
+            return false;
+        }
+        unsafe { (*self.data.get())[tail % QUEUE_SIZE] = byte };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    pub(crate) fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.data.get())[head % QUEUE_SIZE] };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+}
+
+pub(crate) struct State {
+    pub(crate) tx: Queue,
+    pub(crate) rx: Queue,
+    // Bytes in the TX FIFO or on the wire whose responses haven't reached `rx` yet. Only written
+    // by the handler.
+    in_flight: AtomicUsize,
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            tx: Queue::new(),
+            rx: Queue::new(),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes queued or being transferred whose responses haven't been read.
+    pub(crate) fn pending(&self) -> usize {
+        self.tx.len() + self.in_flight.load(Ordering::Relaxed) + self.rx.len()
+    }
+}
+
+// Indexed by `SpiDevice::INDEX`.
+pub(crate) static STATES: [State; 2] = [State::new(), State::new()];
+
+fn modify_imsc(spi: &pac::spi0::RegisterBlock, f: impl FnOnce(u32) -> u32) {
+    spi.sspimsc.modify(|r, w| unsafe { w.bits(f(r.bits())) });
+}
+
+/// Start receiving with interrupts. The TX interrupt is only enabled while there are bytes to
+/// send.
+pub(crate) fn enable(spi: &pac::spi0::RegisterBlock, irq: pac::Interrupt) {
+    cortex_m::interrupt::free(|_| modify_imsc(spi, |bits| bits | RXIM | RTIM));
+    unsafe { pac::NVIC::unmask(irq) };
+}
+
+/// Let the handler send the queued bytes. Called after bytes have been queued or responses read.
+pub(crate) fn kick(spi: &pac::spi0::RegisterBlock) {
+    cortex_m::interrupt::free(|_| modify_imsc(spi, |bits| bits | TXIM));
+}
+
+fn service(spi: &pac::spi0::RegisterBlock, state: &State) {
+    let mut in_flight = state.in_flight.load(Ordering::Relaxed);
+
+    // The received bytes first, making room for the ones sent below. Room in `rx` has been
+    // reserved when they were sent.
+    while spi.sspsr.read().rne().bit_is_set() {
+        state.rx.push(spi.sspdr.read().data().bits() as u8);
+        in_flight = in_flight.saturating_sub(1);
+    }
+    spi.sspicr.write(|w| unsafe { w.bits(RTIM) });
+
+    while spi.sspsr.read().tnf().bit_is_set() && state.rx.len() + in_flight < QUEUE_SIZE {
+        match state.tx.pop() {
+            Some(byte) => {
+                spi.sspdr.write(|w| unsafe { w.data().bits(byte as u16) });
+                in_flight += 1;
+            }
+            None => break,
+        }
+    }
+    state.in_flight.store(in_flight, Ordering::Relaxed);
+
+    // The TX interrupt keeps firing while the FIFO is half empty, so it's disabled when there is
+    // nothing to send or no room for the responses, until `kick`.
+    if state.tx.len() == 0 || state.rx.len() + in_flight == QUEUE_SIZE {
+        modify_imsc(spi, |bits| bits & !TXIM);
+    }
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn SPI0_IRQ() {
+    service(unsafe { &*pac::SPI0::ptr() }, &STATES[0]);
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn SPI1_IRQ() {
+    service(unsafe { &*pac::SPI1::ptr() }, &STATES[1]);
+}
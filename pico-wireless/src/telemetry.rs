@@ -0,0 +1,279 @@
+//! Periodic reports of sensor readings over UDP. The application registers named metric sources,
+//! closures returning the current value, and `Telemetry` sends all of them in a single datagram
+//! every interval:
+//!
+//! ```ignore
+//! let mut read_temperature = || Value::from(sensor.temperature());
+//! let mut read_uptime = || Value::from(uptime_s());
+//! let mut telemetry = Telemetry::new(IpV4::from_slice(&[192, 168, 1, 10]), 9000, 10_000);
+//! telemetry.add("temp", &mut read_temperature)?;
+//! telemetry.add("uptime", &mut read_uptime)?;
+//! loop {
+//!     wifi.poll(&mut esp32, now_ms())?;
+//!     telemetry.poll(&mut esp32, now_ms()).ok();
+//! }
+//! ```
+//!
+//! A report is a CBOR map from the metric names to their values, with two extra keys: "seq", the
+//! number of the report, so that the receiver can tell the lost datagrams, and "missed", the
+//! number of reports that couldn't be sent since the previous one, e.g. while the network was
+//! down. CBOR is self-describing, so the receiver doesn't need to know the set of metrics.
+//!
+//! Like the MQTT client, `Telemetry` doesn't block and has no clock of its own: `poll` takes the
+//! current time in milliseconds and has to be called regularly from the application loop. While
+//! the module isn't connected to a network nothing is sent, and the socket is reopened when it
+//! comes back.
+
+use log::{debug, warn};
+
+use crate::pico_wireless::{ConnectionStatus, Esp32, Esp32Error, IpV4, ProtocolMode, Socket};
+use crate::spi_bus::SpiBus;
+
+pub const MAX_METRICS: usize = 16;
+
+// Below the usual MTU.
+const MAX_REPORT_SIZE: usize = 512;
+
+// CBOR major types.
+const CBOR_UINT: u8 = 0;
+const CBOR_NEGATIVE: u8 = 1;
+const CBOR_TEXT: u8 = 3;
+const CBOR_MAP: u8 = 5;
+// Major type 7 with the additional information selecting the simple value or the float size.
+const CBOR_FALSE: u8 = 0xf4;
+const CBOR_TRUE: u8 = 0xf5;
+const CBOR_FLOAT32: u8 = 0xfa;
+
+/// Reading of a metric.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i32),
+    UInt(u32),
+    Float(f32),
+    Bool(bool),
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::UInt(value)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+struct Metric<'a> {
+    name: &'a str,
+    source: &'a mut dyn FnMut() -> Value,
+}
+
+pub struct Telemetry<'a> {
+    metrics: [Option<Metric<'a>>; MAX_METRICS],
+    num_metrics: usize,
+    destination: (IpV4, u16),
+    interval_ms: u32,
+    // None until the first report, which is sent on the first poll.
+    last_report_ms: Option<u32>,
+    seq: u32,
+    // Reports that were due but couldn't be sent since the last sent one.
+    missed: u32,
+    // Obtained when the first report is sent, and again after an error.
+    socket: Option<Socket>,
+}
+
+impl<'a> Telemetry<'a> {
+    /// Send the reports to `ip`:`port` every `interval_ms`. Nothing is sent to the module until
+    /// the first `poll`.
+    pub fn new(ip: IpV4, port: u16, interval_ms: u32) -> Self {
+        Telemetry {
+            metrics: Default::default(),
+            num_metrics: 0,
+            destination: (ip, port),
+            interval_ms,
+            last_report_ms: None,
+            seq: 0,
+            missed: 0,
+            socket: None,
+        }
+    }
+
+    /// Register a metric, read by calling `source` each time a report is sent. The metrics are
+    /// reported in the order in which they were added.
+    pub fn add(
+        &mut self,
+        name: &'a str,
+        source: &'a mut dyn FnMut() -> Value,
+    ) -> Result<(), Esp32Error> {
+        if self.num_metrics == MAX_METRICS {
+            return Err(Esp32Error::ParamTooLong);
+        }
+        self.metrics[self.num_metrics] = Some(Metric { name, source });
+        self.num_metrics += 1;
+        Ok(())
+    }
+
+    pub fn set_destination(&mut self, ip: IpV4, port: u16) {
+        self.destination = (ip, port);
+    }
+
+    pub fn set_interval(&mut self, interval_ms: u32) {
+        self.interval_ms = interval_ms;
+    }
+
+    /// Number of the next report.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Send a report if one is due. Returns whether a report was sent. A report that can't be sent
+    /// is counted as missed and isn't retried before the next interval.
+    pub fn poll<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        now_ms: u32,
+    ) -> Result<bool, Esp32Error> {
+        if let Some(last_report_ms) = self.last_report_ms {
+            if now_ms.wrapping_sub(last_report_ms) < self.interval_ms {
+                return Ok(false);
+            }
+        }
+        self.last_report_ms = Some(now_ms);
+
+        match self.send_report(esp32) {
+            Ok(true) => {
+                self.seq = self.seq.wrapping_add(1);
+                self.missed = 0;
+                Ok(true)
+            }
+            Ok(false) => {
+                self.missed = self.missed.saturating_add(1);
+                Ok(false)
+            }
+            Err(e) => {
+                self.missed = self.missed.saturating_add(1);
+                Err(e)
+            }
+        }
+    }
+
+    // Returns false if the module isn't connected to a network.
+    fn send_report<S: SpiBus>(&mut self, esp32: &mut Esp32<S>) -> Result<bool, Esp32Error> {
+        if esp32.get_conn_status()? != ConnectionStatus::Connected {
+            debug!("Not connected, skipping telemetry report {}", self.seq);
+            // The socket may be gone by the time the connection is back.
+            if let Some(sock) = self.socket.take() {
+                esp32.stop_client(sock).ok();
+            }
+            return Ok(false);
+        }
+
+        let mut report = [0; MAX_REPORT_SIZE];
+        let len = self.encode_report(&mut report)?;
+
+        let sock = match self.socket {
+            Some(sock) => sock,
+            None => {
+                let sock = esp32.get_socket()?;
+                self.socket = Some(sock);
+                sock
+            }
+        };
+
+        let (ip, port) = self.destination;
+        let sent = esp32
+            .start_client(ip, port, sock, ProtocolMode::Udp)
+            .and_then(|()| esp32.insert_data_buf(sock, &report[..len]))
+            .and_then(|()| esp32.send_data_udp(sock));
+        if let Err(e) = sent {
+            warn!("Couldn't send telemetry report {}: {:?}", self.seq, e);
+            esp32.stop_client(sock).ok();
+            self.socket = None;
+            return Err(e);
+        }
+
+        Ok(true)
+    }
+
+    fn encode_report(&mut self, buf: &mut [u8]) -> Result<usize, Esp32Error> {
+        let mut encoder = Encoder { buf, len: 0 };
+        encoder.head(CBOR_MAP, self.num_metrics as u32 + 2)?;
+        encoder.text("seq")?;
+        encoder.value(Value::UInt(self.seq))?;
+        encoder.text("missed")?;
+        encoder.value(Value::UInt(self.missed))?;
+        for metric in self.metrics.iter_mut().flatten() {
+            encoder.text(metric.name)?;
+            encoder.value((metric.source)())?;
+        }
+        Ok(encoder.len)
+    }
+}
+
+// Writes the subset of CBOR (RFC 8949) needed for the reports.
+struct Encoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Encoder<'b> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), Esp32Error> {
+        let end = self.len + bytes.len();
+        if end > self.buf.len() {
+            return Err(Esp32Error::ParamTooLong);
+        }
+        self.buf[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    // The initial byte of an item and its argument, in the shortest form.
+    fn head(&mut self, major: u8, arg: u32) -> Result<(), Esp32Error> {
+        let major = major << 5;
+        match arg {
+            0..=23 => self.push(&[major | arg as u8]),
+            24..=0xff => self.push(&[major | 24, arg as u8]),
+            0x100..=0xffff => {
+                self.push(&[major | 25])?;
+                self.push(&(arg as u16).to_be_bytes())
+            }
+            _ => {
+                self.push(&[major | 26])?;
+                self.push(&arg.to_be_bytes())
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), Esp32Error> {
+        self.head(CBOR_TEXT, text.len() as u32)?;
+        self.push(text.as_bytes())
+    }
+
+    fn value(&mut self, value: Value) -> Result<(), Esp32Error> {
+        match value {
+            Value::UInt(value) => self.head(CBOR_UINT, value),
+            Value::Int(value) if value >= 0 => self.head(CBOR_UINT, value as u32),
+            // Negative integers are encoded as -1 - n.
+            Value::Int(value) => self.head(CBOR_NEGATIVE, !value as u32),
+            Value::Float(value) => {
+                self.push(&[CBOR_FLOAT32])?;
+                self.push(&value.to_bits().to_be_bytes())
+            }
+            Value::Bool(value) => self.push(&[if value { CBOR_TRUE } else { CBOR_FALSE }]),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Log records sent over WiFi as UDP datagrams, e.g. to udp-listener, so that the log of a
+//! deployed board can be read without a USB cable.
+//!
+//! The logger can't talk to the module itself, since the records come from anywhere, including
+//! the ESP32 driver and the interrupt handlers. They are queued instead, and sent by `flush`,
+//! which has to be called regularly from the application loop. The records that can't be queued,
+//! because no destination is set or the queue is full, go to the fallback logger, typically the
+//! USB console.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use log::{Log, Metadata, Record};
+use pico_usb_console_core::{self as console_core, LogQueue};
+
+use crate::pico_wireless::{Esp32, Esp32Error, IpV4, ProtocolMode, Socket};
+use crate::spi_bus::SpiBus;
+
+const QUEUE_SIZE: usize = 2048;
+// Below the usual MTU, and within the receive buffer of udp-listener.
+const MAX_DATAGRAM_SIZE: usize = 1024;
+// Enough to send a full queue. Records logged while sending wait for the next flush.
+const MAX_DATAGRAMS_PER_FLUSH: usize = QUEUE_SIZE / MAX_DATAGRAM_SIZE + 1;
+
+struct State {
+    queue: LogQueue<QUEUE_SIZE>,
+    destination: Option<(IpV4, u16)>,
+    fallback: Option<&'static dyn Log>,
+    // Obtained on the first flush.
+    socket: Option<Socket>,
+}
+
+pub struct UdpLogger {
+    state: Mutex<RefCell<State>>,
+}
+
+static LOGGER: UdpLogger = UdpLogger {
+    state: Mutex::new(RefCell::new(State {
+        queue: LogQueue::new(),
+        destination: None,
+        fallback: None,
+        socket: None,
+    })),
+};
+
+/// The logger, to be passed to `log::set_logger_racy`.
+pub fn get_logger() -> &'static UdpLogger {
+    &LOGGER
+}
+
+/// Logger receiving the records that can't be sent, such as the USB console.
+pub fn set_fallback(fallback: Option<&'static dyn Log>) {
+    cortex_m::interrupt::free(|cs| LOGGER.state.borrow(cs).borrow_mut().fallback = fallback);
+}
+
+/// Host and port to send the records to. Until it is set, all the records go to the fallback.
+pub fn set_destination(destination: Option<(IpV4, u16)>) {
+    cortex_m::interrupt::free(|cs| LOGGER.state.borrow(cs).borrow_mut().destination = destination);
+}
+
+/// Send the queued records, packing as many whole records in a datagram as fit. If sending fails,
+/// the records stay in the queue for the next attempt.
+pub fn flush<S: SpiBus>(esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+    let (destination, socket) = cortex_m::interrupt::free(|cs| {
+        let state = LOGGER.state.borrow(cs).borrow();
+        (state.destination, state.socket)
+    });
+    let (ip, port) = match destination {
+        Some(destination) => destination,
+        None => return Ok(()),
+    };
+    let sock = match socket {
+        Some(sock) => sock,
+        None => {
+            let sock = esp32.get_socket()?;
+            cortex_m::interrupt::free(|cs| {
+                LOGGER.state.borrow(cs).borrow_mut().socket = Some(sock)
+            });
+            sock
+        }
+    };
+
+    let mut datagram = [0; MAX_DATAGRAM_SIZE];
+    for _ in 0..MAX_DATAGRAMS_PER_FLUSH {
+        let len = cortex_m::interrupt::free(|cs| {
+            let state = LOGGER.state.borrow(cs).borrow();
+            let queued = state.queue.peek();
+            let mut len = core::cmp::min(queued.len(), MAX_DATAGRAM_SIZE);
+            // Don't split a record unless it doesn't fit in a datagram by itself.
+            if len < queued.len() {
+                if let Some(end) = queued[..len].iter().rposition(|&byte| byte == b'\n') {
+                    len = end + 1;
+                }
+            }
+            datagram[..len].copy_from_slice(&queued[..len]);
+            len
+        });
+        if len == 0 {
+            break;
+        }
+
+        let sent = esp32
+            .start_client(ip, port, sock, ProtocolMode::Udp)
+            .and_then(|()| esp32.insert_data_buf(sock, &datagram[..len]))
+            .and_then(|()| esp32.send_data_udp(sock));
+        if let Err(e) = sent {
+            // The socket may be gone, e.g. after a reset of the module. Get a new one next time.
+            esp32.stop_client(sock).ok();
+            cortex_m::interrupt::free(|cs| LOGGER.state.borrow(cs).borrow_mut().socket = None);
+            return Err(e);
+        }
+
+        cortex_m::interrupt::free(|cs| LOGGER.state.borrow(cs).borrow_mut().queue.consume(len));
+    }
+
+    Ok(())
+}
+
+impl Log for UdpLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        console_core::enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = console_core::format_record(record);
+        let (queued, fallback) = cortex_m::interrupt::free(|cs| {
+            let mut state = self.state.borrow(cs).borrow_mut();
+            let queued = state.destination.is_some() && state.queue.push_record(line.as_bytes());
+            (queued, state.fallback)
+        });
+        if !queued {
+            if let Some(fallback) = fallback {
+                fallback.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
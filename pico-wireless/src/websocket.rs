@@ -0,0 +1,408 @@
+//! WebSocket client (RFC 6455) over a TCP or TLS connection.
+//!
+//! Each call to `receive` returns at most one complete frame. Fragmented messages aren't
+//! supported, and the `Sec-WebSocket-Accept` header of the handshake response isn't verified.
+
+use core::fmt::Write as _;
+
+use rp2040_hal::pac;
+
+use crate::http::{self, find, HeaderWriter};
+use crate::pico_wireless::{Esp32, Esp32Error, Socket, SocketHandle};
+use crate::spi_bus::SpiBus;
+
+const HANDSHAKE_TIMEOUT_MS: u32 = 10_000;
+const POLL_INTERVAL_MS: u32 = 10;
+// Maximum size of a received frame, including the header.
+const RX_BUF_SIZE: usize = 1024;
+// Payloads are masked in chunks of this size before sending. Has to be a multiple of 4, so that
+// every chunk starts with the first byte of the mask.
+const TX_CHUNK_SIZE: usize = 256;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+// Control frames can't have longer payloads.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Debug, Clone)]
+pub enum WsError {
+    Esp32(Esp32Error),
+    // Only ws:// and wss:// URLs with a host are supported.
+    InvalidUrl,
+    // The server didn't switch protocols. Contains the HTTP status, if there was one.
+    HandshakeFailed(Option<u16>),
+    Timeout,
+    // The frame doesn't fit into the receive buffer, or the control frame payload is too long.
+    FrameTooLarge,
+    InvalidFrame,
+    // Fragmented messages and unknown opcodes.
+    Unsupported,
+    // The connection has been closed by the server.
+    Closed,
+}
+
+impl From<Esp32Error> for WsError {
+    fn from(e: Esp32Error) -> Self {
+        WsError::Esp32(e)
+    }
+}
+
+impl core::fmt::Display for WsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug)]
+pub enum Message<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+    // The server has closed the connection. The close frame has already been answered.
+    Close,
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    header_len: usize,
+    payload_len: usize,
+}
+
+pub struct WebSocket {
+    sock: SocketHandle,
+    rx: [u8; RX_BUF_SIZE],
+    rx_len: usize,
+    // Size of the frame returned by the last `receive`. It is removed from `rx` on the next call.
+    consumed: usize,
+    closed: bool,
+}
+
+impl WebSocket {
+    /// Connect to a ws:// or wss:// URL and perform the opening handshake.
+    pub fn connect<S: SpiBus>(
+        esp32: &mut Esp32<S>,
+        url: &str,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Result<Self, WsError> {
+        let url = http::parse_url_with_schemes(url, "ws://", "wss://")
+            .map_err(|_| WsError::InvalidUrl)?;
+
+        let mut key = [0; 16];
+        for byte in key.iter_mut() {
+            *byte = random_byte();
+        }
+        let mut encoded_key = [0; 24];
+        base64_encode(&key, &mut encoded_key);
+        // Base64 only contains ASCII characters.
+        let encoded_key = core::str::from_utf8(&encoded_key).unwrap();
+
+        let mut header = HeaderWriter::new();
+        write!(
+            &mut header,
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            url.path, url.host, encoded_key
+        )
+        .map_err(|_| WsError::InvalidUrl)?;
+
+        let sock = if url.tls {
+            esp32.connect_tls(url.host, url.port)?
+        } else {
+            esp32.connect_tcp(url.host, url.port)?
+        };
+
+        let mut ws = WebSocket {
+            sock,
+            rx: [0; RX_BUF_SIZE],
+            rx_len: 0,
+            consumed: 0,
+            closed: false,
+        };
+        match ws.handshake(esp32, header.as_bytes(), delay) {
+            Ok(()) => Ok(ws),
+            Err(e) => {
+                ws.sock.close(esp32)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn handshake<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        request: &[u8],
+        delay: &mut cortex_m::delay::Delay,
+    ) -> Result<(), WsError> {
+        let sock = self.sock.socket();
+        send_all(esp32, sock, request)?;
+
+        let mut elapsed_ms = 0;
+        let headers_end = loop {
+            if let Some(pos) = find(&self.rx[..self.rx_len], b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if self.rx_len == RX_BUF_SIZE {
+                return Err(WsError::HandshakeFailed(None));
+            }
+            if esp32.available(sock)? == 0 {
+                if !esp32.socket_connected(sock)? {
+                    return Err(WsError::HandshakeFailed(None));
+                }
+                if elapsed_ms >= HANDSHAKE_TIMEOUT_MS {
+                    return Err(WsError::Timeout);
+                }
+                delay.delay_ms(POLL_INTERVAL_MS);
+                elapsed_ms += POLL_INTERVAL_MS;
+                continue;
+            }
+            self.rx_len += esp32.recv(sock, &mut self.rx[self.rx_len..])?;
+        };
+
+        // "HTTP/1.1 101 Switching Protocols"
+        let status = self.rx[..headers_end]
+            .get(9..12)
+            .and_then(|status| core::str::from_utf8(status).ok())
+            .and_then(|status| status.parse().ok());
+        if status != Some(101) {
+            return Err(WsError::HandshakeFailed(status));
+        }
+
+        // The server may have sent the first frames right after the response.
+        self.consumed = headers_end;
+        Ok(())
+    }
+
+    pub fn send_text<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        text: &str,
+    ) -> Result<(), WsError> {
+        self.send_frame(esp32, OPCODE_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        data: &[u8],
+    ) -> Result<(), WsError> {
+        self.send_frame(esp32, OPCODE_BINARY, data)
+    }
+
+    /// Send a ping. The pong is consumed by `receive`.
+    pub fn ping<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, payload: &[u8]) -> Result<(), WsError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(WsError::FrameTooLarge);
+        }
+        self.send_frame(esp32, OPCODE_PING, payload)
+    }
+
+    fn send_frame<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        opcode: u8,
+        payload: &[u8],
+    ) -> Result<(), WsError> {
+        if self.closed {
+            return Err(WsError::Closed);
+        }
+        write_frame(esp32, self.sock.socket(), opcode, payload)
+    }
+
+    /// Return the next data frame if it has been fully received, without blocking. Pings are
+    /// answered and pongs are dropped.
+    pub fn receive<S: SpiBus>(&mut self, esp32: &mut Esp32<S>) -> Result<Option<Message>, WsError> {
+        if self.closed {
+            return Err(WsError::Closed);
+        }
+        let sock = self.sock.socket();
+
+        let (opcode, payload) = loop {
+            self.consume();
+
+            if let Some(header) = parse_header(&self.rx[..self.rx_len])? {
+                let frame_len = header.header_len + header.payload_len;
+                if frame_len > RX_BUF_SIZE {
+                    return Err(WsError::FrameTooLarge);
+                }
+                if self.rx_len >= frame_len {
+                    self.consumed = frame_len;
+                    let payload = header.header_len..frame_len;
+
+                    match header.opcode {
+                        OPCODE_TEXT | OPCODE_BINARY if header.fin => {
+                            break (header.opcode, payload)
+                        }
+                        OPCODE_PING => {
+                            write_frame(esp32, sock, OPCODE_PONG, &self.rx[payload])?;
+                        }
+                        OPCODE_PONG => {}
+                        OPCODE_CLOSE => {
+                            // Echo the status code, if there is one.
+                            let status_len = header.payload_len.min(2);
+                            let status = &self.rx[payload.start..payload.start + status_len];
+                            write_frame(esp32, sock, OPCODE_CLOSE, status)?;
+                            self.closed = true;
+                            return Ok(Some(Message::Close));
+                        }
+                        // Fragmented messages and unknown opcodes.
+                        _ => return Err(WsError::Unsupported),
+                    }
+                    continue;
+                }
+            }
+
+            if esp32.available(sock)? == 0 {
+                if !esp32.socket_connected(sock)? {
+                    self.closed = true;
+                    return Err(WsError::Closed);
+                }
+                return Ok(None);
+            }
+            self.rx_len += esp32.recv(sock, &mut self.rx[self.rx_len..])?;
+        };
+
+        let payload = &self.rx[payload];
+        if opcode == OPCODE_TEXT {
+            let text = core::str::from_utf8(payload).map_err(|_| WsError::InvalidFrame)?;
+            Ok(Some(Message::Text(text)))
+        } else {
+            Ok(Some(Message::Binary(payload)))
+        }
+    }
+
+    // Remove the frame returned by the last `receive` from the buffer.
+    fn consume(&mut self) {
+        self.rx.copy_within(self.consumed..self.rx_len, 0);
+        self.rx_len -= self.consumed;
+        self.consumed = 0;
+    }
+
+    /// Send a close frame and close the connection without waiting for the reply.
+    pub fn close<S: SpiBus>(self, esp32: &mut Esp32<S>) -> Result<(), WsError> {
+        if !self.closed {
+            // 1000: normal closure.
+            write_frame(
+                esp32,
+                self.sock.socket(),
+                OPCODE_CLOSE,
+                &1000u16.to_be_bytes(),
+            )?;
+        }
+        self.sock.close(esp32)?;
+        Ok(())
+    }
+}
+
+// Returns None if the header hasn't been fully received yet.
+fn parse_header(data: &[u8]) -> Result<Option<FrameHeader>, WsError> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    // Frames sent by the server must not be masked.
+    if data[1] & MASKED != 0 {
+        return Err(WsError::InvalidFrame);
+    }
+
+    let (header_len, payload_len) = match data[1] & 0x7f {
+        126 => match data.get(2..4) {
+            Some(len) => (4, u16::from_be_bytes([len[0], len[1]]) as usize),
+            None => return Ok(None),
+        },
+        // Such frames wouldn't fit into the buffer anyway.
+        127 => return Err(WsError::FrameTooLarge),
+        len => (2, len as usize),
+    };
+
+    Ok(Some(FrameHeader {
+        fin: data[0] & FIN != 0,
+        opcode: data[0] & 0x0f,
+        header_len,
+        payload_len,
+    }))
+}
+
+fn write_frame<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    sock: Socket,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<(), WsError> {
+    let mut header = [0; 8];
+    header[0] = FIN | opcode;
+    let mut header_len = if payload.len() < 126 {
+        header[1] = MASKED | payload.len() as u8;
+        2
+    } else if payload.len() <= u16::MAX as usize {
+        header[1] = MASKED | 126;
+        header[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        4
+    } else {
+        return Err(WsError::FrameTooLarge);
+    };
+
+    let mut mask = [0; 4];
+    for byte in mask.iter_mut() {
+        *byte = random_byte();
+    }
+    header[header_len..header_len + 4].copy_from_slice(&mask);
+    header_len += 4;
+    send_all(esp32, sock, &header[..header_len])?;
+
+    let mut chunk = [0; TX_CHUNK_SIZE];
+    for data in payload.chunks(TX_CHUNK_SIZE) {
+        for (j, &byte) in data.iter().enumerate() {
+            chunk[j] = byte ^ mask[j % 4];
+        }
+        send_all(esp32, sock, &chunk[..data.len()])?;
+    }
+
+    Ok(())
+}
+
+fn send_all<S: SpiBus>(esp32: &mut Esp32<S>, sock: Socket, data: &[u8]) -> Result<(), WsError> {
+    if esp32.send_all(sock, data)? < data.len() {
+        return Err(WsError::Timeout);
+    }
+    Ok(())
+}
+
+// Random byte from the ring oscillator. Good enough for the masking keys, which only need to be
+// unpredictable for the intermediaries.
+fn random_byte() -> u8 {
+    let rosc = unsafe { &*pac::ROSC::ptr() };
+    let mut byte = 0;
+    for _ in 0..8 {
+        byte = (byte << 1) | rosc.randombit.read().randombit().bit() as u8;
+    }
+    byte
+}
+
+fn base64_encode(data: &[u8], out: &mut [u8]) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    for (chunk, out) in data.chunks(3).zip(out.chunks_mut(4)) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for (i, c) in out.iter_mut().enumerate() {
+            *c = if i <= chunk.len() {
+                ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3f]
+            } else {
+                b'='
+            };
+        }
+    }
+}
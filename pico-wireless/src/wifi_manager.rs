@@ -0,0 +1,252 @@
+//! Keeping the module connected to a network. `WifiManager` joins the network, watches the
+//! connection status and re-joins with exponential backoff whenever the connection is lost.
+//!
+//! The manager can hold several networks, e.g. home, lab and a phone hotspot. Before joining it
+//! scans and tries the known networks in range from the strongest one, then the rest in the order
+//! in which they were added, since hidden networks don't show up in the scan. The backoff starts
+//! once all of them have failed.
+//!
+//! Like the MQTT client, the manager has no clock of its own: `poll` takes the current time in
+//! milliseconds and has to be called regularly from the application loop. It doesn't block,
+//! except for the scan, which the firmware runs while `poll` fetches the found networks (see
+//! `Esp32::scan_complete`).
+//!
+//! At boot, `from_flash` picks up the credentials saved by `credentials_store`. If there are none,
+//! they can be obtained by `provisioning::provision` and saved for the next boot.
+
+use log::{info, warn};
+
+use crate::credentials_store;
+use crate::pico_wireless::{ConnectionStatus, Esp32, Esp32Error};
+use crate::provisioning::Credentials;
+use crate::spi_bus::SpiBus;
+
+pub const MAX_NETWORKS: usize = 8;
+
+// Time given to a single attempt to join the network.
+const JOIN_TIMEOUT_MS: u32 = 20_000;
+// Time given to the scan. If it doesn't finish, the networks are tried in their order.
+const SCAN_TIMEOUT_MS: u32 = 10_000;
+const MIN_BACKOFF_MS: u32 = 1000;
+const MAX_BACKOFF_MS: u32 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // No join attempt has been made yet.
+    Idle,
+    // Looking for the known networks in range.
+    Scanning { started_ms: u32 },
+    Joining { started_ms: u32 },
+    Connected,
+    // Waiting before the next attempt.
+    Backoff { started_ms: u32, duration_ms: u32 },
+}
+
+pub struct WifiManager {
+    // In the order of priority.
+    networks: [Option<Credentials>; MAX_NETWORKS],
+    num_networks: usize,
+    // Indices of the networks in the order in which they are tried in the current round.
+    order: [usize; MAX_NETWORKS],
+    // Position in `order` of the network being joined.
+    attempt: usize,
+    state: State,
+    backoff_ms: u32,
+    on_change: Option<fn(bool)>,
+}
+
+impl WifiManager {
+    /// An empty passphrase means an open network. Nothing is sent to the module until the first
+    /// `poll`.
+    pub fn new(ssid: &str, passphrase: &str) -> Result<Self, Esp32Error> {
+        Ok(Self::from_credentials(&Credentials::new(ssid, passphrase)?))
+    }
+
+    pub fn from_credentials(credentials: &Credentials) -> Self {
+        let mut networks = [None; MAX_NETWORKS];
+        networks[0] = Some(*credentials);
+        WifiManager {
+            networks,
+            num_networks: 1,
+            order: [0; MAX_NETWORKS],
+            attempt: 0,
+            state: State::Idle,
+            backoff_ms: MIN_BACKOFF_MS,
+            on_change: None,
+        }
+    }
+
+    /// Manager for the network saved in the flash, or None if no network has been saved.
+    pub fn from_flash() -> Option<Self> {
+        credentials_store::load().map(|credentials| Self::from_credentials(&credentials))
+    }
+
+    /// Add a network with a lower priority than the ones added before. Fails with `ParamTooLong`
+    /// if the SSID or the passphrase is too long, or if there are already `MAX_NETWORKS`.
+    pub fn add_network(&mut self, ssid: &str, passphrase: &str) -> Result<(), Esp32Error> {
+        if self.num_networks == MAX_NETWORKS {
+            return Err(Esp32Error::ParamTooLong);
+        }
+        self.networks[self.num_networks] = Some(Credentials::new(ssid, passphrase)?);
+        self.num_networks += 1;
+        Ok(())
+    }
+
+    /// Register a function that will be called from `poll` with true when the connection is
+    /// established and with false when it is lost.
+    pub fn set_callback(&mut self, callback: Option<fn(bool)>) {
+        self.on_change = callback;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// SSID of the network that is joined or being joined.
+    pub fn current_ssid(&self) -> &str {
+        self.current().ssid()
+    }
+
+    /// Check the connection status and start joining the network if needed.
+    pub fn poll<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, now_ms: u32) -> Result<(), Esp32Error> {
+        match self.state {
+            State::Idle => self.start_round(esp32, now_ms)?,
+
+            State::Scanning { started_ms } => {
+                if esp32.scan_complete()? {
+                    self.rank_by_signal(esp32)?;
+                    self.join(esp32, now_ms)?;
+                } else if now_ms.wrapping_sub(started_ms) >= SCAN_TIMEOUT_MS {
+                    warn!("Scan timed out");
+                    self.join(esp32, now_ms)?;
+                }
+            }
+
+            State::Joining { started_ms } => match esp32.get_conn_status()? {
+                ConnectionStatus::Connected => {
+                    info!("Joined {}", self.current_ssid());
+                    self.state = State::Connected;
+                    self.backoff_ms = MIN_BACKOFF_MS;
+                    self.notify(true);
+                }
+                status @ (ConnectionStatus::ConnectFailed | ConnectionStatus::NoSsidAvail) => {
+                    let error = esp32.connect_error(status)?;
+                    warn!("Couldn't join {}: {:?}", self.current_ssid(), error);
+                    self.try_next(esp32, now_ms)?;
+                }
+                ConnectionStatus::NoShield => {
+                    warn!("Couldn't join {}: no module", self.current_ssid());
+                    self.back_off(now_ms);
+                }
+                status if now_ms.wrapping_sub(started_ms) >= JOIN_TIMEOUT_MS => {
+                    warn!("Timed out joining {}: {:?}", self.current_ssid(), status);
+                    self.try_next(esp32, now_ms)?;
+                }
+                _ => {}
+            },
+
+            State::Connected => {
+                let status = esp32.get_conn_status()?;
+                if status != ConnectionStatus::Connected {
+                    warn!("Lost connection to {}: {:?}", self.current_ssid(), status);
+                    self.notify(false);
+                    self.start_round(esp32, now_ms)?;
+                }
+            }
+
+            State::Backoff {
+                started_ms,
+                duration_ms,
+            } => {
+                if now_ms.wrapping_sub(started_ms) >= duration_ms {
+                    self.start_round(esp32, now_ms)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Try the networks again, starting from the one with the highest priority. With several
+    // networks, scan first to find which of them are in range.
+    fn start_round<S: SpiBus>(
+        &mut self,
+        esp32: &mut Esp32<S>,
+        now_ms: u32,
+    ) -> Result<(), Esp32Error> {
+        for (i, index) in self.order.iter_mut().enumerate() {
+            *index = i;
+        }
+        self.attempt = 0;
+
+        if self.num_networks > 1 {
+            esp32.start_scan()?;
+            self.state = State::Scanning { started_ms: now_ms };
+            Ok(())
+        } else {
+            self.join(esp32, now_ms)
+        }
+    }
+
+    // Put the networks found by the scan first, from the strongest one.
+    fn rank_by_signal<S: SpiBus>(&mut self, esp32: &mut Esp32<S>) -> Result<(), Esp32Error> {
+        let mut rssi = [None; MAX_NETWORKS];
+        for result in esp32.scan_results()? {
+            for (network, rssi) in self.networks.iter().zip(rssi.iter_mut()) {
+                if let Some(network) = network {
+                    if network.ssid().as_bytes() == result.ssid.as_bytes() {
+                        *rssi = core::cmp::max(*rssi, Some(result.rssi));
+                    }
+                }
+            }
+        }
+
+        self.order[..self.num_networks]
+            .sort_unstable_by_key(|&i| (rssi[i].is_none(), rssi[i].map(|rssi| -rssi), i));
+        Ok(())
+    }
+
+    fn join<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, now_ms: u32) -> Result<(), Esp32Error> {
+        let network = *self.current();
+        let (ssid, passphrase) = (network.ssid(), network.passphrase());
+        info!("Joining {ssid}");
+        if passphrase.is_empty() {
+            esp32.set_network(ssid)?;
+        } else {
+            esp32.wifi_set_passphrase(ssid, passphrase)?;
+        }
+        self.state = State::Joining { started_ms: now_ms };
+        Ok(())
+    }
+
+    // Fall back to the next network, or back off if all of them have failed.
+    fn try_next<S: SpiBus>(&mut self, esp32: &mut Esp32<S>, now_ms: u32) -> Result<(), Esp32Error> {
+        if self.attempt + 1 < self.num_networks {
+            self.attempt += 1;
+            self.join(esp32, now_ms)
+        } else {
+            self.attempt = 0;
+            self.back_off(now_ms);
+            Ok(())
+        }
+    }
+
+    fn back_off(&mut self, now_ms: u32) {
+        self.state = State::Backoff {
+            started_ms: now_ms,
+            duration_ms: self.backoff_ms,
+        };
+        self.backoff_ms = core::cmp::min(2 * self.backoff_ms, MAX_BACKOFF_MS);
+    }
+
+    fn notify(&self, connected: bool) {
+        if let Some(on_change) = self.on_change {
+            on_change(connected);
+        }
+    }
+
+    fn current(&self) -> &Credentials {
+        // `order` only contains the indices of the added networks.
+        self.networks[self.order[self.attempt]].as_ref().unwrap()
+    }
+}
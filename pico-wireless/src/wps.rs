@@ -0,0 +1,95 @@
+//! Joining a network without typing the credentials, started by a button press, e.g. button A of
+//! the Pico Wireless Pack.
+//!
+//! The NINA firmware doesn't expose WPS: its SPI protocol has no command for it. Instead, the
+//! push-button join looks for open networks during the same two-minute window as WPS, and joins
+//! the one with the strongest signal. This suits routers with a guest or setup network left open.
+//! Networks with a password have to be set up with `provisioning` instead.
+//!
+//! ```ignore
+//! if button.pressed() {
+//!     let credentials = wps::push_button_join(&mut esp32, &mut delay)?;
+//!     credentials_store::save(&credentials);
+//! }
+//! ```
+
+use log::{info, warn};
+
+use crate::pico_wireless::{now_us, ConnectError, EncryptionType, Esp32, Esp32Error};
+use crate::provisioning::Credentials;
+use crate::spi_bus::SpiBus;
+
+// Walk time of WPS push-button configuration.
+const WALK_TIME_MS: u32 = 120_000;
+const CONNECT_TIMEOUT_MS: u32 = 20_000;
+const SCAN_INTERVAL_MS: u32 = 2000;
+// Open networks tried after each scan, from the strongest one.
+const MAX_CANDIDATES: usize = 8;
+
+/// Join the strongest open network in range, scanning until one appears for up to two minutes.
+/// Returns the credentials of the joined network, so that the caller can save them with
+/// `credentials_store::save`. Fails with `JoinFailed(NetworkNotFound)` if no open network could
+/// be joined in time.
+pub fn push_button_join<S: SpiBus>(
+    esp32: &mut Esp32<S>,
+    delay: &mut cortex_m::delay::Delay,
+) -> Result<Credentials, Esp32Error> {
+    info!("Looking for an open network to join");
+    let start_us = now_us();
+    while now_us().wrapping_sub(start_us) < WALK_TIME_MS * 1000 {
+        // Strongest first.
+        let mut candidates: [Option<(i32, Credentials)>; MAX_CANDIDATES] = [None; MAX_CANDIDATES];
+        for network in esp32.scan()? {
+            if !matches!(network.encryption, EncryptionType::None) {
+                continue;
+            }
+            let ssid = match network.ssid.as_str() {
+                Ok(ssid) if !ssid.is_empty() => ssid,
+                _ => continue,
+            };
+            insert_candidate(&mut candidates, network.rssi, Credentials::new(ssid, "")?);
+        }
+
+        for (rssi, credentials) in candidates.iter().flatten() {
+            info!("Joining {} (RSSI {rssi})", credentials.ssid());
+            match esp32.connect(credentials.ssid(), "", CONNECT_TIMEOUT_MS, delay) {
+                Ok(()) => return Ok(*credentials),
+                Err(Esp32Error::JoinFailed(error)) => {
+                    warn!("Couldn't join {}: {:?}", credentials.ssid(), error);
+                }
+                Err(Esp32Error::ConnectTimeout(status)) => {
+                    warn!("Timed out joining {}: {:?}", credentials.ssid(), status);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        delay.delay_ms(SCAN_INTERVAL_MS);
+    }
+
+    Err(Esp32Error::JoinFailed(ConnectError::NetworkNotFound))
+}
+
+// Keep the candidates sorted by decreasing RSSI, dropping the weakest one when full. The same SSID
+// may be served by several access points, but it's only tried once.
+fn insert_candidate(
+    candidates: &mut [Option<(i32, Credentials)>],
+    rssi: i32,
+    credentials: Credentials,
+) {
+    if candidates
+        .iter()
+        .flatten()
+        .any(|(_, other)| other.ssid() == credentials.ssid())
+    {
+        return;
+    }
+    let position = candidates.iter().position(|candidate| match candidate {
+        Some((other_rssi, _)) => rssi > *other_rssi,
+        None => true,
+    });
+    if let Some(position) = position {
+        candidates[position..].rotate_right(1);
+        candidates[position] = Some((rssi, credentials));
+    }
+}
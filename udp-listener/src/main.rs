@@ -1,14 +1,16 @@
 use std::net::UdpSocket;
 
+// Prints the datagrams as text, e.g. the log records sent by the udp_log module of pico-wireless.
 fn main() -> std::io::Result<()> {
-    let socket = UdpSocket::bind("127.0.0.1:34254")?;
+    let socket = UdpSocket::bind("0.0.0.0:34254")?;
     println!("Opened socket at port 34254");
     let mut buf = [0; 1024];
 
     loop {
         let (amt, src) = socket.recv_from(&mut buf)?;
-        println!("Received {amt} bytes from {src:?}");
-        println!("{:?}", &buf[0..amt]);
-        println!("");
+        let text = String::from_utf8_lossy(&buf[0..amt]);
+        for line in text.lines() {
+            println!("{src}: {line}");
+        }
     }
-}
\ No newline at end of file
+}